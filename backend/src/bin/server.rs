@@ -1,25 +1,167 @@
 
 
-use async_stream::stream;
-use axum::body::StreamBody;
-use axum::extract::{BodyStream, DefaultBodyLimit, Path, Query};
-use axum::http::HeaderMap;
+use axum::http::header::CONTENT_LENGTH;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
 use axum::routing::MethodRouter;
-use axum::{Extension, Router, Server};
-use futures::stream::Stream;
+use axum::{middleware, Router, Server};
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
 use tower::ServiceBuilder;
 use tower_http::cors::{preflight_request_headers, Any, CorsLayer};
+use tower_http::timeout::TimeoutLayer;
 use tower_http::trace::TraceLayer;
+use uma_rs::ids::IdScheme;
+use uma_rs::storage::KeyedMutex;
+use uma_rs::uma::axum::{
+    into_response, protection_router, BodyLimits, IntrospectionState, PatAuthentication, PermissionState,
+    RateLimitConfig, RateLimiter, ResourceRegistrationState, INTROSPECTION_BATCH_BODY_LIMIT, INTROSPECTION_BODY_LIMIT,
+    PERMISSION_BODY_LIMIT, RESOURCE_REGISTRATION_BODY_LIMIT,
+};
+use uma_rs::uma::errors::{ErrorMessage, FromErrorMessage, RESOURCE_NOT_FOUND};
+use uma_rs::uma::pagination::CursorSigner;
+use uma_rs::uma::permission::PermissionTicketStore;
+use uma_rs::uma::resource_registration::RegistrationConfig;
+use uma_rs::uma::token::{RefreshTokenStore, RptStore};
+
+/// How long a request may take to be answered, unless `SMOTHER_REQUEST_TIMEOUT` says otherwise; a request still
+/// unanswered by then is dropped and answered 408.
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a client may take to send the head of a request before its connection is closed, so that clients sending
+/// headers a trickle at a time cannot hold connections open.
+const HEADER_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The request deadline: the value of `SMOTHER_REQUEST_TIMEOUT` in (possibly fractional) seconds, else the default.
+fn request_timeout(env: Option<String>) -> Result<Duration, String> {
+    let Some(value) = env else {
+        return Ok(DEFAULT_REQUEST_TIMEOUT);
+    };
+    return value
+        .parse::<f64>()
+        .ok()
+        .filter(|seconds| seconds.is_finite() && *seconds > 0.0)
+        .map(Duration::from_secs_f64)
+        .ok_or(format!("{value:?} from SMOTHER_REQUEST_TIMEOUT is not a positive number of seconds"));
+}
+
+/// The keys the protection API validates PATs against: the JWK set, as published by the authorization server that
+/// issues the PATs, in the file that `SMOTHER_PAT_JWKS` names. There is no default, as the protection API is not to be
+/// served without authentication.
+fn pat_authentication(env: Option<String>) -> Result<PatAuthentication, String> {
+    let Some(path) = env else {
+        return Err("SMOTHER_PAT_JWKS must name a file holding the JWK set that PATs are signed with".to_string());
+    };
+    let jwks = std::fs::read_to_string(&path)
+        .map_err(|error| format!("{path:?} from SMOTHER_PAT_JWKS cannot be read: {error}"))?;
+    return PatAuthentication::from_jwks(&jwks)
+        .map_err(|error| format!("{path:?} from SMOTHER_PAT_JWKS does not hold a JWK set: {error}"));
+}
+
+/// The address the server binds to unless `--addr` or `SMOTHER_ADDR` says otherwise.
+const DEFAULT_ADDRESS: &str = "127.0.0.1:3000";
+
+/// The socket address to bind to: the value of `--addr` (given as `--addr <address>` or `--addr=<address>`), else
+/// that of the `SMOTHER_ADDR` environment variable, else the default.
+fn bind_address(args: impl IntoIterator<Item = String>, env: Option<String>) -> Result<SocketAddr, String> {
+    let mut args = args.into_iter();
+    let mut flag = None;
+    while let Some(arg) = args.next() {
+        if arg == "--addr" {
+            flag = Some(args.next().ok_or("--addr requires a value, such as 0.0.0.0:3000")?);
+        } else if let Some(value) = arg.strip_prefix("--addr=") {
+            flag = Some(value.to_string());
+        }
+    }
+    let (source, value) = match (flag, env) {
+        (Some(value), _) => ("--addr", value),
+        (None, Some(value)) => ("SMOTHER_ADDR", value),
+        (None, None) => ("the default", DEFAULT_ADDRESS.to_string()),
+    };
+    return value
+        .parse()
+        .map_err(|error| format!("{value:?} from {source} is not a socket address such as 0.0.0.0:3000: {error}"));
+}
+
+/// Answers requests for paths that no route serves.
+async fn not_found() -> Response {
+    return into_response::<()>(Err(ErrorMessage {
+        error_description: Some(Cow::Borrowed("There is no endpoint at this path.")),
+        ..RESOURCE_NOT_FOUND
+    }
+    .into()));
+}
+
+/// Puts error responses that axum or a tower layer produced itself, such as 405 for an unsupported method, 413 for an
+/// oversized body on a route without an UMA extractor, or 408 for a request past its deadline, into the error message
+/// shape of the UMA endpoints, so that clients see a single shape of error. Their status and other headers are kept.
+async fn error_envelope<B>(request: Request<B>, next: Next<B>) -> Response {
+    let response = next.run(request).await;
+    let status = response.status();
+    let is_error = status.is_client_error() || status.is_server_error();
+    if !is_error || response.extensions().get::<FromErrorMessage>().is_some() {
+        return response;
+    }
+    let (mut parts, _) = response.into_parts();
+    let (envelope, body) = into_response::<()>(Err(ErrorMessage::for_status(status).into())).into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.extend(envelope.headers);
+    return Response::from_parts(parts, body);
+}
+
+/// Resolves on Ctrl+C or, on Unix, SIGTERM (as sent by container runtimes).
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("failed to listen for Ctrl+C");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// The request body limits of the routes below, by the patterns they are matched with; other routes get 1KB.
+fn body_limits() -> BodyLimits {
+    return BodyLimits::new(1024)
+        .route("/rreg", RESOURCE_REGISTRATION_BODY_LIMIT)
+        .route("/rreg/", RESOURCE_REGISTRATION_BODY_LIMIT)
+        .route("/rreg/:id", RESOURCE_REGISTRATION_BODY_LIMIT)
+        .route("/perm", PERMISSION_BODY_LIMIT)
+        .route("/introspect", INTROSPECTION_BODY_LIMIT)
+        .route("/introspect/batch", INTROSPECTION_BATCH_BODY_LIMIT);
+}
+
+/// Configuration errors end the process before it starts serving.
+fn exit_on_error<T>(configured: Result<T, String>) -> T {
+    match configured {
+        Ok(value) => value,
+        Err(message) => {
+            eprintln!("{message}");
+            std::process::exit(2);
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() {
     let trace_layer = TraceLayer::new_for_http();
 
     // https://docs.rs/tower-http/0.4.0/tower_http/trace/index.html
-    let limit_layer = DefaultBodyLimit::max(1024);
 
     let cors_layer = CorsLayer::new()
         .allow_credentials(true)
@@ -30,30 +172,278 @@ async fn main() {
         .max_age(Duration::from_secs(60 * 60 * 24))
         .vary(Vec::from_iter(preflight_request_headers()));
 
-    // Other interesting tower layers are retry, timeout, limit, metrics, request_id and validate_request
+    // Requests unanswered by the deadline are answered 408; slow request heads are cut off when serving, below.
+    let request_timeout = exit_on_error(request_timeout(std::env::var("SMOTHER_REQUEST_TIMEOUT").ok()));
+    let timeout_layer = TimeoutLayer::new(request_timeout);
+
+    // Other interesting tower layers are retry, metrics, request_id and validate_request
 
     let layers = ServiceBuilder::new()
+        .layer(middleware::from_fn(error_envelope))
         .layer(trace_layer)
-        .layer(cors_layer)
-        .layer(limit_layer);
+        .layer(timeout_layer)
+        .layer(cors_layer);
+
+    let resource_registration = ResourceRegistrationState {
+        store: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
+        updates: KeyedMutex::new(),
+        ids: IdScheme::Uuid.generator(),
+        config: RegistrationConfig {
+            base_path: "/rreg".to_string(),
+            // E.g. https://as.example.com/rs/{owner}/resource/{id}/policy; see `RegistrationConfig`.
+            policy_uri_template: std::env::var("SMOTHER_POLICY_URI_TEMPLATE").ok(),
+            // Shared by every instance behind a load balancer, so that each accepts the others' page cursors.
+            cursors: std::env::var("SMOTHER_CURSOR_SECRET")
+                .map_or_else(|_| CursorSigner::random(), |secret| CursorSigner::new(secret.as_bytes())),
+            ..Default::default()
+        },
+    };
+
+    let tickets: Arc<tokio::sync::RwLock<PermissionTicketStore<'static>>> =
+        Arc::new(tokio::sync::RwLock::new(HashMap::new()));
+    let permission = PermissionState {
+        tickets: tickets.into(),
+        ids: IdScheme::Uuid.generator(),
+        config: Default::default(),
+    };
+
+    let rpts: Arc<tokio::sync::RwLock<RptStore>> = Arc::new(tokio::sync::RwLock::new(HashMap::new()));
+    let refresh_tokens: Arc<tokio::sync::RwLock<RefreshTokenStore>> =
+        Arc::new(tokio::sync::RwLock::new(HashMap::new()));
+    let introspection = IntrospectionState {
+        rpts: rpts.into(),
+        refresh_tokens: refresh_tokens.into(),
+        config: Default::default(),
+    };
+
+    // Resource servers call the protection API with a PAT, which has to carry the uma_protection scope.
+    let pat_authentication = exit_on_error(pat_authentication(std::env::var("SMOTHER_PAT_JWKS").ok()));
+    // Opaque PATs are introspected at the authorization server, if it has an endpoint for that, sending the credentials
+    // of this server (e.g. Basic czZCaGRSa3F0MzpnWDFmQmF0M2JW) as the Authorization header.
+    let pat_authentication = match std::env::var("SMOTHER_PAT_INTROSPECTION_ENDPOINT") {
+        Ok(endpoint) => pat_authentication
+            .with_introspection(endpoint, std::env::var("SMOTHER_PAT_INTROSPECTION_CREDENTIALS").ok()),
+        Err(_) => pat_authentication,
+    };
+    // A token bucket per PAT subject; over the limit, clients are answered 429 with a Retry-After header.
+    let rate_limiter = RateLimiter::new(RateLimitConfig::default());
 
     let router = Router::new()
+        .merge(protection_router(resource_registration, permission, introspection, pat_authentication, rate_limiter))
         .route(
             "/",
-            MethodRouter::new(), // .get(get_root)
+            MethodRouter::new().fallback(not_found), // .get(get_root)
         )
         .route(
             "/*path",
-            MethodRouter::new(), // .get(get_resource)
-                                 // .put(put_resource)
-                                 // .post(post_resource)
-                                 // .delete(delete_resource)
-        );
+            MethodRouter::new().fallback(not_found), // .get(get_resource)
+                                                     // .put(put_resource)
+                                                     // .post(post_resource)
+                                                     // .delete(delete_resource)
+        )
+        .fallback(not_found);
 
-    let address = SocketAddr::from(([127, 0, 0, 1], 3000));
+    #[cfg(feature = "openapi")]
+    let router = router.route(
+        "/openapi.json",
+        axum::routing::get(|| async { axum::Json(uma_rs::uma::openapi::specification()) }),
+    );
+
+    #[cfg(feature = "json-schema")]
+    let router = router.route(
+        "/schema/:type",
+        axum::routing::get(|axum::extract::Path(name): axum::extract::Path<String>| async move {
+            match uma_rs::uma::json_schema::schema(&name) {
+                Some(schema) => Ok(axum::Json(schema)),
+                None => Err(axum::http::StatusCode::NOT_FOUND),
+            }
+        }),
+    );
+
+    let router = body_limits().apply(router);
+
+    let address = exit_on_error(bind_address(std::env::args().skip(1), std::env::var("SMOTHER_ADDR").ok()));
 
     Server::bind(&address)
-        .serve(router.layer(layers).into_make_service())
+        .http1_header_read_timeout(HEADER_READ_TIMEOUT)
+        .serve(router.layer(layers).into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown_signal())
         .await
         .unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        return args.iter().map(ToString::to_string).collect();
+    }
+
+    #[test]
+    fn bind_address_from_flag_env_or_default() {
+        assert_eq!(bind_address(args(&[]), None), Ok(SocketAddr::from(([127, 0, 0, 1], 3000))));
+        assert_eq!(bind_address(args(&[]), Some("0.0.0.0:8080".into())), Ok(SocketAddr::from(([0, 0, 0, 0], 8080))));
+        assert_eq!(
+            bind_address(args(&["--addr", "[::1]:9000"]), Some("0.0.0.0:8080".into())),
+            Ok("[::1]:9000".parse().unwrap())
+        );
+        assert_eq!(bind_address(args(&["--addr=10.0.0.2:80"]), None), Ok(SocketAddr::from(([10, 0, 0, 2], 80))));
+    }
+
+    #[test]
+    fn request_timeout_from_env_or_default() {
+        assert_eq!(request_timeout(None), Ok(DEFAULT_REQUEST_TIMEOUT));
+        assert_eq!(request_timeout(Some("2.5".into())), Ok(Duration::from_millis(2500)));
+        assert!(request_timeout(Some("0".into())).is_err());
+        assert!(request_timeout(Some("30s".into())).is_err());
+    }
+
+    #[tokio::test]
+    async fn slow_requests_time_out() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let slow = axum::routing::get(|| async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            "too late"
+        });
+        let deadline = request_timeout(Some("0.05".into())).unwrap();
+        let router = Router::new().route("/slow", slow).layer(TimeoutLayer::new(deadline));
+
+        let request = Request::builder().uri("/slow").body(Body::empty()).unwrap();
+        let response = router.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+    }
+
+    async fn envelope(response: Response) -> serde_json::Value {
+        use axum::body::HttpBody;
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        return serde_json::from_slice(&body).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unknown_routes_are_answered_with_an_error_message() {
+        use axum::body::Body;
+        use axum::http::StatusCode;
+        use tower::ServiceExt;
+
+        let router = Router::new()
+            .route("/known", axum::routing::get(|| async { "known" }))
+            .fallback(not_found)
+            .layer(middleware::from_fn(error_envelope));
+
+        let request = Request::builder().uri("/unknown").body(Body::empty()).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()["content-type"], "application/json");
+        assert_eq!(envelope(response).await["error"], "not_found");
+
+        let request = Request::builder().method("DELETE").uri("/known").body(Body::empty()).unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()["allow"], "GET,HEAD");
+        assert_eq!(response.headers()["content-type"], "application/json");
+        assert_eq!(envelope(response).await["error"], "unsupported_method_type");
+    }
+
+    #[tokio::test]
+    async fn oversized_bodies_are_answered_with_an_error_message() {
+        use axum::body::{Body, Bytes};
+        use axum::extract::DefaultBodyLimit;
+        use axum::http::StatusCode;
+        use tower::ServiceExt;
+
+        let router = Router::new()
+            .route("/echo", axum::routing::post(|body: Bytes| async move { body }))
+            .layer(DefaultBodyLimit::max(16))
+            .layer(middleware::from_fn(error_envelope));
+
+        let request = Request::builder().method("POST").uri("/echo").body(Body::from(vec![b'x'; 1024])).unwrap();
+        let response = router.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(response.headers()["content-type"], "application/json");
+        let error = envelope(response).await;
+        assert_eq!(error["error"], "payload_too_large");
+        assert!(error["error_description"].is_string());
+    }
+
+    #[tokio::test]
+    async fn routes_enforce_their_own_body_limits() {
+        use axum::body::{Body, Bytes};
+        use axum::http::StatusCode;
+        use tower::ServiceExt;
+
+        let echo = || axum::routing::post(|body: Bytes| async move { body });
+        let router = Router::new().route("/small", echo()).route("/large", echo()).route("/other", echo());
+        let router = BodyLimits::new(16).route("/small", 64).route("/large", 1024).apply(router);
+        let post = |uri: &str, size: usize| {
+            return Request::builder().method("POST").uri(uri).body(Body::from(vec![b'x'; size])).unwrap();
+        };
+
+        assert_eq!(router.clone().oneshot(post("/small", 64)).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(router.clone().oneshot(post("/small", 512)).await.unwrap().status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(router.clone().oneshot(post("/large", 512)).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(router.clone().oneshot(post("/large", 2048)).await.unwrap().status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(router.oneshot(post("/other", 64)).await.unwrap().status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn body_limits_cover_the_uma_endpoints() {
+        let limits = body_limits();
+        assert_eq!(limits.routes["/rreg/:id"], RESOURCE_REGISTRATION_BODY_LIMIT);
+        assert_eq!(limits.routes["/perm"], PERMISSION_BODY_LIMIT);
+        assert_eq!(limits.routes["/introspect"], INTROSPECTION_BODY_LIMIT);
+        assert_eq!(limits.routes["/introspect/batch"], INTROSPECTION_BATCH_BODY_LIMIT);
+    }
+
+    #[tokio::test]
+    async fn other_framework_errors_get_an_error_code_after_their_status() {
+        use axum::body::Body;
+        use axum::http::StatusCode;
+        use tower::ServiceExt;
+
+        let slow = axum::routing::get(|| async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            "too late"
+        });
+        let router = Router::new()
+            .route("/slow", slow)
+            .layer(TimeoutLayer::new(Duration::from_millis(50)))
+            .layer(middleware::from_fn(error_envelope));
+
+        let request = Request::builder().uri("/slow").body(Body::empty()).unwrap();
+        let response = router.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(envelope(response).await["error"], "request_timeout");
+    }
+
+    #[test]
+    fn pat_keys_are_required() {
+        assert!(pat_authentication(None).is_err());
+
+        let path = std::env::temp_dir().join(format!("smother-pat-jwks-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{ "keys": [] }"#).unwrap();
+        assert!(pat_authentication(Some(path.display().to_string())).is_ok());
+        std::fs::write(&path, "[]").unwrap();
+        let error = pat_authentication(Some(path.display().to_string())).unwrap_err();
+        assert!(error.contains("does not hold a JWK set"), "{error}");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn invalid_bind_address_is_reported() {
+        let error = bind_address(args(&["--addr", "localhost"]), None).unwrap_err();
+        assert!(error.contains("\"localhost\" from --addr"), "{error}");
+
+        let error = bind_address(args(&[]), Some("127.0.0.1:http".into())).unwrap_err();
+        assert!(error.contains("SMOTHER_ADDR"), "{error}");
+
+        assert!(bind_address(args(&["--addr"]), None).is_err());
+    }
+}
@@ -0,0 +1,72 @@
+//! The time as the authorization server sees it, for expiring tokens, tickets and store entries. Whatever compares
+//! against the current time asks a `Clock` rather than the system directly, so that tests can inject a `FakeClock` and
+//! move it forward past an expiry instead of sleeping until it.
+
+use std::fmt;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// The current time, in seconds since the Unix epoch.
+    fn now(&self) -> i64;
+}
+
+/// The system's wall-clock time; the clock used in production.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> i64 {
+        return time::OffsetDateTime::now_utc().unix_timestamp();
+    }
+}
+
+/// A clock that stands still until it is told otherwise. Only meant for tests.
+#[derive(Debug)]
+pub struct FakeClock {
+    now: AtomicI64,
+}
+
+impl FakeClock {
+    pub fn new(now: i64) -> Self {
+        Self {
+            now: AtomicI64::new(now),
+        }
+    }
+
+    /// Starts at the current system time, for tests that mix faked and real timestamps (e.g. those of signed tokens).
+    pub fn at_system_time() -> Self {
+        return Self::new(SystemClock.now());
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.now.fetch_add(by.as_secs() as i64, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, now: i64) {
+        self.now.store(now, Ordering::Relaxed);
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> i64 {
+        return self.now.load(Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn fake_clock_moves_only_when_told() {
+        let clock = FakeClock::new(1_700_000_000);
+        assert_eq!(clock.now(), 1_700_000_000);
+
+        clock.advance(Duration::from_secs(90));
+        assert_eq!(clock.now(), 1_700_000_090);
+        clock.set(1_600_000_000);
+        assert_eq!(clock.now(), 1_600_000_000);
+    }
+}
@@ -0,0 +1,243 @@
+//! Generation of the identifiers the authorization server hands out, such as resource `_id`s, permission tickets and
+//! tokens. Handlers receive an `IdGenerator` rather than calling `Uuid::new_v4` directly, so that a server can choose
+//! between UUIDs and time-ordered ULIDs through `IdScheme`, and tests can inject a `SeededIdGenerator` and assert on
+//! exact identifiers.
+//!
+//! Once handed out, identifiers of different kinds get types of their own (`Ticket`, `ResourceId`, `RptValue`), so
+//! that passing one where another is expected does not compile.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use ulid::{Generator, Ulid};
+use uuid::{Builder, Uuid};
+
+pub trait IdGenerator: Send + Sync {
+    fn generate(&self) -> String;
+}
+
+/// The kinds of identifiers a server can be configured to hand out.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum IdScheme {
+    #[default]
+    Uuid,
+    Ulid,
+}
+
+impl IdScheme {
+    pub fn generator(self) -> Arc<dyn IdGenerator> {
+        match self {
+            Self::Uuid => Arc::new(UuidGenerator),
+            Self::Ulid => Arc::new(UlidGenerator::new()),
+        }
+    }
+}
+
+/// Random version 4 UUIDs; the generator used in production.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UuidGenerator;
+
+impl IdGenerator for UuidGenerator {
+    fn generate(&self) -> String {
+        Uuid::new_v4().to_string()
+    }
+}
+
+/// ULIDs, which start with their creation time, so that identifiers (and the store listings keyed by them) sort in
+/// the order they were handed out. Within the same millisecond the random part is incremented rather than redrawn, to
+/// keep that order.
+#[derive(Default)]
+pub struct UlidGenerator {
+    generator: Mutex<Generator>,
+}
+
+impl UlidGenerator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl IdGenerator for UlidGenerator {
+    fn generate(&self) -> String {
+        let mut generator = self.generator.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        // Overflow needs 2^80 identifiers within one millisecond.
+        generator.generate().expect("ULID random part overflowed").to_string()
+    }
+}
+
+/// Declares a newtype for one kind of identifier. It dereferences to, displays as and (de)serializes as the plain
+/// string, and compares equal to it.
+macro_rules! identifier {
+    ($(#[$attribute:meta])* $name:ident) => {
+        $(#[$attribute])*
+        #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+        #[serde(transparent)]
+        pub struct $name(String);
+
+        impl $name {
+            /// The identifier as a store keyed by strings holds it.
+            pub fn as_string(&self) -> &String {
+                &self.0
+            }
+
+            pub fn into_inner(self) -> String {
+                self.0
+            }
+        }
+
+        impl From<String> for $name {
+            fn from(value: String) -> Self {
+                Self(value)
+            }
+        }
+
+        impl From<&str> for $name {
+            fn from(value: &str) -> Self {
+                Self(value.to_string())
+            }
+        }
+
+        impl Deref for $name {
+            type Target = str;
+
+            fn deref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl PartialEq<str> for $name {
+            fn eq(&self, other: &str) -> bool {
+                self.0 == other
+            }
+        }
+
+        impl PartialEq<&str> for $name {
+            fn eq(&self, other: &&str) -> bool {
+                self.0 == *other
+            }
+        }
+    };
+}
+
+identifier! {
+    /// A permission ticket, handed to the client in a permission challenge and redeemed at the token endpoint.
+    Ticket
+}
+
+identifier! {
+    /// The _id under which a resource is registered.
+    ResourceId
+}
+
+identifier! {
+    /// The value of an RPT, or of the refresh token presented for introspection in its place.
+    RptValue
+}
+
+/// When an identifier was handed out, if it records that: ULIDs do, UUIDs do not.
+pub fn issued_at(id: &str) -> Option<SystemTime> {
+    let ulid = Ulid::from_string(id).ok()?;
+    Some(UNIX_EPOCH + Duration::from_millis(ulid.timestamp_ms()))
+}
+
+/// Version 4 UUIDs whose "random" bits are drawn from a splitmix64 sequence, so that the same seed always yields the
+/// same identifiers in the same order. Only meant for tests.
+#[derive(Debug)]
+pub struct SeededIdGenerator {
+    state: AtomicU64,
+}
+
+impl SeededIdGenerator {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: AtomicU64::new(seed),
+        }
+    }
+
+    // https://prng.di.unimi.it/splitmix64.c
+    fn next(&self) -> u64 {
+        let mut z = self
+            .state
+            .fetch_add(0x9E3779B97F4A7C15, Ordering::Relaxed)
+            .wrapping_add(0x9E3779B97F4A7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        return z ^ (z >> 31);
+    }
+}
+
+impl IdGenerator for SeededIdGenerator {
+    fn generate(&self) -> String {
+        let mut bytes = [0u8; 16];
+        bytes[..8].copy_from_slice(&self.next().to_be_bytes());
+        bytes[8..].copy_from_slice(&self.next().to_be_bytes());
+        Builder::from_random_bytes(bytes).into_uuid().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn seeded_generator_is_reproducible() {
+        let first = SeededIdGenerator::new(42);
+        let second = SeededIdGenerator::new(42);
+
+        let first: Vec<String> = (0..5).map(|_| first.generate()).collect();
+        let second: Vec<String> = (0..5).map(|_| second.generate()).collect();
+
+        assert_eq!(first, second);
+        assert_ne!(first[0], first[1]);
+        assert_ne!(first[0], SeededIdGenerator::new(43).generate());
+        assert_eq!(Uuid::parse_str(&first[0]).unwrap().get_version_num(), 4);
+    }
+
+    #[test]
+    fn uuid_generator_is_random() {
+        let ids = UuidGenerator;
+
+        let first = Uuid::parse_str(&ids.generate()).unwrap();
+        let second = Uuid::parse_str(&ids.generate()).unwrap();
+
+        assert_eq!(first.get_version_num(), 4);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn ulid_generator_sorts_by_creation() {
+        let ids = UlidGenerator::new();
+
+        let generated: Vec<String> = (0..1000).map(|_| ids.generate()).collect();
+
+        let mut sorted = generated.clone();
+        sorted.sort();
+        assert_eq!(generated, sorted);
+        sorted.dedup();
+        assert_eq!(sorted.len(), generated.len());
+        assert!(generated.iter().all(|id| ulid::Ulid::from_string(id).is_ok()));
+    }
+
+    #[test]
+    fn schemes_select_their_generator() {
+        for scheme in [IdScheme::Uuid, IdScheme::Ulid] {
+            let ids = scheme.generator();
+            let generated: HashSet<String> = (0..1000).map(|_| ids.generate()).collect();
+            assert_eq!(generated.len(), 1000);
+        }
+
+        assert!(Uuid::parse_str(&IdScheme::Uuid.generator().generate()).is_ok());
+        assert!(ulid::Ulid::from_string(&IdScheme::Ulid.generator().generate()).is_ok());
+    }
+}
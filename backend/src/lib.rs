@@ -8,6 +8,15 @@
     // const_trait_impl,
 )]
 
-mod oauth;
-mod storage;
-mod uma;
+pub mod clock;
+pub mod ids;
+pub mod oauth;
+mod oidc;
+pub mod resource;
+pub mod storage;
+pub mod uma;
+
+pub use oidc::jwk_thumbprint;
+
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_support;
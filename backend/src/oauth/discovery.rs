@@ -37,6 +37,7 @@
 //! as well as further chapters of the specification yet to be implemented
 
 use oxiri::Iri;
+use serde::{Deserialize, Serialize};
 
 /// https://datatracker.ietf.org/doc/html/draft-ietf-oauth-discovery-08#section-2
 ///
@@ -46,6 +47,7 @@ use oxiri::Iri;
 ///
 /// Additional authorization server metadata parameters MAY also be used.
 /// Some are defined by other specifications, such as OpenID Connect Discovery 1.0 [OpenID.Discovery].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct AuthorizationServerMetadata {
     // REQUIRED.  The authorization server's issuer identifier, which is
     // a URL that uses the "https" scheme and has no query or fragment
@@ -75,16 +77,19 @@ pub struct AuthorizationServerMetadata {
     // encryption keys are made available, a "use" (public key use)
     // parameter value is REQUIRED for all keys in the referenced JWK Set
     // to indicate each key's intended usage.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub jwks_uri: Option<Iri<String>>,
 
     // OPTIONAL.  URL of the authorization server's OAuth 2.0 Dynamic
     // Client Registration endpoint [RFC7591].
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub registration_endpoint: Option<Iri<String>>,
 
     // RECOMMENDED.  JSON array containing a list of the OAuth 2.0
     // [RFC6749] "scope" values that this authorization server supports.
     // Servers MAY choose not to advertise some supported scope values
     // even when this parameter is used.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub scopes_supported: Option<Vec<String>>,
 
     // REQUIRED.  JSON array containing a list of the OAuth 2.0
@@ -100,6 +105,7 @@ pub struct AuthorizationServerMetadata {
     // [OAuth.Responses].  If omitted, the default is "["query",
     // "fragment"]".  The response mode value "form_post" is also defined
     // in OAuth 2.0 Form Post Response Mode [OAuth.Post].
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub response_modes_supported: Option<Vec<String>>,
 
     // OPTIONAL.  JSON array containing a list of the OAuth 2.0 grant
@@ -108,6 +114,7 @@ pub struct AuthorizationServerMetadata {
     // parameter defined by "OAuth 2.0 Dynamic Client Registration
     // Protocol" [RFC7591].  If omitted, the default value is
     // "["authorization_code", "implicit"]".
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub grant_types_supported: Option<Vec<String>>,
 
     // OPTIONAL.  JSON array containing a list of client authentication
@@ -116,6 +123,7 @@ pub struct AuthorizationServerMetadata {
     // parameter defined in Section 2 of [RFC7591].  If omitted, the
     // default is "client_secret_basic" -- the HTTP Basic Authentication
     // Scheme specified in Section 2.3.1 of OAuth 2.0 [RFC6749].
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub token_endpoint_auth_methods_supported: Option<Vec<String>>,
 
     // OPTIONAL.  JSON array containing a list of the JWS signing
@@ -127,6 +135,7 @@ pub struct AuthorizationServerMetadata {
     // "token_endpoint_auth_methods_supported" entry.  No default
     // algorithms are implied if this entry is omitted.  Servers SHOULD
     // support "RS256".  The value "none" MUST NOT be used.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub token_endpoint_auth_signing_alg_values_supported: Option<Vec<String>>,
 
     // OPTIONAL.  URL of a page containing human-readable information
@@ -135,12 +144,14 @@ pub struct AuthorizationServerMetadata {
     // does not support Dynamic Client Registration, then information on
     // how to register clients needs to be provided in this
     // documentation.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub service_documentation: Option<Iri<String>>,
 
     // OPTIONAL.  Languages and scripts supported for the user interface,
     // represented as a JSON array of BCP47 [RFC5646] language tag
     // values.  If omitted, the set of supported languages and scripts is
     // unspecified.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub ui_locales_supported: Option<Vec<String>>,
 
     // OPTIONAL.  URL that the authorization server provides to the
@@ -152,6 +163,7 @@ pub struct AuthorizationServerMetadata {
     // "op_policy_uri", appearing to be OpenID-specific, its usage in
     // this specification is actually referring to a general OAuth 2.0
     // feature that is not specific to OpenID Connect.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub op_policy_uri: Option<Iri<String>>,
 
     // OPTIONAL.  URL that the authorization server provides to the
@@ -162,10 +174,12 @@ pub struct AuthorizationServerMetadata {
     // "op_tos_uri", appearing to be OpenID-specific, its usage in this
     // specification is actually referring to a general OAuth 2.0 feature
     // that is not specific to OpenID Connect.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub op_tos_uri: Option<Iri<String>>,
 
     // OPTIONAL.  URL of the authorization server's OAuth 2.0 revocation
     // endpoint [RFC7009].
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub revocation_endpoint: Option<Iri<String>>,
 
     // OPTIONAL.  JSON array containing a list of client authentication
@@ -175,6 +189,7 @@ pub struct AuthorizationServerMetadata {
     // [IANA.OAuth.Parameters].  If omitted, the default is
     // "client_secret_basic" -- the HTTP Basic Authentication Scheme
     // specified in Section 2.3.1 of OAuth 2.0 [RFC6749].
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub revocation_endpoint_auth_methods_supported: Option<Vec<String>>,
 
     // OPTIONAL.  JSON array containing a list of the JWS signing
@@ -186,10 +201,12 @@ pub struct AuthorizationServerMetadata {
     // specified in the "revocation_endpoint_auth_methods_supported"
     // entry.  No default algorithms are implied if this entry is
     // omitted.  The value "none" MUST NOT be used.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub revocation_endpoint_auth_signing_alg_values_supported: Option<Vec<String>>,
 
     // OPTIONAL.  URL of the authorization server's OAuth 2.0
     // introspection endpoint [RFC7662].
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub introspection_endpoint: Option<Iri<String>>,
 
     // OPTIONAL.  JSON array containing a list of client authentication
@@ -201,6 +218,7 @@ pub struct AuthorizationServerMetadata {
     // values are and will remain distinct, due to Section 7.2.)  If
     // omitted, the set of supported authentication methods MUST be
     // determined by other means.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub introspection_endpoint_auth_methods_supported: Option<Vec<String>>,
 
     // OPTIONAL.  JSON array containing a list of the JWS signing
@@ -212,6 +230,7 @@ pub struct AuthorizationServerMetadata {
     // specified in the "introspection_endpoint_auth_methods_supported"
     // entry.  No default algorithms are implied if this entry is
     // omitted.  The value "none" MUST NOT be used.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub introspection_endpoint_auth_signing_alg_values_supported: Option<Vec<String>>,
 
     // OPTIONAL.  JSON array containing a list of PKCE [RFC7636] code
@@ -221,6 +240,7 @@ pub struct AuthorizationServerMetadata {
     // challenge method values are those registered in the IANA "PKCE
     // Code Challenge Methods" registry [IANA.OAuth.Parameters].  If
     // omitted, the authorization server does not support PKCE.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub code_challenge_methods_supported: Option<Vec<String>>,
 }
 
@@ -1 +1,2 @@
 pub mod discovery;
+pub mod response_mode;
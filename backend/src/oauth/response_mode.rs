@@ -0,0 +1,238 @@
+//! https://openid.net/specs/oauth-v2-multiple-response-types-1_0.html#ResponseModes
+//! https://openid.net/specs/oauth-v2-form-post-response-mode-1_0.html
+//!
+//! The Response Mode determines how the Authorization Server returns result parameters from the Authorization
+//! Endpoint: encoded in the query string or in the fragment of the redirection URI, or as HTML form values that are
+//! auto-submitted in the User Agent, and thus transmitted via the HTTP POST method to the Client.
+
+use std::fmt;
+use std::str::FromStr;
+
+use http::header::{CACHE_CONTROL, CONTENT_TYPE, LOCATION};
+use http::{Response, StatusCode};
+use oxiri::Iri;
+use thiserror::Error;
+
+use crate::uma::errors::{catch_errors, ErrorMessage};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseMode {
+    /// In this mode, Authorization Response parameters are encoded in the query string added to the redirect_uri when
+    /// redirecting back to the Client.
+    Query,
+
+    /// In this mode, Authorization Response parameters are encoded in the fragment added to the redirect_uri when
+    /// redirecting back to the Client.
+    Fragment,
+
+    /// In this mode, Authorization Response parameters are encoded as HTML form values that are auto-submitted in the
+    /// User Agent, and thus are transmitted via the HTTP POST method to the Client, with the result parameters being
+    /// encoded in the body using the application/x-www-form-urlencoded format.
+    FormPost,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ResponseModeError {
+    #[error("{0:?} is not a supported response mode")]
+    Unsupported(String),
+}
+
+impl ResponseMode {
+    /// The default Response Mode for a Response Type: fragment encoding whenever the response contains a token issued
+    /// from the Authorization Endpoint, query encoding otherwise.
+    pub fn default_for(response_type: &str) -> Self {
+        if response_type.split(' ').any(|value| value == "token" || value == "id_token") {
+            return Self::Fragment;
+        }
+        return Self::Query;
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Query => "query",
+            Self::Fragment => "fragment",
+            Self::FormPost => "form_post",
+        }
+    }
+}
+
+impl FromStr for ResponseMode {
+    type Err = ResponseModeError;
+
+    fn from_str(response_mode: &str) -> Result<Self, Self::Err> {
+        match response_mode {
+            "query" => Ok(Self::Query),
+            "fragment" => Ok(Self::Fragment),
+            "form_post" => Ok(Self::FormPost),
+            other => Err(ResponseModeError::Unsupported(other.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for ResponseMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Returns the authorization response `parameters` (e.g. code and state) to the client at `redirect_uri`: as a 302
+/// redirect for the query and fragment modes, and as a 200 HTML page for form_post. The page is not to be cached, as
+/// it carries the response parameters.
+pub fn render_authorization_response(
+    redirect_uri: &Iri<String>,
+    response_mode: ResponseMode,
+    parameters: &[(&str, &str)],
+) -> Result<Response<String>, Response<ErrorMessage>> {
+    let encoded = form_urlencoded::Serializer::new(String::new()).extend_pairs(parameters).finish();
+    // A redirection URI must not carry a fragment; any it does carry is dropped rather than sent back.
+    let base = match redirect_uri.as_str().split_once('#') {
+        Some((base, _)) => base,
+        None => redirect_uri.as_str(),
+    };
+
+    let location = match response_mode {
+        ResponseMode::Query if redirect_uri.query().is_some() => format!("{base}&{encoded}"),
+        ResponseMode::Query => format!("{base}?{encoded}"),
+        ResponseMode::Fragment => format!("{base}#{encoded}"),
+        ResponseMode::FormPost => {
+            let response = Response::builder()
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, "text/html;charset=UTF-8")
+                .header(CACHE_CONTROL, "no-store")
+                .body(auto_submitting_form(base, parameters));
+            return catch_errors(response);
+        }
+    };
+
+    let response = Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, to_uri(&location))
+        .header(CACHE_CONTROL, "no-store")
+        .body(String::new());
+
+    return catch_errors(response);
+}
+
+/// https://openid.net/specs/oauth-v2-form-post-response-mode-1_0.html#FormPostResponseExample
+fn auto_submitting_form(action: &str, parameters: &[(&str, &str)]) -> String {
+    let mut form = String::from("<html>\n <head><title>Submit This Form</title></head>\n");
+    form.push_str(" <body onload=\"javascript:document.forms[0].submit()\">\n");
+    form.push_str(&format!("  <form method=\"post\" action=\"{}\">\n", escape_html(action)));
+    for (name, value) in parameters {
+        form.push_str(&format!(
+            "    <input type=\"hidden\" name=\"{}\" value=\"{}\"/>\n",
+            escape_html(name),
+            escape_html(value)
+        ));
+    }
+    form.push_str("  </form>\n </body>\n</html>\n");
+    return form;
+}
+
+/// Escapes text for use in an HTML attribute value delimited by double quotes.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            c => escaped.push(c),
+        }
+    }
+    return escaped;
+}
+
+/// Maps an IRI to a URI by percent-encoding its non-ASCII characters (RFC 3987, Section 3.1), as a Location header
+/// cannot carry them.
+fn to_uri(iri: &str) -> String {
+    let mut uri = String::with_capacity(iri.len());
+    for c in iri.chars() {
+        if c.is_ascii() {
+            uri.push(c);
+            continue;
+        }
+        let mut buffer = [0; 4];
+        for byte in c.encode_utf8(&mut buffer).bytes() {
+            uri.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    return uri;
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn iri(uri: &str) -> Iri<String> {
+        return Iri::parse(uri.to_string()).unwrap();
+    }
+
+    #[test]
+    fn query() {
+        let parameters = [("code", "SplxlOBeZQQYbYS6WxSbIA"), ("state", "xyz")];
+
+        let redirect_uri = iri("https://client.example.com/cb");
+        let response = render_authorization_response(&redirect_uri, ResponseMode::Query, &parameters).unwrap();
+
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(response.headers()[LOCATION], "https://client.example.com/cb?code=SplxlOBeZQQYbYS6WxSbIA&state=xyz");
+
+        let redirect_uri = iri("https://client.example.com/cb?app=photoz#ignored");
+        let parameters = [("error", "access_denied"), ("error_description", "The user said no & left")];
+        let response = render_authorization_response(&redirect_uri, ResponseMode::Query, &parameters).unwrap();
+        assert_eq!(
+            response.headers()[LOCATION],
+            "https://client.example.com/cb?app=photoz&error=access_denied&error_description=The+user+said+no+%26+left"
+        );
+    }
+
+    #[test]
+    fn fragment() {
+        let parameters = [("access_token", "2YotnFZFEjr1zCsicMWpAA"), ("state", "xyz"), ("token_type", "example")];
+
+        let redirect_uri = iri("http://example.com/cb");
+        let response = render_authorization_response(&redirect_uri, ResponseMode::Fragment, &parameters).unwrap();
+
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(
+            response.headers()[LOCATION],
+            "http://example.com/cb#access_token=2YotnFZFEjr1zCsicMWpAA&state=xyz&token_type=example"
+        );
+
+        let unicode = iri("https://café.example/cb");
+        let response = render_authorization_response(&unicode, ResponseMode::Fragment, &[]).unwrap();
+        assert_eq!(response.headers()[LOCATION], "https://caf%C3%A9.example/cb#");
+    }
+
+    #[test]
+    fn form_post() {
+        let parameters = [("code", "SplxlOBeZQQYbYS6WxSbIA"), ("state", "\"><script>alert(1)</script>")];
+
+        let redirect_uri = iri("https://client.example.org/callback");
+        let response = render_authorization_response(&redirect_uri, ResponseMode::FormPost, &parameters).unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/html;charset=UTF-8");
+        assert_eq!(response.headers()[CACHE_CONTROL], "no-store");
+        let html = response.body();
+        assert!(html.contains("<body onload=\"javascript:document.forms[0].submit()\">"));
+        assert!(html.contains("<form method=\"post\" action=\"https://client.example.org/callback\">"));
+        assert!(html.contains("<input type=\"hidden\" name=\"code\" value=\"SplxlOBeZQQYbYS6WxSbIA\"/>"));
+        assert!(html.contains("value=\"&quot;&gt;&lt;script&gt;alert(1)&lt;/script&gt;\""));
+        assert!(!html.contains("<script>"));
+    }
+
+    #[test]
+    fn modes() {
+        assert_eq!("form_post".parse(), Ok(ResponseMode::FormPost));
+        assert_eq!("web_message".parse::<ResponseMode>(), Err(ResponseModeError::Unsupported("web_message".into())));
+        assert_eq!(ResponseMode::FormPost.to_string(), "form_post");
+        assert_eq!(ResponseMode::default_for("code"), ResponseMode::Query);
+        assert_eq!(ResponseMode::default_for("code id_token"), ResponseMode::Fragment);
+        assert_eq!(ResponseMode::default_for("token"), ResponseMode::Fragment);
+    }
+}
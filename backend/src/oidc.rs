@@ -1,30 +1,57 @@
-
-use std::time::{SystemTime, UNIX_EPOCH};
-
-use futures::{TryFutureExt, try_join, future::ready, FutureExt};
-use jwt_compact::{UntrustedToken, jwk::JsonWebKey};
-use no_way::{jwk::{JWKSet, JWK}, jws::Unverified, Json};
+use base64ct::{Base64UrlUnpadded, Encoding};
+use futures::{TryFutureExt, try_join, future::ready};
+use http::Response;
+use jwt_compact::{
+  alg::{Ed25519, Es256, Rsa, RsaPublicKey},
+  jwk::{JsonWebKey, JwkError},
+  Algorithm, AlgorithmExt, Claims, Empty, ParseError, UntrustedToken, ValidationError,
+};
 use oxiri::Iri;
-use serde::{Deserialize, Serialize};
-use serde_json::{from_str as from_json, Value};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::sync::OnceLock;
+use std::time::Duration;
 use thiserror::Error;
+use tracing::debug;
 
-#[derive(Debug, Deserialize)]
-struct Cnf {
-  jkt: String
+use crate::clock::{Clock, SystemClock};
+use crate::resource::Agent;
+use crate::uma::errors::{ErrorMessage, INSUFFICIENT_SCOPE, INVALID_TOKEN, TEMPORARILY_UNAVAILABLE};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cnf {
+  pub jkt: String
 }
 
-#[derive(Debug, Deserialize)]
-struct AccessToken {
-  webid: Iri<String>,
-  iss: Iri<String>,
-  sub: String,
-  aud: Vec<String>,
-  azp: Iri<String>,
-  nbf: Option<i64>,
-  iat: i64,
-  exp: i64,
-  cnf: Cnf,
+/// The Solid-OIDC specific claims of an access token. The registered time claims (`iat`, `exp`, `nbf`) are handled by
+/// the surrounding `Claims`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccessToken {
+  pub webid: Iri<String>,
+  pub iss: Iri<String>,
+  pub sub: String,
+  pub aud: Vec<String>,
+  pub azp: Iri<String>,
+  pub cnf: Cnf,
+}
+
+/// A single JSON Web Key as published in an issuer's JWK set. Only the members needed to select and use a key are
+/// kept; the key material itself is delegated to `jwt_compact`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Jwk {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub kid: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub alg: Option<String>,
+  #[serde(flatten)]
+  pub key: JsonWebKey<'static>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JwkSet {
+  pub keys: Vec<Jwk>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -32,11 +59,65 @@ struct IssuerConfig {
   jwks_uri: Iri<String>,
 }
 
-#[derive(Debug, Deserialize)]
+const OIDC_ISSUER: &str = "http://www.w3.org/ns/solid/terms#oidcIssuer";
+
+/// The parts of a WebID profile document that matter for authentication.
+#[derive(Debug, PartialEq)]
 struct WebidDoc {
   issuers: Vec<Iri<String>>,
 }
 
+impl WebidDoc {
+
+  /// Reads the solid:oidcIssuer values of the WebID's node from a JSON-LD profile. This is no full JSON-LD processor:
+  /// node objects are looked up at the top level, in a top-level array or in @graph, and the predicate is recognised
+  /// as the full IRI, as the compact IRI solid:oidcIssuer or as the term oidcIssuer, the spellings used by Solid
+  /// servers. Relative @id values are resolved against the WebID.
+  fn from_json_ld(webid: &Iri<String>, doc: &Value) -> Self {
+
+    let resolve = |id: &str| webid.resolve(id).ok();
+
+    let nodes: Vec<&Value> = match doc {
+      Value::Array(nodes) => nodes.iter().collect(),
+      Value::Object(node) => match node.get("@graph") {
+        Some(Value::Array(nodes)) => nodes.iter().chain([doc]).collect(),
+        _ => vec![doc],
+      },
+      _ => vec![],
+    };
+
+    let subject = nodes.into_iter().find(|node| {
+      node.get("@id").and_then(Value::as_str).and_then(resolve).as_ref() == Some(webid)
+    });
+
+    let values = subject.into_iter()
+      .flat_map(|node| [OIDC_ISSUER, "solid:oidcIssuer", "oidcIssuer"].map(|predicate| node.get(predicate)))
+      .flatten()
+      .flat_map(|value| match value {
+        Value::Array(values) => values.iter().collect(),
+        value => vec![value],
+      });
+
+    let issuers = values
+      .filter_map(|value| match value {
+        Value::String(id) => Some(id.as_str()),
+        Value::Object(node) => node.get("@id").and_then(Value::as_str),
+        _ => None,
+      })
+      .filter_map(resolve)
+      .collect();
+
+    WebidDoc { issuers }
+
+  }
+
+  /// Issuer identifiers are compared as strings, except for a trailing slash some issuers add.
+  fn allows(&self, issuer: &Iri<String>) -> bool {
+    self.issuers.iter().any(|allowed| allowed.trim_end_matches('/') == issuer.trim_end_matches('/'))
+  }
+
+}
+
 // Of the signature and MAC algorithms specified in JSON Web Algorithms
 // [JWA], only HMAC SHA-256 ("HS256") and "none" MUST be implemented by
 // conforming JWT implementations.  It is RECOMMENDED that
@@ -45,99 +126,453 @@ struct WebidDoc {
 // hash algorithm ("ES256").  Support for other algorithms and key sizes
 // is OPTIONAL.
 
-// Support for encrypted JWTs is OPTIONAL. 
+// Support for encrypted JWTs is OPTIONAL.
+
+/// The algorithms signatures can be verified for, each with its own key type: RSA keys for RS256, P-256 keys for ES256
+/// and Ed25519 keys for EdDSA.
+pub const SUPPORTED_ALGS: &[&str] = &["RS256", "ES256", "EdDSA"];
+
+/// The algorithms accepted when no other allowlist is configured: those Solid-OIDC implementations are RECOMMENDED to
+/// support. EdDSA is opt-in, by listing it in `Verifier::allowed_algs`.
+pub const DEFAULT_ALLOWED_ALGS: &[&str] = &["RS256", "ES256"];
 
-async fn authenticate(token_str: &str) -> Result<(), AuthError> {
+/// The time an outbound request may take when no other timeout is configured.
+pub const DEFAULT_FETCH_TIMEOUT: Duration = Duration::from_secs(5);
 
-  let token = from_json::<Unverified<Json<AccessToken>>>(&token_str).map_err(AuthError::InvalidToken)?;
+/// Verification settings for access tokens.
+#[derive(Debug, Clone)]
+pub struct Verifier<'v> {
+  /// Values of the token header's alg that are accepted. Tokens using any other algorithm are rejected before their
+  /// signature is looked at, and "none" is rejected even if listed here.
+  pub allowed_algs: &'v [&'v str],
+  /// How often a fetch of the issuer configuration or JWK set is retried after a failure that may be transient: a
+  /// timeout, a connection failure or a 5xx response. Other failures (4xx responses, invalid JSON) are final.
+  pub fetch_retries: u32,
+  /// The delay before the first retry, doubled for every retry after it. Each delay is jittered down by up to half,
+  /// so that verifiers that failed together do not all retry together.
+  pub fetch_backoff: Duration,
+  /// How long a single outbound request (for the issuer configuration, JWK set or WebID document) may take before it
+  /// is abandoned with `AuthError::Timeout`, so that a slow issuer cannot stall token validation.
+  pub fetch_timeout: Duration,
+  /// The name of the discovery document under the issuer's /.well-known/, which must list the jwks_uri:
+  /// `OPENID_CONFIGURATION` for OpenID providers, `OAUTH_AUTHORIZATION_SERVER` or `UMA2_CONFIGURATION` for others.
+  pub well_known: &'v str,
+  /// What the iat, exp and nbf claims of a token are compared against.
+  pub clock: &'v dyn Clock,
+}
 
-  if !token..aud.iter().any(|s| s == &"solid") { return Err(AuthError::InvalidAudience) }
-  if !token.aud.iter().any(|s| s == &token.azp) { return Err(AuthError::InvalidAudience) }
+impl Default for Verifier<'static> {
+  fn default() -> Self {
+    Verifier {
+      allowed_algs: DEFAULT_ALLOWED_ALGS,
+      fetch_retries: 2,
+      fetch_backoff: Duration::from_millis(200),
+      fetch_timeout: DEFAULT_FETCH_TIMEOUT,
+      well_known: OPENID_CONFIGURATION,
+      clock: &SystemClock,
+    }
+  }
+}
 
-  verify_times(&token).await?;
+/// Verifies an access token with the default settings.
+pub async fn authenticate(token_str: &str) -> Result<AccessToken, AuthError> {
+  Verifier::default().authenticate(token_str).await
+}
 
-  let webid_doc = get_webid_doc(&token.webid).and_then(
-    |doc| ready(doc.issuers.contains(&token.iss).then_some(doc).ok_or(AuthError::IssuerNotAllowed))
-  );
-  
-  let jwks = verify_signature(&token);
+/// The scope a PAT must carry: https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-federated-authz-2.0.html#pat
+pub const PROTECTION_SCOPE: &str = "uma_protection";
 
-  // SHOULD also check client_id document / webid
+/// The claims of a PAT that is a JWT that matter to the protection API.
+#[derive(Debug, Deserialize)]
+struct PatClaims {
+  sub: Option<String>,
+  #[serde(default)]
+  scope: String,
+}
 
-  let (webid_doc, jwks) = try_join!(webid_doc, jwks)?;
+/// The members of an introspection response (RFC 7662) for a PAT that matter to the protection API.
+#[derive(Debug, Deserialize)]
+struct PatIntrospection {
+  active: bool,
+  sub: Option<String>,
+  #[serde(default)]
+  scope: String,
+}
 
-  Ok(())
+/// A validated PAT: the resource owner named by its sub claim and the scopes it carries, uma_protection among them.
+#[derive(Debug, Clone)]
+pub struct VerifiedPat {
+  pub owner: Agent,
+  pub scopes: Vec<String>,
+}
+
+impl VerifiedPat {
+  fn new(sub: Option<&str>, scope: &str) -> Result<Self, AuthError> {
+    let owner = sub.and_then(|sub| Agent::new(sub).ok()).ok_or(AuthError::MissingSubject)?;
+    let scopes: Vec<String> = scope.split_whitespace().map(str::to_owned).collect();
+    if !scopes.iter().any(|scope| scope == PROTECTION_SCOPE) { return Err(AuthError::InsufficientScope) }
+    Ok(Self { owner, scopes })
+  }
+}
+
+/// Whether a bearer token is a JWT, which a PAT middleware can validate offline, rather than an opaque token, which it
+/// has to introspect.
+pub fn is_jwt(token_str: &str) -> bool {
+  UntrustedToken::new(token_str).is_ok()
+}
+
+impl Verifier<'_> {
+
+  pub async fn authenticate(&self, token_str: &str) -> Result<AccessToken, AuthError> {
+
+    let untrusted = self.parse(token_str)?;
+
+    let claims = untrusted.deserialize_claims_unchecked::<AccessToken>().map_err(AuthError::InvalidToken)?;
+    let token = &claims.custom;
+
+    if !token.aud.iter().any(|s| s == "solid") { return Err(AuthError::InvalidAudience) }
+    if !token.aud.iter().any(|s| s == token.azp.as_str()) { return Err(AuthError::InvalidAudience) }
+
+    verify_times(&claims, self.clock).await?;
+
+    let webid_doc = get_webid_doc(&token.webid, self.fetch_timeout).and_then(
+      |doc| ready(doc.allows(&token.iss).then_some(doc).ok_or(AuthError::IssuerNotAllowed))
+    );
+
+    let jwks = self.verify_signature(&token.iss, &untrusted);
+
+    // SHOULD also check client_id document / webid
+
+    try_join!(webid_doc, jwks)?;
+
+    Ok(claims.custom)
+
+  }
+
+  /// Verifies a JWT that is not an access token, such as an ID token pushed as a claim token: that its iss claim names
+  /// one of `trusted_issuers` and its aud claim one of `audiences`, and its algorithm, lifetime and signature, the
+  /// latter against the JWK set of the trusted issuer. Tokens of any other issuer are rejected before anything is
+  /// fetched. The WebID is left to the caller. Returns every claim but the timestamps.
+  pub async fn verify_claims(&self, token_str: &str, trusted_issuers: &[Iri<String>], audiences: &[String])
+    -> Result<Map<String, Value>, AuthError> {
+
+    let untrusted = self.parse(token_str)?;
+
+    let claims = untrusted.deserialize_claims_unchecked::<Map<String, Value>>().map_err(AuthError::InvalidToken)?;
+
+    let Some(iss) = claims.custom.get("iss").and_then(Value::as_str) else { return Err(AuthError::MissingIssuer) };
+    // Issuer identifiers are compared as strings, except for a trailing slash some issuers add.
+    let issuer = trusted_issuers.iter().find(|trusted| trusted.trim_end_matches('/') == iss.trim_end_matches('/'));
+    let Some(issuer) = issuer else { return Err(AuthError::UntrustedIssuer) };
+
+    let aud = match claims.custom.get("aud") {
+      Some(Value::String(aud)) => vec![aud.as_str()],
+      Some(Value::Array(aud)) => aud.iter().filter_map(Value::as_str).collect(),
+      _ => vec![],
+    };
+    if !aud.iter().any(|aud| audiences.iter().any(|audience| audience == aud)) {
+      return Err(AuthError::UnexpectedAudience)
+    }
+
+    verify_times(&claims, self.clock).await?;
+    self.verify_signature(issuer, &untrusted).await?;
+
+    Ok(claims.custom)
+
+  }
+
+  /// Validates a PAT that is a JWT without an introspection round-trip, against `jwks`, the keys of the authorization
+  /// server that issued it: its algorithm, lifetime and signature, and that its scope includes uma_protection. Returns
+  /// the resource owner, named by the sub claim, and the scopes.
+  pub async fn validate_pat_offline(&self, jwt: &str, jwks: &JwkSet) -> Result<VerifiedPat, AuthError> {
+
+    let untrusted = self.parse(jwt)?;
+
+    let claims = untrusted.deserialize_claims_unchecked::<PatClaims>().map_err(AuthError::InvalidToken)?;
+
+    verify_times(&claims, self.clock).await?;
+    verify_with_keys(&untrusted, &jwks.keys)?;
+
+    VerifiedPat::new(claims.custom.sub.as_deref(), &claims.custom.scope)
+
+  }
+
+  /// Validates an opaque PAT by introspecting it (RFC 7662) at the `endpoint` of the authorization server that issued
+  /// it, authenticating with the Authorization header value `credentials`, if any: that it is active, and that its
+  /// scope includes uma_protection. Returns the resource owner, named by the sub member, and the scopes.
+  pub async fn introspect_pat(&self, token: &str, endpoint: &str, credentials: Option<&str>)
+    -> Result<VerifiedPat, AuthError> {
+
+    let form = [("token", token), ("token_type_hint", "access_token")];
+    let introspection: PatIntrospection = self.send_json(|| {
+      let request = client().post(endpoint).form(&form);
+      match credentials {
+        Some(credentials) => request.header(reqwest::header::AUTHORIZATION, credentials),
+        None => request,
+      }
+    }).await.map_err(|error| error.into_auth_error(AuthError::NoIntrospection, AuthError::InvalidIntrospection))?;
+
+    if !introspection.active { return Err(AuthError::InactiveToken) }
+
+    VerifiedPat::new(introspection.sub.as_deref(), &introspection.scope)
+
+  }
+
+  /// Rejects tokens using an algorithm outside the allowlist before anything else is looked at.
+  fn parse<'t>(&self, token_str: &'t str) -> Result<UntrustedToken<'t>, AuthError> {
+
+    let untrusted = UntrustedToken::new(token_str).map_err(AuthError::MalformedToken)?;
+
+    let alg = untrusted.algorithm();
+    if alg.eq_ignore_ascii_case("none") || !self.allowed_algs.contains(&alg) {
+      return Err(AuthError::DisallowedAlgorithm(alg.to_owned()))
+    }
+
+    Ok(untrusted)
+
+  }
+
+  async fn verify_signature(&self, issuer: &Iri<String>, token: &UntrustedToken<'_>) -> Result<(), AuthError> {
+
+    let jwks = self.get_issuer_jwks(issuer).await?;
+
+    verify_with_keys(token, &jwks)
+
+  }
+
+  async fn get_issuer_jwks(&self, issuer: &Iri<String>) -> Result<Vec<Jwk>, AuthError> {
+
+    let cfg_uri = discovery_uri(issuer, self.well_known);
+
+    let IssuerConfig { jwks_uri, ..} = self.fetch_json(&cfg_uri).await
+      .map_err(|error| error.into_auth_error(AuthError::NoIssuerConfig, AuthError::InvalidIssuerConfig))?;
+
+    let JwkSet { keys } = self.fetch_json(jwks_uri.as_str()).await
+      .map_err(|error| error.into_auth_error(AuthError::NoJwks, AuthError::InvalidJwks))?;
+
+    Ok(keys)
+
+  }
+
+  /// Fetches `uri` as JSON, retrying transient failures with exponential backoff.
+  async fn fetch_json<T: DeserializeOwned>(&self, uri: &str) -> Result<T, FetchError> {
+
+    self.send_json(|| client().get(uri)).await
+
+  }
+
+  /// Sends the request `build` builds and reads the response as JSON, retrying transient failures with exponential
+  /// backoff, each time with a request built anew.
+  async fn send_json<T: DeserializeOwned>(&self, build: impl Fn() -> reqwest::RequestBuilder) -> Result<T, FetchError> {
+
+    let mut attempt = 0;
+
+    loop {
+      let sent = build().timeout(self.fetch_timeout).send().await;
+      let error = match sent.and_then(reqwest::Response::error_for_status) {
+        Ok(response) => return response.json::<T>().await.map_err(FetchError::Invalid),
+        Err(error) => error,
+      };
+      if attempt == self.fetch_retries || !is_transient(&error) { return Err(FetchError::Unavailable(error)) }
+      tokio::time::sleep(self.backoff(attempt)).await;
+      attempt += 1;
+    }
+
+  }
+
+  fn backoff(&self, attempt: u32) -> Duration {
+    let delay = self.fetch_backoff.saturating_mul(2u32.saturating_pow(attempt));
+    delay / 2 + delay.mul_f64(fastrand::f64() / 2.0)
+  }
 
 }
 
-async fn verify_times(&AccessToken {iat, exp, nbf, ..}: &AccessToken) -> Result<(), AuthError> {
+async fn verify_times<T>(claims: &Claims<T>, clock: &dyn Clock) -> Result<(), AuthError> {
 
-  let now = time::OffsetDateTime::now_utc().unix_timestamp();
+  let now = clock.now();
 
-  if iat > now { return Err(AuthError::TokenIssuedInFuture) }
-  if exp < now { return Err(AuthError::TokenExpired) }
-  if let Some(nbf) = nbf { if nbf > now { return Err(AuthError::TokenNotYetValid) } }
+  let (Some(iat), Some(exp)) = (claims.issued_at, claims.expiration) else { return Err(AuthError::MissingTimestamps) };
+
+  if iat.timestamp() > now { return Err(AuthError::TokenIssuedInFuture) }
+  if exp.timestamp() < now { return Err(AuthError::TokenExpired) }
+  if let Some(nbf) = claims.not_before { if nbf.timestamp() > now { return Err(AuthError::TokenNotYetValid) } }
 
   Ok(())
 
 }
 
-async fn verify_signature(token: &AccessToken) -> Result<(), AuthError> {
+/// Verifies the signature of `token` against whichever of `keys` it names, or could only have been signed with.
+fn verify_with_keys(token: &UntrustedToken<'_>, keys: &[Jwk]) -> Result<(), AuthError> {
 
-  let jwks = get_issuer_jwks(&token.iss).await?;
+  let alg = token.algorithm();
+  if !SUPPORTED_ALGS.contains(&alg) { return Err(AuthError::UnsupportedAlgorithm(alg.to_owned())) }
 
-  let jwk = jwks.iter().find(|jwk| jwk.specified.common.key_id == token.).ok_or(AuthError::NoMatchingJwk)?;
+  // Keys of another type, or published for another algorithm, are passed over rather than failed on.
+  let usable: Vec<Jwk> = keys.iter()
+    .filter(|jwk| is_usable_for(&jwk.key, alg) && jwk.alg.as_deref().map_or(true, |jwk_alg| jwk_alg == alg))
+    .cloned()
+    .collect();
+  let jwk = find_key(&usable, token.header().key_id.as_deref())?;
 
-  let mut token = UntrustedToken::new(token_str);
+  let validated = match alg {
+    "RS256" => {
+      let key = RsaPublicKey::try_from(&jwk.key).map_err(AuthError::InvalidJwk)?;
+      Rsa::rs256().validator::<Empty>(&key).validate(token)
+    },
+    "ES256" => {
+      let key = <Es256 as Algorithm>::VerifyingKey::try_from(&jwk.key).map_err(AuthError::InvalidJwk)?;
+      Es256.validator::<Empty>(&key).validate(token)
+    },
+    "EdDSA" => {
+      let key = <Ed25519 as Algorithm>::VerifyingKey::try_from(&jwk.key).map_err(AuthError::InvalidJwk)?;
+      Ed25519.validator::<Empty>(&key).validate(token)
+    },
+    alg => return Err(AuthError::UnsupportedAlgorithm(alg.to_owned())),
+  };
 
-  token.validate_signature_with_key(jwk)?;
+  validated.map_err(AuthError::InvalidSignature)?;
 
   Ok(())
 
 }
 
-const well_known: &'static str = ".well-known/openid-configuration";
+/// The key a token names by its kid or, when it names none, the only key there is. A set of several keys without a kid
+/// to choose by is ambiguous, rather than tried one by one.
+fn find_key<'a>(keys: &'a [Jwk], kid: Option<&str>) -> Result<&'a Jwk, AuthError> {
+
+  match (kid, keys) {
+    (Some(kid), keys) => keys.iter().find(|jwk| jwk.kid.as_deref() == Some(kid)).ok_or(AuthError::NoMatchingJwk),
+    (None, [jwk]) => Ok(jwk),
+    (None, []) => Err(AuthError::NoMatchingJwk),
+    (None, _) => Err(AuthError::AmbiguousJwk),
+  }
 
-async fn get_issuer_jwks(issuer: &Iri<String>) -> Result<Vec<JWK>, AuthError> {
-  
-  let client = reqwest::Client::new();
+}
 
-  let cfg_uri =  issuer.trim_end_matches('/').to_owned() + well_known;
-  
-  let IssuerConfig { jwks_uri, ..} = client.get(cfg_uri)
-    .send().map_err(AuthError::NoIssuerConfig).await?
-    .json::<IssuerConfig>().map_err(AuthError::InvalidIssuerConfig).await?;
-    
-  let JWKSet { keys } = client.get(jwks_uri.as_str())
-    .send().map_err(AuthError::NoJwks).await?
-    .json::<JWKSet>().map_err(AuthError::InvalidJwks).await?;
+/// Whether `jwk` holds the type of key that `alg` signs with.
+fn is_usable_for(jwk: &JsonWebKey<'_>, alg: &str) -> bool {
 
-  Ok(keys)
+  match (alg, jwk) {
+    ("RS256", JsonWebKey::Rsa { .. }) => true,
+    ("ES256", JsonWebKey::EllipticCurve { curve, .. }) => curve == "P-256",
+    ("EdDSA", JsonWebKey::KeyPair { curve, .. }) => curve == "Ed25519",
+    _ => false,
+  }
 
 }
 
-async fn get_webid_doc(webid: &Iri<String>) -> Result<WebidDoc, AuthError> {
-  
-  let client = reqwest::Client::new();
-  
-  let WebidDoc { jwks_uri, ..} = client.get(cfg_uri)
-    .send().map_err(AuthError::NoIssuerConfig).await?
-    .json::<IssuerConfig>().map_err(AuthError::InvalidIssuerConfig).await?;
-    
-  let jwks = client.get(jwks_uri.as_str())
-    .send().map_err(AuthError::NoJwks).await?
-    .json::<Vec<JsonWebKey>>().map_err(AuthError::InvalidJwks).await?;
+/// The JWK SHA-256 Thumbprint of RFC 7638, base64url-encoded without padding: the digest of a JSON object holding only
+/// the key type's required members, in lexicographic order and without whitespace. Private members never contribute,
+/// so a key pair and its public key share a thumbprint. This is the value a DPoP-bound token carries as `cnf.jkt`.
+pub fn jwk_thumbprint(jwk: &JsonWebKey<'_>) -> String {
+
+  let b64 = |bytes: &[u8]| Base64UrlUnpadded::encode_string(bytes);
+
+  // serde_json writes the members in the order given here: its string escaping is needed, its map ordering is not.
+  let members = match jwk {
+    JsonWebKey::Rsa { modulus, public_exponent, .. } => vec![
+      ("e", json!(b64(public_exponent))), ("kty", json!("RSA")), ("n", json!(b64(modulus))),
+    ],
+    JsonWebKey::EllipticCurve { curve, x, y, .. } => vec![
+      ("crv", json!(curve)), ("kty", json!("EC")), ("x", json!(b64(x))), ("y", json!(b64(y))),
+    ],
+    JsonWebKey::KeyPair { curve, x, .. } => vec![
+      ("crv", json!(curve)), ("kty", json!("OKP")), ("x", json!(b64(x))),
+    ],
+    JsonWebKey::Symmetric { secret } => vec![
+      ("k", json!(b64(secret))), ("kty", json!("oct")),
+    ],
+    jwk => return b64(&jwk.thumbprint::<Sha256>()),
+  };
+
+  let canonical = members.iter()
+    .map(|(member, value)| format!("\"{member}\":{value}"))
+    .collect::<Vec<_>>()
+    .join(",");
 
-  Ok(jwks)
+  b64(&Sha256::digest(format!("{{{canonical}}}")))
 
 }
 
+/// https://openid.net/specs/openid-connect-discovery-1_0.html#ProviderConfig
+pub const OPENID_CONFIGURATION: &str = "openid-configuration";
+/// https://www.rfc-editor.org/rfc/rfc8414#section-3
+pub const OAUTH_AUTHORIZATION_SERVER: &str = "oauth-authorization-server";
+/// https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-grant-2.0.html#as-config
+pub const UMA2_CONFIGURATION: &str = "uma2-configuration";
+
+/// The location of the discovery document named `suffix`, appended to the issuer with exactly one slash on either side
+/// of /.well-known, whether or not the issuer ends in a slash or the suffix starts with one.
+pub fn discovery_uri(issuer: &Iri<String>, suffix: &str) -> String {
+  format!("{}/.well-known/{}", issuer.trim_end_matches('/'), suffix.trim_start_matches('/'))
+}
+
+/// The client all outbound requests share, for its connection pool. Its timeout is only a backstop: every request
+/// sets its own.
+pub(crate) fn client() -> &'static reqwest::Client {
+  static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+  CLIENT.get_or_init(|| {
+    reqwest::Client::builder().timeout(DEFAULT_FETCH_TIMEOUT).build().expect("cannot build HTTP client")
+  })
+}
+
+/// Only JSON-LD profiles are understood, so that is all the request accepts; servers that can only serve Turtle
+/// respond 406 and the WebID is treated as unreachable.
+async fn get_webid_doc(webid: &Iri<String>, timeout: Duration) -> Result<WebidDoc, AuthError> {
+
+  let doc = client().get(webid.as_str())
+    .header(reqwest::header::ACCEPT, "application/ld+json")
+    .timeout(timeout)
+    .send().and_then(|response| ready(response.error_for_status())).map_err(timed_out_or(AuthError::NoWebidDoc)).await?
+    .json::<Value>().map_err(timed_out_or(AuthError::InvalidWebidDoc)).await?;
+
+  Ok(WebidDoc::from_json_ld(webid, &doc))
+
+}
+
+enum FetchError {
+  /// The document could not be retrieved, not even after retrying.
+  Unavailable(reqwest::Error),
+  /// The document was retrieved, but is not the JSON expected.
+  Invalid(reqwest::Error),
+}
+
+impl FetchError {
+  fn into_auth_error(self, unavailable: fn(reqwest::Error) -> AuthError, invalid: fn(reqwest::Error) -> AuthError)
+    -> AuthError {
+    match self {
+      FetchError::Unavailable(error) => timed_out_or(unavailable)(error),
+      FetchError::Invalid(error) => timed_out_or(invalid)(error),
+    }
+  }
+}
+
+/// Sets timeouts apart from the other failures of a request.
+fn timed_out_or(otherwise: fn(reqwest::Error) -> AuthError) -> impl Fn(reqwest::Error) -> AuthError {
+  move |error| if error.is_timeout() { AuthError::Timeout(error) } else { otherwise(error) }
+}
+
+/// Timeouts, connection failures and server errors may well be over by the next attempt; anything else will not be.
+fn is_transient(error: &reqwest::Error) -> bool {
+  error.is_timeout() || error.is_connect() || error.status().is_some_and(|status| status.is_server_error())
+}
+
 #[derive(Error, Debug)]
-enum AuthError {
+pub enum AuthError {
+    #[error("Access token is not a well-formed JWT")]
+    MalformedToken(#[source] ParseError),
     #[error("Invalid access token")]
-    InvalidToken(#[source] serde_json::Error),
+    InvalidToken(#[source] ValidationError),
     #[error("Token audience does not include solid and client_id")]
     InvalidAudience,
+    #[error("Token lacks an iss claim naming its issuer")]
+    MissingIssuer,
+    #[error("Token issuer is not trusted")]
+    UntrustedIssuer,
+    #[error("Token audience does not include an accepted audience")]
+    UnexpectedAudience,
+    #[error("Token lacks an iat or exp claim")]
+    MissingTimestamps,
     #[error("Token is issued in the future")]
     TokenIssuedInFuture,
     #[error("Token is expired")]
@@ -154,5 +589,510 @@ enum AuthError {
     NoJwks(#[source] reqwest::Error),
     #[error("Jwk set is invalid")]
     InvalidJwks(#[source] reqwest::Error),
+    #[error("No jwk in the issuer's jwk set matches the token")]
+    NoMatchingJwk,
+    #[error("Token names no kid, and the issuer's jwk set holds several keys it could be signed with")]
+    AmbiguousJwk,
+    #[error("Matching jwk cannot be used for the token's algorithm")]
+    InvalidJwk(#[source] JwkError),
+    #[error("Token algorithm {0} is not supported")]
+    UnsupportedAlgorithm(String),
+    #[error("Token algorithm {0} is not allowed")]
+    DisallowedAlgorithm(String),
+    #[error("Token signature is invalid")]
+    InvalidSignature(#[source] ValidationError),
+    #[error("Cannot retrieve webid document")]
+    NoWebidDoc(#[source] reqwest::Error),
+    #[error("Webid document is invalid")]
+    InvalidWebidDoc(#[source] reqwest::Error),
+    #[error("Token issuer is not listed in the webid document")]
     IssuerNotAllowed,
-}
\ No newline at end of file
+    #[error("Token scope does not include uma_protection")]
+    InsufficientScope,
+    #[error("Token lacks a sub claim naming the resource owner")]
+    MissingSubject,
+    #[error("Cannot introspect token")]
+    NoIntrospection(#[source] reqwest::Error),
+    #[error("Introspection response is invalid")]
+    InvalidIntrospection(#[source] reqwest::Error),
+    #[error("Token is not active")]
+    InactiveToken,
+    #[error("Request to the issuer or webid host timed out")]
+    Timeout(#[source] reqwest::Error),
+}
+
+/// Error responses carry a stable error code and a generic description only: what exactly failed, and any URL or
+/// message of an issuer or WebID host involved, is logged rather than sent back. Tokens that cannot be verified are
+/// invalid_token (401), and PATs without uma_protection insufficient_scope (403). Failures to reach the issuer or WebID
+/// host that may well be over by the next attempt are temporarily_unavailable (503).
+impl From<AuthError> for Response<ErrorMessage> {
+  fn from(error: AuthError) -> Self {
+
+    debug!(%error, "token rejected");
+
+    let message = match &error {
+      AuthError::InsufficientScope => INSUFFICIENT_SCOPE.with_required_scope(PROTECTION_SCOPE),
+      AuthError::Timeout(_) => TEMPORARILY_UNAVAILABLE,
+      AuthError::NoIssuerConfig(fetch) | AuthError::NoJwks(fetch) | AuthError::NoWebidDoc(fetch)
+        | AuthError::NoIntrospection(fetch) if is_transient(fetch) => TEMPORARILY_UNAVAILABLE,
+      AuthError::TokenExpired | AuthError::TokenIssuedInFuture | AuthError::TokenNotYetValid => ErrorMessage {
+        error_description: Some(Cow::Borrowed("The access token has expired or is not valid yet.")),
+        ..INVALID_TOKEN
+      },
+      AuthError::MalformedToken(_) | AuthError::InvalidToken(_) | AuthError::InvalidAudience
+        | AuthError::MissingIssuer | AuthError::UntrustedIssuer | AuthError::UnexpectedAudience
+        | AuthError::MissingTimestamps | AuthError::NoIssuerConfig(_)
+        | AuthError::InvalidIssuerConfig(_) | AuthError::NoJwksUri | AuthError::NoJwks(_) | AuthError::InvalidJwks(_)
+        | AuthError::NoMatchingJwk | AuthError::AmbiguousJwk | AuthError::InvalidJwk(_)
+        | AuthError::UnsupportedAlgorithm(_) | AuthError::DisallowedAlgorithm(_) | AuthError::InvalidSignature(_)
+        | AuthError::NoWebidDoc(_) | AuthError::InvalidWebidDoc(_) | AuthError::IssuerNotAllowed
+        | AuthError::MissingSubject | AuthError::NoIntrospection(_) | AuthError::InvalidIntrospection(_)
+        | AuthError::InactiveToken => INVALID_TOKEN,
+    };
+
+    message.into()
+
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::clock::FakeClock;
+  use crate::resource::Resource;
+  use crate::test_support::{access_token, claims, Keypair, MockIssuer, INTROSPECTION_CREDENTIALS, OPAQUE_PAT};
+
+  #[tokio::test]
+  async fn fixture_token_authenticates() {
+
+    let key = Keypair::es256("key-1");
+    let issuer = MockIssuer::start(vec![key.jwk()]).await;
+    let token = access_token(&issuer);
+
+    let jwt = key.sign(&claims(token.clone(), chrono::Duration::minutes(5)));
+
+    assert_eq!(authenticate(&jwt).await.unwrap(), token);
+
+  }
+
+  #[tokio::test]
+  async fn expired_token_is_rejected() {
+
+    let key = Keypair::es256("key-1");
+    let issuer = MockIssuer::start(vec![key.jwk()]).await;
+
+    let jwt = key.sign(&claims(access_token(&issuer), chrono::Duration::minutes(-5)));
+
+    assert!(matches!(authenticate(&jwt).await, Err(AuthError::TokenExpired)));
+
+  }
+
+  #[tokio::test]
+  async fn token_signed_with_unpublished_key_is_rejected() {
+
+    let published = Keypair::es256("key-1");
+    let forged = Keypair::es256("key-1");
+    let issuer = MockIssuer::start(vec![published.jwk()]).await;
+
+    let jwt = forged.sign(&claims(access_token(&issuer), chrono::Duration::minutes(5)));
+
+    assert!(matches!(authenticate(&jwt).await, Err(AuthError::InvalidSignature(_))));
+
+  }
+
+  #[tokio::test]
+  async fn disallowed_algorithm_is_rejected() {
+
+    let key = Keypair::es256("key-1");
+    let issuer = MockIssuer::start(vec![key.jwk()]).await;
+    let jwt = key.sign(&claims(access_token(&issuer), chrono::Duration::minutes(5)));
+
+    let rsa_only = Verifier { allowed_algs: &["RS256"], ..Verifier::default() };
+    assert!(matches!(rsa_only.authenticate(&jwt).await, Err(AuthError::DisallowedAlgorithm(alg)) if alg == "ES256"));
+
+    let es256_only = Verifier { allowed_algs: &["ES256"], ..Verifier::default() };
+    assert!(es256_only.authenticate(&jwt).await.is_ok());
+
+  }
+
+  #[tokio::test]
+  async fn unsecured_token_is_rejected() {
+
+    let key = Keypair::es256("key-1");
+    let issuer = MockIssuer::start(vec![key.jwk()]).await;
+    let claims = serde_json::to_vec(&claims(access_token(&issuer), chrono::Duration::minutes(5))).unwrap();
+    let jwt = format!(
+      "{}.{}.",
+      Base64UrlUnpadded::encode_string(br#"{"alg":"none"}"#),
+      Base64UrlUnpadded::encode_string(&claims),
+    );
+
+    assert!(matches!(authenticate(&jwt).await, Err(AuthError::DisallowedAlgorithm(_))));
+    let careless = Verifier { allowed_algs: &["none", "ES256"], ..Verifier::default() };
+    assert!(matches!(careless.authenticate(&jwt).await, Err(AuthError::DisallowedAlgorithm(_))));
+
+  }
+
+  #[tokio::test]
+  async fn issuer_missing_from_webid_doc_is_rejected() {
+
+    let key = Keypair::es256("key-1");
+    let issuer = MockIssuer::start_with_profile(vec![key.jwk()], |_| serde_json::json!({
+      "@context": { "solid": "http://www.w3.org/ns/solid/terms#" },
+      "@id": "#me",
+      "solid:oidcIssuer": { "@id": "https://other-issuer.example/" },
+    })).await;
+
+    let jwt = key.sign(&claims(access_token(&issuer), chrono::Duration::minutes(5)));
+
+    assert!(matches!(authenticate(&jwt).await, Err(AuthError::IssuerNotAllowed)));
+
+  }
+
+  #[test]
+  fn webid_doc_issuers_from_json_ld() {
+
+    let webid = Iri::parse("https://alice.example/profile/card#me".to_string()).unwrap();
+    let issuer = Iri::parse("https://idp.example".to_string()).unwrap();
+
+    let compact = serde_json::json!({
+      "@context": { "solid": "http://www.w3.org/ns/solid/terms#" },
+      "@id": "#me",
+      "solid:oidcIssuer": [{ "@id": "https://idp.example/" }, { "@id": "https://other-idp.example/" }],
+    });
+    let expanded = serde_json::json!([
+      { "@id": "https://alice.example/profile/card", "http://xmlns.com/foaf/0.1/primaryTopic": { "@id": "#me" } },
+      { "@id": "https://alice.example/profile/card#me", OIDC_ISSUER: [{ "@id": "https://idp.example" }] },
+    ]);
+    let other_subject = serde_json::json!({
+      "@graph": [{ "@id": "#bob", "oidcIssuer": "https://idp.example" }],
+    });
+
+    assert!(WebidDoc::from_json_ld(&webid, &compact).allows(&issuer));
+    assert_eq!(WebidDoc::from_json_ld(&webid, &compact).issuers.len(), 2);
+    assert!(WebidDoc::from_json_ld(&webid, &expanded).allows(&issuer));
+    assert!(!WebidDoc::from_json_ld(&webid, &other_subject).allows(&issuer));
+
+  }
+
+  #[test]
+  fn rfc7638_rsa_thumbprint() {
+
+    // https://www.rfc-editor.org/rfc/rfc7638#section-3.1
+    let jwk: JsonWebKey = serde_json::from_value(serde_json::json!({
+      "kty": "RSA",
+      "n": "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw",
+      "e": "AQAB",
+      "alg": "RS256",
+      "kid": "2011-04-29",
+    })).unwrap();
+
+    assert_eq!(jwk_thumbprint(&jwk), "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs");
+
+  }
+
+  #[test]
+  fn ec_thumbprint_uses_required_members_in_order() {
+
+    let jwk = Keypair::es256("key-1").jwk().key;
+    let JsonWebKey::EllipticCurve { x, y, .. } = &jwk else { panic!("not an EC key") };
+
+    let canonical = format!(
+      r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+      Base64UrlUnpadded::encode_string(x), Base64UrlUnpadded::encode_string(y),
+    );
+
+    assert_eq!(jwk_thumbprint(&jwk), Base64UrlUnpadded::encode_string(&Sha256::digest(canonical)));
+    assert_eq!(jwk_thumbprint(&jwk), Base64UrlUnpadded::encode_string(&jwk.thumbprint::<Sha256>()));
+
+  }
+
+  #[tokio::test]
+  async fn unknown_kid_is_rejected() {
+
+    let published = Keypair::es256("key-1");
+    let other = Keypair::es256("key-2");
+    let issuer = MockIssuer::start(vec![published.jwk()]).await;
+
+    let jwt = other.sign(&claims(access_token(&issuer), chrono::Duration::minutes(5)));
+
+    assert!(matches!(authenticate(&jwt).await, Err(AuthError::NoMatchingJwk)));
+
+  }
+
+
+  #[tokio::test]
+  async fn transient_fetch_failures_are_retried() {
+
+    let key = Keypair::es256("key-1");
+    let issuer = MockIssuer::start_flaky(vec![key.jwk()], 2, reqwest::StatusCode::SERVICE_UNAVAILABLE).await;
+    let jwt = key.sign(&claims(access_token(&issuer), chrono::Duration::minutes(5)));
+
+    let patient = Verifier { fetch_retries: 2, fetch_backoff: Duration::from_millis(1), ..Verifier::default() };
+    assert_eq!(patient.authenticate(&jwt).await.unwrap().webid, issuer.webid());
+    assert_eq!(issuer.config_requests(), 3);
+
+    let issuer = MockIssuer::start_flaky(vec![key.jwk()], 2, reqwest::StatusCode::SERVICE_UNAVAILABLE).await;
+    let jwt = key.sign(&claims(access_token(&issuer), chrono::Duration::minutes(5)));
+
+    let impatient = Verifier { fetch_retries: 1, ..patient };
+    assert!(matches!(impatient.authenticate(&jwt).await, Err(AuthError::NoIssuerConfig(_))));
+    assert_eq!(issuer.config_requests(), 2);
+
+  }
+
+  #[tokio::test]
+  async fn unreachable_issuers_are_temporarily_unavailable() {
+
+    let key = Keypair::es256("key-1");
+    let issuer = MockIssuer::start_flaky(vec![key.jwk()], 1, reqwest::StatusCode::SERVICE_UNAVAILABLE).await;
+    let jwt = key.sign(&claims(access_token(&issuer), chrono::Duration::minutes(5)));
+
+    let verifier = Verifier { fetch_retries: 0, ..Verifier::default() };
+    let response = Response::from(verifier.authenticate(&jwt).await.unwrap_err());
+    assert_eq!(response.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.body().error_code, "temporarily_unavailable");
+
+    // An issuer that does not publish its configuration will not do so on the next attempt either.
+    let issuer = MockIssuer::start_flaky(vec![key.jwk()], 1, reqwest::StatusCode::NOT_FOUND).await;
+    let jwt = key.sign(&claims(access_token(&issuer), chrono::Duration::minutes(5)));
+
+    let response = Response::from(verifier.authenticate(&jwt).await.unwrap_err());
+    assert_eq!(response.status(), http::StatusCode::UNAUTHORIZED);
+    assert_eq!(response.body().error_code, "invalid_token");
+    assert!(!response.body().error_description.as_deref().unwrap().contains(issuer.issuer().as_str()));
+
+  }
+
+  #[tokio::test]
+  async fn client_errors_are_not_retried() {
+
+    let key = Keypair::es256("key-1");
+    let issuer = MockIssuer::start_flaky(vec![key.jwk()], 1, reqwest::StatusCode::NOT_FOUND).await;
+    let jwt = key.sign(&claims(access_token(&issuer), chrono::Duration::minutes(5)));
+
+    let verifier = Verifier { fetch_backoff: Duration::from_millis(1), ..Verifier::default() };
+    assert!(matches!(verifier.authenticate(&jwt).await, Err(AuthError::NoIssuerConfig(_))));
+    assert_eq!(issuer.config_requests(), 1);
+
+  }
+
+  #[test]
+  fn backoff_doubles_with_jitter() {
+
+    let verifier = Verifier { fetch_backoff: Duration::from_millis(100), ..Verifier::default() };
+
+    for (attempt, full) in [(0, 100), (1, 200), (2, 400)] {
+      let delay = verifier.backoff(attempt);
+      assert!(delay >= Duration::from_millis(full / 2) && delay <= Duration::from_millis(full), "{delay:?}");
+    }
+
+  }
+
+
+  #[tokio::test]
+  async fn slow_issuer_times_out() {
+
+    let key = Keypair::es256("key-1");
+    let issuer = MockIssuer::start_slow(vec![key.jwk()], Duration::from_secs(10)).await;
+    let jwt = key.sign(&claims(access_token(&issuer), chrono::Duration::minutes(5)));
+
+    let verifier = Verifier { fetch_retries: 0, fetch_timeout: Duration::from_millis(50), ..Verifier::default() };
+    let started = std::time::Instant::now();
+    assert!(matches!(verifier.authenticate(&jwt).await, Err(AuthError::Timeout(_))));
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+  }
+
+
+  #[tokio::test]
+  async fn each_key_type_verifies() {
+
+    let verifier = Verifier { allowed_algs: SUPPORTED_ALGS, ..Verifier::default() };
+
+    for key in [Keypair::rs256("key-1"), Keypair::es256("key-1"), Keypair::ed25519("key-1")] {
+      let issuer = MockIssuer::start(vec![key.jwk()]).await;
+      let token = access_token(&issuer);
+
+      let jwt = key.sign(&claims(token.clone(), chrono::Duration::minutes(5)));
+
+      assert_eq!(verifier.authenticate(&jwt).await.unwrap(), token);
+    }
+
+  }
+
+  #[tokio::test]
+  async fn eddsa_is_opt_in() {
+
+    let key = Keypair::ed25519("key-1");
+    let issuer = MockIssuer::start(vec![key.jwk()]).await;
+    let jwt = key.sign(&claims(access_token(&issuer), chrono::Duration::minutes(5)));
+
+    assert!(matches!(authenticate(&jwt).await, Err(AuthError::DisallowedAlgorithm(alg)) if alg == "EdDSA"));
+    let with_eddsa = Verifier { allowed_algs: &["ES256", "EdDSA"], ..Verifier::default() };
+    assert!(with_eddsa.authenticate(&jwt).await.is_ok());
+
+  }
+
+  #[tokio::test]
+  async fn keys_of_another_type_are_passed_over() {
+
+    let ec = Keypair::es256("key-1");
+    let ed = Keypair::ed25519("key-1");
+
+    let verifier = Verifier { allowed_algs: SUPPORTED_ALGS, ..Verifier::default() };
+
+    // The kid alone does not tell the keys apart; the EC key must not be tried for an EdDSA token.
+    let issuer = MockIssuer::start(vec![ec.jwk(), ed.jwk()]).await;
+    let jwt = ed.sign(&claims(access_token(&issuer), chrono::Duration::minutes(5)));
+    assert!(verifier.authenticate(&jwt).await.is_ok());
+
+    let issuer = MockIssuer::start(vec![ec.jwk()]).await;
+    let jwt = ed.sign(&claims(access_token(&issuer), chrono::Duration::minutes(5)));
+    assert!(matches!(verifier.authenticate(&jwt).await, Err(AuthError::NoMatchingJwk)));
+
+  }
+
+
+  #[test]
+  fn keys_are_found_by_kid_or_by_being_the_only_one() {
+
+    let first = Keypair::es256("key-1").jwk();
+    let second = Keypair::es256("key-2").jwk();
+    let keys = [first.clone(), second.clone()];
+
+    assert_eq!(find_key(&keys, Some("key-2")).unwrap().kid, second.kid);
+    assert!(matches!(find_key(&keys, Some("key-3")), Err(AuthError::NoMatchingJwk)));
+
+    assert_eq!(find_key(&keys[..1], None).unwrap().kid, first.kid);
+    assert!(matches!(find_key(&[], None), Err(AuthError::NoMatchingJwk)));
+
+    assert!(matches!(find_key(&keys, None), Err(AuthError::AmbiguousJwk)));
+
+  }
+
+
+  #[test]
+  fn discovery_uri_joins_with_a_single_slash() {
+
+    let bare = Iri::parse("https://idp.example".to_string()).unwrap();
+    let slashed = Iri::parse("https://idp.example/tenant/".to_string()).unwrap();
+
+    assert_eq!(discovery_uri(&bare, OPENID_CONFIGURATION), "https://idp.example/.well-known/openid-configuration");
+    assert_eq!(discovery_uri(&slashed, UMA2_CONFIGURATION), "https://idp.example/tenant/.well-known/uma2-configuration");
+    assert_eq!(
+      discovery_uri(&slashed, "/oauth-authorization-server"),
+      "https://idp.example/tenant/.well-known/oauth-authorization-server",
+    );
+
+  }
+
+
+  #[tokio::test]
+  async fn jwt_pat_validates_offline() {
+
+    let key = Keypair::es256("key-1");
+    let jwks = JwkSet { keys: vec![key.jwk()] };
+    let pat = json!({ "sub": "https://alice.example/profile#me", "scope": "openid uma_protection" });
+
+    let jwt = key.sign(&claims(pat, chrono::Duration::minutes(5)));
+
+    assert!(is_jwt(&jwt));
+    let pat = Verifier::default().validate_pat_offline(&jwt, &jwks).await.unwrap();
+    assert_eq!(pat.owner.identifier().to_string(), "https://alice.example/profile#me");
+    assert_eq!(pat.scopes, ["openid", "uma_protection"]);
+
+  }
+
+  #[tokio::test]
+  async fn pat_without_protection_scope_is_rejected() {
+
+    let key = Keypair::es256("key-1");
+    let jwks = JwkSet { keys: vec![key.jwk()] };
+    let pat = json!({ "sub": "https://alice.example/profile#me", "scope": "openid" });
+
+    let jwt = key.sign(&claims(pat, chrono::Duration::minutes(5)));
+
+    let validated = Verifier::default().validate_pat_offline(&jwt, &jwks).await;
+    assert!(matches!(validated, Err(AuthError::InsufficientScope)));
+    assert!(!is_jwt("2YotnFZFEjr1zCsicMWpAA"));
+
+  }
+
+  #[tokio::test]
+  async fn opaque_pat_is_introspected() {
+
+    let issuer = MockIssuer::start(vec![]).await;
+    let endpoint = issuer.introspection_endpoint();
+    let verifier = Verifier::default();
+
+    assert!(!is_jwt(OPAQUE_PAT));
+    let pat = verifier.introspect_pat(OPAQUE_PAT, &endpoint, Some(INTROSPECTION_CREDENTIALS)).await.unwrap();
+    assert_eq!(pat.owner.identifier().to_string(), issuer.webid().as_str());
+    assert_eq!(pat.scopes, ["uma_protection"]);
+
+    let inactive = verifier.introspect_pat("tGzv3JOkF0XG5Qx2TlKWIA", &endpoint, Some(INTROSPECTION_CREDENTIALS)).await;
+    assert!(matches!(inactive, Err(AuthError::InactiveToken)));
+
+    let unauthenticated = verifier.introspect_pat(OPAQUE_PAT, &endpoint, None).await.unwrap_err();
+    assert!(matches!(unauthenticated, AuthError::NoIntrospection(_)));
+    assert_eq!(Response::from(unauthenticated).body().error_code, "invalid_token");
+
+  }
+
+  #[tokio::test]
+  async fn rejected_pats_map_to_error_responses() {
+
+    let key = Keypair::es256("key-1");
+    let jwks = JwkSet { keys: vec![key.jwk()] };
+    let sign = |scope: &str| {
+      let pat = json!({ "sub": "https://alice.example/profile#me", "scope": scope });
+      key.sign(&claims(pat, chrono::Duration::minutes(5)))
+    };
+    let clock = FakeClock::at_system_time();
+    let verifier = Verifier { clock: &clock, ..Verifier::default() };
+
+    let response = Response::from(verifier.validate_pat_offline(&sign("openid"), &jwks).await.unwrap_err());
+    assert_eq!(response.status(), http::StatusCode::FORBIDDEN);
+    assert_eq!(response.body().error_code, "insufficient_scope");
+    let challenge = &response.headers()[http::header::WWW_AUTHENTICATE];
+    assert_eq!(challenge, r#"Bearer error="insufficient_scope", scope="uma_protection""#);
+
+    let response = Response::from(verifier.validate_pat_offline("2YotnFZFEjr1zCsicMWpAA", &jwks).await.unwrap_err());
+    assert_eq!(response.status(), http::StatusCode::UNAUTHORIZED);
+    assert_eq!(response.body().error_code, "invalid_token");
+
+    let forger = Keypair::es256("key-1");
+    let forged = forger.sign(&claims(json!({ "scope": "uma_protection" }), chrono::Duration::hours(1)));
+    let response = Response::from(verifier.validate_pat_offline(&forged, &jwks).await.unwrap_err());
+    assert_eq!(response.status(), http::StatusCode::UNAUTHORIZED);
+    assert_eq!(response.body().error_description, INVALID_TOKEN.error_description);
+
+    let pat = sign("uma_protection");
+    clock.advance(Duration::from_secs(10 * 60));
+    let response = Response::from(verifier.validate_pat_offline(&pat, &jwks).await.unwrap_err());
+    assert_eq!(response.status(), http::StatusCode::UNAUTHORIZED);
+    assert_eq!(response.body().error_code, "invalid_token");
+    assert_eq!(response.body().error_description.as_deref(), Some("The access token has expired or is not valid yet."));
+
+  }
+
+  #[tokio::test]
+  async fn pat_expires_as_the_clock_advances() {
+
+    let key = Keypair::es256("key-1");
+    let jwks = JwkSet { keys: vec![key.jwk()] };
+    let pat = json!({ "sub": "https://alice.example/profile#me", "scope": "uma_protection" });
+    let jwt = key.sign(&claims(pat, chrono::Duration::minutes(5)));
+
+    let clock = FakeClock::at_system_time();
+    let verifier = Verifier { clock: &clock, ..Verifier::default() };
+    assert!(verifier.validate_pat_offline(&jwt, &jwks).await.is_ok());
+
+    clock.advance(Duration::from_secs(5 * 60 + 1));
+    assert!(matches!(verifier.validate_pat_offline(&jwt, &jwks).await, Err(AuthError::TokenExpired)));
+
+  }
+
+}
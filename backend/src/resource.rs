@@ -1,53 +1,139 @@
+use std::fmt;
+use std::str::FromStr;
+
 use oxiri::Iri;
-use serde::Deserialize;
+use thiserror::Error;
 
-enum Identifier {
+/// How an agent or resource is identified. Parsing classifies http(s) IRIs as WebIDs and `did:` URIs as decentralized
+/// identifiers; anything else is kept as an opaque string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Identifier {
   Webid(Iri<String>),
+  Did(String),
+  Opaque(String),
 }
 
-trait Resource {
-  const id: Identifier;
+#[derive(Debug, Error, PartialEq)]
+pub enum IdentifierError {
+  #[error("an identifier cannot be empty")]
+  Empty,
 }
 
-struct Agent { 
-  id: String,
-}
+impl Identifier {
+
+  pub fn parse(identifier: &str) -> Result<Self, IdentifierError> {
+    if identifier.is_empty() {
+      return Err(IdentifierError::Empty);
+    }
+
+    if is_did(identifier) {
+      return Ok(Self::Did(identifier.to_owned()));
+    }
+
+    if let Ok(iri) = Iri::parse(identifier.to_owned()) {
+      if iri.scheme().eq_ignore_ascii_case("http") || iri.scheme().eq_ignore_ascii_case("https") {
+        return Ok(Self::Webid(iri));
+      }
+    }
+
+    Ok(Self::Opaque(identifier.to_owned()))
+  }
 
-impl Resource for Agent {
-  const id: Identifier = Self::id;
 }
 
-fn test() {
-  let agent = Agent { id: "https://example.com/alice#me".to_string() };
-  let webid = match agent.id {
-    String => Iri::new(agent.id).unwrap(),
-    Identifier::Webid(webid) => webid,
+/// https://www.w3.org/TR/did-core/#did-syntax
+///
+/// did = "did:" method-name ":" method-specific-id, where the method name consists of lowercase letters and digits.
+fn is_did(identifier: &str) -> bool {
+  let Some(rest) = identifier.strip_prefix("did:") else {
+    return false;
+  };
+  let Some((method, id)) = rest.split_once(':') else {
+    return false;
   };
-  println!("{}", webid);
+
+  !method.is_empty()
+    && method.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
+    && !id.is_empty()
 }
 
+impl FromStr for Identifier {
+  type Err = IdentifierError;
+
+  fn from_str(identifier: &str) -> Result<Self, Self::Err> {
+    Self::parse(identifier)
+  }
+}
 
-// enum Identifier {
-//   Webid(Iri<String>),
-// }
+impl fmt::Display for Identifier {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Webid(webid) => f.write_str(webid.as_str()),
+      Self::Did(did) => f.write_str(did),
+      Self::Opaque(identifier) => f.write_str(identifier),
+    }
+  }
+}
 
-// trait Resource {
-//   const id: Identifier;
-// }
+/// Anything that can be the subject of a registered resource or a permission, identified at runtime.
+pub trait Resource {
+  fn identifier(&self) -> Identifier;
+}
+
+/// A person or piece of software acting on its own behalf, e.g. a resource owner or requesting party.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Agent {
+  id: Identifier,
+}
 
-// struct Agent { 
-//   id: String,
-// }
+impl Agent {
 
-// impl Resource for Agent {
-//   const id: Identifier = Self::id;
-// }
+  pub fn new(id: &str) -> Result<Self, IdentifierError> {
+    Ok(Self { id: Identifier::parse(id)? })
+  }
 
-// fn test() {
-//   let agent = Agent { id: "https://example.com/alice#me".to_string() };
-//   let webid = match agent.id {
-//     String => Iri::new(agent.id).unwrap(),
-//     Identifier::Webid(webid) => webid,
-//   };
-//   println!("{}", webid);
-// }
\ No newline at end of file
+}
+
+impl Resource for Agent {
+  fn identifier(&self) -> Identifier {
+    self.id.clone()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn webid() {
+    let agent = Agent::new("https://example.com/alice#me").unwrap();
+
+    let webid = match agent.identifier() {
+      Identifier::Webid(webid) => webid,
+      other => panic!("expected a WebID, got {other:?}"),
+    };
+
+    assert_eq!(webid.as_str(), "https://example.com/alice#me");
+    assert!(matches!("http://example.com/bob#me".parse(), Ok(Identifier::Webid(_))));
+    assert_eq!(Agent::new(""), Err(IdentifierError::Empty));
+  }
+
+  #[test]
+  fn did() {
+    let identifier: Identifier = "did:example:123456789abcdefghi".parse().unwrap();
+
+    assert_eq!(identifier, Identifier::Did("did:example:123456789abcdefghi".to_string()));
+    assert_eq!(identifier.to_string(), "did:example:123456789abcdefghi");
+  }
+
+  #[test]
+  fn opaque() {
+    for identifier in ["urn:uuid:f81d4fae-7dec-11d0-a765-00a0c91e6bf6", "alice", "did:Example:123", "did:example"] {
+      assert_eq!(Identifier::parse(identifier), Ok(Identifier::Opaque(identifier.to_string())));
+      assert_eq!(Identifier::parse(identifier).unwrap().to_string(), identifier);
+    }
+    assert_eq!(Identifier::parse(""), Err(IdentifierError::Empty));
+  }
+
+}
@@ -1,15 +1,130 @@
-use std::collections::{hash_map::Keys, HashMap};
+pub mod file;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod typed;
+
+use std::borrow::Cow;
+use std::collections::{hash_map::Keys, BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::clock::{Clock, SystemClock};
+
+/// Separates the components of a composite key, such as an owner namespace and a scope.
+pub const KEY_SEPARATOR: char = '/';
+
+/// Percent-encodes the characters that would make a key component ambiguous (`%` and the separator) or unusable as a
+/// URI path segment (`?` and `#`). Components without such characters are returned as is.
+pub fn escape_key_component(component: &str) -> Cow<'_, str> {
+    if !component.contains(['%', KEY_SEPARATOR, '?', '#']) {
+        return Cow::Borrowed(component);
+    }
+    let mut escaped = String::with_capacity(component.len() + 6);
+    for c in component.chars() {
+        match c {
+            '%' => escaped.push_str("%25"),
+            '/' => escaped.push_str("%2F"),
+            '?' => escaped.push_str("%3F"),
+            '#' => escaped.push_str("%23"),
+            c => escaped.push(c),
+        }
+    }
+    return Cow::Owned(escaped);
+}
+
+/// Joins escaped components with the separator, so that distinct component lists never map onto the same key, e.g.
+/// `["alice/bob", "view"]` and `["alice", "bob/view"]`.
+pub fn composite_key<'c>(components: impl IntoIterator<Item = &'c str>) -> String {
+    let components: Vec<Cow<str>> = components.into_iter().map(escape_key_component).collect();
+    return components.join(&KEY_SEPARATOR.to_string());
+}
 
 pub trait KeyValueStore: Send + Sync {
     type Key;
     type Value;
 
     fn set(&mut self, key: Self::Key, value: Self::Value) -> &Self::Key;
+    /// Conditional create: stores the value only if the key is not present yet, and otherwise hands the value back.
+    /// Check and insert happen under the same exclusive borrow, so two racing creates cannot both succeed.
+    fn set_if_absent(&mut self, key: Self::Key, value: Self::Value) -> Result<&Self::Key, Self::Value>;
     fn get(&self, key: &Self::Key) -> Option<&Self::Value>;
+    /// Read-modify-write access to a stored value in a single lookup. Stores that cannot hand out references into
+    /// their storage (e.g. network backends) can instead keep a local copy obtained through get, and set it back.
+    fn get_mut(&mut self, key: &Self::Key) -> Option<&mut Self::Value>;
     fn del(&mut self, key: &Self::Key) -> Option<Self::Value>;
     fn list<'kvs>(&'kvs self) -> Box<dyn Iterator<Item = &'kvs Self::Key> + 'kvs>;
+
+    /// Resets the expiry of the value under `key` to `new_ttl` from now, for values with a sliding lifetime; false if
+    /// there is no value under it. Goes through get_mut, so stores persist the new expiry as any other change.
+    fn touch(&mut self, key: &Self::Key, new_ttl: Duration) -> bool
+    where
+        Self::Value: Expiring,
+    {
+        return self.touch_at(key, new_ttl, SystemClock.now());
+    }
+
+    /// `touch` as of `now`, in seconds since the Unix epoch.
+    fn touch_at(&mut self, key: &Self::Key, new_ttl: Duration, now: i64) -> bool
+    where
+        Self::Value: Expiring,
+    {
+        let Some(value) = self.get_mut(key) else {
+            return false;
+        };
+        value.expire_at(now + new_ttl.as_secs() as i64);
+        return true;
+    }
+}
+
+/// [NO-SPEC] A value that records when it expires, in seconds since the Unix epoch. Stores do not expire their
+/// entries, so whoever reads such a value checks its expiry.
+pub trait Expiring {
+    fn expire_at(&mut self, exp: i64);
+}
+
+/// [NO-SPEC] A count within a fixed window, which ends at `exp` (in seconds since the Unix epoch).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Counter {
+    pub value: i64,
+    pub exp: i64,
 }
 
+/// A store of counters, e.g. for fixed-window rate limiting. The provided increment reads and writes the counter under
+/// the exclusive borrow of the store, which makes it atomic for stores local to one process; stores shared between
+/// processes (such as Redis) implement `incr_at` with an atomic increment of their own.
+pub trait CounterStore: KeyValueStore<Value = Counter> {
+    /// Adds `by` to the counter under `key` and returns its new value. The first increment, and the first one after
+    /// the window has ended, starts the counter over in a new window of `ttl`.
+    fn incr(&mut self, key: &Self::Key, by: i64, ttl: Duration) -> i64
+    where
+        Self::Key: Clone,
+    {
+        return self.incr_at(key, by, ttl, SystemClock.now());
+    }
+
+    /// `incr` as of `now`, in seconds since the Unix epoch.
+    fn incr_at(&mut self, key: &Self::Key, by: i64, ttl: Duration, now: i64) -> i64
+    where
+        Self::Key: Clone,
+    {
+        if let Some(counter) = self.get_mut(key) {
+            if counter.exp > now {
+                counter.value += by;
+                return counter.value;
+            }
+        }
+        self.set(key.clone(), Counter { value: by, exp: now + ttl.as_secs() as i64 });
+        return by;
+    }
+}
+
+impl<K: Send + Sync + Eq + Hash + Clone> CounterStore for HashMap<K, Counter> {}
+
 impl<K, V> KeyValueStore for HashMap<K, V>
 where
     K: Send + Sync + Eq + std::hash::Hash + Clone,
@@ -23,10 +138,21 @@ where
         return self.get_key_value(&key).unwrap().0;
     }
 
+    fn set_if_absent(&mut self, key: Self::Key, value: Self::Value) -> Result<&Self::Key, Self::Value> {
+        if self.contains_key(&key) {
+            return Err(value);
+        }
+        return Ok(KeyValueStore::set(self, key, value));
+    }
+
     fn get(&self, key: &Self::Key) -> Option<&Self::Value> {
         self.get(key)
     }
 
+    fn get_mut(&mut self, key: &Self::Key) -> Option<&mut Self::Value> {
+        self.get_mut(key)
+    }
+
     fn del(&mut self, key: &Self::Key) -> Option<Self::Value> {
         self.remove(key)
     }
@@ -38,3 +164,404 @@ where
         return keys;
     }
 }
+
+/// An in-memory store holding at most `capacity` entries. Inserting a new key into a full store evicts the least
+/// recently used entry, where both writes and reads (`get` as well as `get_mut`) count as a use; listing does not.
+pub struct LruStore<K, V> {
+    capacity: usize,
+    entries: HashMap<K, (V, AtomicU64)>,
+    /// The keys by the tick of their last use, oldest first. Behind a lock because `get` reorders it through `&self`.
+    recency: Mutex<BTreeMap<u64, K>>,
+    clock: AtomicU64,
+}
+
+impl<K: Eq + Hash + Clone, V> LruStore<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "an LruStore must be able to hold at least one entry");
+        Self {
+            capacity,
+            entries: HashMap::with_capacity(capacity),
+            recency: Mutex::new(BTreeMap::new()),
+            clock: AtomicU64::new(0),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        return self.capacity;
+    }
+
+    /// Moves the entry to the most recently used position. The lock is held while the entry's tick is swapped, so that
+    /// concurrent reads of the same key leave the recency index consistent.
+    fn mark_used(&self, last_used: &AtomicU64) {
+        let mut recency = self.recency.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        let previous = last_used.swap(now, Ordering::Relaxed);
+        if let Some(key) = recency.remove(&previous) {
+            recency.insert(now, key);
+        }
+    }
+}
+
+impl<K, V> KeyValueStore for LruStore<K, V>
+where
+    K: Send + Sync + Eq + Hash + Clone,
+    V: Send + Sync,
+{
+    type Key = K;
+    type Value = V;
+
+    fn set(&mut self, key: Self::Key, value: Self::Value) -> &Self::Key {
+        if let Some((stored, last_used)) = self.entries.get_mut(&key) {
+            *stored = value;
+            let now = self.clock.fetch_add(1, Ordering::Relaxed);
+            let previous = last_used.swap(now, Ordering::Relaxed);
+            let recency = self.recency.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner());
+            recency.remove(&previous);
+            recency.insert(now, key.clone());
+        } else {
+            let recency = self.recency.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if self.entries.len() >= self.capacity {
+                if let Some((_, evicted)) = recency.pop_first() {
+                    self.entries.remove(&evicted);
+                }
+            }
+            let now = self.clock.fetch_add(1, Ordering::Relaxed);
+            recency.insert(now, key.clone());
+            self.entries.insert(key.clone(), (value, AtomicU64::new(now)));
+        }
+        return self.entries.get_key_value(&key).unwrap().0;
+    }
+
+    fn set_if_absent(&mut self, key: Self::Key, value: Self::Value) -> Result<&Self::Key, Self::Value> {
+        if self.entries.contains_key(&key) {
+            return Err(value);
+        }
+        return Ok(self.set(key, value));
+    }
+
+    fn get(&self, key: &Self::Key) -> Option<&Self::Value> {
+        let (value, last_used) = self.entries.get(key)?;
+        self.mark_used(last_used);
+        return Some(value);
+    }
+
+    fn get_mut(&mut self, key: &Self::Key) -> Option<&mut Self::Value> {
+        let (_, last_used) = self.entries.get(key)?;
+        self.mark_used(last_used);
+        return self.entries.get_mut(key).map(|(value, _)| value);
+    }
+
+    fn del(&mut self, key: &Self::Key) -> Option<Self::Value> {
+        let (value, last_used) = self.entries.remove(key)?;
+        let recency = self.recency.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner());
+        recency.remove(&last_used.into_inner());
+        return Some(value);
+    }
+
+    fn list<'kvs>(&'kvs self) -> Box<dyn Iterator<Item = &'kvs Self::Key> + 'kvs> {
+        return Box::new(self.entries.keys());
+    }
+}
+
+impl<K: Send + Sync + Eq + Hash + Clone> CounterStore for LruStore<K, Counter> {}
+
+/// A store that can be shared between concurrent handlers, e.g. as axum state. Cloning it yields another handle to the
+/// same store.
+///
+/// Locking is per store, not per key: each call below holds the lock for one store operation, while `read` and `write`
+/// hold it for as long as the guard lives. Handlers that return references into the store take `&mut *store.write()`
+/// and so serialize with every other writer until their response is built; readers only wait for writers.
+pub struct SharedStore<S: ?Sized> {
+    inner: Arc<RwLock<S>>,
+}
+
+impl<S> SharedStore<S> {
+    pub fn new(store: S) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(store)),
+        }
+    }
+}
+
+impl<S: ?Sized> Clone for SharedStore<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+/// Lets an existing lock be shared, e.g. one behind a `dyn KeyValueStore`.
+impl<S: ?Sized> From<Arc<RwLock<S>>> for SharedStore<S> {
+    fn from(inner: Arc<RwLock<S>>) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S: KeyValueStore + ?Sized> SharedStore<S> {
+    pub async fn read(&self) -> RwLockReadGuard<'_, S> {
+        return self.inner.read().await;
+    }
+
+    pub async fn write(&self) -> RwLockWriteGuard<'_, S> {
+        return self.inner.write().await;
+    }
+
+    pub async fn set(&self, key: S::Key, value: S::Value) {
+        self.inner.write().await.set(key, value);
+    }
+
+    pub async fn set_if_absent(&self, key: S::Key, value: S::Value) -> Result<(), S::Value> {
+        return self.inner.write().await.set_if_absent(key, value).map(|_| ());
+    }
+
+    /// Values are cloned out of the store, so that the lock is released before returning.
+    pub async fn get(&self, key: &S::Key) -> Option<S::Value>
+    where
+        S::Value: Clone,
+    {
+        return self.inner.read().await.get(key).cloned();
+    }
+
+    pub async fn del(&self, key: &S::Key) -> Option<S::Value> {
+        return self.inner.write().await.del(key);
+    }
+}
+
+/// An async lock per key, for operations that span several store calls, such as a read-modify-write of one entry:
+/// operations on the same key serialize, while those on different keys proceed in parallel. Only keys that are locked
+/// or waited for take up memory. Cloning it yields another handle to the same locks.
+pub struct KeyedMutex<K> {
+    locks: Arc<Mutex<HashMap<K, Arc<AsyncMutex<()>>>>>,
+}
+
+impl<K> Default for KeyedMutex<K> {
+    fn default() -> Self {
+        Self {
+            locks: Arc::default(),
+        }
+    }
+}
+
+impl<K> Clone for KeyedMutex<K> {
+    fn clone(&self) -> Self {
+        Self {
+            locks: self.locks.clone(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone> KeyedMutex<K> {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    /// Waits until no one else holds the lock for `key`, which is then held until the guard is dropped, be it on
+    /// success, on an error or on a panic.
+    pub async fn lock(&self, key: K) -> KeyedMutexGuard<K> {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            locks.entry(key.clone()).or_default().clone()
+        };
+        let guard = lock.clone().lock_owned().await;
+        return KeyedMutexGuard {
+            locks: self.locks.clone(),
+            key,
+            lock,
+            guard: Some(guard),
+        };
+    }
+
+    /// The number of keys currently locked or waited for.
+    pub fn len(&self) -> usize {
+        return self.locks.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+}
+
+pub struct KeyedMutexGuard<K: Eq + Hash> {
+    locks: Arc<Mutex<HashMap<K, Arc<AsyncMutex<()>>>>>,
+    key: K,
+    lock: Arc<AsyncMutex<()>>,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+/// Releases the lock, and forgets it if no one else is waiting for it: the map and this guard then hold its only
+/// references. Waiters hold one each, and taking another requires the map's lock, held here.
+impl<K: Eq + Hash> Drop for KeyedMutexGuard<K> {
+    fn drop(&mut self) {
+        self.guard.take();
+        let mut locks = self.locks.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if Arc::strong_count(&self.lock) == 2 {
+            locks.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::uma::federation::ResourceDescription;
+    use crate::uma::grants::{ClaimSet, PersistedClaimsToken};
+
+    #[test]
+    fn get_mut_mutates_in_place() {
+        let mut store = HashMap::new();
+        let store: &mut dyn KeyValueStore<Key = String, Value = ResourceDescription> = &mut store;
+
+        let description = ResourceDescription {
+            _id: "",
+            resource_scopes: vec!["view".into()],
+            description: None,
+            icon_uri: None,
+            name: Some("Photo Album".into()),
+            r#type: None,
+            owner: None,
+            policy_hint: None,
+        };
+        store.set("KX3A-39WE".to_string(), description);
+
+        if let Some(description) = store.get_mut(&"KX3A-39WE".to_string()) {
+            description.name = Some("Holiday Photos".into());
+            description.resource_scopes.push("print".into());
+        }
+
+        let description = store.get(&"KX3A-39WE".to_string()).unwrap();
+        assert_eq!(description.name, Some("Holiday Photos".into()));
+        assert_eq!(description.resource_scopes, vec!["view", "print"]);
+        assert!(store.get_mut(&"9UQU-DUWW".to_string()).is_none());
+    }
+
+    #[test]
+    fn touch_pushes_back_the_expiry() {
+        let mut store = HashMap::new();
+        let store: &mut dyn KeyValueStore<Key = String, Value = PersistedClaimsToken> = &mut store;
+        let now = 1_700_000_000;
+        let pct = "c2F2ZWRjb25zZW50".to_string();
+        store.set(pct.clone(), PersistedClaimsToken { pct: pct.clone(), claims: ClaimSet::new(), exp: Some(now + 1) });
+
+        assert!(store.touch_at(&pct, Duration::from_secs(60), now));
+
+        // Past the original expiry, but not the new one.
+        let touched = store.get(&pct).unwrap();
+        assert!(!touched.is_expired(now + 2));
+        assert!(touched.is_expired(now + 61));
+        assert!(!store.touch(&"unknown".to_string(), Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn composite_keys_are_unambiguous() {
+        assert_eq!(composite_key(["alice", "view"]), "alice/view");
+        assert_ne!(composite_key(["alice/bob", "view"]), composite_key(["alice", "bob/view"]));
+        assert_ne!(composite_key(["a%2Fb"]), composite_key(["a/b"]));
+        assert_eq!(
+            composite_key(["alice", "http://www.example.com/scopes/all"]),
+            "alice/http:%2F%2Fwww.example.com%2Fscopes%2Fall"
+        );
+    }
+
+    #[test]
+    fn lru_store_evicts_least_recently_used() {
+        let mut store = LruStore::new(3);
+        store.set("KX3A-39WE".to_string(), 1);
+        store.set("9UQU-DUWW".to_string(), 2);
+        store.set("Q2UZ-K3PP".to_string(), 3);
+
+        // Reading the oldest entry makes the second one the least recently used.
+        assert_eq!(store.get(&"KX3A-39WE".to_string()), Some(&1));
+        store.set("7HRT-0ZYX".to_string(), 4);
+
+        assert_eq!(store.list().count(), 3);
+        assert_eq!(store.get(&"9UQU-DUWW".to_string()), None);
+        assert_eq!(store.get(&"KX3A-39WE".to_string()), Some(&1));
+
+        // Overwriting counts as a use too, and does not evict anything.
+        store.set("Q2UZ-K3PP".to_string(), 5);
+        store.set("V8ND-UWSG".to_string(), 6);
+        assert_eq!(store.get(&"7HRT-0ZYX".to_string()), None);
+        assert_eq!(store.get(&"Q2UZ-K3PP".to_string()), Some(&5));
+        assert_eq!(store.list().count(), 3);
+    }
+
+    #[test]
+    fn lru_store_deletes_free_capacity() {
+        let mut store = LruStore::new(2);
+        store.set("KX3A-39WE".to_string(), 1);
+        store.set("9UQU-DUWW".to_string(), 2);
+        assert_eq!(store.del(&"KX3A-39WE".to_string()), Some(1));
+
+        store.set("Q2UZ-K3PP".to_string(), 3);
+        assert_eq!(store.get(&"9UQU-DUWW".to_string()), Some(&2));
+        assert_eq!(store.get(&"Q2UZ-K3PP".to_string()), Some(&3));
+    }
+
+    #[test]
+    fn counters_reset_when_their_window_ends() {
+        let mut store = HashMap::new();
+        let store: &mut dyn CounterStore<Key = String> = &mut store;
+        let (alice, bob) = ("alice".to_string(), "bob".to_string());
+        let (start, window) = (1_700_000_000, Duration::from_secs(60));
+
+        assert_eq!(store.incr_at(&alice, 1, window, start), 1);
+        assert_eq!(store.incr_at(&alice, 2, window, start + 30), 3);
+        assert_eq!(store.incr_at(&bob, 1, window, start + 30), 1);
+        // Later increments do not extend the window.
+        assert_eq!(store.get(&alice), Some(&Counter { value: 3, exp: start + 60 }));
+
+        assert_eq!(store.incr_at(&alice, 1, window, start + 60), 1);
+        assert_eq!(store.get(&alice), Some(&Counter { value: 1, exp: start + 120 }));
+        assert_eq!(store.incr_at(&bob, 1, window, start + 89), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn shared_store_handles_concurrent_tasks() {
+        let store = SharedStore::new(HashMap::from([("counter".to_string(), 0)]));
+
+        let tasks: Vec<_> = (0..64)
+            .map(|i| {
+                let store = store.clone();
+                tokio::spawn(async move {
+                    let key = format!("ticket-{i}");
+                    store.set(key.clone(), i).await;
+                    assert_eq!(store.get(&key).await, Some(i));
+                    assert!(store.set_if_absent(key.clone(), i + 1).await.is_err());
+                    // A read-modify-write under one guard loses no increments.
+                    *store.write().await.get_mut(&"counter".to_string()).unwrap() += 1;
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let store = store.read().await;
+        assert_eq!(store.list().count(), 65);
+        assert_eq!(store.get(&"ticket-42".to_string()), Some(&42));
+        assert_eq!(store.get(&"counter".to_string()), Some(&64));
+    }
+
+    #[tokio::test]
+    async fn keyed_mutex_serializes_per_key() {
+        let locks = KeyedMutex::new();
+
+        let photo = locks.lock("photo".to_string()).await;
+        // Another key is not held up.
+        drop(locks.lock("album".to_string()).await);
+
+        let waiter = tokio::spawn({
+            let locks = locks.clone();
+            async move { drop(locks.lock("photo".to_string()).await) }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert!(!waiter.is_finished());
+
+        drop(photo);
+        waiter.await.unwrap();
+        assert!(locks.is_empty());
+    }
+
+}
@@ -0,0 +1,259 @@
+//! A key-value store persisted as a single JSON document on disk. All entries are kept in memory, so that the store can
+//! hand out references like any other `KeyValueStore`; the file is rewritten as a whole on every flush.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::error;
+
+use super::{Counter, CounterStore, KeyValueStore};
+
+/// When writes reach the disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteMode {
+    /// Every write is flushed before it returns.
+    WriteThrough,
+    /// Writes are only flushed on an explicit `flush`, on shutdown, or when the store is dropped.
+    Buffered,
+}
+
+/// Stores whose writes may linger in memory before they are persisted.
+pub trait Persist: Send {
+    /// The number of writes since the last successful flush.
+    fn pending_writes(&self) -> usize;
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+pub struct FileStore<V: Serialize> {
+    path: PathBuf,
+    mode: WriteMode,
+    entries: HashMap<String, V>,
+    pending: usize,
+}
+
+impl<V: Serialize + DeserializeOwned> FileStore<V> {
+    /// Loads the entries from `path`, or starts empty if the file does not exist yet.
+    pub fn open(path: impl AsRef<Path>, mode: WriteMode) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let entries = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(error) => return Err(error),
+        };
+        let store = Self {
+            path,
+            mode,
+            entries,
+            pending: 0,
+        };
+        store.record_pending();
+        return Ok(store);
+    }
+}
+
+impl<V: Serialize + DeserializeOwned + Send> Persist for FileStore<V> {
+    fn pending_writes(&self) -> usize {
+        return self.pending;
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.pending == 0 {
+            return Ok(());
+        }
+        return self.persist();
+    }
+}
+
+impl<V: Serialize + DeserializeOwned + Send + Sync> KeyValueStore for FileStore<V> {
+    type Key = String;
+    type Value = V;
+
+    fn set(&mut self, key: Self::Key, value: Self::Value) -> &Self::Key {
+        self.entries.insert(key.clone(), value);
+        self.written();
+        return self.entries.get_key_value(&key).unwrap().0;
+    }
+
+    fn set_if_absent(&mut self, key: Self::Key, value: Self::Value) -> Result<&Self::Key, Self::Value> {
+        if self.entries.contains_key(&key) {
+            return Err(value);
+        }
+        return Ok(self.set(key, value));
+    }
+
+    fn get(&self, key: &Self::Key) -> Option<&Self::Value> {
+        self.entries.get(key)
+    }
+
+    /// The caller may change the value through the returned reference, so this counts as a write.
+    fn get_mut(&mut self, key: &Self::Key) -> Option<&mut Self::Value> {
+        if !self.entries.contains_key(key) {
+            return None;
+        }
+        self.written();
+        return self.entries.get_mut(key);
+    }
+
+    fn del(&mut self, key: &Self::Key) -> Option<Self::Value> {
+        let value = self.entries.remove(key)?;
+        self.written();
+        return Some(value);
+    }
+
+    fn list<'kvs>(&'kvs self) -> Box<dyn Iterator<Item = &'kvs Self::Key> + 'kvs> {
+        return Box::new(self.entries.keys());
+    }
+}
+
+impl CounterStore for FileStore<Counter> {}
+
+impl<V: Serialize> FileStore<V> {
+    /// Counts a write, and flushes right away in write-through mode. A failed flush leaves the write pending, to be
+    /// retried by the next flush.
+    fn written(&mut self) {
+        self.pending += 1;
+        self.record_pending();
+        if self.mode == WriteMode::WriteThrough {
+            if let Err(error) = self.persist() {
+                error!(%error, path = %self.path.display(), "failed to persist store");
+            }
+        }
+    }
+
+    fn record_pending(&self) {
+        metrics::gauge!("store_pending_writes", "path" => self.path.display().to_string()).set(self.pending as f64);
+    }
+
+    /// Writes to a temporary sibling first and renames it over the store file, so that a crash mid-write leaves the
+    /// previous contents intact.
+    fn persist(&mut self) -> io::Result<()> {
+        let temporary = self.path.with_extension("tmp");
+        fs::write(&temporary, serde_json::to_vec(&self.entries)?)?;
+        fs::rename(&temporary, &self.path)?;
+        self.pending = 0;
+        self.record_pending();
+        return Ok(());
+    }
+}
+
+/// Last resort for stores that were not flushed on shutdown, e.g. because the process unwinds. After a graceful
+/// shutdown there is nothing pending and this does not touch the disk.
+impl<V: Serialize> Drop for FileStore<V> {
+    fn drop(&mut self) {
+        if self.pending == 0 {
+            return;
+        }
+        if let Err(error) = self.persist() {
+            error!(%error, path = %self.path.display(), pending = self.pending, "failed to persist store on drop");
+        }
+    }
+}
+
+/// Flushes every store before the process exits, giving up after `deadline`. Returns whether all writes were persisted;
+/// stores that still have pending writes are logged as errors.
+pub async fn flush_on_shutdown(stores: &[Arc<Mutex<dyn Persist>>], deadline: Duration) -> bool {
+    let flushes = stores.iter().cloned().map(|store| {
+        tokio::task::spawn_blocking(move || {
+            let mut store = store.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if let Err(error) = store.flush() {
+                error!(%error, "failed to persist store on shutdown");
+            }
+            return store.pending_writes();
+        })
+    });
+
+    match tokio::time::timeout(deadline, futures::future::join_all(flushes)).await {
+        Ok(pending) => {
+            let pending: usize = pending.into_iter().map(|pending| pending.unwrap_or(usize::MAX)).sum();
+            if pending > 0 {
+                error!(pending, "stores still have unpersisted writes after shutdown");
+            }
+            return pending == 0;
+        }
+        Err(_) => {
+            error!(?deadline, "timed out flushing stores on shutdown, writes may be lost");
+            return false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use uuid::Uuid;
+
+    fn temporary_path() -> PathBuf {
+        return std::env::temp_dir().join(format!("smother-{}.json", Uuid::new_v4()));
+    }
+
+    fn pending_gauge(recorder: &DebuggingRecorder) -> Option<f64> {
+        return recorder.snapshotter().snapshot().into_vec().into_iter().find_map(|(key, _, _, value)| {
+            match (key.key().name(), value) {
+                ("store_pending_writes", DebugValue::Gauge(value)) => Some(value.into_inner()),
+                _ => None,
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn shutdown_flushes_buffered_writes() {
+        let path = temporary_path();
+        let store = Arc::new(Mutex::new(FileStore::open(&path, WriteMode::Buffered).unwrap()));
+
+        store.lock().unwrap().set("KX3A-39WE".to_string(), vec!["view".to_string()]);
+        store.lock().unwrap().set("9UQU-DUWW".to_string(), vec!["print".to_string()]);
+        assert_eq!(store.lock().unwrap().pending_writes(), 2);
+        assert!(!path.exists());
+
+        let stores: Vec<Arc<Mutex<dyn Persist>>> = vec![store.clone()];
+        assert!(flush_on_shutdown(&stores, Duration::from_secs(5)).await);
+
+        assert_eq!(store.lock().unwrap().pending_writes(), 0);
+        let reopened = FileStore::<Vec<String>>::open(&path, WriteMode::Buffered).unwrap();
+        assert_eq!(reopened.get(&"9UQU-DUWW".to_string()), Some(&vec!["print".to_string()]));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn pending_writes_gauge_returns_to_zero() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = &recorder;
+        let path = temporary_path();
+
+        metrics::with_local_recorder(&recorder, || {
+            let mut store = FileStore::open(&path, WriteMode::Buffered).unwrap();
+            store.set("KX3A-39WE".to_string(), 1);
+            store.set("9UQU-DUWW".to_string(), 2);
+            assert_eq!(pending_gauge(snapshotter), Some(2.0));
+
+            store.flush().unwrap();
+            assert_eq!(pending_gauge(snapshotter), Some(0.0));
+        });
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn drop_flushes_buffered_writes() {
+        let path = temporary_path();
+
+        {
+            let mut store = FileStore::open(&path, WriteMode::Buffered).unwrap();
+            store.set("KX3A-39WE".to_string(), 1);
+        }
+
+        let reopened = FileStore::<i32>::open(&path, WriteMode::Buffered).unwrap();
+        assert_eq!(reopened.get(&"KX3A-39WE".to_string()), Some(&1));
+        assert_eq!(reopened.pending_writes(), 0);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,256 @@
+//! A key-value store persisted in a SQLite table of `(key, value, created_at, expires_at)` rows, with values stored as
+//! JSON text. Like `FileStore`, it mirrors its rows in memory so that it can hand out references like any other
+//! `KeyValueStore`; unlike `FileStore`, every write reaches the database before it returns.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::error;
+
+use crate::clock::{Clock, SystemClock};
+
+use super::file::Persist;
+use super::{Counter, CounterStore, KeyValueStore};
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS entries (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER
+)";
+
+pub struct SqliteStore<V: Serialize> {
+    /// `Connection` is not `Sync`; the lock only makes it so, as every use already goes through `&mut self`.
+    connection: Mutex<Connection>,
+    entries: HashMap<String, V>,
+    /// Keys handed out through `get_mut`, whose rows are rewritten on the next write or flush.
+    dirty: HashSet<String>,
+    /// Tells which rows have expired when loading, and stamps the rows written.
+    clock: Arc<dyn Clock>,
+}
+
+impl<V: Serialize + DeserializeOwned> SqliteStore<V> {
+    /// Opens (or creates) the database at `path`, loading the rows that have not expired.
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Self> {
+        return Self::open_with_clock(path, Arc::new(SystemClock));
+    }
+
+    /// `open`, telling the time by `clock` rather than by the system.
+    pub fn open_with_clock(path: impl AsRef<Path>, clock: Arc<dyn Clock>) -> rusqlite::Result<Self> {
+        return Self::load(Connection::open(path)?, clock);
+    }
+
+    /// A store that lives and dies with the process, mostly useful in tests.
+    pub fn open_in_memory() -> rusqlite::Result<Self> {
+        return Self::load(Connection::open_in_memory()?, Arc::new(SystemClock));
+    }
+
+    fn load(connection: Connection, clock: Arc<dyn Clock>) -> rusqlite::Result<Self> {
+        connection.execute(SCHEMA, [])?;
+        let mut entries = HashMap::new();
+        let now = clock.now();
+        {
+            let mut statement =
+                connection.prepare("SELECT key, value FROM entries WHERE expires_at IS NULL OR expires_at > ?1")?;
+            let rows = statement.query_map([now], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+            for row in rows {
+                let (key, value) = row?;
+                let value = serde_json::from_str(&value).map_err(|error| {
+                    rusqlite::Error::FromSqlConversionFailure(1, rusqlite::types::Type::Text, error.into())
+                })?;
+                entries.insert(key, value);
+            }
+        }
+        return Ok(Self {
+            connection: Mutex::new(connection),
+            entries,
+            dirty: HashSet::new(),
+            clock,
+        });
+    }
+
+    /// Keys in the database, including any that were written by another connection since this store was opened.
+    pub fn keys_in_database(&mut self) -> rusqlite::Result<Vec<String>> {
+        let connection = self.connection.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut statement = connection.prepare("SELECT key FROM entries")?;
+        let keys = statement.query_map([], |row| row.get(0))?.collect();
+        return keys;
+    }
+}
+
+impl<V: Serialize> SqliteStore<V> {
+    fn upsert(&mut self, key: &str) -> io::Result<()> {
+        let value = serde_json::to_string(&self.entries[key])?;
+        let now = self.clock.now();
+        let connection = self.connection.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner());
+        connection
+            .execute(
+                "INSERT INTO entries (key, value, created_at) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+                params![key, value, now],
+            )
+            .map_err(io::Error::other)?;
+        return Ok(());
+    }
+
+    /// Keys that fail to flush stay dirty, to be retried.
+    fn flush_dirty(&mut self) -> io::Result<()> {
+        let dirty: Vec<String> = self.dirty.iter().cloned().collect();
+        for key in dirty {
+            if self.entries.contains_key(&key) {
+                self.upsert(&key)?;
+            }
+            self.dirty.remove(&key);
+        }
+        return Ok(());
+    }
+
+    /// Logs rather than returns failures, as the `KeyValueStore` interface has no room for them; the in-memory entry is
+    /// kept either way, and marked dirty so that the next flush retries it.
+    fn written(&mut self, key: &str) {
+        self.dirty.remove(key);
+        if let Err(error) = self.upsert(key) {
+            error!(%error, key, "failed to persist store entry");
+            self.dirty.insert(key.to_owned());
+        }
+    }
+}
+
+impl<V: Serialize + Send> Persist for SqliteStore<V> {
+    fn pending_writes(&self) -> usize {
+        return self.dirty.len();
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.flush_dirty();
+    }
+}
+
+impl<V: Serialize + DeserializeOwned + Send + Sync> KeyValueStore for SqliteStore<V> {
+    type Key = String;
+    type Value = V;
+
+    fn set(&mut self, key: Self::Key, value: Self::Value) -> &Self::Key {
+        self.entries.insert(key.clone(), value);
+        self.written(&key);
+        return self.entries.get_key_value(&key).unwrap().0;
+    }
+
+    fn set_if_absent(&mut self, key: Self::Key, value: Self::Value) -> Result<&Self::Key, Self::Value> {
+        if self.entries.contains_key(&key) {
+            return Err(value);
+        }
+        return Ok(self.set(key, value));
+    }
+
+    fn get(&self, key: &Self::Key) -> Option<&Self::Value> {
+        self.entries.get(key)
+    }
+
+    /// The caller may change the value through the returned reference, so the row is rewritten on the next flush.
+    fn get_mut(&mut self, key: &Self::Key) -> Option<&mut Self::Value> {
+        if !self.entries.contains_key(key) {
+            return None;
+        }
+        self.dirty.insert(key.clone());
+        return self.entries.get_mut(key);
+    }
+
+    fn del(&mut self, key: &Self::Key) -> Option<Self::Value> {
+        let value = self.entries.remove(key)?;
+        self.dirty.remove(key);
+        let connection = self.connection.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let deleted = connection
+            .query_row("DELETE FROM entries WHERE key = ?1 RETURNING value", [key], |row| row.get::<_, String>(0))
+            .optional();
+        if let Err(error) = deleted {
+            error!(%error, key, "failed to delete store entry");
+        }
+        return Some(value);
+    }
+
+    fn list<'kvs>(&'kvs self) -> Box<dyn Iterator<Item = &'kvs Self::Key> + 'kvs> {
+        return Box::new(self.entries.keys());
+    }
+}
+
+impl CounterStore for SqliteStore<Counter> {}
+
+/// Writes back values that were changed through `get_mut` since the last flush.
+impl<V: Serialize> Drop for SqliteStore<V> {
+    fn drop(&mut self) {
+        if self.dirty.is_empty() {
+            return;
+        }
+        if let Err(error) = self.flush_dirty() {
+            error!(%error, pending = self.dirty.len(), "failed to persist store on drop");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::clock::FakeClock;
+    use std::time::Duration;
+
+    #[test]
+    fn crud() {
+        let mut store = SqliteStore::<Vec<String>>::open_in_memory().unwrap();
+        let view = vec!["view".to_string()];
+
+        assert_eq!(store.set("KX3A-39WE".to_string(), view.clone()), "KX3A-39WE");
+        assert_eq!(store.get(&"KX3A-39WE".to_string()), Some(&view));
+        assert_eq!(store.set_if_absent("KX3A-39WE".to_string(), vec![]), Err(vec![]));
+        assert!(store.set_if_absent("9UQU-DUWW".to_string(), vec![]).is_ok());
+
+        store.get_mut(&"KX3A-39WE".to_string()).unwrap().push("print".to_string());
+        assert_eq!(store.pending_writes(), 1);
+        store.flush().unwrap();
+        assert_eq!(store.pending_writes(), 0);
+
+        let mut keys = store.keys_in_database().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["9UQU-DUWW", "KX3A-39WE"]);
+        assert_eq!(store.list().count(), 2);
+
+        assert_eq!(store.del(&"9UQU-DUWW".to_string()), Some(vec![]));
+        assert_eq!(store.del(&"9UQU-DUWW".to_string()), None);
+        assert_eq!(store.keys_in_database().unwrap(), vec!["KX3A-39WE"]);
+    }
+
+    #[test]
+    fn reopen_restores_entries_and_skips_expired_rows() {
+        let path = std::env::temp_dir().join(format!("smother-{}.sqlite", uuid::Uuid::new_v4()));
+        let clock = Arc::new(FakeClock::new(1_700_000_000));
+
+        {
+            let mut store = SqliteStore::open_with_clock(&path, clock.clone()).unwrap();
+            store.set("KX3A-39WE".to_string(), vec!["view".to_string()]);
+            store.set("9UQU-DUWW".to_string(), vec!["print".to_string()]);
+            store.get_mut(&"KX3A-39WE".to_string()).unwrap().push("print".to_string());
+            store.flush().unwrap();
+
+            let connection = store.connection.get_mut().unwrap();
+            let expires_at = clock.now() + 60;
+            connection.execute("UPDATE entries SET expires_at = ?1 WHERE key = '9UQU-DUWW'", [expires_at]).unwrap();
+        }
+
+        let store = SqliteStore::<Vec<String>>::open_with_clock(&path, clock.clone()).unwrap();
+        assert_eq!(store.get(&"9UQU-DUWW".to_string()), Some(&vec!["print".to_string()]));
+        drop(store);
+
+        clock.advance(Duration::from_secs(60));
+        let store = SqliteStore::<Vec<String>>::open_with_clock(&path, clock).unwrap();
+        assert_eq!(store.get(&"KX3A-39WE".to_string()), Some(&vec!["view".to_string(), "print".to_string()]));
+        assert_eq!(store.get(&"9UQU-DUWW".to_string()), None);
+
+        drop(store);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
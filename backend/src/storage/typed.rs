@@ -0,0 +1,296 @@
+//! A typed view on a store of strings: values are JSON-encoded on their way in and decoded on their way out, so that
+//! any `KeyValueStore<Key = String, Value = String>` can hold e.g. `ResourceDescription`s. Like `SqliteStore`, it
+//! mirrors the decoded values in memory so that it can hand out references like any other `KeyValueStore`.
+//!
+//! Values are stored as `{"version": n, "value": ...}`, so that values written in an older format can be told apart
+//! and brought up to date by a `Migrator` when the store is opened. Values stored without a version tag count as
+//! version 0.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+use tracing::error;
+
+use super::file::Persist;
+use super::{Counter, CounterStore, KeyValueStore};
+
+#[derive(Debug, Error)]
+pub enum SerdeStoreError {
+    #[error("the value stored under {key:?} is not valid JSON for this store: {source}")]
+    Decode { key: String, source: serde_json::Error },
+    #[error("the value stored under {key:?} cannot be migrated from version {version}: {reason}")]
+    Migrate { key: String, version: u32, reason: String },
+}
+
+/// Brings values written in an older format up to date, as JSON, before they are decoded.
+pub trait Migrator: Send + Sync {
+    /// The version values are written with. Versions start at 1; 0 stands for values stored without a version tag.
+    fn version(&self) -> u32;
+
+    /// Rewrites `value`, written with the older `version`, into the current format.
+    fn migrate(&self, version: u32, value: Value) -> Result<Value, String>;
+}
+
+/// Migrates nothing, relying on lenient deserialization instead: members added since are left to their defaults.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lenient;
+
+impl Migrator for Lenient {
+    fn version(&self) -> u32 {
+        return 1;
+    }
+
+    fn migrate(&self, _version: u32, value: Value) -> Result<Value, String> {
+        return Ok(value);
+    }
+}
+
+#[derive(Serialize)]
+struct Versioned<'v, V> {
+    version: u32,
+    value: &'v V,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Stored {
+    version: u32,
+    value: Value,
+}
+
+pub struct SerdeStore<S, V> {
+    inner: S,
+    entries: HashMap<String, V>,
+    /// Keys handed out through `get_mut`, or migrated on opening, whose encoding is rewritten on the next write or
+    /// flush.
+    dirty: HashSet<String>,
+    migrator: Box<dyn Migrator>,
+}
+
+impl<S, V> SerdeStore<S, V>
+where
+    S: KeyValueStore<Key = String, Value = String>,
+    V: Serialize + DeserializeOwned,
+{
+    /// Decodes every value already in `inner`, failing on the first one that does not decode.
+    pub fn new(inner: S) -> Result<Self, SerdeStoreError> {
+        return Self::with_migrator(inner, Lenient);
+    }
+
+    /// Decodes every value already in `inner`, migrating those of an older version first. Migrated values are written
+    /// back in the current version on the next flush.
+    pub fn with_migrator(inner: S, migrator: impl Migrator + 'static) -> Result<Self, SerdeStoreError> {
+        let mut entries = HashMap::new();
+        let mut dirty = HashSet::new();
+        for key in inner.list() {
+            let encoded = inner.get(key).expect("listed keys are present");
+            let decode_error = |source| SerdeStoreError::Decode { key: key.clone(), source };
+            let json: Value = serde_json::from_str(encoded).map_err(decode_error)?;
+            let Stored { version, mut value } = match serde_json::from_value(json.clone()) {
+                Ok(stored) => stored,
+                Err(_) => Stored { version: 0, value: json },
+            };
+            if version > migrator.version() {
+                let reason = format!("the store is at version {}", migrator.version());
+                return Err(SerdeStoreError::Migrate { key: key.clone(), version, reason });
+            }
+            if version < migrator.version() {
+                value = migrator
+                    .migrate(version, value)
+                    .map_err(|reason| SerdeStoreError::Migrate { key: key.clone(), version, reason })?;
+                dirty.insert(key.clone());
+            }
+            entries.insert(key.clone(), serde_json::from_value(value).map_err(decode_error)?);
+        }
+        return Ok(Self { inner, entries, dirty, migrator: Box::new(migrator) });
+    }
+
+    /// The underlying store of strings, with any pending changes written back first.
+    pub fn into_inner(mut self) -> io::Result<S> {
+        self.flush_dirty()?;
+        return Ok(self.inner);
+    }
+
+    fn encode(&mut self, key: &str) -> io::Result<()> {
+        let versioned = Versioned { version: self.migrator.version(), value: &self.entries[key] };
+        let encoded = serde_json::to_string(&versioned)?;
+        self.inner.set(key.to_owned(), encoded);
+        return Ok(());
+    }
+
+    /// Keys that fail to flush stay dirty, to be retried.
+    fn flush_dirty(&mut self) -> io::Result<()> {
+        let dirty: Vec<String> = self.dirty.iter().cloned().collect();
+        for key in dirty {
+            if self.entries.contains_key(&key) {
+                self.encode(&key)?;
+            }
+            self.dirty.remove(&key);
+        }
+        return Ok(());
+    }
+
+    /// Logs rather than returns failures, as the `KeyValueStore` interface has no room for them; the decoded entry is
+    /// kept either way, and marked dirty so that the next flush retries it.
+    fn written(&mut self, key: &str) {
+        self.dirty.remove(key);
+        if let Err(error) = self.encode(key) {
+            error!(%error, key, "failed to encode store entry");
+            self.dirty.insert(key.to_owned());
+        }
+    }
+}
+
+impl<S, V> Persist for SerdeStore<S, V>
+where
+    S: KeyValueStore<Key = String, Value = String>,
+    V: Serialize + DeserializeOwned + Send,
+{
+    fn pending_writes(&self) -> usize {
+        return self.dirty.len();
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.flush_dirty();
+    }
+}
+
+impl<S, V> KeyValueStore for SerdeStore<S, V>
+where
+    S: KeyValueStore<Key = String, Value = String>,
+    V: Serialize + DeserializeOwned + Send + Sync,
+{
+    type Key = String;
+    type Value = V;
+
+    fn set(&mut self, key: Self::Key, value: Self::Value) -> &Self::Key {
+        self.entries.insert(key.clone(), value);
+        self.written(&key);
+        return self.entries.get_key_value(&key).unwrap().0;
+    }
+
+    fn set_if_absent(&mut self, key: Self::Key, value: Self::Value) -> Result<&Self::Key, Self::Value> {
+        if self.entries.contains_key(&key) {
+            return Err(value);
+        }
+        return Ok(self.set(key, value));
+    }
+
+    fn get(&self, key: &Self::Key) -> Option<&Self::Value> {
+        self.entries.get(key)
+    }
+
+    /// The caller may change the value through the returned reference, so it is re-encoded on the next flush.
+    fn get_mut(&mut self, key: &Self::Key) -> Option<&mut Self::Value> {
+        if !self.entries.contains_key(key) {
+            return None;
+        }
+        self.dirty.insert(key.clone());
+        return self.entries.get_mut(key);
+    }
+
+    fn del(&mut self, key: &Self::Key) -> Option<Self::Value> {
+        let value = self.entries.remove(key)?;
+        self.dirty.remove(key);
+        self.inner.del(key);
+        return Some(value);
+    }
+
+    fn list<'kvs>(&'kvs self) -> Box<dyn Iterator<Item = &'kvs Self::Key> + 'kvs> {
+        return Box::new(self.entries.keys());
+    }
+}
+
+impl<S: KeyValueStore<Key = String, Value = String>> CounterStore for SerdeStore<S, Counter> {}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::uma::federation::{ResourceDescription, Scope};
+
+    #[test]
+    fn resource_descriptions_over_strings() {
+        let mut store = SerdeStore::<_, ResourceDescription>::new(HashMap::<String, String>::new()).unwrap();
+        let description = ResourceDescription::builder().scope("view").build().unwrap();
+
+        assert_eq!(store.set("KX3A-39WE".to_string(), description.clone()), "KX3A-39WE");
+        assert_eq!(store.get(&"KX3A-39WE".to_string()), Some(&description));
+        assert!(store.set_if_absent("KX3A-39WE".to_string(), description.clone()).is_err());
+
+        store.get_mut(&"KX3A-39WE".to_string()).unwrap().resource_scopes.push("print".into());
+        assert_eq!(store.pending_writes(), 1);
+        let inner = store.into_inner().unwrap();
+        let encoded: Value = serde_json::from_str(&inner["KX3A-39WE"]).unwrap();
+        assert_eq!(encoded["version"], 1);
+        assert_eq!(encoded["value"]["resource_scopes"], serde_json::json!(["view", "print"]));
+
+        let mut store = SerdeStore::<_, ResourceDescription>::new(inner).unwrap();
+        assert_eq!(store.get(&"KX3A-39WE".to_string()).unwrap().resource_scopes.len(), 2);
+        assert!(store.del(&"KX3A-39WE".to_string()).is_some());
+        assert!(store.into_inner().unwrap().is_empty());
+    }
+
+    #[test]
+    fn undecodable_values_are_reported() {
+        let inner = HashMap::from([("KX3A-39WE".to_string(), "{\"resource_scopes\":".to_string())]);
+
+        let error = SerdeStore::<_, ResourceDescription>::new(inner).err().unwrap();
+
+        assert!(matches!(&error, SerdeStoreError::Decode { key, .. } if key == "KX3A-39WE"));
+        assert!(error.to_string().contains("KX3A-39WE"));
+    }
+
+    /// Version 2 renamed the scopes of UMA 1.0 resource set descriptions to resource_scopes.
+    struct RenameScopes;
+
+    impl Migrator for RenameScopes {
+        fn version(&self) -> u32 {
+            return 2;
+        }
+
+        fn migrate(&self, _version: u32, mut value: Value) -> Result<Value, String> {
+            let members = value.as_object_mut().ok_or("not an object")?;
+            if let Some(scopes) = members.remove("scopes") {
+                members.insert("resource_scopes".to_string(), scopes);
+            }
+            return Ok(value);
+        }
+    }
+
+    #[test]
+    fn old_values_are_migrated_on_opening() {
+        let inner = HashMap::from([
+            ("KX3A-39WE".to_string(), r#"{"scopes":["view"],"name":"Photo album"}"#.to_string()),
+            ("9UQU-DUWW".to_string(), r#"{"version":1,"value":{"scopes":["print"]}}"#.to_string()),
+            ("1ZAG-93KD".to_string(), r#"{"version":2,"value":{"resource_scopes":["edit"]}}"#.to_string()),
+        ]);
+
+        let store = SerdeStore::<_, ResourceDescription>::with_migrator(inner, RenameScopes).unwrap();
+
+        let album = store.get(&"KX3A-39WE".to_string()).unwrap();
+        assert_eq!(album.resource_scopes, vec![Scope::from("view")]);
+        assert_eq!(album.name.as_ref().unwrap().untagged.as_deref(), Some("Photo album"));
+        assert_eq!(album.owner, None);
+        assert_eq!(store.get(&"9UQU-DUWW".to_string()).unwrap().resource_scopes, vec![Scope::from("print")]);
+        assert_eq!(store.pending_writes(), 2);
+
+        let inner = store.into_inner().unwrap();
+        let rewritten: Value = serde_json::from_str(&inner["KX3A-39WE"]).unwrap();
+        assert_eq!(rewritten["version"], 2);
+        assert_eq!(rewritten["value"]["resource_scopes"], serde_json::json!(["view"]));
+    }
+
+    #[test]
+    fn newer_values_are_refused() {
+        let inner = HashMap::from([("KX3A-39WE".to_string(), r#"{"version":3,"value":{}}"#.to_string())]);
+
+        let error = SerdeStore::<_, ResourceDescription>::with_migrator(inner, RenameScopes).err().unwrap();
+
+        assert!(matches!(error, SerdeStoreError::Migrate { version: 3, .. }));
+    }
+
+}
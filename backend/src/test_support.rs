@@ -0,0 +1,260 @@
+//! Fixtures for exercising the Solid-OIDC path in tests: keypairs that sign access tokens, and a mock issuer that
+//! publishes the matching JWK set, OpenID configuration and WebID document on a local port. Enabled for the crate's own
+//! tests and, through the `test-util` feature, for downstream ones.
+
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+
+use axum::http::{header::AUTHORIZATION, HeaderMap, StatusCode};
+use axum::{response::IntoResponse, routing::{get, post}, Form, Json, Router};
+use chrono::{Duration, Utc};
+use jwt_compact::{
+    alg::{Ed25519, Es256, ModulusBits, Rsa, RsaPrivateKey},
+    jwk::JsonWebKey,
+    Algorithm, AlgorithmExt, Claims, Header,
+};
+use oxiri::Iri;
+use rand_core::{OsRng, RngCore};
+use serde::Serialize;
+use serde_json::json;
+use tokio::task::JoinHandle;
+
+pub use crate::oidc::{AccessToken, Cnf, Jwk, JwkSet};
+
+enum SigningKey {
+    Es256(<Es256 as Algorithm>::SigningKey),
+    Rs256(RsaPrivateKey),
+    Ed25519(<Ed25519 as Algorithm>::SigningKey),
+}
+
+/// A signing key together with the `kid` under which its public half is published.
+pub struct Keypair {
+    kid: String,
+    key: SigningKey,
+}
+
+impl Keypair {
+    pub fn es256(kid: &str) -> Self {
+        let key = loop {
+            let mut bytes = [0u8; 32];
+            OsRng.fill_bytes(&mut bytes);
+            // Rejects the (astronomically unlikely) scalars outside the P-256 group order.
+            if let Ok(key) = <Es256 as Algorithm>::SigningKey::from_slice(&bytes) {
+                break key;
+            }
+        };
+        Self {
+            kid: kid.to_owned(),
+            key: SigningKey::Es256(key),
+        }
+    }
+
+    /// Generating a 2048-bit RSA key takes a noticeable while in debug builds; prefer `es256` unless the test is
+    /// about RS256 specifically.
+    pub fn rs256(kid: &str) -> Self {
+        let (key, _) = Rsa::generate(&mut OsRng, ModulusBits::TwoKibibytes).expect("RSA key generation failed");
+        Self {
+            kid: kid.to_owned(),
+            key: SigningKey::Rs256(key.into_inner()),
+        }
+    }
+
+    pub fn ed25519(kid: &str) -> Self {
+        let (key, _) = Ed25519::generate(&mut OsRng);
+        Self {
+            kid: kid.to_owned(),
+            key: SigningKey::Ed25519(key),
+        }
+    }
+
+    /// The public key as it would appear in the issuer's JWK set.
+    pub fn jwk(&self) -> Jwk {
+        // Round-trip through JSON to detach the key material from the borrowed key.
+        let (alg, key) = match &self.key {
+            SigningKey::Es256(key) => ("ES256", serde_json::to_value(JsonWebKey::from(key.verifying_key()))),
+            SigningKey::Rs256(key) => ("RS256", serde_json::to_value(JsonWebKey::from(&key.to_public_key()))),
+            SigningKey::Ed25519(key) => ("EdDSA", serde_json::to_value(JsonWebKey::from(&key.public_key()))),
+        };
+        Jwk {
+            kid: Some(self.kid.clone()),
+            alg: Some(alg.to_owned()),
+            key: serde_json::from_value(key.unwrap()).unwrap(),
+        }
+    }
+
+    pub fn sign<T: Serialize>(&self, claims: &Claims<T>) -> String {
+        let header = Header::empty().with_key_id(&self.kid);
+        let signed = match &self.key {
+            SigningKey::Es256(key) => Es256.token(&header, claims, key),
+            SigningKey::Rs256(key) => Rsa::rs256().token(&header, claims, key),
+            SigningKey::Ed25519(key) => Ed25519.token(&header, claims, key),
+        };
+        signed.expect("signing failed")
+    }
+}
+
+/// The key names of the JSON object `value` serializes to, sorted: serde_json is built without preserve_order, so
+/// the order in which they are serialized is lost. Compare the serialized bytes where that order matters.
+pub fn serialized_keys(value: impl Serialize) -> Vec<String> {
+    return serde_json::to_value(value).unwrap().as_object().unwrap().keys().cloned().collect();
+}
+
+/// An access token for the mock issuer's WebID, with the audience a Solid-OIDC client would request.
+pub fn access_token(issuer: &MockIssuer) -> AccessToken {
+    let client_id = "https://client.example/id";
+    AccessToken {
+        webid: issuer.webid(),
+        iss: issuer.issuer(),
+        sub: "alice".to_owned(),
+        aud: vec!["solid".to_owned(), client_id.to_owned()],
+        azp: Iri::parse(client_id.to_owned()).unwrap(),
+        cnf: Cnf {
+            jkt: "0ZcOCORZNYy-DWpqq30jZyJGHTN0d2HglBV3uiguA4I".to_owned(),
+        },
+    }
+}
+
+/// Wraps `token` in claims issued now and expiring after `lifetime`; a negative lifetime yields an expired token.
+pub fn claims<T>(token: T, lifetime: Duration) -> Claims<T> {
+    let now = Utc::now();
+    let mut claims = Claims::new(token);
+    claims.issued_at = Some(now + Duration::minutes(-1).min(lifetime));
+    claims.expiration = Some(now + lifetime);
+    return claims;
+}
+
+/// The opaque PAT the mock issuer introspects as active, for its WebID, with the uma_protection scope.
+pub const OPAQUE_PAT: &str = "2YotnFZFEjr1zCsicMWpAA";
+
+/// The Authorization header value the mock issuer requires of introspection requests.
+pub const INTROSPECTION_CREDENTIALS: &str = "Basic cnM6czNjcjN0";
+
+/// An HTTP server on an ephemeral local port acting as both the OIDC issuer and the WebID profile host. It serves
+/// `/.well-known/openid-configuration`, `/jwks`, the JSON-LD profile document `/profile` and an `/introspect` endpoint
+/// that knows `OPAQUE_PAT` only, and is shut down when dropped.
+pub struct MockIssuer {
+    addr: SocketAddr,
+    server: JoinHandle<()>,
+    config_requests: Arc<AtomicUsize>,
+}
+
+impl MockIssuer {
+    /// The profile lists the mock issuer itself as the WebID's OIDC issuer.
+    pub async fn start(keys: Vec<Jwk>) -> Self {
+        return Self::start_with_profile(keys, own_profile).await;
+    }
+
+    /// Serves the JSON-LD document `profile` builds from the server's base URL as the WebID profile.
+    pub async fn start_with_profile(keys: Vec<Jwk>, profile: impl FnOnce(&str) -> serde_json::Value) -> Self {
+        return Self::serve(keys, profile, Faults::default()).await;
+    }
+
+    /// Answers the first `failures` requests for the OpenID configuration with `status`, and the ones after normally.
+    pub async fn start_flaky(keys: Vec<Jwk>, failures: usize, status: StatusCode) -> Self {
+        return Self::serve(keys, own_profile, Faults { failures, status, delay: StdDuration::ZERO }).await;
+    }
+
+    /// Takes `delay` to answer every request for the OpenID configuration.
+    pub async fn start_slow(keys: Vec<Jwk>, delay: StdDuration) -> Self {
+        return Self::serve(keys, own_profile, Faults { delay, ..Faults::default() }).await;
+    }
+
+    async fn serve(
+        keys: Vec<Jwk>,
+        profile: impl FnOnce(&str) -> serde_json::Value,
+        faults: Faults,
+    ) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("cannot bind mock issuer");
+        let addr = listener.local_addr().unwrap();
+        let base = format!("http://{addr}");
+
+        let config = json!({ "issuer": base, "jwks_uri": format!("{base}/jwks") });
+        let jwks = serde_json::to_value(JwkSet { keys }).unwrap();
+        let profile = profile(&base);
+        let config_requests = Arc::new(AtomicUsize::new(0));
+
+        let webid = format!("{base}/profile#me");
+        let introspection = move |headers: HeaderMap, Form(form): Form<HashMap<String, String>>| async move {
+            if !headers.get(AUTHORIZATION).is_some_and(|credentials| credentials == INTROSPECTION_CREDENTIALS) {
+                return StatusCode::UNAUTHORIZED.into_response();
+            }
+            if form.get("token").map(String::as_str) != Some(OPAQUE_PAT) {
+                return Json(json!({ "active": false })).into_response();
+            }
+            return Json(json!({ "active": true, "sub": webid, "scope": "uma_protection" })).into_response();
+        };
+
+        let requests = config_requests.clone();
+        let configuration = move || async move {
+            tokio::time::sleep(faults.delay).await;
+            if requests.fetch_add(1, Ordering::SeqCst) < faults.failures {
+                return faults.status.into_response();
+            }
+            return Json(config).into_response();
+        };
+
+        let app = Router::new()
+            .route("/.well-known/openid-configuration", get(configuration))
+            .route("/jwks", get(move || async move { Json(jwks) }))
+            .route("/profile", get(move || async move { Json(profile) }))
+            .route("/introspect", post(introspection));
+
+        let server = axum::Server::from_tcp(listener)
+            .expect("cannot serve mock issuer")
+            .serve(app.into_make_service());
+
+        let server = tokio::spawn(async move {
+            server.await.expect("mock issuer failed");
+        });
+
+        Self { addr, server, config_requests }
+    }
+
+    /// How often the OpenID configuration was requested, failed requests included.
+    pub fn config_requests(&self) -> usize {
+        return self.config_requests.load(Ordering::SeqCst);
+    }
+
+    pub fn issuer(&self) -> Iri<String> {
+        Iri::parse(format!("http://{}", self.addr)).unwrap()
+    }
+
+    pub fn introspection_endpoint(&self) -> String {
+        format!("http://{}/introspect", self.addr)
+    }
+
+    pub fn webid(&self) -> Iri<String> {
+        Iri::parse(format!("http://{}/profile#me", self.addr)).unwrap()
+    }
+}
+
+/// How the OpenID configuration endpoint misbehaves.
+#[derive(Clone, Copy)]
+struct Faults {
+    failures: usize,
+    status: StatusCode,
+    delay: StdDuration,
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        return Faults { failures: 0, status: StatusCode::OK, delay: StdDuration::ZERO };
+    }
+}
+
+fn own_profile(base: &str) -> serde_json::Value {
+    return json!({
+        "@context": { "solid": "http://www.w3.org/ns/solid/terms#" },
+        "@id": "#me",
+        "solid:oidcIssuer": { "@id": base },
+    });
+}
+
+impl Drop for MockIssuer {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
@@ -1,6 +1,14 @@
 pub mod resource_registration;
 pub mod permission;
 pub mod token_introspection;
+pub mod token_revocation;
 pub mod errors;
 pub mod federation;
 pub mod grants;
+pub mod pagination;
+pub mod token;
+pub mod axum;
+#[cfg(feature = "json-schema")]
+pub mod json_schema;
+#[cfg(feature = "openapi")]
+pub mod openapi;
@@ -0,0 +1,1910 @@
+//! Glue between axum and the UMA handlers, which are written against plain `http` types: each route rebuilds the typed
+//! `http::Request` a handler expects from axum's extractors, calls the handler with the shared stores, and turns its
+//! `Response<T>` or `Response<ErrorMessage>` into a JSON response. The resource registration API also speaks CBOR, for
+//! clients that send it or ask for it.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::future;
+use std::net::SocketAddr;
+use std::result;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use ::axum::body::{Bytes, Full, HttpBody};
+use ::axum::async_trait;
+use ::axum::extract::{ConnectInfo, DefaultBodyLimit, FromRequest, MatchedPath, Path, RawQuery, State};
+use ::axum::middleware::{self, Next};
+use ::axum::http::{HeaderMap, Method, Request, Uri};
+use ::axum::response::{IntoResponse, Response};
+use ::axum::routing::{get, post};
+use ::axum::{BoxError, Extension, Json, Router};
+use http::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER};
+use http::{HeaderValue, StatusCode};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tower::{Layer, Service};
+use tracing::info;
+
+use crate::clock::{Clock, SystemClock};
+use crate::ids::{IdGenerator, RptValue};
+use crate::oidc::{is_jwt, JwkSet, Verifier, VerifiedPat, PROTECTION_SCOPE};
+use crate::resource::{Agent, Resource};
+use crate::storage::{CounterStore, KeyedMutex, SharedStore};
+
+use super::errors::{
+    ErrorMessage, INSUFFICIENT_SCOPE, INVALID_REQUEST, INVALID_TOKEN, PAYLOAD_TOO_LARGE, TOO_MANY_REQUESTS,
+    UNSUPPORTED_MEDIA_TYPE,
+};
+use super::federation::ResourceDescription;
+use super::resource_registration::{
+    create_resource_registration, delete_resource_registration, icon_advisory, is_validate_only,
+    list_resource_registration, merge_patch, missing_resource, read_resource_registration, update_resource_registration,
+    validate_resource_registration, IconChecked, RegistrationConfig, ResourceDescriptionStore, ResourceOwnerContext,
+};
+use super::permission::{
+    list_permission_tickets, request_permission_ticket, PatScopes, PermissionConfig, PermissionRequest,
+    PermissionTicketStore, TicketInspectionConfig,
+};
+use super::token::{RefreshTokenStore, RptStore};
+use super::token_introspection::{introspect_token, introspect_tokens, IntrospectionConfig, IntrospectionRequest};
+
+/// Resource descriptions can list many scope IRIs, so their endpoint accepts fairly large bodies.
+pub const RESOURCE_REGISTRATION_BODY_LIMIT: usize = 64 * 1024;
+
+/// An introspection request is a single form-encoded token (and hint).
+pub const INTROSPECTION_BODY_LIMIT: usize = 2 * 1024;
+
+/// A batch introspection request is a JSON array of tokens, as many as the configuration allows.
+pub const INTROSPECTION_BATCH_BODY_LIMIT: usize = 64 * 1024;
+
+/// A permission request names a few resources, each with the scopes the client is after.
+pub const PERMISSION_BODY_LIMIT: usize = 4 * 1024;
+
+/// [NO-SPEC] Request body limits in bytes by route pattern, as the router matches it (e.g. /rreg/:id, or /introspect
+/// for the root of a router nested there), with a default for routes without one. Applied to a whole router with
+/// `apply`; the limit of a route takes precedence over the endpoint limits the UMA routers set themselves, while the
+/// default only covers the routes that have none.
+#[derive(Debug, Clone)]
+pub struct BodyLimits {
+    pub default: usize,
+    pub routes: HashMap<String, usize>,
+}
+
+impl BodyLimits {
+    pub fn new(default: usize) -> Self {
+        return Self { default, routes: HashMap::new() };
+    }
+
+    pub fn route(mut self, pattern: impl Into<String>, limit: usize) -> Self {
+        self.routes.insert(pattern.into(), limit);
+        return self;
+    }
+
+    /// Limits the request bodies of every route `router` has so far, and of its fallback.
+    pub fn apply(self, router: Router) -> Router {
+        return router.layer(middleware::from_fn_with_state(Arc::new(self), limit_bodies));
+    }
+}
+
+/// The limit `BodyLimits` holds for the matched route, for `endpoint_body_limit` to honour.
+#[derive(Debug, Clone, Copy)]
+struct RouteBodyLimit(usize);
+
+async fn limit_bodies<B: Send + 'static>(
+    State(limits): State<Arc<BodyLimits>>,
+    matched_path: Option<MatchedPath>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let limit = matched_path.and_then(|matched_path| limits.routes.get(matched_path.as_str()).copied());
+    if let Some(limit) = limit {
+        request.extensions_mut().insert(RouteBodyLimit(limit));
+    }
+    return with_body_limit(limit.unwrap_or(limits.default), request, next).await;
+}
+
+/// Limits the request bodies of an endpoint to `default`, unless `BodyLimits` holds a limit for its route. Apply with
+/// `middleware::from_fn_with_state`.
+async fn endpoint_body_limit<B: Send + 'static>(
+    State(default): State<usize>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let limit = request.extensions().get::<RouteBodyLimit>().map_or(default, |limit| limit.0);
+    return with_body_limit(limit, request, next).await;
+}
+
+/// Runs the rest of the stack as `DefaultBodyLimit::max(limit)` would, so that body extractors stop reading there.
+async fn with_body_limit<B: Send + 'static>(limit: usize, request: Request<B>, next: Next<B>) -> Response {
+    let mut service = DefaultBodyLimit::max(limit).layer(next);
+    let ready = future::poll_fn(|cx| service.poll_ready(cx)).await;
+    let response = match ready {
+        Ok(()) => service.call(request).await,
+        Err(infallible) => Err(infallible),
+    };
+    return response.unwrap_or_else(|infallible| match infallible {});
+}
+
+/// What the resource registration routes share. The store sits behind a single lock, held for the duration of a
+/// handler call. The updates and deletes of a single resource, which a PATCH spreads over a read and a write, are
+/// serialized by a lock per _id, so that those of different resources do not wait on each other.
+#[derive(Clone)]
+pub struct ResourceRegistrationState {
+    pub store: Arc<Mutex<ResourceDescriptionStore>>,
+    pub updates: KeyedMutex<String>,
+    pub ids: Arc<dyn IdGenerator>,
+    pub config: RegistrationConfig,
+}
+
+/// What the introspection route shares. Introspection only reads, so concurrent requests do not wait on each other.
+#[derive(Clone)]
+pub struct IntrospectionState {
+    pub rpts: SharedStore<RptStore>,
+    pub refresh_tokens: SharedStore<RefreshTokenStore>,
+    pub config: IntrospectionConfig,
+}
+
+/// What the permission route shares. Every request issues a ticket, so it takes the ticket store for writing.
+#[derive(Clone)]
+pub struct PermissionState {
+    pub tickets: SharedStore<PermissionTicketStore<'static>>,
+    pub ids: Arc<dyn IdGenerator>,
+    pub config: PermissionConfig,
+}
+
+/// What the ticket inspection route shares. It only reads, but needs the resource descriptions to tell whose tickets
+/// are whose.
+#[derive(Clone)]
+pub struct TicketInspectionState {
+    pub tickets: SharedStore<PermissionTicketStore<'static>>,
+    pub resources: Arc<Mutex<ResourceDescriptionStore>>,
+    pub config: TicketInspectionConfig,
+}
+
+/// The resource registration API, to be merged into the application router. Its routes sit under the base path of the
+/// registration config (e.g. /rreg), where the collection is reachable both as /rreg/ and as /rreg; nesting the router
+/// instead would make axum route only one of the two.
+pub fn resource_registration_router(state: ResourceRegistrationState) -> Router {
+    let base_path = state.config.base_path.trim_end_matches('/').to_string();
+    let mut router = Router::new()
+        .route(&format!("{base_path}/"), get(list).post(create))
+        .route(&format!("{base_path}/:id"), get(read).put(update).patch(patch).delete(delete));
+    if !base_path.is_empty() {
+        router = router.route(&base_path, get(list).post(create));
+    }
+    return router
+        .route_layer(middleware::from_fn(audit))
+        .route_layer(middleware::from_fn(track_requests))
+        .layer(middleware::from_fn_with_state(RESOURCE_REGISTRATION_BODY_LIMIT, endpoint_body_limit))
+        .with_state(state);
+}
+
+/// The protection API that resource servers call with a PAT: resource registration, at the base path of its config,
+/// the permission endpoint, at /perm, and token introspection, at /introspect. Its routes are behind
+/// `authenticate_pat`, then `rate_limit`, so that every resource owner gets a bucket of their own, and
+/// `require_protection_scope`; requests for any other path pass through untouched, to the fallback of the router it is
+/// merged into.
+pub fn protection_router(
+    registration: ResourceRegistrationState,
+    permission: PermissionState,
+    introspection: IntrospectionState,
+    authentication: PatAuthentication,
+    limiter: RateLimiter,
+) -> Router {
+    return Router::new()
+        .merge(resource_registration_router(registration))
+        .nest("/perm", permission_router(permission))
+        .nest("/introspect", introspection_router(introspection))
+        .route_layer(middleware::from_fn(require_protection_scope))
+        .route_layer(middleware::from_fn_with_state(limiter, rate_limit))
+        .route_layer(middleware::from_fn_with_state(authentication, authenticate_pat));
+}
+
+/// The permission endpoint, to be nested at its path (e.g. /perm).
+pub fn permission_router(state: PermissionState) -> Router {
+    return Router::new()
+        .route(
+            "/",
+            post(request_permission).layer(middleware::from_fn_with_state(PERMISSION_BODY_LIMIT, endpoint_body_limit)),
+        )
+        .route_layer(middleware::from_fn(track_requests))
+        .with_state(state);
+}
+
+/// The token introspection API, to be nested at the introspection endpoint (e.g. /introspect).
+pub fn introspection_router(state: IntrospectionState) -> Router {
+    return Router::new()
+        .route(
+            "/",
+            post(introspect).layer(middleware::from_fn_with_state(INTROSPECTION_BODY_LIMIT, endpoint_body_limit)),
+        )
+        .route(
+            "/batch",
+            post(introspect_batch)
+                .layer(middleware::from_fn_with_state(INTROSPECTION_BATCH_BODY_LIMIT, endpoint_body_limit)),
+        )
+        .route_layer(middleware::from_fn(track_requests))
+        .with_state(state);
+}
+
+/// The ticket inspection, to be nested at an operator path (e.g. /admin/tickets) behind whatever authenticates the PAT
+/// and inserts its `PatScopes`.
+pub fn ticket_inspection_router(state: TicketInspectionState) -> Router {
+    return Router::new()
+        .route("/", get(inspect_tickets))
+        .route_layer(middleware::from_fn(track_requests))
+        .with_state(state);
+}
+
+/// Counts every request in `uma_requests_total{endpoint,method,status}` and records its latency in
+/// `uma_request_duration_seconds{endpoint,method}`. The endpoint is the matched route (e.g. /rreg/:id), not the
+/// requested path, to keep the number of label values bounded.
+pub async fn track_requests<B>(matched_path: MatchedPath, request: Request<B>, next: Next<B>) -> Response {
+    let endpoint = matched_path.as_str().to_owned();
+    let method = request.method().to_string();
+    let started = Instant::now();
+
+    let response = next.run(request).await;
+
+    let status = response.status().as_u16().to_string();
+    let labels = [("endpoint", endpoint.clone()), ("method", method.clone()), ("status", status)];
+    metrics::counter!("uma_requests_total", &labels).increment(1);
+    metrics::histogram!("uma_request_duration_seconds", "endpoint" => endpoint, "method" => method)
+        .record(started.elapsed().as_secs_f64());
+    return response;
+}
+
+/// The _id of a resource registered by the request, for the audit event to name; other operations take it from the path.
+#[derive(Clone)]
+struct RegisteredResource(String);
+
+/// Emits an audit event for every call to the resource registration API, naming the resource owner (the `Agent`
+/// extension inserted by whatever authenticated the PAT), the operation and endpoint, the resource and the response
+/// status. The PAT itself, like every other header, is left out.
+pub async fn audit<B>(
+    matched_path: MatchedPath,
+    path: Option<Path<String>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let owner = request.extensions().get::<Agent>().map(|agent| agent.identifier().to_string());
+    let operation = match (request.method(), &path) {
+        (&Method::POST, _) => "create",
+        (&Method::GET, None) => "list",
+        (&Method::GET, Some(_)) => "read",
+        (&Method::PUT, _) => "update",
+        (&Method::PATCH, _) => "patch",
+        (&Method::DELETE, _) => "delete",
+        _ => "other",
+    };
+
+    let response = next.run(request).await;
+
+    let created = response.extensions().get::<RegisteredResource>().map(|created| created.0.clone());
+    let resource_id = created.or(path.map(|Path(id)| id));
+    info!(
+        owner = owner.as_deref().unwrap_or("anonymous"),
+        endpoint = matched_path.as_str(),
+        operation,
+        resource_id = resource_id.as_deref(),
+        result = response.status().as_u16(),
+        "audit"
+    );
+    return response;
+}
+
+/// [NO-SPEC] How `authenticate_pat` validates PATs: those that are JWTs offline, against the keys of the authorization
+/// server that issues them, and opaque ones, if configured, by introspecting them at its introspection endpoint.
+/// Without one, opaque PATs are invalid tokens.
+#[derive(Debug, Clone)]
+pub struct PatAuthentication {
+    jwks: Arc<JwkSet>,
+    introspection: Option<PatIntrospection>,
+}
+
+/// The introspection endpoint of the authorization server that issues PATs, and the Authorization header value, if
+/// any, with which this server authenticates to it.
+#[derive(Debug, Clone)]
+struct PatIntrospection {
+    endpoint: String,
+    credentials: Option<String>,
+}
+
+impl PatAuthentication {
+    /// From a JWK set as published at the jwks_uri of the authorization server.
+    pub fn from_jwks(json: &str) -> serde_json::Result<Self> {
+        return serde_json::from_str(json).map(|jwks| Self { jwks: Arc::new(jwks), introspection: None });
+    }
+
+    /// Introspects opaque PATs at `endpoint`, sending `credentials`, if any, as the Authorization header.
+    pub fn with_introspection(mut self, endpoint: impl Into<String>, credentials: Option<String>) -> Self {
+        self.introspection = Some(PatIntrospection { endpoint: endpoint.into(), credentials });
+        return self;
+    }
+}
+
+/// Authenticates the PAT a request carries as a Bearer token, offline if it is a JWT and by introspection if it is
+/// opaque, and inserts the resource owner it names as the `Agent` extension, and its scopes as `PatScopes`, for the
+/// layers and handlers after it. Requests without a PAT, or with one that does not validate, are answered with
+/// invalid_token and a Bearer challenge naming the uma_protection scope; those with a PAT without that scope, with
+/// insufficient_scope. Apply with `middleware::from_fn_with_state`.
+pub async fn authenticate_pat<B>(
+    State(authentication): State<PatAuthentication>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let authorization = request.headers().get(AUTHORIZATION).and_then(|value| value.to_str().ok());
+    let token = authorization
+        .and_then(|value| value.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Bearer"))
+        .map(|(_, token)| token.trim());
+    let verifier = Verifier::default();
+    let verified = match (token, &authentication.introspection) {
+        (Some(token), Some(PatIntrospection { endpoint, credentials })) if !is_jwt(token) => {
+            verifier.introspect_pat(token, endpoint, credentials.as_deref()).await
+        }
+        (Some(token), _) => verifier.validate_pat_offline(token, &authentication.jwks).await,
+        (None, _) => {
+            let message = ErrorMessage {
+                error_description: Some(Cow::Borrowed("The request carries no PAT.")),
+                ..INVALID_TOKEN
+            };
+            return into_response::<()>(Err(message.with_required_scope(PROTECTION_SCOPE).into()));
+        }
+    };
+    let VerifiedPat { owner, scopes } = match verified {
+        Ok(verified) => verified,
+        Err(error) => {
+            let (_, message) = http::Response::<ErrorMessage>::from(error).into_parts();
+            let message = match message.status_code {
+                StatusCode::UNAUTHORIZED => message.with_required_scope(PROTECTION_SCOPE),
+                _ => message,
+            };
+            return into_response::<()>(Err(message.into()));
+        }
+    };
+    request.extensions_mut().insert(owner);
+    request.extensions_mut().insert(PatScopes(scopes));
+    return next.run(request).await;
+}
+
+/// Answers requests whose PAT does not carry the uma_protection scope, according to its `PatScopes` extension, with
+/// insufficient_scope and a Bearer challenge naming the scope, so that no other OAuth token can manage resources. The
+/// layer belongs inside whatever authenticates the PAT and inserts its scopes, around the protection API routers.
+pub async fn require_protection_scope<B>(request: Request<B>, next: Next<B>) -> Response {
+    if request.extensions().get::<PatScopes>().is_some_and(|scopes| scopes.contains(PROTECTION_SCOPE)) {
+        return next.run(request).await;
+    }
+    return into_response::<()>(Err(INSUFFICIENT_SCOPE.with_required_scope(PROTECTION_SCOPE).into()));
+}
+
+/// [NO-SPEC] Settings of the rate limiter: every PAT subject gets a bucket of `capacity` requests, refilled by one
+/// request every `refill_interval`.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitConfig {
+    pub capacity: u32,
+    pub refill_interval: Duration,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 60,
+            refill_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    /// The tokens the bucket holds at `now`, before capping at the capacity.
+    fn tokens_at(&self, now: Instant, refill_interval: Duration) -> f64 {
+        return self.tokens + now.saturating_duration_since(self.refilled).as_secs_f64() / refill_interval.as_secs_f64();
+    }
+}
+
+/// Number of buckets past which the rate limiter drops those that have refilled to capacity, as a fresh bucket would
+/// behave the same, so that the subjects seen over time do not accumulate.
+const BUCKET_SWEEP_THRESHOLD: usize = 1024;
+
+/// A token bucket per PAT subject, shared by every route it is layered on. The layer belongs inside whatever
+/// authenticates the PAT; requests that reach it without an `Agent` extension get a bucket per peer address instead.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Arc<std::sync::Mutex<HashMap<String, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        return Self { config, buckets: Default::default() };
+    }
+
+    /// Takes a token from the bucket of `subject`, or returns how long until one becomes available.
+    fn acquire(&self, subject: &str, now: Instant) -> result::Result<(), Duration> {
+        let RateLimitConfig { capacity, refill_interval } = self.config;
+        let mut buckets = self.buckets.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if buckets.len() >= BUCKET_SWEEP_THRESHOLD {
+            buckets.retain(|_, bucket| bucket.tokens_at(now, refill_interval) < capacity as f64);
+        }
+        let bucket = buckets
+            .entry(subject.to_string())
+            .or_insert(Bucket { tokens: capacity as f64, refilled: now });
+
+        bucket.tokens = bucket.tokens_at(now, refill_interval).min(capacity as f64);
+        bucket.refilled = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        return Err(refill_interval.mul_f64(1.0 - bucket.tokens));
+    }
+}
+
+/// Answers requests over the limit of their PAT subject with too_many_requests, and a Retry-After header with the
+/// number of seconds (rounded up) until the bucket holds a token again. Apply with `middleware::from_fn_with_state`.
+pub async fn rate_limit<B>(State(limiter): State<RateLimiter>, request: Request<B>, next: Next<B>) -> Response {
+    let Err(wait) = limiter.acquire(&rate_limit_subject(&request), Instant::now()) else {
+        return next.run(request).await;
+    };
+    return too_many_requests(wait);
+}
+
+/// [NO-SPEC] Settings of the fixed-window rate limiter: every PAT subject may make `limit` requests per `window`.
+#[derive(Debug, Clone, Copy)]
+pub struct WindowLimitConfig {
+    pub limit: u32,
+    pub window: Duration,
+}
+
+impl Default for WindowLimitConfig {
+    fn default() -> Self {
+        Self {
+            limit: 60,
+            window: Duration::from_secs(60),
+        }
+    }
+}
+
+/// The counters of the fixed-window rate limiter, by PAT subject.
+pub type RateLimitCounters = dyn CounterStore<Key = String>;
+
+/// A fixed-window counter per PAT subject, kept in a counter store: instances that share the store (e.g. Redis) share
+/// the limits as well. Coarser than `RateLimiter`, since a client can fit up to twice the limit around the end of a
+/// window. As with `RateLimiter`, the layer belongs inside whatever authenticates the PAT.
+#[derive(Clone)]
+pub struct WindowRateLimiter {
+    config: WindowLimitConfig,
+    counters: SharedStore<RateLimitCounters>,
+}
+
+impl WindowRateLimiter {
+    pub fn new(config: WindowLimitConfig, counters: SharedStore<RateLimitCounters>) -> Self {
+        return Self { config, counters };
+    }
+
+    /// Counts a request of `subject` at `now` (in seconds since the Unix epoch), or returns how long until its window
+    /// ends if that takes it over the limit.
+    async fn acquire(&self, subject: &str, now: i64) -> result::Result<(), Duration> {
+        let WindowLimitConfig { limit, window } = self.config;
+        let subject = subject.to_string();
+        let mut counters = self.counters.write().await;
+
+        if counters.incr_at(&subject, 1, window, now) <= i64::from(limit) {
+            return Ok(());
+        }
+        let exp = counters.get(&subject).map_or(now, |counter| counter.exp);
+        return Err(Duration::from_secs(exp.saturating_sub(now).max(0) as u64));
+    }
+}
+
+/// `rate_limit` for the fixed-window rate limiter: requests over the limit are answered with too_many_requests, and a
+/// Retry-After header with the number of seconds until the window ends.
+pub async fn rate_limit_window<B>(
+    State(limiter): State<WindowRateLimiter>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Err(wait) = limiter.acquire(&rate_limit_subject(&request), SystemClock.now()).await else {
+        return next.run(request).await;
+    };
+    return too_many_requests(wait);
+}
+
+/// Whom a request counts against: the PAT subject, else the IP address of the peer (as the server's
+/// `ConnectInfo<SocketAddr>` has it), so that requests without a PAT do not all share a single limit. Requests with
+/// neither share one after all.
+fn rate_limit_subject<B>(request: &Request<B>) -> String {
+    if let Some(agent) = request.extensions().get::<Agent>() {
+        return agent.identifier().to_string();
+    }
+    let peer = request.extensions().get::<ConnectInfo<SocketAddr>>();
+    return peer.map(|ConnectInfo(address)| address.ip().to_string()).unwrap_or_default();
+}
+
+fn too_many_requests(wait: Duration) -> Response {
+    let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    let mut response = into_response::<()>(Err(TOO_MANY_REQUESTS.into()));
+    response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(retry_after));
+    return response;
+}
+
+/// [NO-SPEC] The media types the protection API speaks. JSON is the default; constrained resource servers may prefer
+/// CBOR, which carries the same data model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MediaType {
+    #[default]
+    Json,
+    Cbor,
+}
+
+impl MediaType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Cbor => "application/cbor",
+        }
+    }
+
+    /// The media type of the request body according to its Content-Type header.
+    pub fn of_content(headers: &HeaderMap) -> Self {
+        let content_type = headers.get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or_default();
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+        if essence.eq_ignore_ascii_case(Self::Cbor.as_str()) {
+            return Self::Cbor;
+        }
+        return Self::Json;
+    }
+
+    /// The media type to respond in according to the Accept header: CBOR if the client prefers it over JSON, either
+    /// by quality or by naming it where JSON is only matched by a wildcard. Ties go to JSON.
+    pub fn accepted(headers: &HeaderMap) -> Self {
+        // (quality, whether named explicitly)
+        let mut json = (0.0, false);
+        let mut cbor = (0.0, false);
+        for value in headers.get_all(ACCEPT).iter().filter_map(|value| value.to_str().ok()) {
+            for range in value.split(',') {
+                let mut parameters = range.split(';').map(str::trim);
+                let media_range = parameters.next().unwrap_or_default().to_ascii_lowercase();
+                let quality = parameters
+                    .filter_map(|parameter| parameter.strip_prefix("q="))
+                    .find_map(|quality| quality.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                let (best, explicit) = match media_range.as_str() {
+                    "application/json" => (&mut json, true),
+                    "application/cbor" => (&mut cbor, true),
+                    "application/*" | "*/*" => (&mut json, false),
+                    _ => continue,
+                };
+                if (quality, explicit) > *best {
+                    *best = (quality, explicit);
+                }
+            }
+        }
+        if cbor.0 > 0.0 && cbor > json {
+            return Self::Cbor;
+        }
+        return Self::Json;
+    }
+
+    fn serialize<T: Serialize>(&self, body: &T) -> Option<Vec<u8>> {
+        return match self {
+            Self::Json => serde_json::to_vec(body).ok(),
+            Self::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(body, &mut bytes).ok().map(|()| bytes)
+            }
+        };
+    }
+}
+
+/// Serializes the body of a handler's response, successful or not, as JSON. Bodies of 204 and 304 responses are
+/// dropped.
+pub fn into_response<T: Serialize>(result: Result<http::Response<T>, http::Response<ErrorMessage>>) -> Response {
+    return negotiated_response(MediaType::Json, result);
+}
+
+/// Serializes the body of a handler's response, successful or not, in the given media type.
+pub fn negotiated_response<T: Serialize>(
+    media_type: MediaType,
+    result: Result<http::Response<T>, http::Response<ErrorMessage>>,
+) -> Response {
+    return match result {
+        Ok(response) => serialized_response(media_type, response),
+        Err(response) if response.body().omit_body => response.into_parts().0.into_response(),
+        Err(response) => serialized_response(media_type, response),
+    };
+}
+
+fn serialized_response<T: Serialize>(media_type: MediaType, response: http::Response<T>) -> Response {
+    let (mut parts, body) = response.into_parts();
+    if parts.status == StatusCode::NO_CONTENT || parts.status == StatusCode::NOT_MODIFIED {
+        return parts.into_response();
+    }
+    let Some(body) = media_type.serialize(&body) else {
+        return serialized_response(media_type, http::Response::from(ErrorMessage::default()));
+    };
+    // Handlers and error messages label their bodies as JSON, which a CBOR body is not.
+    if (media_type == MediaType::Cbor) {
+        parts.headers.remove(CONTENT_TYPE);
+    }
+    parts.headers.entry(CONTENT_TYPE).or_insert(HeaderValue::from_static(media_type.as_str()));
+    return Response::from_parts(parts, Full::from(Bytes::from(body))).into_response();
+}
+
+/// Extracts a JSON or, if the Content-Type header says so, CBOR request body. Meant to be wrapped in `UmaBody`. A body
+/// labelled as anything else, or not labelled at all, is rejected with 415, as `Json` does.
+pub struct JsonOrCbor<T>(pub T);
+
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for JsonOrCbor<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = Response;
+
+    async fn from_request(request: Request<B>, state: &S) -> result::Result<Self, Self::Rejection> {
+        if (MediaType::of_content(request.headers()) == MediaType::Json) {
+            if (!is_json(request.headers())) {
+                return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response());
+            }
+            let bytes = Bytes::from_request(request, state).await.map_err(IntoResponse::into_response)?;
+            let mut deserializer = serde_json::Deserializer::from_slice(&bytes);
+            let body = serde_path_to_error::deserialize(&mut deserializer)
+                .map_err(|error| invalid_body(Some(error.path()), Some(json_position(error.inner()))))?;
+            deserializer.end().map_err(|error| invalid_body(None, Some(json_position(&error))))?;
+            return Ok(Self(body));
+        }
+        let bytes = Bytes::from_request(request, state).await.map_err(IntoResponse::into_response)?;
+        // ciborium does not expose its deserializer to track the path with, so only the offset is known.
+        return ciborium::from_reader(&*bytes).map(Self).map_err(|error| {
+            let offset = match error {
+                ciborium::de::Error::Syntax(offset) => Some(offset),
+                ciborium::de::Error::Semantic(offset, _) => offset,
+                _ => None,
+            };
+            return invalid_body(None, offset.map(|offset| format!("byte {offset}")));
+        });
+    }
+}
+
+/// The description of a body that does not deserialize, carried from `JsonOrCbor` to `UmaBody` in an extension of the
+/// rejection.
+#[derive(Clone)]
+struct InvalidBody(String);
+
+/// A 400 rejection naming where deserialization failed: the member or item at `path`, if known and not the body as a
+/// whole, and the position in the body. serde's own message is left out, as it may quote (a part of) the body.
+fn invalid_body(path: Option<&serde_path_to_error::Path>, position: Option<String>) -> Response {
+    let mut description = match path.filter(|path| path.iter().next().is_some()) {
+        Some(path) => format!("Invalid value at {path}"),
+        None => String::from("Malformed request body"),
+    };
+    if let Some(position) = position {
+        description.push_str(&format!(" ({position})"));
+    }
+    description.push('.');
+    let mut response = StatusCode::BAD_REQUEST.into_response();
+    response.extensions_mut().insert(InvalidBody(description));
+    return response;
+}
+
+fn json_position(error: &serde_json::Error) -> String {
+    return format!("line {}, column {}", error.line(), error.column());
+}
+
+/// Whether the Content-Type header labels the body as JSON: application/json or an application/*+json type, the same
+/// types `Json` accepts.
+fn is_json(headers: &HeaderMap) -> bool {
+    let content_type = headers.get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or_default();
+    let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    return match essence.split_once('/') {
+        Some(("application", subtype)) => subtype == "json" || subtype.ends_with("+json"),
+        _ => false,
+    };
+}
+
+/// Extracts the request body with `E` (e.g. `Json` or `Form`), answering a body it cannot extract with an UMA error
+/// message instead of axum's plain-text rejection: 413 for bodies over the route's limit, 415 for bodies of a content
+/// type `E` does not accept, and invalid_request for anything else, be it malformed or of the wrong shape. For bodies
+/// extracted with `JsonOrCbor`, the error description says where the body went wrong.
+pub struct UmaBody<E>(pub E);
+
+#[async_trait]
+impl<E, S, B> FromRequest<S, B> for UmaBody<E>
+where
+    E: FromRequest<S, B>,
+    E::Rejection: IntoResponse,
+    S: Send + Sync,
+    B: Send + 'static,
+{
+    type Rejection = Response;
+
+    async fn from_request(request: Request<B>, state: &S) -> result::Result<Self, Self::Rejection> {
+        let media_type = MediaType::accepted(request.headers());
+        return match E::from_request(request, state).await {
+            Ok(body) => Ok(Self(body)),
+            Err(rejection) => {
+                let rejection = rejection.into_response();
+                let error = match (rejection.status(), rejection.extensions().get::<InvalidBody>()) {
+                    (StatusCode::PAYLOAD_TOO_LARGE, _) => PAYLOAD_TOO_LARGE,
+                    (StatusCode::UNSUPPORTED_MEDIA_TYPE, _) => UNSUPPORTED_MEDIA_TYPE,
+                    (_, Some(InvalidBody(description))) => ErrorMessage {
+                        error_description: Some(Cow::Owned(description.clone())),
+                        ..INVALID_REQUEST
+                    },
+                    _ => INVALID_REQUEST,
+                };
+                Err(negotiated_response::<()>(media_type, Err(error.into())))
+            }
+        };
+    }
+}
+
+/// The handlers read the resource ID from the request path, relative to the resource registration endpoint.
+fn request<T>(method: Method, path: &str, query: Option<&str>, headers: HeaderMap, body: T) -> Result<Request<T>, Response> {
+    let uri = match query {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_string(),
+    };
+    let Ok(uri) = uri.parse::<Uri>() else {
+        return Err(into_response::<()>(Err(INVALID_REQUEST.into())));
+    };
+    let mut request = Request::new(body);
+    *request.method_mut() = method;
+    *request.uri_mut() = uri;
+    *request.headers_mut() = headers;
+    return Ok(request);
+}
+
+/// The path of a resource relative to the resource registration endpoint. axum hands over the ID percent-decoded, and
+/// the handlers decode it again.
+fn item_path(id: &str) -> String {
+    return format!("/{}", utf8_percent_encode(id, NON_ALPHANUMERIC));
+}
+
+/// Checks the icon_uri of the resource description of `request`, if the configuration asks for it, before the store is
+/// locked rather than while it is.
+async fn with_icon_checked(
+    config: &RegistrationConfig,
+    mut request: Request<ResourceDescription>,
+) -> Request<ResourceDescription> {
+    let advisory = icon_advisory(config, request.body()).await;
+    request.extensions_mut().insert(IconChecked(advisory));
+    return request;
+}
+
+/// Registers the resource description or, if the request asks to validate only, merely validates it.
+async fn create(
+    State(state): State<ResourceRegistrationState>,
+    owner: Option<Extension<Agent>>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    UmaBody(JsonOrCbor(description)): UmaBody<JsonOrCbor<ResourceDescription>>,
+) -> Response {
+    let media_type = MediaType::accepted(&headers);
+    let request = match request(Method::POST, "/", query.as_deref(), headers, description) {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    let owner = ResourceOwnerContext::new(owner.as_deref());
+    if is_validate_only(&request) {
+        let store = state.store.lock().await;
+        let result = validate_resource_registration(&*store, &state.config, &owner, request).await;
+        return negotiated_response(media_type, result);
+    }
+    let request = with_icon_checked(&state.config, request).await;
+    let mut store = state.store.lock().await;
+    let result = create_resource_registration(&mut *store, &*state.ids, &state.config, &owner, request).await;
+    let created = result.as_ref().ok().map(|response| RegisteredResource(response.body()._id.to_string()));
+    let mut response = negotiated_response(media_type, result);
+    if let Some(created) = created {
+        response.extensions_mut().insert(created);
+    }
+    return response;
+}
+
+async fn read(
+    State(state): State<ResourceRegistrationState>,
+    Path(id): Path<String>,
+    owner: Option<Extension<Agent>>,
+    headers: HeaderMap,
+) -> Response {
+    let media_type = MediaType::accepted(&headers);
+    let request = match request(Method::GET, &item_path(&id), None, headers, ()) {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    let owner = ResourceOwnerContext::new(owner.as_deref());
+    let mut store = state.store.lock().await;
+    let result = read_resource_registration(&mut *store, &state.config, &owner, &request).await;
+    return negotiated_response(media_type, result);
+}
+
+async fn update(
+    State(state): State<ResourceRegistrationState>,
+    Path(id): Path<String>,
+    owner: Option<Extension<Agent>>,
+    headers: HeaderMap,
+    UmaBody(JsonOrCbor(description)): UmaBody<JsonOrCbor<ResourceDescription>>,
+) -> Response {
+    let media_type = MediaType::accepted(&headers);
+    let request = match request(Method::PUT, &item_path(&id), None, headers, description) {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    let owner = ResourceOwnerContext::new(owner.as_deref());
+    let request = with_icon_checked(&state.config, request).await;
+    let _update = state.updates.lock(id).await;
+    let mut store = state.store.lock().await;
+    let result = update_resource_registration(&mut *store, &state.config, &owner, request).await;
+    return negotiated_response(media_type, result);
+}
+
+/// Applies a JSON merge patch to the stored resource description and stores the result as a PUT would. The store is
+/// not held while the patch is applied, but the resource's update lock is, so that no other update slips in between.
+async fn patch(
+    State(state): State<ResourceRegistrationState>,
+    Path(id): Path<String>,
+    owner: Option<Extension<Agent>>,
+    headers: HeaderMap,
+    UmaBody(Json(patch)): UmaBody<Json<serde_json::Value>>,
+) -> Response {
+    let media_type = MediaType::accepted(&headers);
+    let owner = ResourceOwnerContext::new(owner.as_deref());
+    let _update = state.updates.lock(id.clone()).await;
+    let current = owner.get(&*state.store.lock().await, &id).cloned();
+    let Some(current) = current else {
+        return negotiated_response::<()>(media_type, Err(missing_resource(&state.config, &id)));
+    };
+    let description = match merge_patch(&current, patch) {
+        Ok(description) => description,
+        Err(error) => return negotiated_response::<()>(media_type, Err(error)),
+    };
+    let request = match request(Method::PUT, &item_path(&id), None, headers, description) {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    let request = with_icon_checked(&state.config, request).await;
+    let mut store = state.store.lock().await;
+    let result = update_resource_registration(&mut *store, &state.config, &owner, request).await;
+    return negotiated_response(media_type, result);
+}
+
+async fn delete(
+    State(state): State<ResourceRegistrationState>,
+    Path(id): Path<String>,
+    owner: Option<Extension<Agent>>,
+    headers: HeaderMap,
+) -> Response {
+    let request = match request(Method::DELETE, &item_path(&id), None, HeaderMap::new(), ()) {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    let owner = ResourceOwnerContext::new(owner.as_deref());
+    let _update = state.updates.lock(id).await;
+    let mut store = state.store.lock().await;
+    let result = delete_resource_registration(&mut *store, &state.config, &owner, &request).await;
+    return negotiated_response(MediaType::accepted(&headers), result);
+}
+
+async fn list(
+    State(state): State<ResourceRegistrationState>,
+    owner: Option<Extension<Agent>>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+) -> Response {
+    let request = match request(Method::GET, "/", query.as_deref(), HeaderMap::new(), ()) {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    let owner = ResourceOwnerContext::new(owner.as_deref());
+    let mut store = state.store.lock().await;
+    let result = list_resource_registration(&mut *store, &state.config, &owner, &request).await;
+    return negotiated_response(MediaType::accepted(&headers), result);
+}
+
+async fn inspect_tickets(
+    State(state): State<TicketInspectionState>,
+    scopes: Option<Extension<PatScopes>>,
+    RawQuery(query): RawQuery,
+) -> Response {
+    let mut request = match request(Method::GET, "/", query.as_deref(), HeaderMap::new(), ()) {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    if let Some(Extension(scopes)) = scopes {
+        request.extensions_mut().insert(scopes);
+    }
+    let tickets = state.tickets.read().await;
+    let resources = state.resources.lock().await;
+    return into_response(list_permission_tickets(&*tickets, &*resources, &state.config, &request).await);
+}
+
+async fn request_permission(
+    State(state): State<PermissionState>,
+    headers: HeaderMap,
+    UmaBody(Json(permissions)): UmaBody<Json<PermissionRequest<'static>>>,
+) -> Response {
+    let request = match request(Method::POST, "/", None, headers, permissions) {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    let mut tickets = state.tickets.write().await;
+    return into_response(request_permission_ticket(&mut *tickets, &*state.ids, &state.config, request).await);
+}
+
+/// The form body is parsed by `IntrospectionRequest::parse` rather than by `Form`, so that a wrong content type or a
+/// missing token is answered the way the handler would.
+async fn introspect(
+    State(state): State<IntrospectionState>,
+    headers: HeaderMap,
+    UmaBody(body): UmaBody<Bytes>,
+) -> Response {
+    let request = match request(Method::POST, "/", None, headers, body) {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    let request = match IntrospectionRequest::parse(request) {
+        Ok(request) => request,
+        Err(error) => return into_response::<()>(Err(error)),
+    };
+    let rpts = state.rpts.read().await;
+    let refresh_tokens = state.refresh_tokens.read().await;
+    return into_response(introspect_token(&*rpts, &*refresh_tokens, &state.config, request).await);
+}
+
+async fn introspect_batch(
+    State(state): State<IntrospectionState>,
+    headers: HeaderMap,
+    UmaBody(Json(tokens)): UmaBody<Json<Vec<RptValue>>>,
+) -> Response {
+    let request = match request(Method::POST, "/batch", None, headers, tokens) {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    let rpts = state.rpts.read().await;
+    let refresh_tokens = state.refresh_tokens.read().await;
+    return into_response(introspect_tokens(&*rpts, &*refresh_tokens, &state.config, request).await);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::ids::SeededIdGenerator;
+    use crate::test_support::{claims, Keypair, MockIssuer, INTROSPECTION_CREDENTIALS, OPAQUE_PAT};
+    use crate::uma::resource_registration::NotFoundBody;
+    use ::axum::body::{Body, HttpBody};
+    use std::collections::HashMap;
+    use tower::ServiceExt;
+    use tracing_test::traced_test;
+
+    fn router() -> Router {
+        return router_with(RegistrationConfig::default());
+    }
+
+    fn router_with(config: RegistrationConfig) -> Router {
+        return resource_registration_router(ResourceRegistrationState {
+            store: Arc::new(Mutex::new(HashMap::new())),
+            updates: KeyedMutex::new(),
+            ids: Arc::new(SeededIdGenerator::new(7)),
+            config,
+        });
+    }
+
+    async fn body(response: Response) -> serde_json::Value {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        return serde_json::from_slice(&bytes).unwrap();
+    }
+
+    #[tokio::test]
+    async fn create_then_read() {
+        let router = router();
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{ "resource_scopes": ["view"], "name": "Photo Album" }"#))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        let id = body(response).await["_id"].as_str().unwrap().to_string();
+        assert_eq!(id, SeededIdGenerator::new(7).generate());
+
+        let request = Request::builder().uri(format!("/{id}")).body(Body::empty()).unwrap();
+        let response = router.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let description = body(response).await;
+        assert_eq!(description["_id"], id.as_str());
+        assert_eq!(description["resource_description"]["name"], "Photo Album");
+    }
+
+    #[tokio::test]
+    async fn slow_icons_hold_up_no_other_request() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let icon_uri = format!("http://{}/icon.png", listener.local_addr().unwrap());
+        let slow = get(|| async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            [(CONTENT_TYPE, "image/png")]
+        });
+        let icon_host = Router::new().route("/icon.png", slow).into_make_service();
+        tokio::spawn(::axum::Server::from_tcp(listener).unwrap().serve(icon_host));
+        let router = router_with(RegistrationConfig {
+            icon_check_timeout: Some(Duration::from_secs(10)),
+            ..RegistrationConfig::default()
+        });
+
+        let create = Request::builder()
+            .method(Method::POST)
+            .uri("/")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(format!(r#"{{ "resource_scopes": ["view"], "icon_uri": "{icon_uri}" }}"#)))
+            .unwrap();
+        let creating = tokio::spawn(router.clone().oneshot(create));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let list = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let response = tokio::time::timeout(Duration::from_secs(1), router.oneshot(list)).await;
+        assert_eq!(response.expect("the store was locked during the icon check").unwrap().status(), StatusCode::OK);
+        assert!(!creating.is_finished());
+        creating.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_delete_during_a_patch_is_not_undone() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let icon_uri = format!("http://{}/icon.png", listener.local_addr().unwrap());
+        let slow = get(|| async {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            [(CONTENT_TYPE, "image/png")]
+        });
+        let icon_host = Router::new().route("/icon.png", slow).into_make_service();
+        tokio::spawn(::axum::Server::from_tcp(listener).unwrap().serve(icon_host));
+        let router = router_with(RegistrationConfig {
+            icon_check_timeout: Some(Duration::from_secs(10)),
+            ..RegistrationConfig::default()
+        });
+        let request = Request::builder()
+            .method(Method::PUT)
+            .uri("/album")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{ "resource_scopes": ["view"] }"#))
+            .unwrap();
+        assert_eq!(router.clone().oneshot(request).await.unwrap().status(), StatusCode::OK);
+
+        // The PATCH reads the resource, then waits on the icon check without holding the store.
+        let patch = Request::builder()
+            .method(Method::PATCH)
+            .uri("/album")
+            .header(CONTENT_TYPE, "application/merge-patch+json")
+            .body(Body::from(format!(r#"{{ "icon_uri": "{icon_uri}" }}"#)))
+            .unwrap();
+        let patching = tokio::spawn(router.clone().oneshot(patch));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let delete = Request::builder().method(Method::DELETE).uri("/album").body(Body::empty()).unwrap();
+        assert_eq!(router.clone().oneshot(delete).await.unwrap().status(), StatusCode::NO_CONTENT);
+        assert_eq!(patching.await.unwrap().unwrap().status(), StatusCode::OK);
+
+        let read = Request::builder().uri("/album").body(Body::empty()).unwrap();
+        assert_eq!(router.oneshot(read).await.unwrap().status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn read_unknown_resource() {
+        let request = Request::builder().uri("/KX3A-39WE").body(Body::empty()).unwrap();
+        let response = router().oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(body(response).await["error"], "not_found");
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn calls_are_audited_with_their_owner() {
+        let router = router();
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri("/")
+            .header(CONTENT_TYPE, "application/json")
+            .header("Authorization", "Bearer MHg3OUZEQkZBMjcx")
+            .body(Body::from(r#"{ "resource_scopes": ["view"] }"#))
+            .unwrap();
+        request.extensions_mut().insert(Agent::new("https://alice.example/#me").unwrap());
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let id = SeededIdGenerator::new(7).generate();
+
+        assert!(logs_contain("audit"));
+        assert!(logs_contain("owner=\"https://alice.example/#me\""));
+        assert!(logs_contain("operation=\"create\""));
+        assert!(logs_contain(&format!("resource_id=\"{id}\"")));
+        assert!(logs_contain("result=201"));
+        assert!(!logs_contain("MHg3OUZEQkZBMjcx"));
+
+        let request = Request::builder().uri("/unknown").body(Body::empty()).unwrap();
+        router.oneshot(request).await.unwrap();
+        assert!(logs_contain("owner=\"anonymous\""));
+        assert!(logs_contain("resource_id=\"unknown\""));
+        assert!(logs_contain("result=404"));
+    }
+
+    async fn cbor_body(response: Response) -> serde_json::Value {
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/cbor");
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        return ciborium::from_reader(&*bytes).unwrap();
+    }
+
+    #[tokio::test]
+    async fn create_then_read_as_cbor() {
+        let router = router();
+        let description = serde_json::json!({ "resource_scopes": ["view", "print"], "name": "Photo Album" });
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&description, &mut cbor).unwrap();
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/")
+            .header(CONTENT_TYPE, "application/cbor")
+            .header(ACCEPT, "application/cbor")
+            .body(Body::from(cbor))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let id = cbor_body(response).await["_id"].as_str().unwrap().to_string();
+
+        let request = Request::builder().uri(format!("/{id}")).header(ACCEPT, "application/cbor").body(Body::empty());
+        let response = router.clone().oneshot(request.unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let read = cbor_body(response).await;
+        assert_eq!(read["resource_description"]["name"], "Photo Album");
+        assert_eq!(read["resource_description"]["resource_scopes"], serde_json::json!(["view", "print"]));
+
+        // JSON remains the default, and errors follow the negotiated media type too.
+        let request = Request::builder().uri(format!("/{id}")).body(Body::empty()).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/")
+            .header(CONTENT_TYPE, "application/cbor")
+            .header(ACCEPT, "application/cbor")
+            .body(Body::from(vec![0xff, 0x00]))
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(cbor_body(response).await["error"], "invalid_request");
+    }
+
+    #[test]
+    fn accept_header_is_negotiated() {
+        let accepted = |accept: &str| MediaType::accepted(&HeaderMap::from_iter([(ACCEPT, accept.parse().unwrap())]));
+
+        assert_eq!(MediaType::accepted(&HeaderMap::new()), MediaType::Json);
+        assert_eq!(accepted("application/cbor"), MediaType::Cbor);
+        assert_eq!(accepted("application/cbor, */*;q=0.8"), MediaType::Cbor);
+        assert_eq!(accepted("application/cbor, */*"), MediaType::Cbor);
+        assert_eq!(accepted("application/json, application/cbor"), MediaType::Json);
+        assert_eq!(accepted("application/json;q=0.5, application/cbor"), MediaType::Cbor);
+        assert_eq!(accepted("application/cbor;q=0, */*"), MediaType::Json);
+        assert_eq!(accepted("text/html"), MediaType::Json);
+    }
+
+    #[tokio::test]
+    async fn mounted_at_the_registration_endpoint() {
+        let config = RegistrationConfig { base_path: "/rreg".to_string(), ..RegistrationConfig::default() };
+        let state = ResourceRegistrationState {
+            store: Arc::new(Mutex::new(HashMap::new())),
+            updates: KeyedMutex::new(),
+            ids: Arc::new(SeededIdGenerator::new(7)),
+            config,
+        };
+        let router = Router::new().merge(resource_registration_router(state));
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/rreg/")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{ "resource_scopes": ["view"] }"#))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        let id = body(response).await["_id"].as_str().unwrap().to_string();
+
+        for list in ["/rreg/", "/rreg"] {
+            let request = Request::builder().uri(list).body(Body::empty()).unwrap();
+            let response = router.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(body(response).await, serde_json::json!([id]));
+        }
+
+        let request = Request::builder().uri(format!("/rreg/{id}")).body(Body::empty()).unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await["_id"], id.as_str());
+    }
+
+    #[tokio::test]
+    async fn not_found_with_an_error_message() {
+        let router = router();
+
+        for method in [Method::GET, Method::DELETE] {
+            let request = Request::builder().method(method).uri("/KX3A-39WE").body(Body::empty()).unwrap();
+            let response = router.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+            assert_eq!(body(response).await["error"], "not_found");
+        }
+    }
+
+    #[tokio::test]
+    async fn not_found_without_a_body() {
+        let config = RegistrationConfig { not_found_body: NotFoundBody::Empty, ..RegistrationConfig::default() };
+        let router = router_with(config);
+
+        for method in [Method::GET, Method::DELETE] {
+            let request = Request::builder().method(method).uri("/KX3A-39WE").body(Body::empty()).unwrap();
+            let response = router.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            assert!(response.headers().get(CONTENT_TYPE).is_none());
+            assert!(response.into_body().collect().await.unwrap().to_bytes().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn uri_ids_are_percent_encoded_in_the_path() {
+        let router = router();
+        let path = "/http%3A%2F%2Fphotoz.example.com%2Falbum";
+
+        let request = Request::builder()
+            .method(Method::PUT)
+            .uri(path)
+            .header(CONTENT_TYPE, "application/json")
+            .header("If-None-Match", "*")
+            .body(Body::from(r#"{ "resource_scopes": ["view"] }"#))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(body(response).await["_id"], "http://photoz.example.com/album");
+
+        let request = Request::builder().uri(path).body(Body::empty()).unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await["_id"], "http://photoz.example.com/album");
+    }
+
+    #[tokio::test]
+    async fn validate_only_registers_nothing() {
+        let router = router();
+        let create = |uri: &str, prefer: Option<&str>, description: &'static str| {
+            let mut request = Request::builder().method(Method::POST).uri(uri).header(CONTENT_TYPE, "application/json");
+            if let Some(prefer) = prefer {
+                request = request.header("Prefer", prefer);
+            }
+            request.body(Body::from(description)).unwrap()
+        };
+
+        let request = create("/?validate_only=true", None, r#"{ "resource_scopes": ["view"] }"#);
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["preference-applied"], "validate-only");
+        let validation = body(response).await;
+        assert_eq!(validation, serde_json::json!({ "valid": true }));
+
+        let request = create("/", Some("return=minimal, validate-only"), r#"{ "resource_scopes": ["view"] }"#);
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let request = create("/?validate_only=true", None, r#"{ "resource_scopes": ["not a scope"] }"#);
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body(response).await["error"], "invalid_request");
+
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(body(response).await, serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn requests_over_the_limit_are_told_to_retry_later() {
+        let config = RateLimitConfig { capacity: 2, refill_interval: Duration::from_secs(30) };
+        let router = router().layer(middleware::from_fn_with_state(RateLimiter::new(config), rate_limit));
+        let request = |agent: &str| {
+            let mut request = Request::builder().uri("/").body(Body::empty()).unwrap();
+            request.extensions_mut().insert(Agent::new(agent).unwrap());
+            request
+        };
+
+        for _ in 0..2 {
+            let response = router.clone().oneshot(request("https://alice.example/#me")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = router.clone().oneshot(request("https://alice.example/#me")).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "30");
+        assert_eq!(body(response).await["error"], "too_many_requests");
+
+        // Every PAT subject has a bucket of its own.
+        let response = router.oneshot(request("https://bob.example/#me")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn pats_without_the_protection_scope_are_refused() {
+        let router = router().layer(middleware::from_fn(require_protection_scope));
+        let request = |scopes: &[&str]| {
+            let mut request = Request::builder().uri("/").body(Body::empty()).unwrap();
+            request.extensions_mut().insert(PatScopes(scopes.iter().map(ToString::to_string).collect()));
+            request
+        };
+
+        let response = router.clone().oneshot(request(&["openid", "uma_protection"])).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        for scopes in [&["openid"][..], &[]] {
+            let response = router.clone().oneshot(request(scopes)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+            let challenge = &response.headers()[http::header::WWW_AUTHENTICATE];
+            assert_eq!(challenge, r#"Bearer error="insufficient_scope", scope="uma_protection""#);
+            assert_eq!(body(response).await["error"], "insufficient_scope");
+        }
+
+        // Without scopes to go by, the PAT is not taken to carry any.
+        let response = router.oneshot(Request::builder().uri("/").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    /// The protection API as the server mounts it, accepting the PATs `key` signs.
+    fn protected(key: &Keypair) -> Router {
+        return protected_with(key, RateLimiter::new(RateLimitConfig::default()));
+    }
+
+    fn protected_with(key: &Keypair, limiter: RateLimiter) -> Router {
+        let jwks = serde_json::json!({ "keys": [key.jwk()] }).to_string();
+        return protected_by(PatAuthentication::from_jwks(&jwks).unwrap(), limiter);
+    }
+
+    fn protected_by(authentication: PatAuthentication, limiter: RateLimiter) -> Router {
+        let registration = ResourceRegistrationState {
+            store: Arc::new(Mutex::new(HashMap::new())),
+            updates: KeyedMutex::new(),
+            ids: Arc::new(SeededIdGenerator::new(7)),
+            config: RegistrationConfig { base_path: "/rreg".to_string(), ..RegistrationConfig::default() },
+        };
+        let rpts: Arc<tokio::sync::RwLock<RptStore>> = Arc::new(tokio::sync::RwLock::new(HashMap::new()));
+        let refresh_tokens: Arc<tokio::sync::RwLock<RefreshTokenStore>> =
+            Arc::new(tokio::sync::RwLock::new(HashMap::new()));
+        let introspection = IntrospectionState {
+            rpts: rpts.into(),
+            refresh_tokens: refresh_tokens.into(),
+            config: IntrospectionConfig::default(),
+        };
+        return protection_router(registration, permission_state(), introspection, authentication, limiter);
+    }
+
+    fn bearer(key: &Keypair, sub: &str, scope: &str) -> String {
+        let pat = serde_json::json!({ "sub": sub, "scope": scope });
+        return format!("Bearer {}", key.sign(&claims(pat, chrono::Duration::minutes(5))));
+    }
+
+    #[tokio::test]
+    async fn opaque_pats_are_introspected() {
+        let issuer = MockIssuer::start(vec![]).await;
+        let endpoint = issuer.introspection_endpoint();
+        let authentication = PatAuthentication::from_jwks(r#"{ "keys": [] }"#).unwrap();
+        let introspecting = authentication.clone().with_introspection(endpoint, Some(INTROSPECTION_CREDENTIALS.into()));
+        let list = |pat: &str| {
+            let request = Request::builder().uri("/rreg").header(AUTHORIZATION, format!("Bearer {pat}"));
+            return request.body(Body::empty()).unwrap();
+        };
+        let limiter = || RateLimiter::new(RateLimitConfig::default());
+
+        let response = protected_by(introspecting.clone(), limiter()).oneshot(list(OPAQUE_PAT)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = protected_by(introspecting, limiter()).oneshot(list("tGzv3JOkF0XG5Qx2TlKWIA")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(body(response).await["error"], "invalid_token");
+
+        // Without an introspection endpoint, an opaque PAT cannot be validated at all.
+        let response = protected_by(authentication, limiter()).oneshot(list(OPAQUE_PAT)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn the_protection_api_requires_a_pat_with_the_protection_scope() {
+        let key = Keypair::es256("key-1");
+        let router = protected(&key);
+        let alice = "https://alice.example/#me";
+        let create = |authorization: Option<String>| {
+            let mut request =
+                Request::builder().method(Method::POST).uri("/rreg").header(CONTENT_TYPE, "application/json");
+            if let Some(authorization) = authorization {
+                request = request.header(AUTHORIZATION, authorization);
+            }
+            return request.body(Body::from(r#"{ "resource_scopes": ["view"] }"#)).unwrap();
+        };
+
+        let response = router.clone().oneshot(create(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let challenge = &response.headers()[http::header::WWW_AUTHENTICATE];
+        assert_eq!(challenge, r#"Bearer error="invalid_token", scope="uma_protection""#);
+        assert_eq!(body(response).await["error"], "invalid_token");
+
+        let forged = bearer(&Keypair::es256("key-1"), alice, "uma_protection");
+        let response = router.clone().oneshot(create(Some(forged))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = router.clone().oneshot(create(Some(bearer(&key, alice, "openid")))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(body(response).await["error"], "insufficient_scope");
+
+        let introspect = Request::builder()
+            .method(Method::POST)
+            .uri("/introspect")
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from("token=2YotnFZFEjr1zCsicMWpAA"))
+            .unwrap();
+        assert_eq!(router.clone().oneshot(introspect).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+
+        let response = router.clone().oneshot(create(Some(bearer(&key, alice, "uma_protection")))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let id = body(response).await["_id"].as_str().unwrap().to_string();
+
+        // The resource is registered to the PAT's subject, and to no one else.
+        let read = |authorization: String| {
+            let request = Request::builder().uri(format!("/rreg/{id}")).header(AUTHORIZATION, authorization);
+            return request.body(Body::empty()).unwrap();
+        };
+        let response = router.clone().oneshot(read(bearer(&key, alice, "uma_protection"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = router.oneshot(read(bearer(&key, "https://bob.example/#me", "uma_protection"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn the_protection_api_rate_limits_every_resource_owner_on_their_own() {
+        let key = Keypair::es256("key-1");
+        let config = RateLimitConfig { capacity: 2, refill_interval: Duration::from_secs(30) };
+        let router = protected_with(&key, RateLimiter::new(config));
+        let list = |sub: &str| {
+            let request = Request::builder().uri("/rreg").header(AUTHORIZATION, bearer(&key, sub, "uma_protection"));
+            return request.body(Body::empty()).unwrap();
+        };
+
+        for _ in 0..2 {
+            let response = router.clone().oneshot(list("https://alice.example/#me")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = router.clone().oneshot(list("https://alice.example/#me")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let response = router.oneshot(list("https://bob.example/#me")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn requests_without_a_pat_are_limited_per_peer() {
+        let config = RateLimitConfig { capacity: 1, refill_interval: Duration::from_secs(30) };
+        let router = router().layer(middleware::from_fn_with_state(RateLimiter::new(config), rate_limit));
+        let request = |peer: [u8; 4], port: u16| {
+            let mut request = Request::builder().uri("/").body(Body::empty()).unwrap();
+            request.extensions_mut().insert(ConnectInfo(SocketAddr::from((peer, port))));
+            request
+        };
+
+        let response = router.clone().oneshot(request([192, 0, 2, 1], 50000)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        // A new connection from the same address counts against the same bucket.
+        let response = router.clone().oneshot(request([192, 0, 2, 1], 50001)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let response = router.oneshot(request([192, 0, 2, 2], 50000)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let limiter = RateLimiter::new(RateLimitConfig { capacity: 2, refill_interval: Duration::from_secs(10) });
+        let start = Instant::now();
+
+        assert_eq!(limiter.acquire("alice", start), Ok(()));
+        assert_eq!(limiter.acquire("alice", start), Ok(()));
+        assert_eq!(limiter.acquire("alice", start + Duration::from_secs(4)), Err(Duration::from_secs(6)));
+        assert_eq!(limiter.acquire("alice", start + Duration::from_secs(10)), Ok(()));
+        // A long pause refills the bucket up to its capacity only.
+        let later = start + Duration::from_secs(60 * 60);
+        assert_eq!(limiter.acquire("alice", later), Ok(()));
+        assert_eq!(limiter.acquire("alice", later), Ok(()));
+        assert!(limiter.acquire("alice", later).is_err());
+    }
+
+    #[test]
+    fn full_buckets_are_swept() {
+        let limiter = RateLimiter::new(RateLimitConfig { capacity: 2, refill_interval: Duration::from_secs(10) });
+        let start = Instant::now();
+        assert_eq!(limiter.acquire("alice", start), Ok(()));
+        assert_eq!(limiter.acquire("alice", start), Ok(()));
+        for subject in 1..BUCKET_SWEEP_THRESHOLD {
+            assert_eq!(limiter.acquire(&subject.to_string(), start), Ok(()));
+        }
+        assert_eq!(limiter.buckets.lock().unwrap().len(), BUCKET_SWEEP_THRESHOLD);
+
+        let later = start + Duration::from_secs(10);
+        assert_eq!(limiter.acquire("bob", later), Ok(()));
+        let mut subjects: Vec<String> = limiter.buckets.lock().unwrap().keys().cloned().collect();
+        subjects.sort();
+        assert_eq!(subjects, ["alice", "bob"]);
+        // The bucket that has not refilled yet is kept, and so still limits its subject.
+        assert_eq!(limiter.acquire("alice", later), Ok(()));
+        assert_eq!(limiter.acquire("alice", later), Err(Duration::from_secs(10)));
+    }
+
+    fn window_rate_limiter(limit: u32, window: Duration) -> WindowRateLimiter {
+        let counters: Arc<tokio::sync::RwLock<RateLimitCounters>> = Arc::new(tokio::sync::RwLock::new(HashMap::new()));
+        return WindowRateLimiter::new(WindowLimitConfig { limit, window }, counters.into());
+    }
+
+    #[tokio::test]
+    async fn windows_limit_requests_until_they_end() {
+        let limiter = window_rate_limiter(2, Duration::from_secs(60));
+        let start = 1_700_000_000;
+
+        assert_eq!(limiter.acquire("alice", start).await, Ok(()));
+        assert_eq!(limiter.acquire("alice", start + 10).await, Ok(()));
+        assert_eq!(limiter.acquire("alice", start + 15).await, Err(Duration::from_secs(45)));
+        assert_eq!(limiter.acquire("bob", start + 15).await, Ok(()));
+        // The window does not slide: a new one starts with the first request after it ended.
+        assert_eq!(limiter.acquire("alice", start + 60).await, Ok(()));
+        assert_eq!(limiter.acquire("alice", start + 61).await, Ok(()));
+        assert_eq!(limiter.acquire("alice", start + 62).await, Err(Duration::from_secs(58)));
+    }
+
+    #[tokio::test]
+    async fn requests_over_the_window_limit_are_told_to_retry_later() {
+        let limiter = window_rate_limiter(1, Duration::from_secs(3600));
+        let router = router().layer(middleware::from_fn_with_state(limiter.clone(), rate_limit_window));
+        let request = || {
+            let mut request = Request::builder().uri("/").body(Body::empty()).unwrap();
+            request.extensions_mut().insert(Agent::new("https://alice.example/#me").unwrap());
+            request
+        };
+
+        let response = router.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = router.oneshot(request()).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = response.headers()[RETRY_AFTER].to_str().unwrap().parse().unwrap();
+        assert!((3598..=3600).contains(&retry_after));
+        assert_eq!(body(response).await["error"], "too_many_requests");
+    }
+
+    #[test]
+    fn requests_are_counted() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        // The local recorder is bound to this thread, so the requests run on a runtime confined to it.
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+
+        metrics::with_local_recorder(&recorder, || {
+            runtime.block_on(async {
+                let router = router();
+                let request = Request::builder()
+                    .method(Method::POST)
+                    .uri("/")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(r#"{ "resource_scopes": ["view"] }"#))
+                    .unwrap();
+                let response = router.clone().oneshot(request).await.unwrap();
+                let id = body(response).await["_id"].as_str().unwrap().to_string();
+
+                let request = Request::builder().uri(format!("/{id}")).body(Body::empty()).unwrap();
+                router.oneshot(request).await.unwrap();
+            })
+        });
+
+        let snapshot = snapshotter.snapshot().into_vec();
+        let count = |endpoint: &str, method: &str, status: &str| {
+            snapshot.iter().find_map(|(key, _, _, value)| {
+                let labels: Vec<_> = key.key().labels().map(|label| (label.key(), label.value())).collect();
+                let wanted = [("endpoint", endpoint), ("method", method), ("status", status)];
+                let matches = key.key().name() == "uma_requests_total" && wanted.iter().all(|l| labels.contains(l));
+                match value {
+                    DebugValue::Counter(count) if matches => Some(*count),
+                    _ => None,
+                }
+            })
+        };
+        assert_eq!(count("/", "POST", "201"), Some(1));
+        assert_eq!(count("/:id", "GET", "200"), Some(1));
+        assert!(snapshot.iter().any(|(key, _, _, value)| {
+            let latency = matches!(value, DebugValue::Histogram(values) if values.len() == 1);
+            key.key().name() == "uma_request_duration_seconds" && latency
+        }));
+    }
+
+    fn introspection() -> Router {
+        return introspection_with(IntrospectionConfig::default());
+    }
+
+    fn introspection_with(config: IntrospectionConfig) -> Router {
+        let rpts: Arc<tokio::sync::RwLock<RptStore>> = Arc::new(tokio::sync::RwLock::new(HashMap::new()));
+        let refresh_tokens: Arc<tokio::sync::RwLock<RefreshTokenStore>> =
+            Arc::new(tokio::sync::RwLock::new(HashMap::new()));
+        return introspection_router(IntrospectionState {
+            rpts: rpts.into(),
+            refresh_tokens: refresh_tokens.into(),
+            config,
+        });
+    }
+
+    fn permission_state() -> PermissionState {
+        let tickets: Arc<tokio::sync::RwLock<PermissionTicketStore<'static>>> =
+            Arc::new(tokio::sync::RwLock::new(HashMap::new()));
+        return PermissionState {
+            tickets: tickets.into(),
+            ids: Arc::new(SeededIdGenerator::new(7)),
+            config: PermissionConfig::default(),
+        };
+    }
+
+    /// A permission request for `count` resources, of some 56 bytes each.
+    fn permission_request(count: usize) -> Request<Body> {
+        let permissions: Vec<_> = (0..count)
+            .map(|i| serde_json::json!({ "resource_id": format!("photo-{i:04}"), "resource_scopes": ["view"] }))
+            .collect();
+        return Request::builder()
+            .method(Method::POST)
+            .uri("/")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(&permissions).unwrap()))
+            .unwrap();
+    }
+
+    fn registration(scope_count: usize) -> Request<Body> {
+        let scopes: Vec<String> =
+            (0..scope_count).map(|i| format!("http://photoz.example.com/dev/actions/{i}")).collect();
+        let body = serde_json::json!({ "resource_scopes": scopes }).to_string();
+        return Request::builder()
+            .method(Method::POST)
+            .uri("/")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap();
+    }
+
+    fn introspection_request(token: &str) -> Request<Body> {
+        return Request::builder()
+            .method(Method::POST)
+            .uri("/")
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(format!("token={token}&token_type_hint=access_token")))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn resource_registration_body_limit() {
+        // Some 5KB of scopes, well over the server-wide default but within the endpoint's limit.
+        let response = router().oneshot(registration(100)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let response = router().oneshot(registration(2000)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(body(response).await["error"], "payload_too_large");
+    }
+
+    #[tokio::test]
+    async fn introspection_body_limit() {
+        let response = introspection().oneshot(introspection_request("016f84e8")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await, serde_json::json!({ "active": false }));
+
+        let response = introspection().oneshot(introspection_request(&"0".repeat(4096))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body(response).await["error"], "payload_too_large");
+    }
+
+    #[tokio::test]
+    async fn permission_body_limit() {
+        let request = permission_request(70);
+        assert!((3 * 1024..PERMISSION_BODY_LIMIT).contains(&(request.size_hint().lower() as usize)));
+        let response = permission_router(permission_state()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(body(response).await["ticket"].is_string());
+
+        let request = permission_request(80);
+        assert!(request.size_hint().lower() as usize > PERMISSION_BODY_LIMIT);
+        let response = permission_router(permission_state()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body(response).await["error"], "payload_too_large");
+    }
+
+    #[tokio::test]
+    async fn body_limits_take_precedence_over_endpoint_limits() {
+        let router = Router::new().nest("/introspect", introspection());
+        let router = BodyLimits::new(1024).route("/introspect", 16).apply(router);
+        let request = |token: &str| {
+            let mut request = introspection_request(token);
+            *request.uri_mut() = Uri::from_static("/introspect");
+            request
+        };
+
+        let response = router.clone().oneshot(request("016f84e8")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body(response).await["error"], "payload_too_large");
+
+        // The batch endpoint, for which no limit is given, keeps its own rather than the default.
+        let batch = serde_json::to_vec(&vec!["0".repeat(64); 32]).unwrap();
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/introspect/batch")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(batch))
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_ne!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn introspection_requires_a_form() {
+        let mut request = introspection_request("016f84e8");
+        request.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        let response = introspection().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(body(response).await["error"], "unsupported_media_type");
+
+        let mut request = introspection_request("");
+        *request.body_mut() = Body::from("token_type_hint=access_token");
+        let response = introspection().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body(response).await["error_description"], "The token parameter is missing.");
+    }
+
+    #[tokio::test]
+    async fn batch_introspection() {
+        let batch = |tokens: serde_json::Value| {
+            return Request::builder()
+                .method(Method::POST)
+                .uri("/batch")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(tokens.to_string()))
+                .unwrap();
+        };
+        let tokens = serde_json::json!(["016f84e8", "0".repeat(4096)]);
+
+        let response = introspection().oneshot(batch(tokens.clone())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let config = IntrospectionConfig { max_batch_size: Some(10), ..IntrospectionConfig::default() };
+        let response = introspection_with(config).oneshot(batch(tokens)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await, serde_json::json!([{ "active": false }, { "active": false }]));
+    }
+
+    #[tokio::test]
+    async fn malformed_json_is_an_invalid_request() {
+        for body_text in [r#"{ "resource_scopes": ["view" "#, r#"{ "name": "Photo Album" }"#] {
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri("/")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body_text))
+                .unwrap();
+            let response = router().oneshot(request).await.unwrap();
+
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+            let error = body(response).await;
+            assert_eq!(error["error"], "invalid_request");
+            assert!(error["error_description"].is_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_request_names_the_bad_member() {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{ "resource_scopes": ["view", "print", 3] }"#))
+            .unwrap();
+        let response = router().oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let error = body(response).await;
+        assert_eq!(error["error"], "invalid_request");
+        assert_eq!(error["error_description"], "Invalid value at resource_scopes[2] (line 1, column 40).");
+    }
+
+    #[tokio::test]
+    async fn bodies_must_be_labelled_json() {
+        let registration = |content_type: Option<&str>| {
+            let mut request = Request::builder().method(Method::POST).uri("/");
+            if let Some(content_type) = content_type {
+                request = request.header(CONTENT_TYPE, content_type);
+            }
+            request.body(Body::from(r#"{ "resource_scopes": ["view"] }"#)).unwrap()
+        };
+
+        for content_type in ["application/json", "application/json; charset=utf-8"] {
+            let response = router().oneshot(registration(Some(content_type))).await.unwrap();
+            assert_eq!(response.status(), StatusCode::CREATED, "{content_type}");
+        }
+
+        for content_type in [Some("text/plain"), Some("application/x-www-form-urlencoded"), None] {
+            let response = router().oneshot(registration(content_type)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE, "{content_type:?}");
+            assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+            assert_eq!(body(response).await["error"], "unsupported_media_type");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn simultaneous_patches_both_survive() {
+        let router = router();
+        let request = Request::builder()
+            .method(Method::PUT)
+            .uri("/album")
+            .header(CONTENT_TYPE, "application/json")
+            .header("If-None-Match", "*")
+            .body(Body::from(r#"{ "resource_scopes": ["view"] }"#))
+            .unwrap();
+        assert_eq!(router.clone().oneshot(request).await.unwrap().status(), StatusCode::CREATED);
+
+        let patch = |patch: &'static str| {
+            let request = Request::builder()
+                .method(Method::PATCH)
+                .uri("/album")
+                .header(CONTENT_TYPE, "application/merge-patch+json")
+                .body(Body::from(patch))
+                .unwrap();
+            tokio::spawn(router.clone().oneshot(request))
+        };
+        let (name, description) = tokio::join!(
+            patch(r#"{ "name": "Photo album" }"#),
+            patch(r#"{ "description": "Holiday snaps" }"#),
+        );
+        assert_eq!(name.unwrap().unwrap().status(), StatusCode::OK);
+        assert_eq!(description.unwrap().unwrap().status(), StatusCode::OK);
+
+        let request = Request::builder().uri("/album").body(Body::empty()).unwrap();
+        let patched = body(router.clone().oneshot(request).await.unwrap()).await;
+        assert_eq!(patched["resource_description"]["name"], "Photo album");
+        assert_eq!(patched["resource_description"]["description"], "Holiday snaps");
+        assert_eq!(patched["resource_description"]["resource_scopes"], serde_json::json!(["view"]));
+
+        let request = Request::builder()
+            .method(Method::PATCH)
+            .uri("/album")
+            .header(CONTENT_TYPE, "application/merge-patch+json")
+            .body(Body::from(r#"{ "resource_scopes": null }"#))
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body(response).await["error"], "invalid_request");
+    }
+
+}
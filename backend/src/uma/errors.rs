@@ -5,28 +5,64 @@
 //! with the following members in the body of the HTTP response.
 
 use std::borrow::Cow;
+use std::result;
 
-use http::{Response, StatusCode};
+use http::{header, HeaderValue, Method, Response, StatusCode};
 use oxiri::Iri;
 use serde::Serialize;
+use tracing::{error, Span};
+use uuid::Uuid;
 
+/// Serialized with its members in a fixed order, that of the fields below: error, error_description, error_uri and
+/// error_context, each left out when absent. Responses that are otherwise alike are thus alike byte for byte, as entity
+/// tags and snapshot tests need; reordering the fields changes the wire format.
 #[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct ErrorMessage {
     /// [NO-SPEC] REQUIRED. HTTP status code for responses carrying this error message.
     #[serde(skip_serializing)]
+    #[cfg_attr(feature = "json-schema", schemars(skip))]
     pub status_code: StatusCode,
 
     /// REQUIRED except as noted. A single error code. Values for this parameter are defined throughout this specification.
     #[serde(rename = "error")]
+    #[cfg_attr(feature = "openapi", schema(value_type = String))]
     pub error_code: Cow<'static, str>,
 
     /// OPTIONAL. Human-readable text providing additional information.
     #[serde(skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<String>))]
     pub error_description: Option<Cow<'static, str>>,
 
     /// OPTIONAL. A URI identifying a human-readable web page with information about the error.
     #[serde(skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<String>, format = "uri"))]
+    #[cfg_attr(feature = "json-schema", schemars(with = "Option<String>", url))]
     pub error_uri: Option<Iri<String>>,
+
+    /// [NO-SPEC] OPTIONAL. Correlation ID under which the server logged the details of an internal error, so that a
+    /// client report can be matched with the log entry.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_context: Option<String>,
+
+    /// [NO-SPEC] OPTIONAL. For 405 (Method Not Allowed) responses, the methods the endpoint does support, sent in the
+    /// Allow header as RFC 9110 requires.
+    #[serde(skip_serializing)]
+    #[cfg_attr(feature = "json-schema", schemars(skip))]
+    pub allowed_methods: &'static [Method],
+
+    /// [NO-SPEC] OPTIONAL. For insufficient_scope and invalid_token responses, the scope the PAT lacks or has to carry,
+    /// sent in a Bearer challenge in the WWW-Authenticate header as RFC 6750 describes.
+    #[serde(skip_serializing)]
+    #[cfg_attr(feature = "json-schema", schemars(skip))]
+    pub required_scope: Option<&'static str>,
+
+    /// [NO-SPEC] Whether the response is to carry the status code and headers only, for errors whose error code the
+    /// specification leaves optional, such as not_found.
+    #[serde(skip_serializing)]
+    #[cfg_attr(feature = "json-schema", schemars(skip))]
+    pub omit_body: bool,
 }
 
 // use the following when const_convert feature is back:  fn f<'a>(s: impl Into<Cow<'a, str>>) -> Cow<'a, str> {
@@ -42,8 +78,24 @@ impl ErrorMessage {
             error_code: error_code,
             error_description,
             error_uri,
+            error_context: None,
+            allowed_methods: &[],
+            required_scope: None,
+            omit_body: false,
         }
     }
+
+    pub const fn with_allowed_methods(self, allowed_methods: &'static [Method]) -> Self {
+        Self { allowed_methods, ..self }
+    }
+
+    pub const fn with_required_scope(self, required_scope: &'static str) -> Self {
+        Self { required_scope: Some(required_scope), ..self }
+    }
+
+    pub const fn without_body(self) -> Self {
+        Self { omit_body: true, ..self }
+    }
 }
 
 const DEFAULT: ErrorMessage = ErrorMessage::new(
@@ -55,23 +107,108 @@ const DEFAULT: ErrorMessage = ErrorMessage::new(
     None,
 );
 
+impl ErrorMessage {
+    /// [NO-SPEC] The error message for a response of `status` that came without one, as axum and tower layers produce
+    /// them: the matching error message below if there is one, else one whose error code is the canonical reason
+    /// phrase of the status in snake case, e.g. request_timeout.
+    pub fn for_status(status: StatusCode) -> Self {
+        return match status {
+            StatusCode::BAD_REQUEST => INVALID_REQUEST,
+            StatusCode::NOT_FOUND => RESOURCE_NOT_FOUND,
+            StatusCode::METHOD_NOT_ALLOWED => UNSUPPORTED_METHOD_TYPE,
+            StatusCode::CONFLICT => CONFLICT,
+            StatusCode::GONE => RESOURCE_GONE,
+            StatusCode::PRECONDITION_FAILED => PRECONDITION_FAILED,
+            StatusCode::PAYLOAD_TOO_LARGE => PAYLOAD_TOO_LARGE,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => UNSUPPORTED_MEDIA_TYPE,
+            StatusCode::TOO_MANY_REQUESTS => TOO_MANY_REQUESTS,
+            StatusCode::INTERNAL_SERVER_ERROR => DEFAULT,
+            _ => {
+                let reason = status.canonical_reason().unwrap_or("error");
+                let error_code = reason.to_ascii_lowercase().replace([' ', '-'], "_").replace('\'', "");
+                Self::new(status, Cow::Owned(error_code), None, None)
+            }
+        };
+    }
+}
+
+/// [NO-SPEC] Marks a response as built from an error message, be it with or without a body, so that a layer putting
+/// other error responses into the same shape leaves it be.
+#[derive(Debug, Clone, Copy)]
+pub struct FromErrorMessage;
+
 impl Default for ErrorMessage {
     fn default() -> Self {
         DEFAULT
     }
 }
 
+/// Built without `http::response::Builder`, whose only failure modes are invalid status codes and header values, so the
+/// conversion cannot fail (and must not fall back to a default response that would hide the error).
 impl From<ErrorMessage> for Response<ErrorMessage> {
     fn from(msg: ErrorMessage) -> Response<ErrorMessage> {
-        return Response::builder()
-            .status(msg.status_code)
-            .header("Content-Type", "application/json")
-            .header("Cache-Control", "no-store")
-            .body(msg)
-            .unwrap_or_default();
+        Span::current().record("outcome", msg.error_code.as_ref());
+        let status_code = msg.status_code;
+        let mut response = Response::new(msg);
+        *response.status_mut() = status_code;
+        let omit_body = response.body().omit_body;
+        let headers = response.headers_mut();
+        if !omit_body {
+            headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        }
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        response.extensions_mut().insert(FromErrorMessage);
+        if !response.body().allowed_methods.is_empty() {
+            let allow = response.body().allowed_methods.iter().map(Method::as_str).collect::<Vec<_>>().join(", ");
+            // Method names are tokens, which are valid header values; should one not be, the header is left out.
+            if let Ok(allow) = HeaderValue::from_str(&allow) {
+                response.headers_mut().insert(header::ALLOW, allow);
+            }
+        }
+        if let Some(scope) = response.body().required_scope {
+            let error_code = &response.body().error_code;
+            // RFC 6749 restricts error codes and scope tokens to printable ASCII without quotes or backslashes, but
+            // either can be set to anything; rather than a malformed challenge, a bare one is sent then.
+            let challenge = match is_challenge_value(error_code) && is_challenge_value(scope) {
+                true => HeaderValue::from_str(&format!(r#"Bearer error="{error_code}", scope="{scope}""#)).ok(),
+                false => None,
+            };
+            let challenge = challenge.unwrap_or(HeaderValue::from_static("Bearer"));
+            response.headers_mut().insert(header::WWW_AUTHENTICATE, challenge);
+        }
+        return response;
     }
 }
 
+/// Whether `value` is made of the characters RFC 6749 allows in error codes and scope tokens, and so can be quoted in a
+/// challenge as it is.
+fn is_challenge_value(value: &str) -> bool {
+    return value.bytes().all(|byte| matches!(byte, 0x20..=0x21 | 0x23..=0x5B | 0x5D..=0x7E));
+}
+
+/// Turns a failure to build a response into a generic internal server error, logging the underlying http::Error under
+/// a fresh correlation ID that is also returned to the client. On success, the status code is recorded as the outcome
+/// of the handler's span; error responses record their error code when converted from an ErrorMessage.
+pub fn catch_errors<T>(
+    result: http::Result<Response<T>>,
+) -> result::Result<Response<T>, Response<ErrorMessage>> {
+    return match result {
+        Ok(response) => {
+            Span::current().record("outcome", response.status().as_u16());
+            Ok(response)
+        }
+        Err(error) => {
+            let correlation_id = Uuid::new_v4().to_string();
+            error!(%error, %correlation_id, "failed to build response");
+            Err(ErrorMessage {
+                error_context: Some(correlation_id),
+                ..ErrorMessage::default()
+            }
+            .into())
+        }
+    };
+}
+
 /// If the request to the resource registration endpoint is incorrect, then the authorization server instead responds as follows (see Section 6 for information about error messages):
 pub enum ResourceRegistrationFailure {
     /// If the referenced resource cannot be found, the authorization server MUST respond with an HTTP 404 (Not Found) status code and MAY respond with a not_found error code.
@@ -91,8 +228,18 @@ pub const RESOURCE_NOT_FOUND: ErrorMessage = ErrorMessage::new(
     None,
 );
 
+/// [NO-SPEC] For a resource that was recently deleted, rather than never registered. It keeps the not_found error code,
+/// so that clients unaware of the distinction handle it as they would a 404.
+pub const RESOURCE_GONE: ErrorMessage = ErrorMessage::new(
+    StatusCode::GONE,
+    Cow::Borrowed("not_found"),
+    Some(Cow::Borrowed("The referenced resource has been deleted.")),
+    None,
+);
+
+/// Endpoints should attach the methods they support with `with_allowed_methods`, to be sent in the Allow header.
 pub const UNSUPPORTED_METHOD_TYPE: ErrorMessage = ErrorMessage::new(
-    StatusCode::NOT_FOUND,
+    StatusCode::METHOD_NOT_ALLOWED,
     Cow::Borrowed("unsupported_method_type"),
     Some(Cow::Borrowed(
         "The request used an unsupported HTTP method.",
@@ -106,3 +253,173 @@ pub const INVALID_REQUEST: ErrorMessage = ErrorMessage::new(
   Some(Cow::Borrowed("The request is missing a required parameter, includes an invalid parameter value, includes a parameter more than once, or is otherwise malformed.")), 
   None
 );
+
+/// [NO-SPEC] If a conditional request (e.g. a PUT with If-None-Match: *) finds its precondition unmet, the authorization
+/// server responds with the HTTP 412 (Precondition Failed) status code.
+pub const PRECONDITION_FAILED: ErrorMessage = ErrorMessage::new(
+    StatusCode::PRECONDITION_FAILED,
+    Cow::Borrowed("precondition_failed"),
+    Some(Cow::Borrowed("A precondition given in the request headers was not met.")),
+    None,
+);
+
+/// [NO-SPEC] If a request would overwrite an existing resource description with a different one, e.g. a retried create
+/// whose Idempotency-Key is already in use for another description, the authorization server responds with the HTTP
+/// 409 (Conflict) status code.
+pub const CONFLICT: ErrorMessage = ErrorMessage::new(
+    StatusCode::CONFLICT,
+    Cow::Borrowed("conflict"),
+    Some(Cow::Borrowed("The request conflicts with the current state of the referenced resource.")),
+    None,
+);
+
+/// [NO-SPEC] If a request body exceeds the size limit of its endpoint, the authorization server responds with the HTTP
+/// 413 (Content Too Large) status code.
+pub const PAYLOAD_TOO_LARGE: ErrorMessage = ErrorMessage::new(
+    StatusCode::PAYLOAD_TOO_LARGE,
+    Cow::Borrowed("payload_too_large"),
+    Some(Cow::Borrowed("The request body is larger than this endpoint accepts.")),
+    None,
+);
+
+/// [NO-SPEC] If a request body is not in a media type its endpoint accepts, as told by the Content-Type header, the
+/// authorization server responds with the HTTP 415 (Unsupported Media Type) status code.
+pub const UNSUPPORTED_MEDIA_TYPE: ErrorMessage = ErrorMessage::new(
+    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+    Cow::Borrowed("unsupported_media_type"),
+    Some(Cow::Borrowed("The request body is not in a media type this endpoint accepts.")),
+    None,
+);
+
+/// [NO-SPEC] If registering a resource would take its resource owner over the number of resources the authorization
+/// server allows per owner, it responds with the HTTP 403 (Forbidden) status code.
+pub const QUOTA_EXCEEDED: ErrorMessage = ErrorMessage::new(
+    StatusCode::FORBIDDEN,
+    Cow::Borrowed("quota_exceeded"),
+    Some(Cow::Borrowed("The resource owner has registered as many resources as this authorization server allows.")),
+    None,
+);
+
+/// https://www.rfc-editor.org/rfc/rfc6750#section-3.1
+///
+/// The request requires higher privileges than provided by the access token. The resource server SHOULD respond with
+/// the HTTP 403 (Forbidden) status code.
+pub const INSUFFICIENT_SCOPE: ErrorMessage = ErrorMessage::new(
+    StatusCode::FORBIDDEN,
+    Cow::Borrowed("insufficient_scope"),
+    Some(Cow::Borrowed("The PAT does not carry the scope this endpoint requires.")),
+    None,
+);
+
+/// https://www.rfc-editor.org/rfc/rfc6750#section-3.1
+///
+/// The access token provided is expired, revoked, malformed, or invalid for other reasons. The resource SHOULD respond
+/// with the HTTP 401 (Unauthorized) status code.
+pub const INVALID_TOKEN: ErrorMessage = ErrorMessage::new(
+    StatusCode::UNAUTHORIZED,
+    Cow::Borrowed("invalid_token"),
+    Some(Cow::Borrowed("The access token could not be verified.")),
+    None,
+);
+
+/// https://www.rfc-editor.org/rfc/rfc6749#section-4.1.2.1
+///
+/// The authorization server is currently unable to handle the request due to a temporary overloading or maintenance of
+/// the server. [NO-SPEC] Sent with the HTTP 503 (Service Unavailable) status code, e.g. when the services a token has
+/// to be verified against cannot be reached.
+pub const TEMPORARILY_UNAVAILABLE: ErrorMessage = ErrorMessage::new(
+    StatusCode::SERVICE_UNAVAILABLE,
+    Cow::Borrowed("temporarily_unavailable"),
+    Some(Cow::Borrowed("The access token cannot be verified at the moment; retry later.")),
+    None,
+);
+
+/// [NO-SPEC] If a client makes more requests on behalf of a PAT subject than the authorization server allows, it
+/// responds with the HTTP 429 (Too Many Requests) status code and a Retry-After header.
+pub const TOO_MANY_REQUESTS: ErrorMessage = ErrorMessage::new(
+    StatusCode::TOO_MANY_REQUESTS,
+    Cow::Borrowed("too_many_requests"),
+    Some(Cow::Borrowed("Too many requests were made on behalf of this resource owner; retry later.")),
+    None,
+);
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use tracing_test::traced_test;
+
+    #[traced_test]
+    #[test]
+    fn catch_errors_logs_builder_failure() {
+        let response = Response::builder().header("in valid", "header").body(());
+
+        let error = catch_errors(response).unwrap_err();
+
+        assert_eq!(error.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(logs_contain("failed to build response"));
+        assert!(logs_contain("invalid HTTP header name"));
+    }
+
+    #[traced_test]
+    #[test]
+    fn catch_errors_returns_internal_server_error_body() {
+        let response = Response::builder().header("Location", "\n").body(());
+
+        let error = catch_errors(response).unwrap_err();
+
+        assert_eq!(error.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(error.headers()["Content-Type"], "application/json");
+        let body = serde_json::to_value(error.body()).unwrap();
+        assert_eq!(body["error"], "internal_server_error");
+        assert!(body["error_description"].is_string());
+        let correlation_id = body["error_context"].as_str().unwrap();
+        assert!(Uuid::parse_str(correlation_id).is_ok());
+        assert!(logs_contain(correlation_id));
+    }
+
+    #[test]
+    fn error_message_converts_without_builder() {
+        let response: Response<ErrorMessage> = PRECONDITION_FAILED.into();
+
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(response.headers()["Cache-Control"], "no-store");
+        assert_eq!(response.body().error_code, "precondition_failed");
+    }
+
+    #[test]
+    fn unquotable_challenges_fall_back_to_a_bare_one() {
+        let quoted = ErrorMessage { error_code: Cow::Borrowed(r#"invalid"token"#), ..INVALID_TOKEN };
+        let response: Response<ErrorMessage> = quoted.with_required_scope("uma_protection").into();
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+
+        let controlled = ErrorMessage { error_code: Cow::Borrowed("invalid\ntoken"), ..INVALID_TOKEN };
+        let response: Response<ErrorMessage> = controlled.with_required_scope("uma_protection").into();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+
+        let response: Response<ErrorMessage> = INVALID_TOKEN.with_required_scope("uma_protection").into();
+        let challenge = &response.headers()[header::WWW_AUTHENTICATE];
+        assert_eq!(challenge, r#"Bearer error="invalid_token", scope="uma_protection""#);
+    }
+
+    #[test]
+    fn serialized_member_order_is_fixed() {
+        let full = ErrorMessage {
+            error_uri: Some(Iri::parse("https://as.example.com/errors/not_found".to_string()).unwrap()),
+            error_context: Some("5e0c3d9a".to_string()),
+            ..RESOURCE_NOT_FOUND
+        };
+        assert_eq!(
+            serde_json::to_string(&full).unwrap(),
+            concat!(
+                r#"{"error":"not_found","error_description":"The referenced resource could be found.","#,
+                r#""error_uri":"https://as.example.com/errors/not_found","error_context":"5e0c3d9a"}"#,
+            )
+        );
+
+        let bare = ErrorMessage { error_description: None, ..RESOURCE_NOT_FOUND };
+        assert_eq!(serde_json::to_string(&bare).unwrap(), r#"{"error":"not_found"}"#);
+    }
+
+}
@@ -41,11 +41,24 @@
 //! The resource server's resource registration operations at the authorization server result in a set of resource owner-specific resource identifiers. When the client makes a resource request that is unaccompanied by an access token or its resource request fails, the resource server is responsible for interpreting that request and mapping it to a choice of authorization server, resource owner, resource identifier(s), and set of scopes for each identifier, in order to request one or more permissions -- resource identifiers and a set of scopes -- and obtain a permission ticket on the client's behalf. Finally, when the client has made a resource request accompanied by an RPT and token introspection is in use, the returned token introspection object reveals the structure of permissions, potentially including expiration of individual permissions.
 
 use either::Either;
+use futures::future::join_all;
 use oxiri::Iri;
-use serde::Serialize;
+use serde::de::Error as _;
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
 use std::ops::Deref;
+use std::time::Duration;
+use thiserror::Error;
+use unicode_normalization::{is_nfc, UnicodeNormalization};
 
 use crate::oauth::discovery::AuthorizationServerMetadata as OauthASM;
+use crate::oidc::client;
+use crate::resource::Identifier;
+use crate::storage::KeyValueStore;
 
 /// This specification makes use of the authorization server discovery document structure and endpoint defined in [UMAGrant]. The resource server uses this discovery document to discover the endpoints it needs.
 ///
@@ -54,7 +67,9 @@ use crate::oauth::discovery::AuthorizationServerMetadata as OauthASM;
 /// The authorization server SHOULD document any profiled or extended features it supports explicitly, ideally by supplying the URI identifying each UMA profile and extension as an uma_profiles_supported metadata array value (defined in [UMAGrant]), and by using extension metadata to indicate specific usage details as necessary.
 ///
 /// Following are additional requirements related to metadata: introspection_endpoint; If the authorization server supports token introspection as defined in this specification, it MUST supply this metadata value (defined in [OAuthMeta]).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct AuthorizationServerMetadata {
+    #[serde(flatten)]
     oauth: OauthASM,
 
     /// REQUIRED. The endpoint URI at which the resource server requests permissions on the client's behalf.
@@ -81,29 +96,435 @@ pub struct ProtectionApiAccessToken; // PAT
 /// https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-federated-authz-2.0.html#resource-set-desc
 ///
 /// A resource description is a JSON document that describes the characteristics of a resource sufficiently for an authorization server to protect it. A resource description has the following parameters:
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct ResourceDescription {
   
+    #[cfg_attr(feature = "openapi", schema(value_type = String))]
+    #[cfg_attr(feature = "json-schema", schemars(with = "Option<String>", extend("readOnly" = true)))]
     pub _id: &'static str,
 
     /// REQUIRED. An array of strings, serving as scope identifiers, indicating the available scopes for this resource. Any of the strings MAY be either a plain string or a URI.
-    pub resource_scopes: Vec<String>,
+    #[cfg_attr(feature = "openapi", schema(value_type = Vec<String>))]
+    #[cfg_attr(feature = "json-schema", schemars(with = "Vec<String>"))]
+    pub resource_scopes: Vec<Scope>,
 
     /// OPTIONAL. A human-readable string describing the resource at length. The authorization server MAY use this description in any user interface it presents to a resource owner, for example, for resource protection monitoring or policy setting. The value of this parameter MAY be internationalized, as described in Section 2.2 of [RFC7591].
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub description: Option<String>,
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<String>))]
+    #[cfg_attr(feature = "json-schema", schemars(with = "Option<String>"))]
+    pub description: Option<LocalizedString>,
 
     /// OPTIONAL. A URI for a graphic icon representing the resource. The authorization server MAY use the referenced icon in any user interface it presents to a resource owner, for example, for resource protection monitoring or policy setting.
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<String>))]
+    #[cfg_attr(feature = "json-schema", schemars(with = "Option<String>"))]
     pub icon_uri: Option<Either<Iri<String>, String>>,
 
     /// OPTIONAL. A human-readable string naming the resource. The authorization server MAY use this name in any user interface it presents to a resource owner, for example, for resource protection monitoring or policy setting. The value of this parameter MAY be internationalized, as described in Section 2.2 of [RFC7591].
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub name: Option<String>,
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<String>))]
+    #[cfg_attr(feature = "json-schema", schemars(with = "Option<String>"))]
+    pub name: Option<LocalizedString>,
 
     /// OPTIONAL. A string identifying the semantics of the resource. For example, if the resource is an identity claim that leverages standardized claim semantics for "verified email address", the value of this parameter could be an identifying URI for this claim. The authorization server MAY use this information in processing information about the resource or displaying information about it in any user interface it presents to a resource owner.
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "openapi", schema(rename = "type"))]
+    #[cfg_attr(feature = "json-schema", schemars(rename = "type"))]
     pub r#type: Option<String>,
+
+    /// [NO-SPEC] The resource owner on whose behalf the resource was registered, as authenticated by the PAT. The
+    /// authorization server sets it from the request, whatever owner member the resource server may have sent.
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<String>, read_only))]
+    #[cfg_attr(feature = "json-schema", schemars(with = "Option<String>", extend("readOnly" = true)))]
+    pub owner: Option<Identifier>,
+
+    /// [NO-SPEC] OPTIONAL. Default policy conditions the resource server suggests for the resource, e.g. from an
+    /// onboarding flow, which the authorization server may pre-seed the policy of the resource owner with when they
+    /// follow the user_access_policy_uri. Stored and returned as sent; clients unaware of it ignore it.
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<Object>))]
+    #[cfg_attr(feature = "json-schema", schemars(with = "Option<serde_json::Map<String, Value>>"))]
+    pub policy_hint: Option<Map<String, Value>>,
+}
+
+/// The localized parameters are spread over several JSON members (e.g. name and name#nl), which the derived
+/// implementations cannot express; the other parameters serialize as they would when derived, with icon_uri untagged.
+impl Serialize for ResourceDescription {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("_id", self._id)?;
+        map.serialize_entry("resource_scopes", &self.resource_scopes)?;
+        if let Some(description) = &self.description {
+            description.serialize_members("description", &mut map)?;
+        }
+        if let Some(icon_uri) = &self.icon_uri {
+            map.serialize_entry("icon_uri", &either::for_both!(icon_uri, uri => uri.as_str()))?;
+        }
+        if let Some(name) = &self.name {
+            name.serialize_members("name", &mut map)?;
+        }
+        if let Some(r#type) = &self.r#type {
+            map.serialize_entry("type", r#type)?;
+        }
+        if let Some(owner) = &self.owner {
+            map.serialize_entry("owner", &owner.to_string())?;
+        }
+        if let Some(policy_hint) = &self.policy_hint {
+            map.serialize_entry("policy_hint", policy_hint)?;
+        }
+        return map.end();
+    }
+}
+
+/// The _id is assigned by the authorization server, so any _id member in the document is ignored.
+impl<'de> Deserialize<'de> for ResourceDescription {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Members {
+            resource_scopes: Vec<Scope>,
+            #[serde(default, with = "either::serde_untagged_optional")]
+            icon_uri: Option<Either<Iri<String>, String>>,
+            #[serde(default, rename = "type")]
+            r#type: Option<String>,
+            #[serde(default)]
+            owner: Option<String>,
+            #[serde(default)]
+            policy_hint: Option<Map<String, Value>>,
+            #[serde(flatten)]
+            other: BTreeMap<String, Value>,
+        }
+
+        let members = Members::deserialize(deserializer)?;
+        let mut description = None;
+        let mut name = None;
+        for (member, value) in members.other {
+            let (parameter, tag) = match member.split_once('#') {
+                Some((parameter, tag)) => (parameter, Some(tag)),
+                None => (member.as_str(), None),
+            };
+            let localized: &mut Option<LocalizedString> = match parameter {
+                "description" => &mut description,
+                "name" => &mut name,
+                _ => continue,
+            };
+            let Value::String(value) = value else {
+                return Err(D::Error::custom(format!("{member} must be a string")));
+            };
+            localized.get_or_insert_with(LocalizedString::default).insert(tag, value);
+        }
+
+        let owner = members.owner.map(|owner| Identifier::parse(&owner)).transpose().map_err(D::Error::custom)?;
+
+        return Ok(ResourceDescription {
+            _id: "",
+            resource_scopes: members.resource_scopes,
+            description,
+            icon_uri: members.icon_uri,
+            name,
+            r#type: members.r#type,
+            owner,
+            policy_hint: members.policy_hint,
+        });
+    }
+}
+
+/// [NO-SPEC] A human-readable string that MAY be internationalized, as described in Section 2.2 of [RFC7591]: the
+/// parameter's plain member holds the value without a language tag, and members named like name#nl hold the values for
+/// the BCP47 language tag after the #.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LocalizedString {
+    pub untagged: Option<String>,
+    /// Values by language tag, lowercased since tags are case-insensitive.
+    pub tagged: BTreeMap<String, String>,
+}
+
+impl LocalizedString {
+    pub fn insert(&mut self, tag: Option<&str>, value: String) {
+        match tag {
+            Some(tag) => _ = self.tagged.insert(tag.to_ascii_lowercase(), value),
+            None => self.untagged = Some(value),
+        }
+    }
+
+    pub fn with(mut self, tag: &str, value: impl Into<String>) -> Self {
+        self.insert(Some(tag), value.into());
+        return self;
+    }
+
+    /// Every value, untagged or tagged.
+    pub fn values(&self) -> impl Iterator<Item = &String> {
+        return self.untagged.iter().chain(self.tagged.values());
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut String> {
+        return self.untagged.iter_mut().chain(self.tagged.values_mut());
+    }
+
+    /// The value for the language tag `locale`, falling back to ever shorter prefixes of the tag (so that nl-BE finds
+    /// nl), then to the untagged value.
+    pub fn get(&self, locale: &str) -> Option<&str> {
+        let mut locale = locale.to_ascii_lowercase();
+        loop {
+            if let Some(value) = self.tagged.get(&locale) {
+                return Some(value);
+            }
+            match locale.rfind('-') {
+                Some(end) => locale.truncate(end),
+                None => return self.untagged.as_deref(),
+            }
+        }
+    }
+
+    fn serialize_members<M: SerializeMap>(&self, parameter: &str, map: &mut M) -> Result<(), M::Error> {
+        if let Some(value) = &self.untagged {
+            map.serialize_entry(parameter, value)?;
+        }
+        for (tag, value) in &self.tagged {
+            map.serialize_entry(&format!("{parameter}#{tag}"), value)?;
+        }
+        return Ok(());
+    }
+}
+
+impl From<String> for LocalizedString {
+    fn from(value: String) -> Self {
+        return Self {
+            untagged: Some(value),
+            tagged: BTreeMap::new(),
+        };
+    }
+}
+
+impl From<&str> for LocalizedString {
+    fn from(value: &str) -> Self {
+        return value.to_string().into();
+    }
+}
+
+/// [NO-SPEC] Fluent construction of a resource description, leaving unset optional parameters out.
+#[derive(Debug, Default, Clone)]
+pub struct ResourceDescriptionBuilder {
+    resource_scopes: Vec<Scope>,
+    description: Option<LocalizedString>,
+    icon_uri: Option<Either<Iri<String>, String>>,
+    name: Option<LocalizedString>,
+    r#type: Option<String>,
+    policy_hint: Option<Map<String, Value>>,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ResourceDescriptionError {
+    #[error("A resource description needs at least one scope")]
+    NoScopes,
+    #[error("{0:?} is neither a URI nor a valid plain scope")]
+    InvalidScope(String),
+    #[error("A resource description may have at most {0} scopes")]
+    TooManyScopes(usize),
+    #[error("{0:?} contains a control character")]
+    ControlCharacter(String),
+}
+
+/// [NO-SPEC] How strictly the human-readable name and description of a resource description are checked, as they end
+/// up in the user interface of the authorization server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TextValidation {
+    /// Any string, kept as it is.
+    #[default]
+    Lenient,
+    /// Any string, kept in Unicode Normalization Form C (see `ResourceDescription::normalize`).
+    Normalized,
+    /// A string without control characters, kept in Unicode Normalization Form C.
+    Strict,
+}
+
+/// [NO-SPEC] How many scopes a resource description, or a requested permission, may have by default; anything longer
+/// is an invalid_request rather than something to keep in memory.
+pub const DEFAULT_MAX_SCOPES: usize = 256;
+
+impl ResourceDescription {
+    pub fn builder() -> ResourceDescriptionBuilder {
+        return ResourceDescriptionBuilder::default();
+    }
+
+    /// Fails if the description has more than `max_scopes` scopes, or if one of them is invalid (see `is_valid_scope`),
+    /// or if a name or description is not acceptable as text (see `TextValidation`).
+    pub fn validate(&self, max_scopes: usize, text: TextValidation) -> Result<(), ResourceDescriptionError> {
+        if (self.resource_scopes.len() > max_scopes) {
+            return Err(ResourceDescriptionError::TooManyScopes(max_scopes));
+        }
+        if let Some(scope) = self.resource_scopes.iter().find(|scope| !scope.is_valid()) {
+            return Err(ResourceDescriptionError::InvalidScope(scope.to_string()));
+        }
+        if (text == TextValidation::Strict) {
+            let mut texts = self.name.iter().chain(&self.description).flat_map(LocalizedString::values);
+            if let Some(text) = texts.find(|text| text.chars().any(char::is_control)) {
+                return Err(ResourceDescriptionError::ControlCharacter(text.clone()));
+            }
+        }
+        return Ok(());
+    }
+
+    /// Brings every name and description into Unicode Normalization Form C, so that strings that only differ in how
+    /// their characters are composed (e.g. é as one code point, or as e and a combining accent) are stored, compared and
+    /// shown alike.
+    pub fn normalize(&mut self) {
+        let texts = self.name.iter_mut().chain(&mut self.description).flat_map(LocalizedString::values_mut);
+        for text in texts {
+            if !is_nfc(text) {
+                *text = text.nfc().collect();
+            }
+        }
+    }
+}
+
+impl ResourceDescriptionBuilder {
+    pub fn scope(mut self, scope: impl Into<Scope>) -> Self {
+        self.resource_scopes.push(scope.into());
+        return self;
+    }
+
+    pub fn description(mut self, description: impl Into<LocalizedString>) -> Self {
+        self.description = Some(description.into());
+        return self;
+    }
+
+    pub fn icon_uri(mut self, icon_uri: Iri<String>) -> Self {
+        self.icon_uri = Some(Either::Left(icon_uri));
+        return self;
+    }
+
+    pub fn name(mut self, name: impl Into<LocalizedString>) -> Self {
+        self.name = Some(name.into());
+        return self;
+    }
+
+    pub fn r#type(mut self, r#type: impl Into<String>) -> Self {
+        self.r#type = Some(r#type.into());
+        return self;
+    }
+
+    pub fn policy_hint(mut self, policy_hint: Map<String, Value>) -> Self {
+        self.policy_hint = Some(policy_hint);
+        return self;
+    }
+
+    /// Fails if no scope was added, or if one of them would be rejected at registration.
+    pub fn build(self) -> Result<ResourceDescription, ResourceDescriptionError> {
+        if self.resource_scopes.is_empty() {
+            return Err(ResourceDescriptionError::NoScopes);
+        }
+        if let Some(scope) = self.resource_scopes.iter().find(|scope| !scope.is_valid()) {
+            return Err(ResourceDescriptionError::InvalidScope(scope.to_string()));
+        }
+        return Ok(ResourceDescription {
+            _id: "",
+            resource_scopes: self.resource_scopes,
+            description: self.description,
+            icon_uri: self.icon_uri,
+            name: self.name,
+            r#type: self.r#type,
+            owner: None,
+            policy_hint: self.policy_hint,
+        });
+    }
+}
+
+/// [NO-SPEC] Whether a string is acceptable as a scope identifier. A URI scope may contain any URI characters, and must
+/// be escaped (see `storage::escape_key_component`) wherever it appears in a path segment or composite store key. A
+/// plain scope must be a non-empty scope-token as defined in Section 3.3 of [RFC6749] that moreover does not contain the
+/// `/` path and key separator, so that it can be used verbatim.
+pub fn is_valid_scope(scope: &str) -> bool {
+    if Iri::parse(scope).is_ok() {
+        return true;
+    }
+    return !scope.is_empty()
+        && scope.chars().all(|c| matches!(c, '\x21' | '\x23'..='\x5B' | '\x5D'..='\x7E') && c != '/');
+}
+
+/// A scope identifier, which MAY be either a plain string or a URI. It serializes as the bare string, and is classified
+/// when parsed: anything that parses as an absolute IRI is a URI scope. Scopes compare by their string, whatever their
+/// variant.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(into = "String", from = "String")]
+pub enum Scope {
+    Plain(String),
+    Uri(Iri<String>),
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &str {
+        return match self {
+            Self::Plain(scope) => scope,
+            Self::Uri(scope) => scope.as_str(),
+        };
+    }
+
+    /// See `is_valid_scope`; URI scopes are valid by construction.
+    pub fn is_valid(&self) -> bool {
+        return match self {
+            Self::Plain(scope) => is_valid_scope(scope),
+            Self::Uri(_) => true,
+        };
+    }
+}
+
+impl From<String> for Scope {
+    fn from(scope: String) -> Self {
+        return match Iri::parse(scope.clone()) {
+            Ok(scope) => Self::Uri(scope),
+            Err(_) => Self::Plain(scope),
+        };
+    }
+}
+
+impl From<&str> for Scope {
+    fn from(scope: &str) -> Self {
+        return scope.to_string().into();
+    }
+}
+
+impl From<Scope> for String {
+    fn from(scope: Scope) -> Self {
+        return match scope {
+            Scope::Plain(scope) => scope,
+            Scope::Uri(scope) => scope.into_inner(),
+        };
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return f.write_str(self.as_str());
+    }
+}
+
+impl PartialEq for Scope {
+    fn eq(&self, other: &Self) -> bool {
+        return self.as_str() == other.as_str();
+    }
+}
+
+impl Eq for Scope {}
+
+impl std::hash::Hash for Scope {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state);
+    }
+}
+
+impl PartialEq<str> for Scope {
+    fn eq(&self, other: &str) -> bool {
+        return self.as_str() == other;
+    }
+}
+
+impl PartialEq<&str> for Scope {
+    fn eq(&self, other: &&str) -> bool {
+        return self.as_str() == *other;
+    }
+}
+
+impl PartialEq<String> for Scope {
+    fn eq(&self, other: &String) -> bool {
+        return self.as_str() == other;
+    }
 }
 
 /// https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-federated-authz-2.0.html#rfc.section.3.1.1
@@ -114,13 +535,292 @@ pub struct ResourceDescription {
 /// While a scope URI appearing in a resource description (see Section 3.1) MAY resolve to a scope description document, and thus scope description documents are possible to standardize and reference publicly, the authorization server is not expected to resolve scope description details at resource registration time or at any other run-time requirement. The resource server and authorization server are presumed to have negotiated any required interpretation of scope handling out of band.
 ///
 /// A scope description has the following parameters:
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct ScopeDescription {
     /// OPTIONAL. A human-readable string describing the resource at length. The authorization server MAY use this description in any user interface it presents to a resource owner, for example, for resource protection monitoring or policy setting. The value of this parameter MAY be internationalized, as described in Section 2.2 of [RFC7591].
+    #[serde(rename = "description", default, skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
 
     /// OPTIONAL. A URI for a graphic icon representing the scope. The authorization server MAY use the referenced icon in any user interface it presents to a resource owner, for example, for resource protection monitoring or policy setting.
-    pub icon_uri: Iri<String>,
+    #[serde(rename = "icon_uri", default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "json-schema", schemars(with = "Option<String>", url))]
+    pub icon_uri: Option<Iri<String>>,
 
     /// OPTIONAL. A human-readable string naming the scope. The authorization server MAY use this name in any user interface it presents to a resource owner, for example, for resource protection monitoring or policy setting. The value of this parameter MAY be internationalized, as described in Section 2.2 of [RFC7591].
+    #[serde(rename = "name", default, skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
 }
+
+/// [NO-SPEC] Scope descriptions known locally, by scope URI.
+pub type ScopeDescriptionStore = dyn KeyValueStore<Key = String, Value = ScopeDescription>;
+
+/// [NO-SPEC] Finds the scope description a scope URI resolves to, if any. Implemented by a local scope description
+/// store, by `HttpScopeResolver`, and by a pair of resolvers, which asks the second one whatever the first one does not
+/// find.
+pub trait ScopeResolver: Send + Sync {
+    fn resolve(&self, scope: &Iri<String>) -> impl Future<Output = Option<ScopeDescription>> + Send;
+}
+
+impl ScopeResolver for ScopeDescriptionStore {
+    async fn resolve(&self, scope: &Iri<String>) -> Option<ScopeDescription> {
+        return self.get(&scope.to_string()).cloned();
+    }
+}
+
+impl<R: ScopeResolver + ?Sized> ScopeResolver for &R {
+    async fn resolve(&self, scope: &Iri<String>) -> Option<ScopeDescription> {
+        return (**self).resolve(scope).await;
+    }
+}
+
+impl<A: ScopeResolver, B: ScopeResolver> ScopeResolver for (A, B) {
+    async fn resolve(&self, scope: &Iri<String>) -> Option<ScopeDescription> {
+        return match self.0.resolve(scope).await {
+            Some(description) => Some(description),
+            None => self.1.resolve(scope).await,
+        };
+    }
+}
+
+/// [NO-SPEC] Fetches the scope description a scope URI resolves to as a JSON document. Anything but a 2xx response
+/// within the timeout, holding a scope description, resolves to nothing.
+#[derive(Debug, Clone)]
+pub struct HttpScopeResolver {
+    pub timeout: Duration,
+}
+
+impl Default for HttpScopeResolver {
+    fn default() -> Self {
+        return Self { timeout: Duration::from_secs(2) };
+    }
+}
+
+impl ScopeResolver for HttpScopeResolver {
+    async fn resolve(&self, scope: &Iri<String>) -> Option<ScopeDescription> {
+        let request = client().get(scope.as_str()).header(reqwest::header::ACCEPT, "application/json");
+        let sent = request.timeout(self.timeout).send().await;
+        let response = sent.and_then(reqwest::Response::error_for_status).ok()?;
+        return response.json().await.ok();
+    }
+}
+
+/// [NO-SPEC] A scope of a resource description, with the scope description it resolves to, if any.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ResolvedScope<'rs> {
+    #[serde(rename = "scope")]
+    pub scope: &'rs Scope,
+
+    #[serde(rename = "scope_description", skip_serializing_if = "Option::is_none")]
+    pub scope_description: Option<ScopeDescription>,
+}
+
+/// [NO-SPEC] The scopes of `description`, each URI scope along with the scope description `resolver` finds for it, e.g.
+/// to render them in a user interface. Plain scopes, and URI scopes that resolve to nothing, come as they are. As the
+/// authorization server is not expected to resolve scopes, this only happens on request; the scopes are resolved
+/// concurrently.
+pub async fn resolve_scopes<'d>(
+    description: &'d ResourceDescription,
+    resolver: &impl ScopeResolver,
+) -> Vec<ResolvedScope<'d>> {
+    let resolved = description.resource_scopes.iter().map(|scope| async move {
+        let scope_description = match scope {
+            Scope::Uri(uri) => resolver.resolve(uri).await,
+            Scope::Plain(_) => None,
+        };
+        return ResolvedScope { scope, scope_description };
+    });
+    return join_all(resolved).await;
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::uma::grants::AuthorizationServerMetadata as GrantASM;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    #[test]
+    fn build_minimal_description() {
+        let description = ResourceDescription::builder().scope("view").build().unwrap();
+
+        assert_eq!(serde_json::to_value(&description).unwrap(), json!({ "_id": "", "resource_scopes": ["view"] }));
+    }
+
+    #[test]
+    fn build_full_description() {
+        let description = ResourceDescription::builder()
+            .scope("http://photoz.example.com/dev/scopes/view")
+            .scope("public-read")
+            .description("Collection of digital photographs")
+            .icon_uri(Iri::parse("http://www.example.com/icons/sky.png".to_string()).unwrap())
+            .name("Photo Album")
+            .r#type("http://www.example.com/rsrcs/photoalbum")
+            .build()
+            .unwrap();
+
+        assert_eq!(description.resource_scopes, vec!["http://photoz.example.com/dev/scopes/view", "public-read"]);
+        assert_eq!(description.description, Some("Collection of digital photographs".into()));
+        assert_eq!(
+            description.icon_uri.unwrap().left().unwrap().as_str(),
+            "http://www.example.com/icons/sky.png"
+        );
+        assert_eq!(description.name, Some("Photo Album".into()));
+        assert_eq!(description.r#type.as_deref(), Some("http://www.example.com/rsrcs/photoalbum"));
+    }
+
+    #[test]
+    fn localized_name_variants() {
+        let document = json!({
+            "resource_scopes": ["view"],
+            "name": "Photo Album",
+            "name#nl": "Fotoalbum",
+            "description#NL": "Verzameling digitale foto's",
+            "icon_uri": "http://www.example.com/icons/sky.png",
+        });
+
+        let description: ResourceDescription = serde_json::from_value(document).unwrap();
+
+        let name = description.name.as_ref().unwrap();
+        assert_eq!(name.get("nl"), Some("Fotoalbum"));
+        assert_eq!(name.get("nl-BE"), Some("Fotoalbum"));
+        assert_eq!(name.get("en"), Some("Photo Album"));
+        let localized = description.description.as_ref().unwrap();
+        assert_eq!(localized.get("nl"), Some("Verzameling digitale foto's"));
+        assert_eq!(localized.get("en"), None);
+
+        assert_eq!(
+            serde_json::to_value(&description).unwrap(),
+            json!({
+                "_id": "",
+                "resource_scopes": ["view"],
+                "name": "Photo Album",
+                "name#nl": "Fotoalbum",
+                "description#nl": "Verzameling digitale foto's",
+                "icon_uri": "http://www.example.com/icons/sky.png",
+            })
+        );
+        assert!(serde_json::from_value::<ResourceDescription>(json!({ "resource_scopes": [], "name#nl": 1 })).is_err());
+    }
+
+    #[test]
+    fn build_rejects_missing_or_invalid_scopes() {
+        assert_eq!(ResourceDescription::builder().name("Photo Album").build().unwrap_err(), ResourceDescriptionError::NoScopes);
+        assert_eq!(
+            ResourceDescription::builder().scope("read/private").build().unwrap_err(),
+            ResourceDescriptionError::InvalidScope("read/private".to_string())
+        );
+    }
+
+    #[test]
+    fn uma2_configuration_round_trip() {
+        let oauth = json!({
+            "issuer": "https://as.example.com",
+            "authorization_endpoint": "https://as.example.com/authz",
+            "token_endpoint": "https://as.example.com/token",
+            "jwks_uri": "https://as.example.com/jwks",
+            "response_types_supported": ["code"],
+            "grant_types_supported": ["authorization_code", "urn:ietf:params:oauth:grant-type:uma-ticket"],
+            "introspection_endpoint": "https://as.example.com/rs/status",
+        });
+        let federation = json!({
+            "permission_endpoint": "https://as.example.com/rs/perm",
+            "resource_registration_endpoint": "https://as.example.com/rs/rreg",
+        });
+        let grant = json!({
+            "claims_interaction_endpoint": "https://as.example.com/rqp_claims",
+            "uma_profiles_supported": ["https://example.com/uma-profile"],
+        });
+
+        let mut document = oauth.clone();
+        document.as_object_mut().unwrap().extend(federation.as_object().unwrap().clone());
+        document.as_object_mut().unwrap().extend(grant.as_object().unwrap().clone());
+
+        let metadata: AuthorizationServerMetadata = serde_json::from_value(document.clone()).unwrap();
+        assert_eq!(metadata.permission_endpoint.as_str(), "https://as.example.com/rs/perm");
+        assert_eq!(metadata.token_endpoint.as_str(), "https://as.example.com/token");
+
+        let mut expected = oauth.clone();
+        expected.as_object_mut().unwrap().extend(federation.as_object().unwrap().clone());
+        assert_eq!(serde_json::to_value(&metadata).unwrap(), expected);
+
+        let metadata: GrantASM = serde_json::from_value(document).unwrap();
+        assert_eq!(metadata.claims_interaction_endpoint.as_ref().unwrap().as_str(), "https://as.example.com/rqp_claims");
+        assert_eq!(metadata.issuer.as_str(), "https://as.example.com");
+
+        let mut expected = oauth;
+        expected.as_object_mut().unwrap().extend(grant.as_object().unwrap().clone());
+        assert_eq!(serde_json::to_value(&metadata).unwrap(), expected);
+    }
+
+    #[test]
+    fn scopes_are_classified() {
+        let scopes: Vec<Scope> =
+            serde_json::from_value(json!(["view", "http://photoz.example.com/dev/actions/print"])).unwrap();
+
+        assert!(matches!(&scopes[0], Scope::Plain(scope) if scope == "view"));
+        assert!(matches!(&scopes[1], Scope::Uri(scope) if scope.scheme() == "http"));
+        assert_eq!(
+            serde_json::to_value(&scopes).unwrap(),
+            json!(["view", "http://photoz.example.com/dev/actions/print"])
+        );
+
+        // Comparison is by string, whatever the variant.
+        assert_eq!(Scope::Plain("urn:x".to_string()), Scope::from("urn:x"));
+        assert!(!Scope::from("not a scope").is_valid());
+    }
+
+    fn photo_album() -> ResourceDescription {
+        return ResourceDescription::builder()
+            .scope("http://photoz.example.com/dev/scopes/view")
+            .scope("http://127.0.0.1:1/scopes/print")
+            .scope("public-read")
+            .build()
+            .unwrap();
+    }
+
+    fn view() -> ScopeDescription {
+        return serde_json::from_value(json!({
+            "description": "Read-only access to a resource",
+            "icon_uri": "http://www.example.com/icons/reading-glasses",
+            "name": "View",
+        }))
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn resolves_a_locally_known_scope() {
+        let scopes = HashMap::from([("http://photoz.example.com/dev/scopes/view".to_string(), view())]);
+        let description = photo_album();
+
+        let resolved = resolve_scopes(&description, &(&scopes as &ScopeDescriptionStore)).await;
+
+        assert_eq!(resolved[0].scope, "http://photoz.example.com/dev/scopes/view");
+        assert_eq!(resolved[0].scope_description, Some(view()));
+        assert_eq!(
+            serde_json::to_value(&resolved[0]).unwrap()["scope_description"],
+            json!({
+                "description": "Read-only access to a resource",
+                "icon_uri": "http://www.example.com/icons/reading-glasses",
+                "name": "View",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn leaves_unresolvable_scopes_as_they_are() {
+        let scopes = HashMap::from([("http://photoz.example.com/dev/scopes/view".to_string(), view())]);
+        let resolver = (&scopes as &ScopeDescriptionStore, HttpScopeResolver::default());
+        let description = photo_album();
+
+        let resolved = resolve_scopes(&description, &resolver).await;
+
+        // The print scope is not known locally, and nothing listens on port 1 to resolve it.
+        assert_eq!(resolved[0].scope_description, Some(view()));
+        assert_eq!(
+            serde_json::to_value(&resolved[1..]).unwrap(),
+            json!([{ "scope": "http://127.0.0.1:1/scopes/print" }, { "scope": "public-read" }])
+        );
+    }
+}
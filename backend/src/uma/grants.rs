@@ -6,10 +6,20 @@
 //!
 //! An OPTIONAL second specification, [UMAFedAuthz], defines a means for an UMA-enabled authorization server and resource server to be loosely coupled, or federated, in a resource owner context. This specification, together with [UMAFedAuthz], constitutes UMA 2.0.
 
+use std::borrow::Cow;
 use std::ops::Deref;
 
 use crate::oauth::discovery::AuthorizationServerMetadata as OauthASM;
+use crate::oidc::Verifier;
+use crate::storage::Expiring;
 use oxiri::Iri;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tracing::debug;
+
+use super::errors::ErrorMessage;
+use super::permission;
+use super::token::INVALID_GRANT;
 
 impl Deref for AuthorizationServerMetadata {
     type Target = OauthASM;
@@ -23,16 +33,21 @@ impl Deref for AuthorizationServerMetadata {
 /// The authorization server supplies metadata in a discovery document to declare its endpoints. The client uses this discovery document to discover these endpoints for use in the flows defined in Section 3.
 ///
 /// The authorization server MUST make a discovery document available. The structure of the discovery document MUST conform to that defined in [OAuthMeta]. The discovery document MUST be available at an endpoint formed by concatenating the string /.well-known/uma2-configuration to the issuer metadata value defined in [OAuthMeta], using the well-known URI syntax and semantics defined in [RFC5785]. In addition to the metadata defined in [OAuthMeta], this specification defines the following metadata for inclusion in the discovery document:
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct AuthorizationServerMetadata {
+    #[serde(flatten)]
     oauth: OauthASM,
 
     /// OPTIONAL. A static endpoint URI at which the authorization server declares that it interacts with end-user requesting parties to gather claims. If the authorization server also provides a claims interaction endpoint URI as part of its redirect_user hint in a need_info response to a client on authorization failure (see Section 3.3.6), that value overrides this metadata value. Providing the static endpoint URI is useful for enabling interactive claims gathering prior to any pushed-claims flows taking place, for example, for gathering authorization for subsequent claim pushing (see Section 3.3.2).
-    pub claims_interaction_endpoint: Iri<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub claims_interaction_endpoint: Option<Iri<String>>,
 
     ///OPTIONAL. UMA profiles and extensions supported by this authorization server. The value is an array of string values, where each string value is a URI identifying an UMA profile or extension. As discussed in Section 4, an authorization server supporting a profile or extension related to UMA SHOULD supply the specification's identifying URI (if any) here.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub uma_profiles_supported: Vec<String>,
 
     ///OPTIONAL. Array of one or more claims redirection URIs. If the authorization server supports dynamic client registration, it MUST allow client applications to register claims_redirect_uri metadata, as defined in Section 3.3.2, using the following metadata field:
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub claims_redirect_uris: Vec<Iri<String>>,
 }
 
@@ -83,13 +98,100 @@ pub struct PermissionTicket;
 /// or other statements of agreement.
 pub struct Claim;
 
+/// https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-grant-2.0.html#uma-grant-type
+///
+/// The claim token format identifying an ID token or other JWT, as defined in [OIDCCore] and [RFC7519].
+pub const JWT_CLAIM_TOKEN_FORMAT: &str = "urn:ietf:params:oauth:token-type:jwt";
+
+/// [NO-SPEC] Which pushed claim tokens the authorization server trusts: those issued by one of `trusted_issuers` for
+/// one of `audiences` (e.g. the client IDs an ID token may be issued to). A claim token of any other issuer is rejected
+/// before anything is fetched, so that a client can neither vouch for claims with keys of its own nor have the
+/// authorization server fetch from a host of its choosing. Without any trusted issuer, no claim token is accepted.
+#[derive(Debug, Clone, Default)]
+pub struct ClaimTokenConfig {
+    pub trusted_issuers: Vec<Iri<String>>,
+    pub audiences: Vec<String>,
+}
+
 /// A package of claims provided directly by the client to the authorization server through claims pushing.
-pub struct ClaimToken;
+///
+/// The claim_token_format parameter identifies the format of the token, which the authorization server MUST be able
+/// to interpret. Only JWTs are supported: these are verified against the JWK set of their (trusted) issuer, through
+/// the same machinery that authenticates Solid-OIDC access tokens, before their claims are exposed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClaimToken {
+    pub format: String,
+    pub claims: Map<String, Value>,
+}
+
+impl ClaimToken {
+    /// Interprets a pushed claim_token according to its claim_token_format. Tokens in an unknown format, and tokens
+    /// that fail verification, are rejected as an invalid grant; why verification failed is logged rather than sent
+    /// back.
+    pub async fn parse(
+        claim_token: &str,
+        claim_token_format: &str,
+        config: &ClaimTokenConfig,
+    ) -> Result<Self, ErrorMessage> {
+        if (claim_token_format != JWT_CLAIM_TOKEN_FORMAT) {
+            return Err(ErrorMessage {
+                error_description: Some(Cow::Owned(format!(
+                    "The claim token format {claim_token_format} is not supported."
+                ))),
+                ..INVALID_GRANT
+            });
+        }
+
+        let verified = Verifier::default().verify_claims(claim_token, &config.trusted_issuers, &config.audiences).await;
+        let claims = match verified {
+            Ok(claims) => claims,
+            Err(error) => {
+                debug!(%error, "claim token rejected");
+                return Err(ErrorMessage {
+                    error_description: Some(Cow::Borrowed("The claim token is invalid.")),
+                    ..INVALID_GRANT
+                });
+            }
+        };
+
+        return Ok(Self { format: claim_token_format.to_string(), claims });
+    }
+
+    /// The value of a single claim, e.g. "email" or "sub".
+    pub fn claim(&self, name: &str) -> Option<&Value> {
+        return self.claims.get(name);
+    }
+}
 
 /// A correlation handle issued by an authorization server that represents a set of claims
 /// collected during one authorization process, available for a client to use in attempting
 /// to optimize a future authorization process.
-pub struct PersistedClaimsToken;
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PersistedClaimsToken {
+    /// The handle as handed to the client, under which the token is stored.
+    pub pct: String,
+
+    /// The claims collected during the authorization process in which the token was issued.
+    pub claims: ClaimSet,
+
+    /// [NO-SPEC] When the token expires, if ever, in seconds since the Unix epoch. The token endpoint pushes it back
+    /// whenever the token is used (see `TokenConfig::pct_lifetime`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<i64>,
+}
+
+impl PersistedClaimsToken {
+    /// Whether the token has expired at `now`, in seconds since the Unix epoch.
+    pub fn is_expired(&self, now: i64) -> bool {
+        return self.exp.is_some_and(|exp| exp <= now);
+    }
+}
+
+impl Expiring for PersistedClaimsToken {
+    fn expire_at(&mut self, exp: i64) {
+        self.exp = Some(exp);
+    }
+}
 
 /// The process through which the authorization server determines whether it should issue an RPT to the client
 /// on the requesting party's behalf, based on a variety of inputs.
@@ -101,10 +203,52 @@ fn claimsCollection() -> () {}
 fn claimsPushing() -> () {}
 fn claimsGathering() -> () {}
 
+/// The claims collected during one authorization process, by name, e.g. those of a pushed claim token.
+pub type ClaimSet = Map<String, Value>;
+
+/// The claims an authorization policy lacks to make an authorization determination.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RequiredClaims {
+    /// The names of the missing claims, e.g. "email".
+    pub claims: Vec<String>,
+
+    /// The claims interaction endpoint URI to which to redirect the end-user requesting party, if the claims can be
+    /// gathered interactively.
+    pub redirect_user: Option<Iri<String>>,
+}
+
+/// The result of an authorization assessment, which determines the response of the token endpoint.
+#[derive(Debug, Clone)]
+pub enum AssessmentOutcome<'p> {
+    /// The permissions to issue an RPT for, possibly fewer than requested.
+    Granted(Vec<permission::Permission<'p>>),
+
+    /// The claims are insufficient to make a determination; the client is answered with need_info.
+    NeedInfo(RequiredClaims),
+
+    /// The client is answered with request_denied.
+    Denied,
+}
+
 /// Authorization assessment involves the authorization server assembling and evaluating policy conditions,
 /// scopes, claims, and any other relevant information sourced outside of UMA claims collection flows,
 /// in order to mitigate access authorization risk.
-fn authorizationAssessment() -> () {}
+///
+/// [NO-SPEC] Deployments plug in their policy logic by implementing this trait.
+pub trait AuthorizationPolicy: Send + Sync {
+    /// Assesses the permissions of a permission ticket against the claims collected for the requesting party.
+    fn assess<'p>(&self, permissions: &[permission::Permission<'p>], claims: &ClaimSet) -> AssessmentOutcome<'p>;
+}
+
+/// [NO-SPEC] A policy granting every requested permission regardless of the claims, fit for development only.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GrantAll;
+
+impl AuthorizationPolicy for GrantAll {
+    fn assess<'p>(&self, permissions: &[permission::Permission<'p>], _claims: &ClaimSet) -> AssessmentOutcome<'p> {
+        return AssessmentOutcome::Granted(permissions.to_vec());
+    }
+}
 
 /// The authorization server either returns a success code (as defined in Section 3.3.5),
 /// an RPT, and an optional PCT, or an error code (as defined in Section 3.3.6).
@@ -112,3 +256,86 @@ fn authorizationAssessment() -> () {}
 /// giving the client an opportunity to continue within the same authorization process
 /// (including engaging in further claims collection).
 fn authorizationResultsDetermination() -> () {}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::test_support::{claims, Keypair, MockIssuer};
+    use serde_json::json;
+
+    const CLIENT: &str = "https://client.example.org";
+
+    fn trusting(issuer: &MockIssuer) -> ClaimTokenConfig {
+        return ClaimTokenConfig { trusted_issuers: vec![issuer.issuer()], audiences: vec![CLIENT.to_string()] };
+    }
+
+    #[tokio::test]
+    async fn pushed_id_token_exposes_its_claims() {
+        let key = Keypair::es256("key-1");
+        let issuer = MockIssuer::start(vec![key.jwk()]).await;
+        let id_token = json!({
+            "iss": issuer.issuer().as_str(),
+            "sub": "alice",
+            "aud": CLIENT,
+            "email": "alice@example.org",
+        });
+        let jwt = key.sign(&claims(id_token, chrono::Duration::minutes(5)));
+
+        let token = ClaimToken::parse(&jwt, JWT_CLAIM_TOKEN_FORMAT, &trusting(&issuer)).await.unwrap();
+
+        assert_eq!(token.format, JWT_CLAIM_TOKEN_FORMAT);
+        assert_eq!(token.claim("email"), Some(&json!("alice@example.org")));
+        assert_eq!(token.claim("sub"), Some(&json!("alice")));
+        assert_eq!(token.claim("phone_number"), None);
+    }
+
+    #[tokio::test]
+    async fn unverifiable_claim_tokens_are_invalid_grants() {
+        let published = Keypair::es256("key-1");
+        let forged = Keypair::es256("key-1");
+        let issuer = MockIssuer::start(vec![published.jwk()]).await;
+        let config = trusting(&issuer);
+        let id_token = json!({ "iss": issuer.issuer().as_str(), "sub": "mallory", "aud": CLIENT });
+
+        let jwt = forged.sign(&claims(id_token.clone(), chrono::Duration::minutes(5)));
+        let error = ClaimToken::parse(&jwt, JWT_CLAIM_TOKEN_FORMAT, &config).await.unwrap_err();
+        assert_eq!(error.error_code, "invalid_grant");
+        assert_eq!(error.error_description.as_deref(), Some("The claim token is invalid."));
+
+        let jwt = published.sign(&claims(id_token, chrono::Duration::minutes(5)));
+        let format = "https://openid.net/specs/openid-connect-core-1_0.html#IDToken";
+        let error = ClaimToken::parse(&jwt, format, &config).await.unwrap_err();
+        assert_eq!(error.error_code, "invalid_grant");
+
+        let unsigned = published.sign(&claims(json!({ "sub": "mallory" }), chrono::Duration::minutes(5)));
+        let error = ClaimToken::parse(&unsigned, JWT_CLAIM_TOKEN_FORMAT, &config).await.unwrap_err();
+        assert_eq!(error.error_code, "invalid_grant");
+    }
+
+    #[tokio::test]
+    async fn claim_tokens_of_untrusted_issuers_or_audiences_are_invalid_grants() {
+        let key = Keypair::es256("key-1");
+        let trusted = MockIssuer::start(vec![key.jwk()]).await;
+        let other = MockIssuer::start(vec![key.jwk()]).await;
+        let config = trusting(&trusted);
+
+        // Validly signed by an issuer of the client's own choosing, which is never even asked for its keys.
+        let id_token = json!({ "iss": other.issuer().as_str(), "aud": CLIENT, "email": "alice@example.org" });
+        let jwt = key.sign(&claims(id_token, chrono::Duration::minutes(5)));
+        let error = ClaimToken::parse(&jwt, JWT_CLAIM_TOKEN_FORMAT, &config).await.unwrap_err();
+        assert_eq!(error.error_code, "invalid_grant");
+        assert_eq!(other.config_requests(), 0);
+
+        let id_token = json!({ "iss": trusted.issuer().as_str(), "aud": "https://other.example.org" });
+        let jwt = key.sign(&claims(id_token, chrono::Duration::minutes(5)));
+        let error = ClaimToken::parse(&jwt, JWT_CLAIM_TOKEN_FORMAT, &config).await.unwrap_err();
+        assert_eq!(error.error_code, "invalid_grant");
+
+        let id_token = json!({ "iss": trusted.issuer().as_str(), "aud": [CLIENT] });
+        let jwt = key.sign(&claims(id_token, chrono::Duration::minutes(5)));
+        let error = ClaimToken::parse(&jwt, JWT_CLAIM_TOKEN_FORMAT, &ClaimTokenConfig::default()).await.unwrap_err();
+        assert_eq!(error.error_code, "invalid_grant");
+        assert!(ClaimToken::parse(&jwt, JWT_CLAIM_TOKEN_FORMAT, &config).await.is_ok());
+    }
+}
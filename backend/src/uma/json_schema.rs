@@ -0,0 +1,83 @@
+//! [NO-SPEC] JSON Schemas of the request and response bodies, for client tooling that validates against them rather
+//! than against an OpenAPI document. The server binary serves them at /schema/:type, e.g. /schema/ResourceDescription.
+//!
+//! Like the OpenAPI description, the schema of a resource description lists the plain members of name and description
+//! only; their internationalized variants (e.g. name#nl) pass as additional properties.
+
+use schemars::{schema_for, Schema};
+
+use super::errors::ErrorMessage;
+use super::federation::{ResourceDescription, ScopeDescription};
+use super::permission::Permission;
+
+impl ResourceDescription {
+    pub fn schema() -> Schema {
+        return schema_for!(ResourceDescription);
+    }
+}
+
+impl ScopeDescription {
+    pub fn schema() -> Schema {
+        return schema_for!(ScopeDescription);
+    }
+}
+
+impl Permission<'_> {
+    pub fn schema() -> Schema {
+        return schema_for!(Permission);
+    }
+}
+
+impl ErrorMessage {
+    pub fn schema() -> Schema {
+        return schema_for!(ErrorMessage);
+    }
+}
+
+/// The schema of the type called `name`, if it is one of the types above.
+pub fn schema(name: &str) -> Option<Schema> {
+    return match name {
+        "ResourceDescription" => Some(ResourceDescription::schema()),
+        "ScopeDescription" => Some(ScopeDescription::schema()),
+        "Permission" => Some(Permission::schema()),
+        "ErrorMessage" => Some(ErrorMessage::schema()),
+        _ => None,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn resource_scopes_are_required() {
+        let schema = ResourceDescription::schema().to_value();
+
+        assert_eq!(schema["required"], json!(["resource_scopes"]));
+        assert_eq!(schema["properties"]["resource_scopes"]["type"], "array");
+        assert_eq!(schema["properties"]["resource_scopes"]["items"]["type"], "string");
+        assert_eq!(schema["properties"]["_id"]["readOnly"], true);
+        for optional in ["description", "icon_uri", "name", "type", "owner"] {
+            assert!(schema["properties"].get(optional).is_some(), "{optional} is missing");
+        }
+        assert_eq!(schema["properties"]["icon_uri"]["type"], json!(["string", "null"]));
+    }
+
+    #[test]
+    fn schemas_by_name() {
+        let error = schema("ErrorMessage").unwrap().to_value();
+        assert_eq!(error["required"], json!(["error"]));
+        assert!(error["properties"].get("status_code").is_none());
+        assert!(error["properties"].get("allowed_methods").is_none());
+
+        let permission = schema("Permission").unwrap().to_value();
+        assert_eq!(permission["required"], json!(["resource_id", "resource_scopes"]));
+
+        let scope = schema("ScopeDescription").unwrap().to_value();
+        assert_eq!(scope.get("required"), None::<&Value>);
+
+        assert!(schema("Scope").is_none());
+    }
+}
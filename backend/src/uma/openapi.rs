@@ -0,0 +1,97 @@
+//! [NO-SPEC] An OpenAPI 3.1 description of the protection API, derived from the request and response types and the
+//! handler annotations, for client tooling to generate against. Paths assume the endpoints are mounted as in the
+//! server binary: resource registration at /rreg, the permission endpoint at /perm and token introspection at
+//! /introspect.
+//!
+//! Resource descriptions are described by their plain members only; the internationalized variants of name and
+//! description (e.g. name#nl) are not expressible as named properties.
+
+use utoipa::OpenApi;
+
+use super::errors::ErrorMessage;
+use super::federation::ResourceDescription;
+use super::permission::{self, Permission};
+use super::resource_registration;
+use super::token_introspection::{self, IntrospectionRequest, IntrospectionResponse};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "UMA 2.0 protection API"),
+    paths(
+        resource_registration::list_resource_registration,
+        resource_registration::create_resource_registration,
+        resource_registration::read_resource_registration,
+        resource_registration::update_resource_registration,
+        resource_registration::delete_resource_registration,
+        permission::request_permission_ticket,
+        token_introspection::introspect_token,
+    ),
+    components(schemas(
+        ErrorMessage,
+        ResourceDescription,
+        resource_registration::SuccessfulResponse,
+        Permission,
+        permission::SuccessfulResponse,
+        IntrospectionRequest,
+        IntrospectionResponse,
+        token_introspection::SuccessfulResponse,
+    ))
+)]
+pub struct ProtectionApiDoc;
+
+/// The OpenAPI document, as served at /openapi.json.
+pub fn specification() -> utoipa::openapi::OpenApi {
+    return ProtectionApiDoc::openapi();
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use serde_json::Value;
+
+    /// Every local reference must point at a schema in the components.
+    fn assert_references_resolve(document: &Value, value: &Value) {
+        match value {
+            Value::Object(members) => {
+                if let Some(Value::String(reference)) = members.get("$ref") {
+                    let pointer = reference.strip_prefix('#').expect("only local references");
+                    assert!(document.pointer(pointer).is_some(), "dangling reference {reference}");
+                }
+                members.values().for_each(|member| assert_references_resolve(document, member));
+            }
+            Value::Array(items) => items.iter().for_each(|item| assert_references_resolve(document, item)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn specification_describes_the_protection_api() {
+        let json = specification().to_json().unwrap();
+        let document: Value = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(document["openapi"], "3.1.0");
+        assert!(serde_json::from_str::<utoipa::openapi::OpenApi>(&json).is_ok());
+        assert_references_resolve(&document, &document);
+
+        let paths = document["paths"].as_object().unwrap();
+        let mut endpoints: Vec<&str> = paths.keys().map(String::as_str).collect();
+        endpoints.sort();
+        assert_eq!(endpoints, vec!["/introspect", "/perm", "/rreg/", "/rreg/{_id}"]);
+
+        for method in ["get", "post"] {
+            assert!(paths["/rreg/"].get(method).is_some());
+        }
+        for method in ["get", "put", "delete"] {
+            assert!(paths["/rreg/{_id}"].get(method).is_some());
+        }
+        assert!(paths["/perm"].get("post").is_some());
+        assert!(paths["/introspect"]["post"]["requestBody"]["content"]["application/x-www-form-urlencoded"].is_object());
+
+        let schemas = document["components"]["schemas"].as_object().unwrap();
+        assert!(schemas["ResourceDescription"]["required"].as_array().unwrap().contains(&"resource_scopes".into()));
+        assert!(schemas["ResourceDescription"]["properties"].get("type").is_some());
+        assert!(schemas["ErrorMessage"]["properties"].get("error").is_some());
+        assert!(schemas["ErrorMessage"]["properties"].get("status_code").is_none());
+    }
+}
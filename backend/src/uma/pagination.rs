@@ -0,0 +1,320 @@
+//! [NO-SPEC] Pagination of list results. The specification has the resource registration endpoint list every _id at
+//! once, so lists are paginated only when a request asks for it with a limit query parameter; the response is then a
+//! page object rather than an array.
+//!
+//! Items are paged in the order of their keys (e.g. _ids), and the cursor to the next page holds the last key of the
+//! current one, so that registrations and deletions between requests shift neither items onto nor off the next page,
+//! and so that stores can resume a keyset query or scan after it rather than count their way to an offset.
+//!
+//! Cursors are opaque to clients: the key, base64url-encoded, followed by a dot and an HMAC-SHA256 of it, so that a
+//! client cannot forge one to resume at a key it was never handed.
+
+use base64ct::{Base64UrlUnpadded, Encoding};
+use hmac::{Hmac, Mac};
+use http::Response;
+use serde::Serialize;
+use sha2::Sha256;
+use std::fmt::{self, Debug, Formatter};
+use std::sync::Arc;
+use std::{ops::Deref, result};
+
+use super::errors::{ErrorMessage, INVALID_REQUEST};
+
+/// Seals keys into cursors and opens them again. Clones share the same secret.
+#[derive(Clone)]
+pub struct CursorSigner {
+    secret: Arc<[u8]>,
+}
+
+impl CursorSigner {
+    /// Signs with `secret`, e.g. one shared by every instance of the server, so that their cursors are interchangeable
+    /// and outlive a restart.
+    pub fn new(secret: &[u8]) -> Self {
+        return Self { secret: secret.into() };
+    }
+
+    /// Signs with a random secret, so that cursors are only valid for this process.
+    pub fn random() -> Self {
+        let mut secret = [0; 32];
+        getrandom::getrandom(&mut secret).expect("cannot generate cursor secret");
+        return Self::new(&secret);
+    }
+
+    /// The cursor that resumes after `key`.
+    pub fn seal(&self, key: &str) -> String {
+        let tag = self.mac(key.as_bytes()).finalize().into_bytes();
+        let key = Base64UrlUnpadded::encode_string(key.as_bytes());
+        return format!("{key}.{}", Base64UrlUnpadded::encode_string(&tag));
+    }
+
+    /// The key `cursor` resumes after, if this signer sealed it.
+    pub fn open(&self, cursor: &str) -> Option<String> {
+        let (key, tag) = cursor.split_once('.')?;
+        let key = Base64UrlUnpadded::decode_vec(key).ok()?;
+        let tag = Base64UrlUnpadded::decode_vec(tag).ok()?;
+        self.mac(&key).verify_slice(&tag).ok()?;
+        return String::from_utf8(key).ok();
+    }
+
+    fn mac(&self, message: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC takes keys of any length");
+        mac.update(message);
+        return mac;
+    }
+}
+
+impl Default for CursorSigner {
+    fn default() -> Self {
+        return Self::random();
+    }
+}
+
+/// Keeps the secret out of logs.
+impl Debug for CursorSigner {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        return f.debug_struct("CursorSigner").finish_non_exhaustive();
+    }
+}
+
+/// What a list request asks for through its limit and cursor query parameters: at most `limit` items, starting after
+/// the one keyed `cursor`, or at the first item without one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageRequest {
+    pub limit: usize,
+    /// The key the cursor was opened to.
+    pub cursor: Option<String>,
+}
+
+impl PageRequest {
+    /// Reads the page asked for from the query string of a list request, if it carries a limit. A limit that is not a
+    /// positive integer, a cursor that `cursors` did not seal, or a cursor without a limit, is an invalid_request.
+    pub fn from_query(query: &str, cursors: &CursorSigner) -> result::Result<Option<Self>, Response<ErrorMessage>> {
+        let mut limit = None;
+        let mut cursor = None;
+        for (parameter, value) in form_urlencoded::parse(query.as_bytes()) {
+            match parameter.as_ref() {
+                "limit" => limit = Some(value.parse::<usize>().ok().filter(|limit| *limit > 0).ok_or(INVALID_REQUEST)?),
+                "cursor" => cursor = Some(cursors.open(&value).ok_or(INVALID_REQUEST)?),
+                _ => {}
+            }
+        }
+        return match (limit, cursor) {
+            (Some(limit), cursor) => Ok(Some(Self { limit, cursor })),
+            (None, Some(_)) => Err(INVALID_REQUEST.into()),
+            (None, None) => Ok(None),
+        };
+    }
+}
+
+/// A page of list results, serialized as {items, total, next}.
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+pub struct Page<T> {
+    /// The items on this page.
+    #[serde(rename = "items")]
+    pub items: Vec<T>,
+
+    /// How many items there are on all pages together.
+    #[serde(rename = "total")]
+    pub total: usize,
+
+    /// How many items precede this page.
+    #[serde(skip)]
+    pub offset: usize,
+
+    /// The cursor to pass for the next page, if there is one, sealed.
+    #[serde(rename = "next", skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+impl<T> Page<T> {
+    /// Cuts the page `request` asks for out of `keys`, in their order.
+    pub fn of(mut keys: Vec<T>, request: &PageRequest, cursors: &CursorSigner) -> Self
+    where
+        T: AsRef<str>,
+    {
+        keys.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+        let total = keys.len();
+        let offset = match &request.cursor {
+            Some(cursor) => keys.partition_point(|key| key.as_ref() <= cursor.as_str()),
+            None => 0,
+        };
+        let items: Vec<T> = keys.into_iter().skip(offset).take(request.limit).collect();
+        let next_cursor = match (offset + items.len() < total, items.last()) {
+            (true, Some(last)) => Some(cursors.seal(last.as_ref())),
+            _ => None,
+        };
+        return Self { items, total, offset, next_cursor };
+    }
+
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> Page<U> {
+        return Page {
+            items: self.items.into_iter().map(f).collect(),
+            total: self.total,
+            offset: self.offset,
+            next_cursor: self.next_cursor,
+        };
+    }
+}
+
+/// The result of a list request: every item, as the specification has it, or a page of them if asked for.
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Listing<T> {
+    All(Vec<T>),
+    Page(Page<T>),
+}
+
+impl<T> Listing<T> {
+    /// Lists `keys`: all of them, or the page `request` asks for, if any.
+    pub fn of(keys: Vec<T>, request: Option<&PageRequest>, cursors: &CursorSigner) -> Self
+    where
+        T: AsRef<str>,
+    {
+        return match request {
+            Some(request) => Self::Page(Page::of(keys, request, cursors)),
+            None => Self::All(keys),
+        };
+    }
+
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> Listing<U> {
+        return match self {
+            Self::All(items) => Listing::All(items.into_iter().map(f).collect()),
+            Self::Page(page) => Listing::Page(page.map(f)),
+        };
+    }
+}
+
+impl<T> Deref for Listing<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &Self::Target {
+        return match self {
+            Self::All(items) => items,
+            Self::Page(page) => &page.items,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Pages through `keys` `limit` at a time, letting `between` change them after each page; returns the keys listed
+    /// and the pages.
+    fn page_through(
+        mut keys: Vec<String>,
+        limit: usize,
+        mut between: impl FnMut(&mut Vec<String>),
+    ) -> (Vec<String>, Vec<Page<String>>) {
+        let cursors = CursorSigner::random();
+        let mut pages: Vec<Page<String>> = Vec::new();
+        loop {
+            let cursor = pages.last().and_then(|page| page.next_cursor.clone());
+            if (!pages.is_empty() && cursor.is_none()) {
+                break;
+            }
+            let query = match cursor {
+                Some(cursor) => format!("limit={limit}&cursor={cursor}"),
+                None => format!("limit={limit}"),
+            };
+            let request = PageRequest::from_query(&query, &cursors).unwrap().unwrap();
+            pages.push(Page::of(keys.clone(), &request, &cursors));
+            between(&mut keys);
+        }
+        return (pages.iter().flat_map(|page| page.items.clone()).collect(), pages);
+    }
+
+    fn keys(count: usize) -> Vec<String> {
+        return (0..count).rev().map(|i| format!("{i:03}")).collect();
+    }
+
+    #[test]
+    fn pages_through_every_key_once() {
+        for (count, limit) in [(0, 3), (1, 3), (9, 3), (10, 3), (10, 1), (10, 20)] {
+            let (listed, pages) = page_through(keys(count), limit, |_| {});
+
+            let mut expected = keys(count);
+            expected.sort();
+            assert_eq!(listed, expected, "{count} keys by {limit}");
+            assert_eq!(pages.len(), count.div_ceil(limit).max(1), "{count} keys by {limit}");
+            assert!(pages.iter().all(|page| page.total == count));
+            let offsets: Vec<usize> = pages.iter().map(|page| page.offset).collect();
+            assert_eq!(offsets, (0..pages.len()).map(|i| i * limit).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn changes_between_pages_neither_skip_nor_repeat_keys() {
+        let (listed, _) = page_through(keys(10), 3, |keys| {
+            // Delete the first key, insert one right after the new first, i.e. before the cursor, and one at the end.
+            keys.sort();
+            keys.remove(0);
+            let first = keys[0].clone();
+            keys.push(format!("{first}a"));
+            if !keys.iter().any(|key| key == "zzz") {
+                keys.push("zzz".to_string());
+            }
+        });
+
+        let mut deduplicated = listed.clone();
+        deduplicated.dedup();
+        assert_eq!(listed, deduplicated);
+        let originals: Vec<&String> = listed.iter().filter(|key| key.len() == 3 && *key != "zzz").collect();
+        assert_eq!(originals, keys(10).iter().rev().collect::<Vec<_>>());
+        assert_eq!(listed.last().unwrap(), "zzz");
+    }
+
+    #[test]
+    fn cursor_of_a_deleted_key_still_finds_its_place() {
+        let cursors = CursorSigner::random();
+        let request = PageRequest::from_query(&format!("limit=1&cursor={}", cursors.seal("b")), &cursors).unwrap();
+        let page = Page::of(vec!["a", "c", "d"], &request.unwrap(), &cursors);
+
+        assert_eq!((page.items.as_slice(), page.offset), (["c"].as_slice(), 1));
+        assert_eq!(page.next_cursor.map(|cursor| cursors.open(&cursor).unwrap()).as_deref(), Some("c"));
+    }
+
+    #[test]
+    fn cursors_are_opaque_and_tamper_evident() {
+        let cursors = CursorSigner::new(b"secret");
+        let cursor = cursors.seal("KX3A-39WE");
+        assert!(!cursor.contains("KX3A-39WE"));
+        assert!(cursor.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"-_.".contains(&byte)));
+        assert_eq!(cursors.open(&cursor).as_deref(), Some("KX3A-39WE"));
+        assert_eq!(CursorSigner::new(b"secret").open(&cursor).as_deref(), Some("KX3A-39WE"));
+
+        let (_, tag) = cursor.split_once('.').unwrap();
+        let forged = format!("{}.{tag}", Base64UrlUnpadded::encode_string(b"9UQU-DUWW"));
+        for invalid in [forged.as_str(), "KX3A-39WE", "", ".", &cursor[1..]] {
+            assert_eq!(cursors.open(invalid), None, "{invalid}");
+        }
+        assert_eq!(CursorSigner::new(b"another secret").open(&cursor), None);
+        assert_eq!(CursorSigner::random().open(&cursor), None);
+    }
+
+    #[test]
+    fn reads_the_page_from_the_query() {
+        let cursors = CursorSigner::random();
+        assert_eq!(PageRequest::from_query("deep=true", &cursors).unwrap(), None);
+        let request = PageRequest { limit: 10, cursor: None };
+        assert_eq!(PageRequest::from_query("limit=10", &cursors).unwrap(), Some(request));
+        let request = PageRequest { limit: 10, cursor: Some("KX3A-39WE".to_string()) };
+        let query = format!("limit=10&cursor={}", cursors.seal("KX3A-39WE"));
+        assert_eq!(PageRequest::from_query(&query, &cursors).unwrap(), Some(request));
+
+        let unsealed = format!("cursor={}", cursors.seal("KX3A-39WE"));
+        for invalid in ["limit=0", "limit=ten", "limit=-1", "limit=10&cursor=KX3A-39WE", unsealed.as_str()] {
+            let error = PageRequest::from_query(invalid, &cursors).unwrap_err();
+            assert_eq!(error.body().error_code, "invalid_request", "{invalid}");
+        }
+    }
+
+    #[test]
+    fn last_page_has_no_next_cursor() {
+        let cursors = CursorSigner::random();
+        let page = Page::of(vec!["e", "a"], &PageRequest { limit: 2, cursor: None }, &cursors);
+
+        assert_eq!(serde_json::to_value(&page).unwrap(), serde_json::json!({ "items": ["a", "e"], "total": 2 }));
+    }
+}
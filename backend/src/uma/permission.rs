@@ -56,40 +56,58 @@
 // use titles as # Panics and # Examples
 
 
+use crate::clock::{Clock, SystemClock};
+use crate::ids::{issued_at, IdGenerator, Ticket};
+use crate::resource::Identifier;
 use crate::storage::KeyValueStore;
-use http::{Method, Request, Response, StatusCode};
+use base64ct::{Base64UrlUnpadded, Encoding};
+use http::{header, HeaderValue, Method, Request, Response, StatusCode};
+use tracing::field::Empty;
+use tracing::instrument;
 use oxiri::Iri;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::Duration;
 use std::{ops::Deref, result};
-use uuid::Uuid;
 
-use super::errors::{ErrorMessage, INVALID_REQUEST, RESOURCE_NOT_FOUND, UNSUPPORTED_METHOD_TYPE};
-use super::federation::ResourceDescription;
+use super::errors::{
+    catch_errors, ErrorMessage, INSUFFICIENT_SCOPE, INVALID_REQUEST, RESOURCE_NOT_FOUND, UNSUPPORTED_METHOD_TYPE,
+};
+use super::federation::{ResourceDescription, Scope, DEFAULT_MAX_SCOPES};
 
 // https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-federated-authz-2.0.html#rfc.section.4.1
 
 
 /// The resource server uses the POST method at the permission endpoint. The body of the HTTP request message contains a JSON object for requesting a permission for single resource identifier, or an array of one or more objects for requesting permissions for a corresponding number of resource identifiers. The object format in both cases is derived from the resource description format specified in Section 3.1; it has the following parameters:
-#[derive(Debug, Serialize, Clone/*, Copy*/)]
+#[derive(Debug, Serialize, Deserialize, Clone/*, Copy*/)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct Permission<'p> {
 
     /// REQUIRED. The identifier for a resource to which the resource server is requesting a permission on behalf of the client. The identifier MUST correspond to a resource that was previously registered.
-    pub resource_id: &'p str,
+    #[cfg_attr(feature = "openapi", schema(value_type = String))]
+    #[cfg_attr(feature = "json-schema", schemars(with = "String"))]
+    #[serde(rename = "resource_id")]
+    pub resource_id: Cow<'p, str>,
 
     /// REQUIRED. An array referencing zero or more identifiers of scopes to which the resource server is requesting access for this resource on behalf of the client. Each scope identifier MUST correspond to a scope that was previously registered by this resource server for the referenced resource.
-    pub resource_scopes: Vec<&'p str>,
+    #[cfg_attr(feature = "openapi", schema(value_type = Vec<String>))]
+    #[cfg_attr(feature = "json-schema", schemars(with = "Vec<String>"))]
+    #[serde(rename = "resource_scopes")]
+    pub resource_scopes: Vec<Scope>,
 
 }
 
 impl<'p> Permission<'p> {
     pub fn new(
-        resource_id: &'p str,
-        resource_scopes: Vec<&'p str>,
+        resource_id: impl Into<Cow<'p, str>>,
+        resource_scopes: Vec<impl Into<Scope>>,
     ) -> Self {
         Self {
-            resource_id,
-            resource_scopes,
+            resource_id: resource_id.into(),
+            resource_scopes: resource_scopes.into_iter().map(Into::into).collect(),
         }
     }
 }
@@ -103,18 +121,80 @@ pub type PermissionRequest<'pr> = Vec<Permission<'pr>>; // !! or single object
 pub struct PermissionTicket<'pt> {
 
     /// REQUIRED. The identifier for a resource to which the resource server is requesting a permission on behalf of the client. The identifier MUST correspond to a resource that was previously registered.
+    #[serde(rename = "ticket")]
     pub ticket: &'pt str,
 
     /// REQUIRED. An array referencing zero or more identifiers of scopes to which the resource server is requesting access for this resource on behalf of the client. Each scope identifier MUST correspond to a scope that was previously registered by this resource server for the referenced resource.
+    #[serde(rename = "permissions")]
     pub permissions: Vec<Permission<'pt>>,
 
 }
 
 #[derive(Debug, Serialize, Clone/*, Copy*/)]
-pub struct SuccessfulResponse<'sr> { pub ticket: &'sr str  }
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema), schema(as = PermissionTicketResponse))]
+pub struct SuccessfulResponse<'sr> {
+    #[serde(rename = "ticket")]
+    pub ticket: &'sr str,
+    /// [NO-SPEC] OPTIONAL. Seconds until the ticket expires, if it does, so that the resource server knows how long
+    /// the client has to redeem it.
+    #[serde(rename = "expires_in", skip_serializing_if = "Option::is_none")]
+    pub expires_in: Option<u64>,
+}
 
 impl<'sr> SuccessfulResponse<'sr> {
-    pub fn new( ticket: &'sr str ) -> Self { Self { ticket } }
+    pub fn new( ticket: &'sr str, expires_in: Option<u64> ) -> Self { Self { ticket, expires_in } }
+}
+
+/// [NO-SPEC] A permission ticket as remembered by the authorization server: the permissions it represents, and when it
+/// expires, if ever. Like an RPT, it records its own expiry, as stores do not expire their entries.
+#[derive(Debug, Clone)]
+pub struct IssuedTicket<'it> {
+    pub permissions: Vec<Permission<'it>>,
+    pub exp: Option<i64>,
+}
+
+impl<'it> IssuedTicket<'it> {
+    /// Whether the ticket has expired at `now`, in seconds since the Unix epoch.
+    pub fn is_expired(&self, now: i64) -> bool {
+        return self.exp.is_some_and(|exp| exp <= now);
+    }
+
+    /// Seconds left until the ticket expires at `now`, if it does.
+    pub fn expires_in(&self, now: i64) -> Option<u64> {
+        return self.exp.map(|exp| (exp - now).max(0) as u64);
+    }
+}
+
+/// A ticket that never expires.
+impl<'it> From<Vec<Permission<'it>>> for IssuedTicket<'it> {
+    fn from(permissions: Vec<Permission<'it>>) -> Self {
+        return Self { permissions, exp: None };
+    }
+}
+
+/// [NO-SPEC] Settings of the permission endpoint.
+#[derive(Debug, Clone)]
+pub struct PermissionConfig {
+    /// How long a permission ticket can be redeemed after it was issued; tickets never expire if None. A ticket rotated
+    /// on need_info keeps the expiry of the ticket it replaces, so that claims gathering cannot extend it indefinitely.
+    pub ticket_lifetime: Option<Duration>,
+
+    /// How many resource_scopes each requested permission may have, once those for the same resource are merged; a
+    /// request with one that has more is an invalid_request.
+    pub max_scopes: usize,
+
+    /// What the expiry of an issued ticket is counted from.
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for PermissionConfig {
+    fn default() -> Self {
+        return Self {
+            ticket_lifetime: Some(Duration::from_secs(5 * 60)),
+            max_scopes: DEFAULT_MAX_SCOPES,
+            clock: Arc::new(SystemClock),
+        };
+    }
 }
 
 // https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-federated-authz-2.0.html#rfc.section.4.3
@@ -137,50 +217,225 @@ pub const INVALID_SCOPE: ErrorMessage = ErrorMessage::new(
     None,
 );
 
-fn catch_errors<T>(result: http::Result<Response<T>>) -> Result<T> {
-    return result.map_err(|error: http::Error| {
-        // log error
-        return ErrorMessage::default().into();
-    });
-}
-
 type ResourceDescriptionStore = dyn KeyValueStore<Key = String, Value = ResourceDescription>;
-type PermissionTicketStore<'pts> = dyn KeyValueStore<Key = String, Value = Vec<Permission<'pts>>>;
+pub type PermissionTicketStore<'pts> = dyn KeyValueStore<Key = String, Value = IssuedTicket<'pts>>;
 type Result<T> = result::Result<Response<T>, Response<ErrorMessage>>;
 
 ///
-pub async fn request_permission_ticket<'sr>(
-    store: &'sr mut PermissionTicketStore<'sr>,
-    request: Request<PermissionRequest<'sr>>,
+#[cfg_attr(feature = "openapi", utoipa::path(
+    post,
+    path = "/perm",
+    tag = "permission",
+    request_body = Vec<Permission>,
+    responses(
+        (status = 201, description = "A permission ticket for the requested permissions", body = SuccessfulResponse),
+        (status = 400, description = "The request is invalid", body = ErrorMessage),
+    ),
+))]
+#[instrument(skip_all, fields(method = %request.method(), outcome = Empty))]
+pub async fn request_permission_ticket<'sr, 'p>(
+    store: &'sr mut PermissionTicketStore<'p>,
+    ids: &dyn IdGenerator,
+    config: &PermissionConfig,
+    request: Request<PermissionRequest<'p>>,
 ) -> Result<SuccessfulResponse<'sr>> {
     if (request.method() != Method::POST) {
         return Err(UNSUPPORTED_METHOD_TYPE.into());
     }
 
-    let permission_request = request.into_body();
+    let permission_request = merge_duplicates(request.into_body());
+    if permission_request.iter().any(|permission| permission.resource_scopes.len() > config.max_scopes) {
+        return Err(INVALID_REQUEST.into());
+    }
 
     // ...
     let granted_permissions = permission_request;
     // ...
 
-    let ticket = Uuid::new_v4().to_string();
-    let ticket = store.set(ticket, granted_permissions);
+    let now = config.clock.now();
+    let exp = config.ticket_lifetime.map(|lifetime| now + lifetime.as_secs() as i64);
+    let issued = IssuedTicket { permissions: granted_permissions, exp };
+    let expires_in = issued.expires_in(now);
+
+    let ticket = ids.generate();
+    let ticket = store.set(ticket, issued);
 
     let response = Response::builder()
         .status(StatusCode::CREATED)
-        .body(SuccessfulResponse::new(ticket));
+        .body(SuccessfulResponse::new(ticket, expires_in));
 
     return catch_errors(response);
 }
 
+/// [NO-SPEC] Merges the permissions requested for the same resource into the first of them, joining their scopes, and
+/// drops repeated scopes, so that a ticket lists every resource and scope once. The order of first mention is kept.
+fn merge_duplicates(permissions: PermissionRequest<'_>) -> PermissionRequest<'_> {
+    let mut merged: PermissionRequest = Vec::with_capacity(permissions.len());
+    for permission in permissions {
+        let index = match merged.iter().position(|existing| existing.resource_id == permission.resource_id) {
+            Some(index) => index,
+            None => {
+                merged.push(Permission { resource_id: permission.resource_id, resource_scopes: vec![] });
+                merged.len() - 1
+            }
+        };
+        for scope in permission.resource_scopes {
+            if !merged[index].resource_scopes.contains(&scope) {
+                merged[index].resource_scopes.push(scope);
+            }
+        }
+    }
+    return merged;
+}
+
+/// [NO-SPEC] The PAT scope that operator endpoints, such as the ticket inspection, require on top of uma_protection.
+pub const ADMIN_SCOPE: &str = "uma_admin";
+
+/// [NO-SPEC] The scopes of the PAT a request was made with, as an extension inserted next to the `Agent` by whatever
+/// authenticated the PAT.
+#[derive(Debug, Clone, Default)]
+pub struct PatScopes(pub Vec<String>);
+
+impl PatScopes {
+    pub fn contains(&self, scope: &str) -> bool {
+        return self.0.iter().any(|granted| granted == scope);
+    }
+}
+
+/// [NO-SPEC] Settings of the ticket inspection.
+#[derive(Debug, Clone)]
+pub struct TicketInspectionConfig {
+    /// Whether tickets are listed by a fingerprint rather than by themselves, which a client could redeem.
+    pub redact_tickets: bool,
+}
+
+impl Default for TicketInspectionConfig {
+    fn default() -> Self {
+        return Self { redact_tickets: true };
+    }
+}
+
+/// [NO-SPEC] An outstanding permission ticket, as listed for an operator.
+#[derive(Debug, Serialize, Clone)]
+pub struct TicketSummary<'ts> {
+    /// The ticket or, if redacted, the first 12 characters of its base64url-encoded SHA-256 digest.
+    pub ticket: Cow<'ts, str>,
+
+    /// The permissions the ticket stands for, with their resource identifiers and scopes.
+    pub permissions: &'ts [Permission<'ts>],
+
+    /// Seconds since the ticket was issued, if its identifier records that (see `ids::issued_at`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age: Option<u64>,
+}
+
+/// [NO-SPEC] Lists the outstanding permission tickets for the resources of the resource owner named by the owner query
+/// parameter, for operators debugging authorization flows that got stuck. Requires a PAT with the admin scope, as
+/// carried by a `PatScopes` extension. Tickets are listed in the order of their identifiers, i.e. of issuance for
+/// ULIDs.
+#[instrument(skip_all, fields(method = %request.method(), outcome = Empty))]
+pub async fn list_permission_tickets<'sr, 'p: 'sr>(
+    tickets: &'sr PermissionTicketStore<'p>,
+    resources: &ResourceDescriptionStore,
+    config: &TicketInspectionConfig,
+    request: &Request<()>,
+) -> Result<Vec<TicketSummary<'sr>>> {
+    if (request.method() != Method::GET) {
+        return Err(UNSUPPORTED_METHOD_TYPE.with_allowed_methods(&[Method::GET]).into());
+    }
+    if !request.extensions().get::<PatScopes>().is_some_and(|scopes| scopes.contains(ADMIN_SCOPE)) {
+        return Err(INSUFFICIENT_SCOPE.with_required_scope(ADMIN_SCOPE).into());
+    }
+
+    let query = request.uri().query().unwrap_or_default();
+    let owner = form_urlencoded::parse(query.as_bytes()).find(|(name, _)| name == "owner").map(|(_, owner)| owner);
+    let Some(Ok(owner)) = owner.as_deref().map(Identifier::parse) else {
+        return Err(INVALID_REQUEST.into());
+    };
+
+    let owns = |permission: &Permission| {
+        let description = resources.get(&permission.resource_id.to_string());
+        return description.is_some_and(|description| description.owner.as_ref() == Some(&owner));
+    };
+    let mut ids: Vec<&String> = tickets
+        .list()
+        .filter(|id| tickets.get(id).is_some_and(|issued| issued.permissions.iter().any(owns)))
+        .collect();
+    ids.sort();
+
+    let summaries = ids
+        .into_iter()
+        .map(|id| TicketSummary {
+            ticket: match config.redact_tickets {
+                true => Cow::Owned(Base64UrlUnpadded::encode_string(&Sha256::digest(id))[..12].to_string()),
+                false => Cow::Borrowed(id.as_str()),
+            },
+            permissions: tickets.get(id).map(|issued| issued.permissions.as_slice()).unwrap_or_default(),
+            age: issued_at(id).and_then(|issued| issued.elapsed().ok()).map(|age| age.as_secs()),
+        })
+        .collect();
+
+    return catch_errors(Response::builder().status(StatusCode::OK).body(summaries));
+}
+
+// https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-grant-2.0.html#rfc.section.3.2
+// https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-grant-2.0.html#permission-success-to-client
+
+/// The resource server responds to the client's tokenless resource request (or one with an insufficient RPT) with the
+/// HTTP 401 (Unauthorized) status code and a WWW-Authenticate header with the authentication scheme UMA, carrying the
+/// issuer URI of the authorization server in the as_uri parameter and the permission ticket it obtained from the
+/// permission endpoint in the ticket parameter.
+pub fn permission_challenge(ticket: &Ticket, as_uri: &Iri<String>) -> Response<()> {
+    let challenge = format!("UMA as_uri={}, ticket={}", quoted_string(as_uri.as_str()), quoted_string(ticket));
+    let mut response = Response::new(());
+    *response.status_mut() = StatusCode::UNAUTHORIZED;
+    // Quoting drops control characters, the only ones a header value cannot carry.
+    let challenge = HeaderValue::from_str(&challenge).expect("quoted strings are valid header values");
+    response.headers_mut().insert(header::WWW_AUTHENTICATE, challenge);
+    return response;
+}
+
+/// An RFC 9110 quoted-string, escaping quotes and backslashes.
+fn quoted_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars().filter(|c| !c.is_control()) {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    return quoted;
+}
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::ids::SeededIdGenerator;
+    use crate::test_support::serialized_keys;
+    use std::collections::HashMap;
 
     // assert! assert_eq! assert_ne! #[should_panic(expected = "panic msg")] -> Result<(), String> ?
 
+    #[test]
+    fn serialized_keys_are_pinned() {
+        let permission = Permission::new("112210f47de98100", vec!["view"]);
+        assert_eq!(serialized_keys(&permission), ["resource_id", "resource_scopes"]);
+        let serialized = serde_json::to_string(&permission).unwrap();
+        assert_eq!(serialized, r#"{"resource_id":"112210f47de98100","resource_scopes":["view"]}"#);
+
+        let ticket = PermissionTicket { ticket: "016f84e8", permissions: vec![permission] };
+        assert_eq!(serialized_keys(&ticket), ["permissions", "ticket"]);
+        assert!(serde_json::to_string(&ticket).unwrap().starts_with(r#"{"ticket":"016f84e8","permissions":["#));
+
+        let response = SuccessfulResponse::new("016f84e8", Some(300));
+        assert_eq!(serialized_keys(&response), ["expires_in", "ticket"]);
+        assert_eq!(serde_json::to_string(&response).unwrap(), r#"{"ticket":"016f84e8","expires_in":300}"#);
+        assert_eq!(serialized_keys(SuccessfulResponse::new("016f84e8", None)), ["ticket"]);
+    }
+
     #[test]
     fn test() {
 
@@ -243,4 +498,132 @@ mod tests {
         // }
         // ]
 
+    #[test]
+    fn permission_challenge_format() {
+        let as_uri = Iri::parse("https://as.example.com".to_string()).unwrap();
+
+        let response = permission_challenge(&Ticket::from("016f84e8-f9b9-11e0-bd6f-0021cc6004de"), &as_uri);
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers()[header::WWW_AUTHENTICATE],
+            r#"UMA as_uri="https://as.example.com", ticket="016f84e8-f9b9-11e0-bd6f-0021cc6004de""#
+        );
+
+        let response = permission_challenge(&Ticket::from("a\"b\nc"), &as_uri);
+        assert!(response.headers()[header::WWW_AUTHENTICATE].to_str().unwrap().ends_with(r#"ticket="a\"bc""#));
+    }
+
+    /// Alice owns the photo and the album, Bob the video; one ticket each for the photo and the video, and a ULID one
+    /// for the album.
+    fn ticket_stores() -> (HashMap<String, IssuedTicket<'static>>, HashMap<String, ResourceDescription>) {
+        let owned_by = |owner: &str| ResourceDescription {
+            owner: Some(Identifier::parse(owner).unwrap()),
+            ..ResourceDescription::builder().scope("view").build().unwrap()
+        };
+        let resources = HashMap::from([
+            ("photo".to_string(), owned_by("https://alice.example/#me")),
+            ("album".to_string(), owned_by("https://alice.example/#me")),
+            ("video".to_string(), owned_by("https://bob.example/#me")),
+        ]);
+        let tickets = HashMap::from([
+            ("016f84e8".to_string(), vec![Permission::new("photo", vec!["view"])].into()),
+            ("01ARZ3NDEKTSV4RRFFQ69G5FAV".to_string(), vec![Permission::new("album", vec!["view", "print"])].into()),
+            ("9c1ea5b0".to_string(), vec![Permission::new("video", vec!["view"])].into()),
+        ]);
+        return (tickets, resources);
+    }
+
+    fn inspection_request(scopes: &[&str]) -> Request<()> {
+        let mut request = Request::builder().uri("/?owner=https%3A%2F%2Falice.example%2F%23me").body(()).unwrap();
+        request.extensions_mut().insert(PatScopes(scopes.iter().map(ToString::to_string).collect()));
+        return request;
+    }
+
+    #[tokio::test]
+    async fn lists_the_tickets_of_an_owner() {
+        let (tickets, resources) = ticket_stores();
+        let request = inspection_request(&["uma_protection", "uma_admin"]);
+        let config = TicketInspectionConfig { redact_tickets: false };
+
+        let response = list_permission_tickets(&tickets, &resources, &config, &request).await.unwrap();
+
+        let listed = serde_json::to_value(response.body()).unwrap();
+        assert_eq!(listed.as_array().unwrap().len(), 2);
+        assert_eq!(listed[0]["ticket"], "016f84e8");
+        assert_eq!(listed[0]["permissions"][0]["resource_id"], "photo");
+        assert!(listed[0].get("age").is_none());
+        assert_eq!(listed[1]["ticket"], "01ARZ3NDEKTSV4RRFFQ69G5FAV");
+        assert_eq!(listed[1]["permissions"][0]["resource_scopes"], serde_json::json!(["view", "print"]));
+        assert!(listed[1]["age"].as_u64().unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn redacts_tickets_by_default() {
+        let (tickets, resources) = ticket_stores();
+        let request = inspection_request(&["uma_protection", "uma_admin"]);
+
+        let response =
+            list_permission_tickets(&tickets, &resources, &TicketInspectionConfig::default(), &request).await.unwrap();
+
+        let redacted: Vec<&str> = response.body().iter().map(|summary| summary.ticket.as_ref()).collect();
+        assert_eq!(redacted.len(), 2);
+        assert!(redacted.iter().all(|ticket| ticket.len() == 12 && !tickets.contains_key(*ticket)));
+    }
+
+    #[tokio::test]
+    async fn refuses_a_pat_without_the_admin_scope() {
+        let (tickets, resources) = ticket_stores();
+        let request = inspection_request(&["uma_protection"]);
+
+        let error = list_permission_tickets(&tickets, &resources, &TicketInspectionConfig::default(), &request)
+            .await
+            .unwrap_err();
+
+        assert_eq!(error.status(), StatusCode::FORBIDDEN);
+        assert_eq!(error.body().error_code, "insufficient_scope");
+        let challenge = &error.headers()[header::WWW_AUTHENTICATE];
+        assert_eq!(challenge, r#"Bearer error="insufficient_scope", scope="uma_admin""#);
+    }
+
+    #[tokio::test]
+    async fn caps_the_scopes_of_a_permission() {
+        let ids = SeededIdGenerator::new(7);
+        let config = PermissionConfig::default();
+        let scopes = |count: usize| (0..count).map(|n| format!("scope{n}")).collect::<Vec<_>>();
+        let request = |count: usize| {
+            let permissions = vec![Permission::new("photo", vec!["view"]), Permission::new("album", scopes(count))];
+            return Request::builder().method(Method::POST).uri("/perm").body(permissions).unwrap();
+        };
+
+        let mut store = HashMap::new();
+        let issued = request_permission_ticket(&mut store, &ids, &config, request(DEFAULT_MAX_SCOPES)).await;
+        assert_eq!(issued.unwrap().status(), StatusCode::CREATED);
+
+        let mut store = HashMap::new();
+        let error = request_permission_ticket(&mut store, &ids, &config, request(DEFAULT_MAX_SCOPES + 1)).await;
+        assert_eq!(error.unwrap_err().body().error_code, "invalid_request");
+        assert!(store.is_empty());
+    }
+
+    #[tokio::test]
+    async fn duplicate_resources_are_merged() {
+        let ids = SeededIdGenerator::new(7);
+        let permissions = vec![
+            Permission::new("photo", vec!["view", "view"]),
+            Permission::new("album", vec!["view"]),
+            Permission::new("photo", vec!["print", "view"]),
+        ];
+        let request = Request::builder().method(Method::POST).uri("/perm").body(permissions).unwrap();
+
+        let mut store = HashMap::new();
+        let response = request_permission_ticket(&mut store, &ids, &PermissionConfig::default(), request).await;
+        let ticket = response.unwrap().body().ticket.to_string();
+
+        let issued = &store[&ticket].permissions;
+        let resource_ids: Vec<&str> = issued.iter().map(|permission| permission.resource_id.as_ref()).collect();
+        assert_eq!(resource_ids, ["photo", "album"]);
+        assert_eq!(issued[0].resource_scopes, vec!["view", "print"]);
+        assert_eq!(issued[1].resource_scopes, vec!["view"]);
+    }
 }
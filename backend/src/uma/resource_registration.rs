@@ -82,15 +82,32 @@
 // https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-federated-authz-2.0.html#rfc.section.3.2
 // https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-federated-authz-2.0.html#reg-api
 
+use crate::ids::IdGenerator;
+use crate::oidc::client;
+use crate::resource::{Agent, Identifier, Resource};
 use crate::storage::KeyValueStore;
-use http::{Method, Request, Response, StatusCode};
+use base64ct::{Base64UrlUnpadded, Encoding};
+use either::Either;
+use http::header::{HeaderName, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use http::{HeaderValue, Method, Request, Response, StatusCode};
+use tracing::field::Empty;
+use tracing::{error, instrument, warn, Span};
 use oxiri::Iri;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
 use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{ops::Deref, result};
-use uuid::Uuid;
 
-use super::errors::{ErrorMessage, INVALID_REQUEST, RESOURCE_NOT_FOUND, UNSUPPORTED_METHOD_TYPE};
-use super::federation::ResourceDescription;
+use super::errors::{
+    catch_errors, ErrorMessage, CONFLICT, INVALID_REQUEST, PRECONDITION_FAILED, QUOTA_EXCEEDED, RESOURCE_GONE,
+    RESOURCE_NOT_FOUND, UNSUPPORTED_METHOD_TYPE,
+};
+use super::federation::{ResourceDescription, TextValidation, DEFAULT_MAX_SCOPES};
+use super::pagination::{CursorSigner, Listing, PageRequest};
 
 /// The authorization server MUST support the following five registration options and MUST require a valid PAT for
 /// access to them; any other operations are undefined by this specification. Here, rreguri stands for the resource
@@ -103,15 +120,18 @@ use super::federation::ResourceDescription;
 /// - Update resource description: PUT rreguri/_id
 /// - Delete resource description: DELETE rreguri/_id
 /// - List resource descriptions: GET rreguri/
-
+///
 /// Within the JSON body of a successful response, the authorization server includes common parameters, possibly in
 /// addition to method-specific parameters, as follows:
-#[derive(Debug, Serialize, Clone, Copy)]
+#[derive(Debug, Serialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema), schema(as = ResourceRegistrationResponse))]
 pub struct SuccessfulResponse<'sr> {
     /// REQUIRED (except for the Delete and List methods). A string value repeating the authorization server-defined
     /// identifier for the web resource corresponding to the resource. Its appearance in the body makes it readily
     /// available as an identifier for various protected resource management tasks.
-    pub _id: &'sr str,
+    #[cfg_attr(feature = "openapi", schema(value_type = String))]
+    #[serde(rename = "_id")]
+    pub _id: Cow<'sr, str>,
 
     /// OPTIONAL. A URI that allows the resource server to redirect an end-user resource owner to a specific user
     /// interface within the authorization server where the resource owner can immediately set or modify access policies
@@ -119,23 +139,31 @@ pub struct SuccessfulResponse<'sr> {
     /// targeted user interface, for example, in the case of a deletion action, enabling the resource server to direct the
     /// end-user to a policy-setting interface for an overall "folder" resource formerly "containing" the deleted resource
     /// (a relationship the authorization server is not aware of), to enable adjustment of related policies.
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub user_access_policy_uri: Option<Iri<&'sr str>>,
+    #[serde(rename = "user_access_policy_uri", skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<String>, format = "uri"))]
+    pub user_access_policy_uri: Option<Iri<String>>,
 
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "resource_description", skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<ResourceDescription>))]
     pub resource_description: Option<&'sr ResourceDescription>,
+
+    /// [NO-SPEC] OPTIONAL. Problems noticed with the resource description that did not prevent its registration, e.g.
+    /// an icon_uri that does not resolve to an image.
+    #[serde(rename = "advisories", skip_serializing_if = "Vec::is_empty")]
+    pub advisories: Vec<String>,
 }
 
 impl<'sr> SuccessfulResponse<'sr> {
     pub fn new(
-        _id: &'sr str,
-        user_access_policy_uri: Option<Iri<&'sr str>>,
+        _id: impl Into<Cow<'sr, str>>,
+        user_access_policy_uri: Option<Iri<String>>,
         resource_description: Option<&'sr ResourceDescription>,
     ) -> Self {
         Self {
-            _id,
+            _id: _id.into(),
             user_access_policy_uri,
             resource_description,
+            advisories: Vec::new(),
         }
     }
 }
@@ -148,37 +176,579 @@ impl<'sr> Deref for SuccessfulResponse<'sr> {
     }
 }
 
-fn catch_errors<T>(result: http::Result<Response<T>>) -> Result<T> {
-    return result.map_err(|error: http::Error| {
-        // log error
-        return ErrorMessage::default().into();
-    });
-}
+const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+const PREFER: HeaderName = HeaderName::from_static("prefer");
+const PREFERENCE_APPLIED: HeaderName = HeaderName::from_static("preference-applied");
 
-type ResourceDescriptionStore = dyn KeyValueStore<Key = String, Value = ResourceDescription>;
+/// The methods of the resource registration collection endpoint (e.g. /rreg/) and of its items (e.g. /rreg/:id).
+const COLLECTION_METHODS: &[Method] = &[Method::GET, Method::POST];
+const ITEM_METHODS: &[Method] = &[Method::GET, Method::PUT, Method::PATCH, Method::DELETE];
+
+pub type ResourceDescriptionStore = dyn KeyValueStore<Key = String, Value = ResourceDescription>;
 type Result<T> = result::Result<Response<T>, Response<ErrorMessage>>;
 
+/// [NO-SPEC] Limits protecting the authorization server from a misbehaving resource server.
+#[derive(Debug, Clone)]
+pub struct RegistrationConfig {
+    /// How many resources a single resource owner may have registered at once; unlimited if None. Requests without an
+    /// authenticated owner share a single quota.
+    pub max_resources_per_owner: Option<usize>,
+
+    /// The path at which the resource registration endpoint is mounted (e.g. /rreg), to be skipped when looking for a
+    /// resource ID in a request path. Paths without it, as passed on by a router that already stripped it, are taken as
+    /// they are.
+    pub base_path: String,
+
+    /// Whether a resource that is not found, or gone, is answered with the not_found error message the specification
+    /// allows for, or with an empty body.
+    pub not_found_body: NotFoundBody,
+
+    /// [NO-SPEC] The user_access_policy_uri of create and read responses, if any, as a template in which {id} stands
+    /// for the resource's _id and {owner} for its owner, both percent-encoded, e.g.
+    /// https://as.example.com/rs/{owner}/resource/{id}/policy. Resources without an owner get none if it uses {owner}.
+    pub policy_uri_template: Option<String>,
+
+    /// Where to remember deleted resources, so that reading one yields 410 (Gone) rather than 404 (Not Found) for a
+    /// while; deleted resources are simply not found if None.
+    pub tombstones: Option<Tombstones>,
+
+    /// Whether the type of a resource description may be any string, or must be an IRI.
+    pub type_validation: TypeValidation,
+
+    /// How long to wait for the icon_uri of a created or updated resource description to answer a HEAD request with
+    /// an image; icons are not checked if None. An icon that does not only earns the response an advisory.
+    pub icon_check_timeout: Option<Duration>,
+
+    /// Seals the cursors of paged listings (see `pagination`); by default with a secret of this process alone, so
+    /// servers behind a load balancer need to share one.
+    pub cursors: CursorSigner,
+
+    /// How many resource_scopes a resource description may have; one with more is an invalid_request.
+    pub max_scopes: usize,
+
+    /// Whether the name and description of a resource description are kept as they are sent, normalized, or checked
+    /// for control characters as well.
+    pub text_validation: TextValidation,
+}
+
+impl Default for RegistrationConfig {
+    fn default() -> Self {
+        return Self {
+            max_resources_per_owner: None,
+            base_path: String::new(),
+            not_found_body: NotFoundBody::default(),
+            policy_uri_template: None,
+            tombstones: None,
+            type_validation: TypeValidation::default(),
+            icon_check_timeout: None,
+            cursors: CursorSigner::default(),
+            max_scopes: DEFAULT_MAX_SCOPES,
+            text_validation: TextValidation::default(),
+        };
+    }
+}
+
+/// [NO-SPEC] How strictly the type of a resource description is checked. The specification describes it as a string
+/// uniquely identifying the semantics of the resource, e.g. a URI, but does not require one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TypeValidation {
+    /// Any string.
+    #[default]
+    Lenient,
+    /// An absolute IRI; anything else is an invalid_request.
+    Strict,
+}
+
+/// What the body of a 404 (Not Found) or 410 (Gone) response holds. The specification requires the status code, and
+/// only allows for the not_found error code.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NotFoundBody {
+    /// A not_found error message.
+    #[default]
+    Error,
+    /// Nothing.
+    Empty,
+}
+
+/// [NO-SPEC] The _ids of deleted resources, each remembered for `ttl` after its deletion. Clones share the same
+/// tombstones, so that every handler holding a clone of the configuration sees the same deletions.
+#[derive(Debug, Clone)]
+pub struct Tombstones {
+    ttl: Duration,
+    deleted: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl Tombstones {
+    pub fn new(ttl: Duration) -> Self {
+        return Self { ttl, deleted: Arc::default() };
+    }
+
+    /// Remembers `id` as deleted just now, forgetting whatever has outlived its TTL on the way.
+    fn bury(&self, id: String) {
+        let mut deleted = self.deleted.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        deleted.retain(|_, at| at.elapsed() < self.ttl);
+        deleted.insert(id, Instant::now());
+    }
+
+    fn is_buried(&self, id: &str) -> bool {
+        let deleted = self.deleted.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        return deleted.get(id).is_some_and(|at| at.elapsed() < self.ttl);
+    }
+}
+
+/// The error for a resource that is not in the store: gone if it was deleted recently, and not found otherwise, with or
+/// without a body as configured.
+pub fn missing_resource(config: &RegistrationConfig, id: &str) -> Response<ErrorMessage> {
+    let buried = config.tombstones.as_ref().is_some_and(|tombstones| tombstones.is_buried(id));
+    let error = if buried { RESOURCE_GONE } else { RESOURCE_NOT_FOUND };
+    return match config.not_found_body {
+        NotFoundBody::Error => error.into(),
+        NotFoundBody::Empty => error.without_body().into(),
+    };
+}
+
+/// What a value needs percent-encoded to fit in a path segment: anything but the unreserved characters of RFC 3986.
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+/// The user_access_policy_uri for the resource registered under `id`, expanded from the configured template; none if
+/// there is no template, if it needs an owner the resource lacks, or if the expansion is no valid IRI.
+fn policy_uri(config: &RegistrationConfig, id: &str, description: Option<&ResourceDescription>) -> Option<Iri<String>> {
+    let template = config.policy_uri_template.as_ref()?;
+    let mut uri = template.replace("{id}", &utf8_percent_encode(id, PATH_SEGMENT).to_string());
+    if uri.contains("{owner}") {
+        let owner = description?.owner.as_ref()?.to_string();
+        uri = uri.replace("{owner}", &utf8_percent_encode(&owner, PATH_SEGMENT).to_string());
+    }
+    return Iri::parse(uri).ok();
+}
+
+/// [NO-SPEC] The resource owner on whose behalf a request is made, as established by whatever validated the PAT.
+/// Several resource owners share one store, so every handler is scoped to the owner's resources: it registers resources
+/// in their name, counts only theirs against the quota, lists only theirs, and answers a read, update or delete of
+/// someone else's resource as if it were not registered. Without a PAT subject the context is anonymous, and sees only
+/// resources registered anonymously.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResourceOwnerContext {
+    owner: Option<Identifier>,
+}
+
+impl ResourceOwnerContext {
+    pub fn new(agent: Option<&Agent>) -> Self {
+        return Self { owner: agent.map(Resource::identifier) };
+    }
+
+    /// The context of the `Agent` extension of `request`, if any.
+    pub fn of<T>(request: &Request<T>) -> Self {
+        return Self::new(request.extensions().get::<Agent>());
+    }
+
+    pub fn owner(&self) -> Option<&Identifier> {
+        return self.owner.as_ref();
+    }
+
+    /// Whether `description` was registered by this resource owner.
+    pub fn owns(&self, description: &ResourceDescription) -> bool {
+        return description.owner == self.owner;
+    }
+
+    /// The description registered under `id`, if it is this resource owner's.
+    pub fn get<'s>(&self, store: &'s ResourceDescriptionStore, id: &str) -> Option<&'s ResourceDescription> {
+        return store.get(&id.to_string()).filter(|description| self.owns(description));
+    }
+
+    /// The _ids of this resource owner's resources.
+    pub fn ids<'s>(&self, store: &'s ResourceDescriptionStore) -> impl Iterator<Item = &'s String> + 's {
+        let owner = self.owner.clone();
+        return store.list().filter(move |id| store.get(id).is_some_and(|description| description.owner == owner));
+    }
+}
+
+/// The resource ID of a request path, if anything follows the base path. It is the last segment of the path, so that
+/// it is found behind whatever mount point the endpoint has, configured or not, and percent-decoded, so that IDs that
+/// are themselves URIs (e.g. http%3A%2F%2Fphotoz.example.com%2Falbum) can be addressed.
+fn resource_id<'r, T>(config: &RegistrationConfig, request: &'r Request<T>) -> Option<Cow<'r, str>> {
+    let path = request.uri().path();
+    let path = match path.strip_prefix(config.base_path.trim_end_matches('/')) {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+        _ => path,
+    };
+    let segment = path.split('/').rfind(|segment| !segment.is_empty())?;
+    return Some(percent_decode_str(segment).decode_utf8_lossy());
+}
+
+/// The Idempotency-Key of a create request, if any.
+fn idempotency_key<T>(request: &Request<T>) -> result::Result<Option<String>, Response<ErrorMessage>> {
+    return match request.headers().get(IDEMPOTENCY_KEY).map(HeaderValue::to_str) {
+        None => Ok(None),
+        Some(Ok(key)) if !key.is_empty() && !key.contains(['/', '?', '#']) => Ok(Some(key.to_string())),
+        Some(_) => Err(INVALID_REQUEST.into()),
+    };
+}
+
+/// The _id a create carrying the Idempotency-Key `key` registers under for `owner`: derived from both, so that the keys
+/// of different resource owners never meet, and a key tells nothing about the resources of others.
+fn idempotent_id(owner: &ResourceOwnerContext, key: &str) -> String {
+    let owner = owner.owner().map(ToString::to_string).unwrap_or_default();
+    let digest = Sha256::new().chain_update(owner).chain_update([0]).chain_update(key).finalize();
+    return Base64UrlUnpadded::encode_string(&digest)[..22].to_string();
+}
+
+/// Whether the scopes of `description` are valid and not too many, and its type and texts are, as far as the
+/// configuration checks them.
+fn is_valid(config: &RegistrationConfig, description: &ResourceDescription) -> bool {
+    let valid_type = match (config.type_validation, &description.r#type) {
+        (TypeValidation::Strict, Some(r#type)) => Iri::parse(r#type.as_str()).is_ok(),
+        _ => true,
+    };
+    return valid_type && description.validate(config.max_scopes, config.text_validation).is_ok();
+}
+
+/// Scopes `description` to the resource owner, and normalizes its texts if the configuration asks for it.
+fn prepare(config: &RegistrationConfig, owner: &ResourceOwnerContext, description: &mut ResourceDescription) {
+    description.owner = owner.owner().cloned();
+    if (config.text_validation != TextValidation::Lenient) {
+        description.normalize();
+    }
+}
+
+/// Rejects registering `additional` more resources for `owner` with quota_exceeded if that would exceed the limit.
+fn check_quota(
+    store: &ResourceDescriptionStore,
+    config: &RegistrationConfig,
+    owner: &ResourceOwnerContext,
+    additional: usize,
+) -> result::Result<(), Response<ErrorMessage>> {
+    let Some(max) = config.max_resources_per_owner else {
+        return Ok(());
+    };
+    let registered = owner.ids(store).count();
+    if registered + additional > max {
+        return Err(QUOTA_EXCEEDED.into());
+    }
+    return Ok(());
+}
+
 /// https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-federated-authz-2.0.html#rfc.section.3.2.1
 /// https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-federated-authz-2.0.html#create-rreg
-
+///
 /// Adds a new resource description to the authorization server using the POST method. If the request is successful, the
 /// resource is thereby registered and the authorization server MUST respond with an HTTP 201 status message that
 /// includes a Location header and an _id parameter.
-
+///
+/// [NO-SPEC] A resource server that may retry a failed POST can send an Idempotency-Key header, from which, together
+/// with the resource owner, the _id is derived. A retry carrying the same key and an identical resource description
+/// responds 200 with the existing _id instead of registering a duplicate; a retry with a different description responds
+/// 409 (Conflict). Keys are scoped to the resource owner: the same key sent for another owner is a fresh one.
+///
+/// [NO-SPEC] Once the resource owner has as many resources registered as the configuration allows, registering another
+/// one yields quota_exceeded; a retry of a registration that already succeeded does not count against the quota.
+///
+/// [NO-SPEC] A request that only asks for validation (see `is_validate_only`) is for validate_resource_registration.
+///
+/// [NO-SPEC] A request carrying Prefer: return=representation (RFC 7240) gets the stored resource description in the
+/// body of the 201 response as well, e.g. to see how it was normalized; others get the minimal body.
+///
+/// [NO-SPEC] If the configuration asks for it, the icon_uri is checked first (see `icon_advisory` and `IconChecked`).
+#[cfg_attr(feature = "openapi", utoipa::path(
+    post,
+    path = "/rreg/",
+    tag = "resource registration",
+    request_body = ResourceDescription,
+    responses(
+        (status = 201, description = "The resource is registered", body = SuccessfulResponse),
+        (status = 400, description = "The request is invalid", body = ErrorMessage),
+        (status = 403, description = "The resource owner has reached their quota", body = ErrorMessage),
+        (status = 413, description = "The resource description is too large", body = ErrorMessage),
+    ),
+))]
+#[instrument(skip_all, fields(method = %request.method(), resource_id = Empty, outcome = Empty))]
 pub async fn create_resource_registration<'sr>(
     store: &'sr mut ResourceDescriptionStore,
+    ids: &dyn IdGenerator,
+    config: &RegistrationConfig,
+    owner: &ResourceOwnerContext,
     request: Request<ResourceDescription>,
 ) -> Result<SuccessfulResponse<'sr>> {
     if (request.method() != Method::POST) {
-        return Err(UNSUPPORTED_METHOD_TYPE.into());
+        return Err(UNSUPPORTED_METHOD_TYPE.with_allowed_methods(COLLECTION_METHODS).into());
+    }
+    if !is_valid(config, request.body()) {
+        return Err(INVALID_REQUEST.into());
+    }
+
+    let idempotency_key = idempotency_key(&request)?;
+    let representation = prefers_representation(&request);
+    let advisory = request_icon_advisory(config, &request).await;
+
+    let mut description = request.into_body();
+    prepare(config, owner, &mut description);
+
+    let Some(key) = idempotency_key else {
+        check_quota(store, config, owner, 1)?;
+        let id = register_under_fresh_id(store, ids, description)?;
+        Span::current().record("resource_id", id.as_str());
+        return advise(created(store, config, id, representation), advisory);
+    };
+
+    let key = idempotent_id(owner, &key);
+    Span::current().record("resource_id", key.as_str());
+    match store.get(&key).map(|existing| (owner.owns(existing), *existing == description)) {
+        None => {
+            check_quota(store, config, owner, 1)?;
+            store.set(key.clone(), description);
+            return advise(created(store, config, key, representation), advisory);
+        }
+        // Only a resource another owner PUT at the derived _id gets here; the key is as good as fresh.
+        Some((false, _)) => {
+            check_quota(store, config, owner, 1)?;
+            let id = register_under_fresh_id(store, ids, description)?;
+            Span::current().record("resource_id", id.as_str());
+            return advise(created(store, config, id, representation), advisory);
+        }
+        Some((true, false)) => return Err(CONFLICT.into()),
+        Some((true, true)) => {}
     }
 
-    let id = Uuid::new_v4().to_string();
-    let id = store.set(id, request.into_body());
+    let store: &'sr ResourceDescriptionStore = store;
+    let Some(id) = store.list().find(|id| **id == key) else {
+        return Err(RESOURCE_NOT_FOUND.into());
+    };
 
+    let policy_uri = policy_uri(config, id, store.get(id));
     let response = Response::builder()
-        .status(StatusCode::CREATED)
-        .body(SuccessfulResponse::new(&id, None, None));
+        .status(StatusCode::OK)
+        .body(SuccessfulResponse::new(id, policy_uri, None));
+
+    return advise(catch_errors(response), advisory);
+}
+
+/// [NO-SPEC] Checks that the icon_uri of `description`, if any, answers a HEAD request with an image within the
+/// configured timeout. An icon that does not is no reason to refuse the registration, as it may only be unreachable
+/// from here, or for now; rather than an error, it yields an advisory for the response.
+pub async fn icon_advisory(config: &RegistrationConfig, description: &ResourceDescription) -> Option<String> {
+    let (Some(timeout), Some(icon_uri)) = (config.icon_check_timeout, &description.icon_uri) else {
+        return None;
+    };
+    let Either::Left(icon_uri) = icon_uri else {
+        return Some(format!("The icon_uri {icon_uri} is not an IRI, so its icon could not be checked."));
+    };
+
+    let sent = client().head(icon_uri.as_str()).timeout(timeout).send().await;
+    let response = match sent.and_then(reqwest::Response::error_for_status) {
+        Ok(response) => response,
+        Err(error) => {
+            warn!(icon_uri = icon_uri.as_str(), %error, "icon is unavailable");
+            return Some(format!("The icon at {icon_uri} could not be retrieved."));
+        }
+    };
+    let content_type = response.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok());
+    if !content_type.is_some_and(|content_type| content_type.starts_with("image/")) {
+        return Some(format!("The icon at {icon_uri} is not an image."));
+    }
+    return None;
+}
+
+/// [NO-SPEC] The outcome of `icon_advisory`, as an extension of a request whose icon_uri was already checked, e.g.
+/// before the store was locked, so that a slow icon host holds up no other request. Handlers given one do not check
+/// again.
+#[derive(Debug, Clone, Default)]
+pub struct IconChecked(pub Option<String>);
+
+/// The advisory of the `IconChecked` extension of `request`, or else of checking its icon_uri now.
+async fn request_icon_advisory(config: &RegistrationConfig, request: &Request<ResourceDescription>) -> Option<String> {
+    if let Some(IconChecked(advisory)) = request.extensions().get::<IconChecked>() {
+        return advisory.clone();
+    }
+    return icon_advisory(config, request.body()).await;
+}
+
+/// Adds `advisory`, if any, to a successful response.
+fn advise<'sr>(result: Result<SuccessfulResponse<'sr>>, advisory: Option<String>) -> Result<SuccessfulResponse<'sr>> {
+    return result.map(|mut response| {
+        response.body_mut().advisories.extend(advisory);
+        response
+    });
+}
+
+/// How many _ids a create draws before giving up on finding one that is not taken yet.
+pub const ID_ATTEMPTS: usize = 5;
+
+/// Registers `description` under a freshly generated _id, drawing another one while the one drawn is taken, which
+/// shorter or time-ordered identifiers make less unlikely than UUIDs. Rather than overwrite a registration, it gives up
+/// with an internal server error after `ID_ATTEMPTS` draws.
+fn register_under_fresh_id(
+    store: &mut ResourceDescriptionStore,
+    ids: &dyn IdGenerator,
+    mut description: ResourceDescription,
+) -> result::Result<String, Response<ErrorMessage>> {
+    for _ in 0..ID_ATTEMPTS {
+        let id = ids.generate();
+        match store.set_if_absent(id.clone(), description) {
+            Ok(_) => return Ok(id),
+            Err(rejected) => {
+                warn!(id, "generated _id is taken, drawing another");
+                description = rejected;
+            }
+        }
+    }
+    error!(attempts = ID_ATTEMPTS, "found no unused _id");
+    return Err(ErrorMessage {
+        error_description: Some(Cow::Borrowed("No unused resource identifier could be generated.")),
+        ..ErrorMessage::default()
+    }
+    .into());
+}
+
+/// The 201 response to a create that registered `id`, carrying the stored description if the request prefers it.
+fn created<'sr>(
+    store: &'sr ResourceDescriptionStore,
+    config: &RegistrationConfig,
+    id: String,
+    representation: bool,
+) -> Result<SuccessfulResponse<'sr>> {
+    let policy_uri = policy_uri(config, &id, store.get(&id));
+    let mut response = Response::builder().status(StatusCode::CREATED);
+    let mut description = None;
+    if representation {
+        response = response.header(PREFERENCE_APPLIED, "return=representation");
+        description = store.get(&id);
+    }
+    return catch_errors(response.body(SuccessfulResponse::new(id, policy_uri, description)));
+}
+
+/// [NO-SPEC] The body of a successful response to a validate-only create request.
+#[derive(Debug, Serialize, Clone, Copy)]
+pub struct ValidationResponse {
+    /// Always true, as an invalid resource description yields an error response instead.
+    pub valid: bool,
+}
+
+/// [NO-SPEC] Whether a create request only asks for its resource description to be validated, through a
+/// validate_only=true query parameter or a Prefer: validate-only header (RFC 7240).
+pub fn is_validate_only<T>(request: &Request<T>) -> bool {
+    let query = request.uri().query().unwrap_or_default();
+    if form_urlencoded::parse(query.as_bytes()).any(|(name, value)| name == "validate_only" && value == "true") {
+        return true;
+    }
+    return preferences(request)
+        .map(|preference| preference.split('=').next().unwrap_or_default().trim())
+        .any(|preference| preference.eq_ignore_ascii_case("validate-only"));
+}
+
+/// [NO-SPEC] Whether a request asks for the resource description in the response, through Prefer:
+/// return=representation (RFC 7240).
+fn prefers_representation<T>(request: &Request<T>) -> bool {
+    return preferences(request).any(|preference| match preference.split_once('=') {
+        Some((name, value)) => {
+            name.trim().eq_ignore_ascii_case("return")
+                && value.trim().trim_matches('"').eq_ignore_ascii_case("representation")
+        }
+        None => false,
+    });
+}
+
+/// The preferences of a request's Prefer headers, without their parameters, e.g. validate-only or return=minimal.
+fn preferences<T>(request: &Request<T>) -> impl Iterator<Item = &str> {
+    return request
+        .headers()
+        .get_all(PREFER)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|preference| preference.split(';').next().unwrap_or_default().trim());
+}
+
+/// [NO-SPEC] Runs the checks of create_resource_registration on a resource description without registering it: nothing
+/// is written to the store and no _id is allocated. A description that would be registered yields an HTTP 200 status
+/// message; one that would not yields the error the registration would have.
+#[instrument(skip_all, fields(method = %request.method(), outcome = Empty))]
+pub async fn validate_resource_registration(
+    store: &ResourceDescriptionStore,
+    config: &RegistrationConfig,
+    owner: &ResourceOwnerContext,
+    request: Request<ResourceDescription>,
+) -> Result<ValidationResponse> {
+    if (request.method() != Method::POST) {
+        return Err(UNSUPPORTED_METHOD_TYPE.with_allowed_methods(COLLECTION_METHODS).into());
+    }
+    if !is_valid(config, request.body()) {
+        return Err(INVALID_REQUEST.into());
+    }
+    let idempotency_key = idempotency_key(&request)?;
+
+    let mut description = request.into_body();
+    prepare(config, owner, &mut description);
+
+    // As on create, the key stands for the _id it derives to, and a resource of another owner there for none at all.
+    let existing = idempotency_key.and_then(|key| owner.get(store, &idempotent_id(owner, &key)));
+    match existing.map(|existing| *existing == description) {
+        None => check_quota(store, config, owner, 1)?,
+        Some(false) => return Err(CONFLICT.into()),
+        Some(true) => {}
+    }
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(PREFERENCE_APPLIED, "validate-only")
+        .body(ValidationResponse { valid: true });
+
+    return catch_errors(response);
+}
+
+/// [NO-SPEC] Registers several resource descriptions in a single POST, as a resource server onboarding many resources
+/// at once would otherwise need one request per resource. The request body is an array of resource descriptions; the
+/// authorization server responds with an HTTP 201 status message that includes an array of _id parameters, in the order
+/// of the request. The batch is all or nothing: if any description is invalid, none is registered, and the
+/// invalid_request error names the index of the first invalid one. Likewise, a batch that would take the resource owner
+/// over their quota registers nothing.
+#[instrument(skip_all, fields(method = %request.method(), count = Empty, outcome = Empty))]
+pub async fn create_resource_registrations<'sr>(
+    store: &'sr mut ResourceDescriptionStore,
+    ids: &dyn IdGenerator,
+    config: &RegistrationConfig,
+    owner: &ResourceOwnerContext,
+    request: Request<Vec<ResourceDescription>>,
+) -> Result<Vec<SuccessfulResponse<'sr>>> {
+    if (request.method() != Method::POST) {
+        return Err(UNSUPPORTED_METHOD_TYPE.with_allowed_methods(COLLECTION_METHODS).into());
+    }
+
+    let descriptions = request.into_body();
+    if descriptions.is_empty() {
+        return Err(ErrorMessage {
+            error_description: Some(Cow::Borrowed("The batch contains no resource descriptions.")),
+            ..INVALID_REQUEST
+        }
+        .into());
+    }
+    if let Some(index) = descriptions.iter().position(|description| !is_valid(config, description)) {
+        return Err(ErrorMessage {
+            error_description: Some(Cow::Owned(format!("The resource description at index {index} is invalid."))),
+            ..INVALID_REQUEST
+        }
+        .into());
+    }
+    Span::current().record("count", descriptions.len());
+    check_quota(store, config, owner, descriptions.len())?;
+
+    let mut created: Vec<String> = Vec::with_capacity(descriptions.len());
+    for mut description in descriptions {
+        prepare(config, owner, &mut description);
+        created.push(register_under_fresh_id(store, ids, description)?);
+    }
+
+    // The keys are looked up again once all writes are done, as each set only lends out its key until the next one.
+    let store: &'sr ResourceDescriptionStore = store;
+    let keys: HashMap<&str, &'sr String> = store.list().map(|id| (id.as_str(), id)).collect();
+    let mut body = Vec::with_capacity(created.len());
+    for id in &created {
+        let Some(id) = keys.get(id.as_str()) else {
+            return Err(RESOURCE_NOT_FOUND.into());
+        };
+        body.push(SuccessfulResponse::new(id.as_str(), policy_uri(config, id, store.get(id)), None));
+    }
+
+    let response = Response::builder().status(StatusCode::CREATED).body(body);
 
     return catch_errors(response);
 }
@@ -189,50 +759,179 @@ pub async fn create_resource_registration<'sr>(
 /// Reads a previously registered resource description using the GET method. If the request is successful, the
 /// authorization server MUST respond with an HTTP 200 status message that includes a body containing the referenced
 /// resource description, along with an _id parameter.
-
+///
+/// [NO-SPEC] The response carries an ETag, so that a resource server can poll for changes with If-None-Match; if the
+/// tag still matches, the authorization server responds with an HTTP 304 (Not Modified) status message, whose body is
+/// not to be sent.
+///
+/// [NO-SPEC] A resource that was deleted recently, while the configuration keeps tombstones, yields 410 (Gone) rather
+/// than 404 (Not Found).
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/rreg/{_id}",
+    tag = "resource registration",
+    params(("_id" = String, Path, description = "The authorization server-defined identifier of the resource")),
+    responses(
+        (status = 200, description = "The resource description", body = SuccessfulResponse),
+        (status = 304, description = "The resource description still matches If-None-Match"),
+        (status = 404, description = "The resource is not registered", body = ErrorMessage),
+        (status = 410, description = "The resource was deregistered recently", body = ErrorMessage),
+    ),
+))]
+#[instrument(skip_all, fields(method = %request.method(), resource_id = Empty, outcome = Empty))]
 pub async fn read_resource_registration<'sr>(
     store: &'sr mut ResourceDescriptionStore,
-    request: &'sr Request<!>,
+    config: &RegistrationConfig,
+    owner: &ResourceOwnerContext,
+    request: &'sr Request<()>,
 ) -> Result<SuccessfulResponse<'sr>> {
     if (request.method() != Method::GET) {
-        return Err(UNSUPPORTED_METHOD_TYPE.into());
+        return Err(UNSUPPORTED_METHOD_TYPE.with_allowed_methods(ITEM_METHODS).into());
     }
 
-    let id = request.uri().path().trim_start_matches("/");
+    let id = resource_id(config, request).unwrap_or_default();
+    Span::current().record("resource_id", id.as_ref());
 
-    match store.get(&id.to_string()) {
+    match owner.get(store, &id) {
         Some(description) => {
+            let etag = entity_tag(description);
+            let status = match request.headers().get(IF_NONE_MATCH).map(HeaderValue::to_str) {
+                Some(Ok(tags)) if matches_entity_tag(tags, &etag) => StatusCode::NOT_MODIFIED,
+                _ => StatusCode::OK,
+            };
+            let policy_uri = policy_uri(config, &id, Some(description));
             let response = Response::builder()
-                .status(StatusCode::OK)
-                .body(SuccessfulResponse::new(id.clone(), None, Some(description)));
+                .status(status)
+                .header(ETAG, etag)
+                .body(SuccessfulResponse::new(id, policy_uri, Some(description)));
             return catch_errors(response);
         }
-        None => return Err(RESOURCE_NOT_FOUND.into()),
+        None => return Err(missing_resource(config, &id)),
     }
 }
 
+/// A strong entity tag over the JSON serialization of the description, which is deterministic: members are written in
+/// a fixed order, and language-tagged ones sorted by tag.
+fn entity_tag(description: &ResourceDescription) -> String {
+    let json = serde_json::to_vec(description).unwrap_or_default();
+    return format!("\"{}\"", Base64UrlUnpadded::encode_string(&Sha256::digest(json)));
+}
+
+/// https://www.rfc-editor.org/rfc/rfc9110#section-13.1.2
+///
+/// If-None-Match holds either "*" or a list of entity tags, compared using the weak comparison function, i.e. ignoring
+/// a W/ prefix.
+fn matches_entity_tag(if_none_match: &str, etag: &str) -> bool {
+    return if_none_match.trim() == "*"
+        || if_none_match.split(',').any(|tag| tag.trim().trim_start_matches("W/") == etag);
+}
+
 /// https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-federated-authz-2.0.html#rfc.section.3.2.3
 /// https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-federated-authz-2.0.html#update-resource-set
 ///
 /// Updates a previously registered resource description, by means of a complete replacement of the previous resource
 /// description, using the PUT method. If the request is successful, the authorization server MUST respond with an HTTP
 /// 200 status message that includes an _id parameter.
+///
+/// [NO-SPEC] A PUT carrying If-None-Match: * creates the resource description under the caller-proposed _id instead,
+/// responding 201 if it did not exist yet and 412 (Precondition Failed) if it did. The check and the insert are a
+/// single store operation, so of several racing creates at the same _id exactly one succeeds. Such a create counts
+/// against the resource owner's quota like any other, as does a plain PUT at an _id that is not registered yet.
+///
+/// [NO-SPEC] A PUT replacing a resource of another resource owner yields 404 (Not Found), as a read of it would.
+#[cfg_attr(feature = "openapi", utoipa::path(
+    put,
+    path = "/rreg/{_id}",
+    tag = "resource registration",
+    params(("_id" = String, Path, description = "The authorization server-defined identifier of the resource")),
+    request_body = ResourceDescription,
+    responses(
+        (status = 200, description = "The resource description is replaced", body = SuccessfulResponse),
+        (status = 201, description = "The resource is registered under the proposed _id", body = SuccessfulResponse),
+        (status = 400, description = "The request is invalid", body = ErrorMessage),
+        (status = 403, description = "The resource owner has reached their quota", body = ErrorMessage),
+        (status = 404, description = "The resource is not registered", body = ErrorMessage),
+        (status = 412, description = "A resource is already registered under the proposed _id", body = ErrorMessage),
+    ),
+))]
+#[instrument(skip_all, fields(method = %request.method(), resource_id = Empty, outcome = Empty))]
 pub async fn update_resource_registration<'sr>(
     store: &'sr mut ResourceDescriptionStore,
+    config: &RegistrationConfig,
+    owner: &ResourceOwnerContext,
     request: Request<ResourceDescription>,
 ) -> Result<SuccessfulResponse<'sr>> {
     if (request.method() != Method::PUT) {
-        return Err(UNSUPPORTED_METHOD_TYPE.into());
+        return Err(UNSUPPORTED_METHOD_TYPE.with_allowed_methods(ITEM_METHODS).into());
     }
+    if !is_valid(config, request.body()) {
+        return Err(INVALID_REQUEST.into());
+    }
+
+    let create_only = request.headers().get(IF_NONE_MATCH).map_or(false, |value| value == "*");
+    let id = resource_id(config, &request).unwrap_or_default().to_string();
+    Span::current().record("resource_id", id.as_str());
+    let advisory = request_icon_advisory(config, &request).await;
+    let mut description = request.into_body();
+    prepare(config, owner, &mut description);
+    if store.get(&id).is_none() {
+        check_quota(store, config, owner, 1)?;
+    }
+
+    if create_only {
+        let Ok(id) = store.set_if_absent(id, description) else {
+            return Err(PRECONDITION_FAILED.into());
+        };
 
-    let id = request.uri().path().trim_start_matches("/");
-    let id = store.set(id.to_string(), request.into_body());
+        let response = Response::builder()
+            .status(StatusCode::CREATED)
+            .body(SuccessfulResponse::new(id.as_str(), None, None));
+
+        return advise(catch_errors(response), advisory);
+    }
+
+    if store.get(&id).is_some_and(|existing| !owner.owns(existing)) {
+        return Err(missing_resource(config, &id));
+    }
+    let id = store.set(id, description);
 
     let response = Response::builder()
         .status(StatusCode::OK)
-        .body(SuccessfulResponse::new(&id, None, None));
+        .body(SuccessfulResponse::new(id.as_str(), None, None));
 
-    return catch_errors(response);
+    return advise(catch_errors(response), advisory);
+}
+
+/// https://www.rfc-editor.org/rfc/rfc7396
+///
+/// [NO-SPEC] Applies a JSON merge patch to a resource description, for a PATCH to go through the same update as a PUT:
+/// members set to null are removed, objects are merged member by member, and anything else replaces what was there.
+/// A patch that leaves no valid resource description is an invalid_request.
+pub fn merge_patch(
+    description: &ResourceDescription,
+    patch: serde_json::Value,
+) -> result::Result<ResourceDescription, Response<ErrorMessage>> {
+    let mut target = serde_json::to_value(description).map_err(|_| Response::from(INVALID_REQUEST))?;
+    apply_merge_patch(&mut target, patch);
+    return serde_json::from_value(target).map_err(|_| INVALID_REQUEST.into());
+}
+
+fn apply_merge_patch(target: &mut serde_json::Value, patch: serde_json::Value) {
+    let serde_json::Value::Object(members) = patch else {
+        *target = patch;
+        return;
+    };
+    if !target.is_object() {
+        *target = serde_json::Value::Object(Default::default());
+    }
+    let target = target.as_object_mut().expect("target is an object");
+    for (name, value) in members {
+        if value.is_null() {
+            target.remove(&name);
+        } else {
+            apply_merge_patch(target.entry(name).or_insert(serde_json::Value::Null), value);
+        }
+    }
 }
 
 /// https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-federated-authz-2.0.html#rfc.section.3.2.4
@@ -240,24 +939,48 @@ pub async fn update_resource_registration<'sr>(
 ///
 /// Deletes a previously registered resource description using the DELETE method. If the request is successful, the
 /// resource is thereby deregistered and the authorization server MUST respond with an HTTP 200 or 204 status message.
+///
+/// [NO-SPEC] If the configuration keeps tombstones, the _id is remembered, and reading or deleting it again yields 410
+/// (Gone) until its tombstone expires.
+#[cfg_attr(feature = "openapi", utoipa::path(
+    delete,
+    path = "/rreg/{_id}",
+    tag = "resource registration",
+    params(("_id" = String, Path, description = "The authorization server-defined identifier of the resource")),
+    responses(
+        (status = 204, description = "The resource is deregistered"),
+        (status = 404, description = "The resource is not registered", body = ErrorMessage),
+        (status = 410, description = "The resource was deregistered recently", body = ErrorMessage),
+    ),
+))]
+#[instrument(skip_all, fields(method = %request.method(), resource_id = Empty, outcome = Empty))]
 pub async fn delete_resource_registration<'sr>(
     store: &'sr mut ResourceDescriptionStore,
-    request: &'sr Request<!>,
+    config: &RegistrationConfig,
+    owner: &ResourceOwnerContext,
+    request: &'sr Request<()>,
 ) -> Result<SuccessfulResponse<'sr>> {
     if (request.method() != Method::DELETE) {
-        return Err(UNSUPPORTED_METHOD_TYPE.into());
+        return Err(UNSUPPORTED_METHOD_TYPE.with_allowed_methods(ITEM_METHODS).into());
     }
 
-    let id = request.uri().path().trim_start_matches("/");
+    let id = resource_id(config, request).unwrap_or_default();
+    Span::current().record("resource_id", id.as_ref());
 
+    if owner.get(store, &id).is_none() {
+        return Err(missing_resource(config, &id));
+    }
     match store.del(&id.to_string()) {
         Some(_) => {
+            if let Some(tombstones) = &config.tombstones {
+                tombstones.bury(id.to_string());
+            }
             let response = Response::builder()
                 .status(StatusCode::NO_CONTENT)
                 .body(SuccessfulResponse::new(id, None, None));
             return catch_errors(response);
         }
-        None => return Err(RESOURCE_NOT_FOUND.into()),
+        None => return Err(missing_resource(config, &id)),
     }
 }
 
@@ -269,20 +992,79 @@ pub async fn delete_resource_registration<'sr>(
 ///
 /// The resource server can use this method as a first step in checking whether its understanding of protected resources
 /// is in full synchronization with the authorization server's understanding.
+///
+/// [NO-SPEC] With the query parameter deep=true, the array instead holds a full representation of each resource
+/// description, as returned by the read method, so that the resource server can reconcile its state in one request.
+/// Each scope=<value> query parameter restricts the list to resource descriptions that include that scope among their
+/// resource_scopes.
+///
+/// [NO-SPEC] With a limit=<n> query parameter, the response is instead a page of at most n of them, as {items, total,
+/// next}; passing the opaque next cursor as the cursor query parameter gets the page after it (see `pagination`).
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum ResourceList<'sr> {
+    Ids(Listing<&'sr String>),
+    Descriptions(Listing<SuccessfulResponse<'sr>>),
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/rreg/",
+    tag = "resource registration",
+    params(
+        ("deep" = Option<bool>, Query, description = "Whether to list full resource descriptions instead of _ids"),
+        ("scope" = Option<Vec<String>>, Query, description = "Only list resources registered with these scopes"),
+        ("limit" = Option<usize>, Query, description = "How many resources to list at most, as a page"),
+        ("cursor" = Option<String>, Query, description = "The next member of the previous page"),
+    ),
+    responses(
+        (status = 200, description = "The registered resources", body = Vec<String>),
+        (status = 400, description = "The request is invalid", body = ErrorMessage),
+    ),
+))]
+#[instrument(skip_all, fields(method = %request.method(), outcome = Empty))]
 pub async fn list_resource_registration<'it>(
     store: &'it mut ResourceDescriptionStore,
-    request: &'it Request<!>,
-) -> Result<Box<dyn Iterator<Item = &'it String> + 'it>> {
+    config: &RegistrationConfig,
+    owner: &ResourceOwnerContext,
+    request: &'it Request<()>,
+) -> Result<ResourceList<'it>> {
     if (request.method() != Method::GET) {
-        return Err(UNSUPPORTED_METHOD_TYPE.into());
+        return Err(UNSUPPORTED_METHOD_TYPE.with_allowed_methods(COLLECTION_METHODS).into());
     }
-    if (request.uri().path() != "/") {
+    if resource_id(config, request).is_some() {
         return Err(INVALID_REQUEST.into());
     }
 
-    let keys = store.list();
+    let query = request.uri().query().unwrap_or_default();
+    let mut deep = false;
+    let mut scopes = Vec::new();
+    for (parameter, value) in form_urlencoded::parse(query.as_bytes()) {
+        match (parameter.as_ref(), value.as_ref()) {
+            ("deep", "true") => deep = true,
+            ("deep", "false") => deep = false,
+            ("scope", _) => scopes.push(value.into_owned()),
+            ("limit" | "cursor", _) => {}
+            _ => return Err(INVALID_REQUEST.into()),
+        }
+    }
+    let page = PageRequest::from_query(query, &config.cursors)?;
 
-    let response = Response::builder().status(StatusCode::OK).body(keys);
+    let store: &'it ResourceDescriptionStore = store;
+    let matching = owner.ids(store).filter(|id| {
+        let Some(description) = store.get(id) else {
+            return false;
+        };
+        return scopes.iter().all(|scope| description.resource_scopes.iter().any(|s| s == scope));
+    });
+    let ids = Listing::of(matching.collect(), page.as_ref(), &config.cursors);
+    let list = if deep {
+        ResourceList::Descriptions(ids.map(|id| SuccessfulResponse::new(id, None, store.get(id))))
+    } else {
+        ResourceList::Ids(ids)
+    };
+
+    let response = Response::builder().status(StatusCode::OK).body(list);
 
     return catch_errors(response);
 }
@@ -291,6 +1073,20 @@ pub async fn list_resource_registration<'it>(
 mod tests {
 
     use super::*;
+    use crate::ids::SeededIdGenerator;
+    use crate::test_support::serialized_keys;
+    use crate::uma::federation::{ResourceDescriptionError, Scope};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    fn anonymous() -> ResourceOwnerContext {
+        return ResourceOwnerContext::default();
+    }
+
+    fn owner(agent: &str) -> ResourceOwnerContext {
+        return ResourceOwnerContext::new(Some(&Agent::new(agent).unwrap()));
+    }
 
     // assert! assert_eq! assert_ne! #[should_panic(expected = "panic msg")] -> Result<(), String> ?
 
@@ -325,6 +1121,525 @@ mod tests {
 
     }
 
+    #[tokio::test]
+    async fn create_with_seeded_ids() {
+        let mut store = HashMap::new();
+        let expected = SeededIdGenerator::new(7).generate();
+
+        let description = ResourceDescription {
+            _id: "",
+            resource_scopes: vec!["read-public".into()],
+            description: None,
+            icon_uri: None,
+            name: Some("Tweedl Social Service".into()),
+            r#type: None,
+            owner: None,
+            policy_hint: None,
+        };
+        let request = Request::builder().method(Method::POST).uri("/").body(description).unwrap();
+
+        let config = RegistrationConfig::default();
+        let ids = SeededIdGenerator::new(7);
+        let response = create_resource_registration(&mut store, &ids, &config, &anonymous(), request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.body()._id, expected);
+    }
+
+    #[tokio::test]
+    async fn create_draws_another_id_when_one_is_taken() {
+        let seeded = SeededIdGenerator::new(7);
+        let (taken, fresh) = (seeded.generate(), seeded.generate());
+        let existing = ResourceDescription::builder().scope("print").build().unwrap();
+        let mut store = HashMap::from([(taken.clone(), existing.clone())]);
+
+        let description = ResourceDescription::builder().scope("view").build().unwrap();
+        let request = Request::builder().method(Method::POST).uri("/").body(description).unwrap();
+        let config = RegistrationConfig::default();
+        let ids = SeededIdGenerator::new(7);
+        let response = create_resource_registration(&mut store, &ids, &config, &anonymous(), request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.body()._id, fresh);
+        assert_eq!(store[&taken], existing);
+        assert_eq!(store[&fresh].resource_scopes, vec!["view"]);
+    }
+
+    #[tokio::test]
+    async fn create_gives_up_when_every_id_is_taken() {
+        let seeded = SeededIdGenerator::new(7);
+        let existing = ResourceDescription::builder().scope("print").build().unwrap();
+        let mut store: HashMap<String, ResourceDescription> =
+            (0..ID_ATTEMPTS).map(|_| (seeded.generate(), existing.clone())).collect();
+
+        let description = ResourceDescription::builder().scope("view").build().unwrap();
+        let request = Request::builder().method(Method::POST).uri("/").body(description).unwrap();
+        let config = RegistrationConfig::default();
+        let ids = SeededIdGenerator::new(7);
+        let error = create_resource_registration(&mut store, &ids, &config, &anonymous(), request).await.unwrap_err();
+
+        assert_eq!(error.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(store.len(), ID_ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn create_and_read_expand_the_policy_uri_template() {
+        let config = RegistrationConfig {
+            policy_uri_template: Some("https://as.example.com/rs/{owner}/resource/{id}/policy".to_string()),
+            ..RegistrationConfig::default()
+        };
+        let mut store = HashMap::new();
+        let description = ResourceDescription::builder().scope("view").build().unwrap();
+        let request = Request::builder().method(Method::POST).uri("/").body(description).unwrap();
+        let owner = owner("222");
+
+        let ids = SeededIdGenerator::new(7);
+        let response = create_resource_registration(&mut store, &ids, &config, &owner, request).await.unwrap();
+
+        let id = response.body()._id.to_string();
+        let expected = format!("https://as.example.com/rs/222/resource/{id}/policy");
+        let body = serde_json::to_value(response.body()).unwrap();
+        assert_eq!(body["user_access_policy_uri"], expected);
+
+        let request = Request::builder().method(Method::GET).uri(format!("/{id}")).body(()).unwrap();
+        let response = read_resource_registration(&mut store, &config, &owner, &request).await.unwrap();
+        assert_eq!(response.body().user_access_policy_uri.as_ref().map(Iri::as_str), Some(expected.as_str()));
+    }
+
+    #[tokio::test]
+    async fn policy_hints_round_trip() {
+        let config = RegistrationConfig::default();
+        let mut store = HashMap::new();
+        let owner = owner("222");
+        let ids = SeededIdGenerator::new(7);
+
+        let hinted: ResourceDescription = serde_json::from_value(serde_json::json!({
+            "resource_scopes": ["view"],
+            "policy_hint": {"audience": "friends", "max_age": 3600},
+        }))
+        .unwrap();
+        let request = Request::builder().method(Method::POST).uri("/").body(hinted).unwrap();
+        let response = create_resource_registration(&mut store, &ids, &config, &owner, request).await.unwrap();
+        let hinted_id = response.body()._id.to_string();
+
+        let plain = ResourceDescription::builder().scope("view").build().unwrap();
+        let request = Request::builder().method(Method::POST).uri("/").body(plain).unwrap();
+        let response = create_resource_registration(&mut store, &ids, &config, &owner, request).await.unwrap();
+        let plain_id = response.body()._id.to_string();
+
+        let request = Request::builder().method(Method::GET).uri(format!("/{hinted_id}")).body(()).unwrap();
+        let response = read_resource_registration(&mut store, &config, &owner, &request).await.unwrap();
+        let body = serde_json::to_value(response.body()).unwrap();
+        let expected = serde_json::json!({"audience": "friends", "max_age": 3600});
+        assert_eq!(body["resource_description"]["policy_hint"], expected);
+
+        let request = Request::builder().method(Method::GET).uri(format!("/{plain_id}")).body(()).unwrap();
+        let response = read_resource_registration(&mut store, &config, &owner, &request).await.unwrap();
+        let body = serde_json::to_value(response.body()).unwrap();
+        assert!(body["resource_description"].get("policy_hint").is_none());
+    }
+
+    #[tokio::test]
+    async fn create_returns_minimal_body_by_default() {
+        let mut store = HashMap::new();
+        let description = ResourceDescription::builder().scope("view").name("Photo album").build().unwrap();
+        let request = Request::builder().method(Method::POST).uri("/").header("Prefer", "return=minimal");
+        let request = request.body(description).unwrap();
+
+        let config = RegistrationConfig::default();
+        let ids = SeededIdGenerator::new(7);
+        let response = create_resource_registration(&mut store, &ids, &config, &anonymous(), request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(response.headers().get(PREFERENCE_APPLIED).is_none());
+        assert_eq!(response.body().resource_description, None);
+    }
+
+    #[test]
+    fn serialized_keys_are_pinned() {
+        let description = ResourceDescription::builder().scope("view").build().unwrap();
+        let policy_uri = Iri::parse("https://as.example.com/policy".to_string()).unwrap();
+        let response = SuccessfulResponse {
+            advisories: vec!["The icon at https://rs.example.com/icon.png is not an image.".to_string()],
+            ..SuccessfulResponse::new("KX3A-39WE", Some(policy_uri), Some(&description))
+        };
+        let expected = ["_id", "advisories", "resource_description", "user_access_policy_uri"];
+        assert_eq!(serialized_keys(&response), expected);
+        let expected = format!(
+            concat!(
+                r#"{{"_id":"KX3A-39WE","user_access_policy_uri":"https://as.example.com/policy","#,
+                r#""resource_description":{},"#,
+                r#""advisories":["The icon at https://rs.example.com/icon.png is not an image."]}}"#,
+            ),
+            serde_json::to_string(&description).unwrap(),
+        );
+        assert_eq!(serde_json::to_string(&response).unwrap(), expected);
+
+        assert_eq!(serialized_keys(SuccessfulResponse::new("KX3A-39WE", None, None)), ["_id"]);
+    }
+
+    /// Serves an image at /icon.png and a page at /page, on an ephemeral local port, until the test ends.
+    async fn serve_icon() -> String {
+        use axum::routing::get;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let app = axum::Router::new()
+            .route("/icon.png", get(|| async { ([(CONTENT_TYPE, "image/png")], vec![0x89, b'P', b'N', b'G']) }))
+            .route("/page", get(|| async { ([(CONTENT_TYPE, "text/html")], "<p>Not an icon</p>") }));
+        let server = axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service());
+        tokio::spawn(server);
+        return base;
+    }
+
+    /// Registers a resource with the given icon, checking icons, and returns the advisories of the response.
+    async fn create_with_icon(icon_uri: &str) -> Vec<String> {
+        let mut store = HashMap::new();
+        let config = RegistrationConfig {
+            icon_check_timeout: Some(Duration::from_secs(2)),
+            ..RegistrationConfig::default()
+        };
+        let icon_uri = Iri::parse(icon_uri.to_string()).unwrap();
+        let description = ResourceDescription::builder().scope("view").icon_uri(icon_uri).build().unwrap();
+        let request = Request::builder().method(Method::POST).uri("/").body(description).unwrap();
+
+        let ids = SeededIdGenerator::new(7);
+        let response = create_resource_registration(&mut store, &ids, &config, &anonymous(), request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body = serde_json::to_value(response.body()).unwrap();
+        assert_eq!(body.get("advisories").is_some(), !response.body().advisories.is_empty());
+        let advisories = response.body().advisories.clone();
+        assert_eq!(store.len(), 1);
+        return advisories;
+    }
+
+    #[tokio::test]
+    async fn reachable_icon_earns_no_advisory() {
+        let base = serve_icon().await;
+
+        assert!(create_with_icon(&format!("{base}/icon.png")).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unusable_icon_earns_an_advisory() {
+        let base = serve_icon().await;
+        let unreachable = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/icon.png", listener.local_addr().unwrap())
+        };
+
+        let advisories = create_with_icon(&unreachable).await;
+        assert_eq!(advisories, vec![format!("The icon at {unreachable} could not be retrieved.")]);
+
+        let advisories = create_with_icon(&format!("{base}/page")).await;
+        assert_eq!(advisories, vec![format!("The icon at {base}/page is not an image.")]);
+    }
+
+    #[tokio::test]
+    async fn icons_are_not_checked_by_default() {
+        let mut store = HashMap::new();
+        let icon_uri = Iri::parse("http://127.0.0.1:1/icon.png".to_string()).unwrap();
+        let description = ResourceDescription::builder().scope("view").icon_uri(icon_uri).build().unwrap();
+        let request = Request::builder().method(Method::POST).uri("/").body(description).unwrap();
+
+        let config = RegistrationConfig::default();
+        let ids = SeededIdGenerator::new(7);
+        let response = create_resource_registration(&mut store, &ids, &config, &anonymous(), request).await.unwrap();
+
+        assert!(response.body().advisories.is_empty());
+    }
+
+    #[tokio::test]
+    async fn create_returns_representation_on_request() {
+        let mut store = HashMap::new();
+        let description = ResourceDescription::builder().scope("view").name("Photo album").build().unwrap();
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/")
+            .header("Prefer", "return=representation")
+            .body(description)
+            .unwrap();
+
+        let config = RegistrationConfig::default();
+        let (ids, alice) = (SeededIdGenerator::new(7), owner("https://alice.example/#me"));
+        let response = create_resource_registration(&mut store, &ids, &config, &alice, request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()[PREFERENCE_APPLIED], "return=representation");
+        let body = serde_json::to_value(response.body()).unwrap();
+        assert_eq!(body["_id"], response.body()._id.as_ref());
+        assert_eq!(body["resource_description"]["name"], "Photo album");
+        assert_eq!(body["resource_description"]["owner"], "https://alice.example/#me");
+    }
+
+    #[tokio::test]
+    async fn idempotent_create() {
+        let mut store = HashMap::new();
+        let nobody = anonymous();
+        let ids = SeededIdGenerator::new(7);
+        let config = RegistrationConfig::default();
+
+        let create = |name: &str| {
+            let description = ResourceDescription {
+                _id: "",
+                resource_scopes: vec!["view".into()],
+                description: None,
+                icon_uri: None,
+                name: Some(name.into()),
+                r#type: None,
+                owner: None,
+                policy_hint: None,
+            };
+            Request::builder()
+                .method(Method::POST)
+                .uri("/")
+                .header("Idempotency-Key", "KX3A-39WE")
+                .body(description)
+                .unwrap()
+        };
+
+        let first = create_resource_registration(&mut store, &ids, &config, &nobody, create("Photo Album")).await.unwrap();
+        assert_eq!(first.status(), StatusCode::CREATED);
+        let id = first.body()._id.to_string();
+
+        let retry = create_resource_registration(&mut store, &ids, &config, &nobody, create("Photo Album")).await.unwrap();
+        assert_eq!(retry.status(), StatusCode::OK);
+        assert_eq!(retry.body()._id, id);
+
+        let conflict = create_resource_registration(&mut store, &ids, &config, &nobody, create("Holiday Photos")).await.unwrap_err();
+        assert_eq!(conflict.status(), StatusCode::CONFLICT);
+
+        assert_eq!(store.len(), 1);
+        assert_eq!(store[&id].name, Some("Photo Album".into()));
+    }
+
+    #[tokio::test]
+    async fn idempotency_keys_are_scoped_to_the_owner() {
+        let mut store = HashMap::new();
+        let ids = SeededIdGenerator::new(7);
+        let config = RegistrationConfig::default();
+        let (alice, bob) = (owner("https://alice.example/#me"), owner("did:example:bob"));
+        let create = || {
+            let description = ResourceDescription::builder().scope("view").build().unwrap();
+            let request = Request::builder().method(Method::POST).uri("/").header("Idempotency-Key", "KX3A-39WE");
+            return request.body(description).unwrap();
+        };
+
+        let alices = create_resource_registration(&mut store, &ids, &config, &alice, create()).await.unwrap();
+        let alices = alices.body()._id.to_string();
+
+        // The same key tells bob nothing about alice's resource: it registers his own.
+        let bobs = create_resource_registration(&mut store, &ids, &config, &bob, create()).await.unwrap();
+        assert_eq!(bobs.status(), StatusCode::CREATED);
+        let bobs = bobs.body()._id.to_string();
+        assert_ne!(bobs, alices);
+
+        let retry = create_resource_registration(&mut store, &ids, &config, &bob, create()).await.unwrap();
+        assert_eq!(retry.status(), StatusCode::OK);
+        assert_eq!(retry.body()._id, bobs);
+
+        // Even where bob holds the _id alice's key derives to, alice gets a resource of her own rather than a 409.
+        let squatted = format!("/{}", idempotent_id(&alice, "KX3A-39WE"));
+        store.remove(&alices);
+        let description = ResourceDescription::builder().scope("view").build().unwrap();
+        let squat = Request::builder().method(Method::PUT).uri(squatted).body(description).unwrap();
+        update_resource_registration(&mut store, &config, &bob, squat).await.unwrap();
+        let response = create_resource_registration(&mut store, &ids, &config, &alice, create()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(store.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn scopes_with_slashes_must_be_uris() {
+        let mut store = HashMap::new();
+        let nobody = anonymous();
+
+        let create = |resource_scopes: Vec<&str>| {
+            let description = ResourceDescription {
+                _id: "",
+                resource_scopes: resource_scopes.into_iter().map(Scope::from).collect(),
+                description: None,
+                icon_uri: None,
+                name: None,
+                r#type: None,
+                owner: None,
+                policy_hint: None,
+            };
+            Request::builder().method(Method::POST).uri("/").body(description).unwrap()
+        };
+
+        let ids = SeededIdGenerator::new(7);
+        let config = RegistrationConfig::default();
+        let accepted = create_resource_registration(&mut store, &ids, &config, &nobody, create(vec!["read-public"])).await;
+        assert_eq!(accepted.unwrap().status(), StatusCode::CREATED);
+        let accepted = create_resource_registration(&mut store, &ids, &config, &nobody, create(vec!["http://www.example.com/scopes/all"])).await;
+        assert_eq!(accepted.unwrap().status(), StatusCode::CREATED);
+        let rejected = create_resource_registration(&mut store, &ids, &config, &nobody, create(vec!["view", "read/private"])).await;
+        assert_eq!(rejected.unwrap_err().status(), StatusCode::BAD_REQUEST);
+        let rejected = create_resource_registration(&mut store, &ids, &config, &nobody, create(vec![""])).await;
+        assert_eq!(rejected.unwrap_err().status(), StatusCode::BAD_REQUEST);
+
+        assert_eq!(store.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn list_shallow_and_deep() {
+        let config = RegistrationConfig::default();
+        let mut store = HashMap::new();
+        let description = ResourceDescription {
+            _id: "",
+            resource_scopes: vec!["view".into()],
+            description: None,
+            icon_uri: None,
+            name: Some("Photo Album".into()),
+            r#type: None,
+            owner: None,
+            policy_hint: None,
+        };
+        store.insert("KX3A-39WE".to_string(), description);
+
+        let request = Request::builder().method(Method::GET).uri("/").body(()).unwrap();
+        let response = list_resource_registration(&mut store, &config, &anonymous(), &request).await.unwrap();
+        assert_eq!(serde_json::to_value(response.body()).unwrap(), serde_json::json!(["KX3A-39WE"]));
+
+        let request = Request::builder().method(Method::GET).uri("/?deep=true").body(()).unwrap();
+        let response = list_resource_registration(&mut store, &config, &anonymous(), &request).await.unwrap();
+        assert_eq!(
+            serde_json::to_value(response.body()).unwrap(),
+            serde_json::json!([{
+                "_id": "KX3A-39WE",
+                "resource_description": { "_id": "", "resource_scopes": ["view"], "name": "Photo Album" }
+            }])
+        );
+
+        let request = Request::builder().method(Method::GET).uri("/?deep=yes").body(()).unwrap();
+        let response = list_resource_registration(&mut store, &config, &anonymous(), &request).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn list_in_pages() {
+        let config = RegistrationConfig::default();
+        let mut store = HashMap::new();
+        for id in ["KX3A-39WE", "9UQU-DUWW", "3E2P-ZB8F", "QG5R-8N3T", "ZU2W-7AAT"] {
+            store.insert(id.to_string(), ResourceDescription::builder().scope("view").build().unwrap());
+        }
+        let nobody = anonymous();
+
+        let request = Request::builder().method(Method::GET).uri("/?limit=2").body(()).unwrap();
+        let response = list_resource_registration(&mut store, &config, &nobody, &request).await.unwrap();
+        let ResourceList::Ids(Listing::Page(page)) = response.body() else { panic!("not a page of _ids") };
+        assert_eq!((page.items.len(), page.total, page.offset), (2, 5, 0));
+        let next = page.next_cursor.clone().unwrap();
+        assert_eq!(
+            serde_json::to_value(response.body()).unwrap(),
+            serde_json::json!({ "items": ["3E2P-ZB8F", "9UQU-DUWW"], "total": 5, "next": next })
+        );
+
+        let request = Request::builder().method(Method::GET).uri(format!("/?limit=2&cursor={next}&deep=true"));
+        let request = request.body(()).unwrap();
+        let response = list_resource_registration(&mut store, &config, &nobody, &request).await.unwrap();
+        let ResourceList::Descriptions(Listing::Page(page)) = response.body() else { panic!("not a page") };
+        let ids: Vec<&str> = page.items.iter().map(|item| item._id.as_ref()).collect();
+        assert_eq!((ids, page.total, page.offset), (vec!["KX3A-39WE", "QG5R-8N3T"], 5, 2));
+        let next = page.next_cursor.clone().unwrap();
+        assert_eq!(config.cursors.open(&next).as_deref(), Some("QG5R-8N3T"));
+        assert!(page.items.iter().all(|item| item.resource_description.is_some()));
+
+        let request = Request::builder().method(Method::GET).uri(format!("/?cursor={next}&limit=2")).body(()).unwrap();
+        let response = list_resource_registration(&mut store, &config, &nobody, &request).await.unwrap();
+        assert_eq!(
+            serde_json::to_value(response.body()).unwrap(),
+            serde_json::json!({ "items": ["ZU2W-7AAT"], "total": 5 })
+        );
+
+        for invalid in ["/?limit=0", "/?limit=2&cursor=QG5R-8N3T"] {
+            let request = Request::builder().method(Method::GET).uri(invalid).body(()).unwrap();
+            let error = list_resource_registration(&mut store, &config, &nobody, &request).await.unwrap_err();
+            assert_eq!(error.body().error_code, "invalid_request");
+        }
+    }
+
+    #[test]
+    fn list_filtered_by_scopes() {
+        let config = RegistrationConfig::default();
+        let mut store = HashMap::new();
+        for (id, resource_scopes) in [
+            ("KX3A-39WE", vec!["view", "print"]),
+            ("9UQU-DUWW", vec!["view", "http://photoz.example.com/dev/scopes/edit"]),
+            ("3E2P-ZB8F", vec!["print"]),
+        ] {
+            let description = ResourceDescription {
+                _id: "",
+                resource_scopes: resource_scopes.into_iter().map(Scope::from).collect(),
+                description: None,
+                icon_uri: None,
+                name: None,
+                r#type: None,
+                owner: None,
+                policy_hint: None,
+            };
+            store.insert(id.to_string(), description);
+        }
+
+        let nobody = anonymous();
+        let mut list = |uri: &str| {
+            let request = Request::builder().method(Method::GET).uri(uri).body(()).unwrap();
+            let response = list_resource_registration(&mut store, &config, &nobody, &request);
+            let response = futures::executor::block_on(response).unwrap();
+            let mut ids: Vec<String> = serde_json::from_value(serde_json::to_value(response.body()).unwrap()).unwrap();
+            ids.sort();
+            return ids;
+        };
+
+        assert_eq!(list("/?scope=view"), vec!["9UQU-DUWW", "KX3A-39WE"]);
+        assert_eq!(list("/?scope=view&scope=print"), vec!["KX3A-39WE"]);
+        assert_eq!(list("/?scope=http%3A%2F%2Fphotoz.example.com%2Fdev%2Fscopes%2Fedit"), vec!["9UQU-DUWW"]);
+        assert!(list("/?scope=edit").is_empty());
+    }
+
+    #[tokio::test]
+    async fn concurrent_conditional_creates() {
+        let store = Arc::new(Mutex::new(HashMap::new()));
+
+        let create = |store: Arc<Mutex<HashMap<String, ResourceDescription>>>| async move {
+            let description = ResourceDescription {
+                _id: "",
+                resource_scopes: vec!["view".into()],
+                description: None,
+                icon_uri: None,
+                name: None,
+                r#type: None,
+                owner: None,
+                policy_hint: None,
+            };
+            let request = Request::builder()
+                .method(Method::PUT)
+                .uri("/KX3A-39WE")
+                .header(IF_NONE_MATCH, "*")
+                .body(description)
+                .unwrap();
+
+            let mut store = store.lock().await;
+            let config = RegistrationConfig::default();
+            match update_resource_registration(&mut *store, &config, &anonymous(), request).await {
+                Ok(response) => response.status(),
+                Err(response) => response.status(),
+            }
+        };
+
+        let (first, second) = tokio::join!(
+            tokio::spawn(create(store.clone())),
+            tokio::spawn(create(store.clone()))
+        );
+        let mut statuses = vec![first.unwrap(), second.unwrap()];
+        statuses.sort();
+
+        assert_eq!(statuses, vec![StatusCode::CREATED, StatusCode::PRECONDITION_FAILED]);
+        assert_eq!(store.lock().await.len(), 1);
+    }
+
     // GET /rreg/KX3A-39WE HTTP/1.1
     // Authorization: Bearer MHg3OUZEQkZBMjcx
     // ...
@@ -384,4 +1699,466 @@ mod tests {
     //   "9UQU-DUWW"
     // ]
 
+    #[tokio::test]
+    async fn paths_under_a_base_path() {
+        let description = ResourceDescription::builder().scope("view").build().unwrap();
+        let mut store = HashMap::from([("KX3A-39WE".to_string(), description)]);
+        let nobody = anonymous();
+        let config = RegistrationConfig { base_path: "/rreg".to_string(), ..RegistrationConfig::default() };
+        let get = |uri: &str| Request::builder().method(Method::GET).uri(uri).body(()).unwrap();
+
+        for list in ["/rreg/", "/rreg", "/"] {
+            let request = get(list);
+            let response = list_resource_registration(&mut store, &config, &nobody, &request).await.unwrap();
+            assert_eq!(serde_json::to_value(response.body()).unwrap(), serde_json::json!(["KX3A-39WE"]));
+        }
+        let error = list_resource_registration(&mut store, &config, &nobody, &get("/rreg/KX3A-39WE")).await.unwrap_err();
+        assert_eq!(error.body().error_code, "invalid_request");
+
+        for read in ["/rreg/KX3A-39WE", "/KX3A-39WE"] {
+            let request = get(read);
+            let response = read_resource_registration(&mut store, &config, &nobody, &request).await.unwrap();
+            assert_eq!(response.body()._id, "KX3A-39WE");
+        }
+        // Only whole segments make up the base path.
+        let request = get("/rregKX3A-39WE");
+        let error = read_resource_registration(&mut store, &config, &nobody, &request).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::NOT_FOUND);
+
+        let request = Request::builder().method(Method::DELETE).uri("/rreg/KX3A-39WE").body(()).unwrap();
+        let response = delete_resource_registration(&mut store, &config, &nobody, &request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(store.is_empty());
+    }
+
+    #[tokio::test]
+    async fn resource_id_is_the_last_path_segment() {
+        let config = RegistrationConfig::default();
+        let description = ResourceDescription::builder().scope("view").build().unwrap();
+        let mut store = HashMap::from([("KX3A-39WE".to_string(), description)]);
+
+        let request = Request::builder().method(Method::GET).uri("/rreg/KX3A-39WE").body(()).unwrap();
+        let response = read_resource_registration(&mut store, &config, &anonymous(), &request).await.unwrap();
+        assert_eq!(response.body()._id, "KX3A-39WE");
+
+        let update = ResourceDescription::builder().scope("view").scope("print").build().unwrap();
+        let request = Request::builder().method(Method::PUT).uri("/uma/rreg/KX3A-39WE/").body(update).unwrap();
+        let response = update_resource_registration(&mut store, &config, &anonymous(), request).await.unwrap();
+        assert_eq!(response.body()._id, "KX3A-39WE");
+        assert_eq!(store["KX3A-39WE"].resource_scopes.len(), 2);
+        assert!(!store.contains_key("uma/rreg/KX3A-39WE/"));
+
+        let request = Request::builder().method(Method::DELETE).uri("/rreg/KX3A-39WE").body(()).unwrap();
+        let response = delete_resource_registration(&mut store, &config, &anonymous(), &request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(store.is_empty());
+    }
+
+    #[tokio::test]
+    async fn deleted_resources_are_gone_for_a_while() {
+        let config = RegistrationConfig {
+            tombstones: Some(Tombstones::new(Duration::from_secs(60))),
+            ..RegistrationConfig::default()
+        };
+        let description = ResourceDescription::builder().scope("view").build().unwrap();
+        let mut store = HashMap::from([("KX3A-39WE".to_string(), description)]);
+        let request = |method: Method, id: &str| {
+            return Request::builder().method(method).uri(format!("/{id}")).body(()).unwrap();
+        };
+
+        let never_existed = request(Method::GET, "9UQU-DUWW");
+        let response = read_resource_registration(&mut store, &config, &anonymous(), &never_existed).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let delete = request(Method::DELETE, "KX3A-39WE");
+        delete_resource_registration(&mut store, &config, &anonymous(), &delete).await.unwrap();
+        let read = request(Method::GET, "KX3A-39WE");
+        let response = read_resource_registration(&mut store, &config, &anonymous(), &read).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::GONE);
+        assert_eq!(response.body().error_code, "not_found");
+        let response = delete_resource_registration(&mut store, &config, &anonymous(), &delete).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::GONE);
+    }
+
+    #[tokio::test]
+    async fn tombstones_expire() {
+        for tombstones in [None, Some(Tombstones::new(Duration::ZERO))] {
+            let config = RegistrationConfig { tombstones, ..RegistrationConfig::default() };
+            let description = ResourceDescription::builder().scope("view").build().unwrap();
+            let mut store = HashMap::from([("KX3A-39WE".to_string(), description)]);
+
+            let delete = Request::builder().method(Method::DELETE).uri("/KX3A-39WE").body(()).unwrap();
+            delete_resource_registration(&mut store, &config, &anonymous(), &delete).await.unwrap();
+            let read = Request::builder().method(Method::GET).uri("/KX3A-39WE").body(()).unwrap();
+            let response = read_resource_registration(&mut store, &config, &anonymous(), &read).await.unwrap_err();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test]
+    async fn percent_encoded_resource_id() {
+        let config = RegistrationConfig::default();
+        let description = ResourceDescription::builder().scope("view").build().unwrap();
+        let mut store = HashMap::from([("http://photoz.example.com/album".to_string(), description)]);
+
+        let uri = "/rreg/http%3A%2F%2Fphotoz.example.com%2Falbum";
+        let request = Request::builder().method(Method::GET).uri(uri).body(()).unwrap();
+        let response = read_resource_registration(&mut store, &config, &anonymous(), &request).await.unwrap();
+
+        assert_eq!(response.body()._id, "http://photoz.example.com/album");
+    }
+
+    #[tokio::test]
+    async fn unsupported_method_on_collection_lists_allowed_methods() {
+        let config = RegistrationConfig::default();
+        let mut store = HashMap::new();
+        let request = Request::builder().method(Method::DELETE).uri("/").body(()).unwrap();
+
+        let response = list_resource_registration(&mut store, &config, &anonymous(), &request).await.unwrap_err();
+
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[http::header::ALLOW], "GET, POST");
+    }
+
+    #[tokio::test]
+    async fn unsupported_method_on_item_lists_allowed_methods() {
+        let config = RegistrationConfig::default();
+        let mut store = HashMap::new();
+        let request = Request::builder().method(Method::POST).uri("/KX3A-39WE").body(()).unwrap();
+
+        let response = read_resource_registration(&mut store, &config, &anonymous(), &request).await.unwrap_err();
+
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[http::header::ALLOW], "GET, PUT, PATCH, DELETE");
+    }
+
+    fn batch(scopes: &[&[&str]]) -> Request<Vec<ResourceDescription>> {
+        let descriptions = scopes
+            .iter()
+            .map(|scopes| ResourceDescription {
+                _id: "",
+                resource_scopes: scopes.iter().map(|&scope| scope.into()).collect(),
+                description: None,
+                icon_uri: None,
+                name: None,
+                r#type: None,
+                owner: None,
+                policy_hint: None,
+            })
+            .collect();
+        return Request::builder().method(Method::POST).uri("/").body(descriptions).unwrap();
+    }
+
+    #[tokio::test]
+    async fn create_batch() {
+        let mut store = HashMap::new();
+        let expected = SeededIdGenerator::new(7);
+        let expected: Vec<String> = (0..3).map(|_| expected.generate()).collect();
+
+        let request = batch(&[&["view"], &["print"], &["view", "http://www.example.com/scopes/all"]]);
+        let config = RegistrationConfig::default();
+        let ids = SeededIdGenerator::new(7);
+        let response = create_resource_registrations(&mut store, &ids, &config, &anonymous(), request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let ids: Vec<&str> = response.body().iter().map(|created| created._id.as_ref()).collect();
+        assert_eq!(ids, expected);
+        assert_eq!(serde_json::to_value(response.body()).unwrap()[1], serde_json::json!({ "_id": expected[1] }));
+        assert_eq!(store[&expected[1]].resource_scopes, vec!["print"]);
+    }
+
+    #[tokio::test]
+    async fn create_batch_with_invalid_entry() {
+        let mut store = HashMap::new();
+        let nobody = anonymous();
+
+        let request = batch(&[&["view"], &["view", "not a scope"], &["print"]]);
+        let config = RegistrationConfig::default();
+        let ids = SeededIdGenerator::new(7);
+        let response = create_resource_registrations(&mut store, &ids, &config, &nobody, request).await.unwrap_err();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.body().error_code, "invalid_request");
+        assert!(response.body().error_description.as_deref().unwrap().contains("index 1"));
+        assert!(store.is_empty());
+    }
+
+    #[tokio::test]
+    async fn conditional_read() {
+        let config = RegistrationConfig::default();
+        let mut store = HashMap::new();
+        let description = |scopes: Vec<&str>| ResourceDescription {
+            _id: "",
+            resource_scopes: scopes.into_iter().map(Scope::from).collect(),
+            description: None,
+            icon_uri: None,
+            name: Some("Photo Album".into()),
+            r#type: None,
+            owner: None,
+            policy_hint: None,
+        };
+        store.insert("KX3A-39WE".to_string(), description(vec!["view"]));
+        let read = |if_none_match: Option<&str>| {
+            let request = Request::builder().method(Method::GET).uri("/KX3A-39WE");
+            let request = match if_none_match {
+                Some(tags) => request.header(IF_NONE_MATCH, tags),
+                None => request,
+            };
+            request.body(()).unwrap()
+        };
+
+        let request = read(None);
+        let response = read_resource_registration(&mut store, &config, &anonymous(), &request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[ETAG].to_str().unwrap().to_string();
+
+        // Identical descriptions serialize, and so are tagged, identically.
+        store.insert("KX3A-39WE".to_string(), description(vec!["view"]));
+        let request = read(Some(&format!("\"other\", W/{etag}")));
+        let response = read_resource_registration(&mut store, &config, &anonymous(), &request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[ETAG], etag.as_str());
+
+        store.insert("KX3A-39WE".to_string(), description(vec!["view", "print"]));
+        let request = read(Some(&etag));
+        let response = read_resource_registration(&mut store, &config, &anonymous(), &request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(response.headers()[ETAG], etag.as_str());
+    }
+
+    #[tokio::test]
+    async fn type_validation() {
+        let mut store = HashMap::new();
+        let ids = SeededIdGenerator::new(7);
+        let nobody = anonymous();
+        let request = |method: Method, uri: &str, r#type: &str| {
+            let description = ResourceDescription::builder().scope("view").r#type(r#type).build().unwrap();
+            return Request::builder().method(method).uri(uri).body(description).unwrap();
+        };
+        let post = |r#type: &str| request(Method::POST, "/", r#type);
+        let album = "http://www.example.com/rsrcs/photoalbum";
+
+        let lenient = RegistrationConfig::default();
+        let created = create_resource_registration(&mut store, &ids, &lenient, &nobody, post("album"));
+        let id = created.await.unwrap().body()._id.to_string();
+        let path = format!("/{id}");
+        let put = |r#type: &str| request(Method::PUT, &path, r#type);
+        let updated = update_resource_registration(&mut store, &lenient, &nobody, put("photos"));
+        assert_eq!(updated.await.unwrap().status(), StatusCode::OK);
+
+        let strict = RegistrationConfig { type_validation: TypeValidation::Strict, ..RegistrationConfig::default() };
+        let created = create_resource_registration(&mut store, &ids, &strict, &nobody, post("album"));
+        assert_eq!(created.await.unwrap_err().body().error_code, "invalid_request");
+        let updated = update_resource_registration(&mut store, &strict, &nobody, put("photos"));
+        assert_eq!(updated.await.unwrap_err().body().error_code, "invalid_request");
+        assert_eq!(store[&id].r#type.as_deref(), Some("photos"));
+
+        let created = create_resource_registration(&mut store, &ids, &strict, &nobody, post(album));
+        assert_eq!(created.await.unwrap().status(), StatusCode::CREATED);
+        let updated = update_resource_registration(&mut store, &strict, &nobody, put(album));
+        assert_eq!(updated.await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn caps_the_number_of_scopes() {
+        let mut store = HashMap::new();
+        let ids = SeededIdGenerator::new(7);
+        let (config, nobody) = (RegistrationConfig::default(), anonymous());
+        let post = |count: usize| {
+            let builder = (0..count).fold(ResourceDescription::builder(), |builder, n| builder.scope(format!("s{n}")));
+            return Request::builder().method(Method::POST).uri("/").body(builder.build().unwrap()).unwrap();
+        };
+
+        let created = create_resource_registration(&mut store, &ids, &config, &nobody, post(DEFAULT_MAX_SCOPES));
+        assert_eq!(created.await.unwrap().status(), StatusCode::CREATED);
+        let created = create_resource_registration(&mut store, &ids, &config, &nobody, post(DEFAULT_MAX_SCOPES + 1));
+        assert_eq!(created.await.unwrap_err().body().error_code, "invalid_request");
+        assert_eq!(store.len(), 1);
+
+        let description = post(DEFAULT_MAX_SCOPES + 1).into_body();
+        let too_many = ResourceDescriptionError::TooManyScopes(DEFAULT_MAX_SCOPES);
+        assert_eq!(description.validate(DEFAULT_MAX_SCOPES, TextValidation::Lenient), Err(too_many));
+        assert_eq!(description.validate(DEFAULT_MAX_SCOPES + 1, TextValidation::Lenient), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn normalizes_names_to_nfc() {
+        let mut store = HashMap::new();
+        let ids = SeededIdGenerator::new(7);
+        let nobody = anonymous();
+        let post = || {
+            let description = ResourceDescription::builder().scope("view").name("Cafe\u{301} photos").build().unwrap();
+            return Request::builder().method(Method::POST).uri("/").body(description).unwrap();
+        };
+
+        let lenient = RegistrationConfig::default();
+        let created = create_resource_registration(&mut store, &ids, &lenient, &nobody, post()).await.unwrap();
+        let id = created.body()._id.to_string();
+        assert_eq!(store[&id].name, Some("Cafe\u{301} photos".into()));
+
+        let normalized = TextValidation::Normalized;
+        let normalized = RegistrationConfig { text_validation: normalized, ..RegistrationConfig::default() };
+        let created = create_resource_registration(&mut store, &ids, &normalized, &nobody, post()).await.unwrap();
+        let id = created.body()._id.to_string();
+        assert_eq!(store[&id].name, Some("Caf\u{e9} photos".into()));
+    }
+
+    #[tokio::test]
+    async fn rejects_control_characters_when_strict() {
+        let mut store = HashMap::new();
+        let ids = SeededIdGenerator::new(7);
+        let nobody = anonymous();
+        let post = || {
+            let description = ResourceDescription::builder().scope("view").name("Photo\u{7}Album").build().unwrap();
+            return Request::builder().method(Method::POST).uri("/").body(description).unwrap();
+        };
+
+        let strict = RegistrationConfig { text_validation: TextValidation::Strict, ..RegistrationConfig::default() };
+        let created = create_resource_registration(&mut store, &ids, &strict, &nobody, post());
+        assert_eq!(created.await.unwrap_err().body().error_code, "invalid_request");
+        assert!(store.is_empty());
+
+        let lenient = RegistrationConfig::default();
+        let created = create_resource_registration(&mut store, &ids, &lenient, &nobody, post());
+        assert_eq!(created.await.unwrap().status(), StatusCode::CREATED);
+
+        let description = post().into_body();
+        let control = ResourceDescriptionError::ControlCharacter("Photo\u{7}Album".into());
+        assert_eq!(description.validate(DEFAULT_MAX_SCOPES, TextValidation::Strict), Err(control));
+    }
+
+    #[tokio::test]
+    async fn owners_do_not_see_each_others_resources() {
+        let mut store = HashMap::new();
+        let ids = SeededIdGenerator::new(7);
+        let config = RegistrationConfig::default();
+        let (alice, bob) = (owner("https://alice.example/#me"), owner("did:example:bob"));
+        let create = |scope: &str| {
+            let description = ResourceDescription::builder().scope(scope).build().unwrap();
+            return Request::builder().method(Method::POST).uri("/").body(description).unwrap();
+        };
+
+        let album = create_resource_registration(&mut store, &ids, &config, &alice, create("view")).await.unwrap();
+        let album = album.body()._id.to_string();
+        let diary = create_resource_registration(&mut store, &ids, &config, &bob, create("read")).await.unwrap();
+        let diary = diary.body()._id.to_string();
+
+        let list = Request::builder().method(Method::GET).uri("/").body(()).unwrap();
+        let listed = list_resource_registration(&mut store, &config, &alice, &list).await.unwrap();
+        assert_eq!(serde_json::to_value(listed.body()).unwrap(), serde_json::json!([album]));
+        let listed = list_resource_registration(&mut store, &config, &bob, &list).await.unwrap();
+        assert_eq!(serde_json::to_value(listed.body()).unwrap(), serde_json::json!([diary]));
+        let listed = list_resource_registration(&mut store, &config, &anonymous(), &list).await.unwrap();
+        assert_eq!(serde_json::to_value(listed.body()).unwrap(), serde_json::json!([]));
+
+        let read = Request::builder().method(Method::GET).uri(format!("/{album}")).body(()).unwrap();
+        let response = read_resource_registration(&mut store, &config, &alice, &read).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let error = read_resource_registration(&mut store, &config, &bob, &read).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::NOT_FOUND);
+
+        let description = ResourceDescription::builder().scope("print").build().unwrap();
+        let update = Request::builder().method(Method::PUT).uri(format!("/{album}")).body(description).unwrap();
+        let error = update_resource_registration(&mut store, &config, &bob, update).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::NOT_FOUND);
+
+        let delete = Request::builder().method(Method::DELETE).uri(format!("/{album}")).body(()).unwrap();
+        let error = delete_resource_registration(&mut store, &config, &bob, &delete).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::NOT_FOUND);
+
+        assert_eq!(store[&album].resource_scopes, vec!["view"]);
+        assert_eq!(store[&album].owner.as_ref(), alice.owner());
+    }
+
+    #[tokio::test]
+    async fn quota_per_owner() {
+        let mut store = HashMap::new();
+        let ids = SeededIdGenerator::new(7);
+        let config = RegistrationConfig { max_resources_per_owner: Some(2), ..RegistrationConfig::default() };
+
+        let create = || {
+            let description = ResourceDescription::builder().scope("view").build().unwrap();
+            return Request::builder().method(Method::POST).uri("/").body(description).unwrap();
+        };
+        let (alice, bob) = (owner("https://alice.example/#me"), owner("did:example:bob"));
+
+        let first = create_resource_registration(&mut store, &ids, &config, &alice, create()).await;
+        let first = first.unwrap().body()._id.to_string();
+        let second = create_resource_registration(&mut store, &ids, &config, &alice, create()).await;
+        assert_eq!(second.unwrap().status(), StatusCode::CREATED);
+
+        let exceeded = create_resource_registration(&mut store, &ids, &config, &alice, create()).await;
+        let exceeded = exceeded.unwrap_err();
+        assert_eq!(exceeded.status(), StatusCode::FORBIDDEN);
+        assert_eq!(exceeded.body().error_code, "quota_exceeded");
+        assert_eq!(store.len(), 2);
+
+        let read = Request::builder().method(Method::GET).uri(format!("/{first}")).body(()).unwrap();
+        let response = read_resource_registration(&mut store, &config, &alice, &read).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let other = create_resource_registration(&mut store, &ids, &config, &bob, create()).await;
+        assert_eq!(other.unwrap().status(), StatusCode::CREATED);
+        let owner = Identifier::parse("https://alice.example/#me").unwrap();
+        assert_eq!(store.values().filter(|description| description.owner == Some(owner.clone())).count(), 2);
+
+        let exceeded = create_resource_registrations(&mut store, &ids, &config, &alice, batch(&[&["view"]])).await;
+        let exceeded = exceeded.unwrap_err();
+        assert_eq!(exceeded.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn validation_looks_up_idempotency_keys_as_create_does() {
+        let mut store = HashMap::new();
+        let ids = SeededIdGenerator::new(7);
+        let config = RegistrationConfig { max_resources_per_owner: Some(1), ..RegistrationConfig::default() };
+        let (alice, bob) = (owner("https://alice.example/#me"), owner("did:example:bob"));
+        let request = |key: &str, scope: &str| {
+            let description = ResourceDescription::builder().scope(scope).build().unwrap();
+            let request = Request::builder().method(Method::POST).uri("/").header("Idempotency-Key", key);
+            return request.body(description).unwrap();
+        };
+
+        let alices = create_resource_registration(&mut store, &ids, &config, &alice, request("KX3A-39WE", "view"));
+        let alices = alices.await.unwrap().body()._id.to_string();
+        let bobs = create_resource_registration(&mut store, &ids, &config, &bob, request("7M2B-PQ4D", "view")).await;
+        assert_eq!(bobs.unwrap().status(), StatusCode::CREATED);
+
+        // A retry of alice's registration is valid, over her quota or not, and a different one under her key is not.
+        let retry = validate_resource_registration(&store, &config, &alice, request("KX3A-39WE", "view")).await;
+        assert_eq!(retry.unwrap().status(), StatusCode::OK);
+        let conflict = validate_resource_registration(&store, &config, &alice, request("KX3A-39WE", "print")).await;
+        assert_eq!(conflict.unwrap_err().status(), StatusCode::CONFLICT);
+
+        // Alice's _id as bob's key is as good as fresh: it tells bob nothing about her resource, nor spares his quota.
+        for scope in ["view", "print"] {
+            let error = validate_resource_registration(&store, &config, &bob, request(&alices, scope)).await;
+            assert_eq!(error.unwrap_err().body().error_code, "quota_exceeded");
+        }
+    }
+
+    #[tokio::test]
+    async fn puts_at_new_ids_count_against_the_quota() {
+        let mut store = HashMap::new();
+        let config = RegistrationConfig { max_resources_per_owner: Some(1), ..RegistrationConfig::default() };
+        let alice = owner("https://alice.example/#me");
+        let put = |id: &str, scope: &str| {
+            let description = ResourceDescription::builder().scope(scope).build().unwrap();
+            return Request::builder().method(Method::PUT).uri(format!("/{id}")).body(description).unwrap();
+        };
+
+        let first = update_resource_registration(&mut store, &config, &alice, put("KX3A-39WE", "view")).await;
+        assert_eq!(first.unwrap().status(), StatusCode::OK);
+
+        let exceeded = update_resource_registration(&mut store, &config, &alice, put("7M2B-PQ4D", "view")).await;
+        let exceeded = exceeded.unwrap_err();
+        assert_eq!(exceeded.status(), StatusCode::FORBIDDEN);
+        assert_eq!(exceeded.body().error_code, "quota_exceeded");
+        assert_eq!(store.len(), 1);
+
+        // Replacing a registered resource adds nothing to count.
+        let replaced = update_resource_registration(&mut store, &config, &alice, put("KX3A-39WE", "print")).await;
+        assert_eq!(replaced.unwrap().status(), StatusCode::OK);
+        assert_eq!(store["KX3A-39WE"].resource_scopes, vec!["print"]);
+    }
 }
@@ -0,0 +1,1325 @@
+//! https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-grant-2.0.html#rfc.section.3.3
+//! https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-grant-2.0.html#uma-grant-type
+//!
+//! The client makes a request to the token endpoint in order to obtain an RPT. The authorization server assesses the
+//! permissions bound to the request against the resource owner's policy conditions and, if successful, issues an RPT
+//! representing the granted permissions.
+//!
+//! [NO-SPEC] Besides the UMA grant itself, the token endpoint optionally supports a refresh_token style grant
+//! ([RFC6749] Section 6), so that clients holding a short-lived RPT can rotate it without re-running the full ticket
+//! flow. Each refresh token is single use: a successful refresh invalidates it (and the RPT it was issued with) and
+//! returns a fresh pair. Permissions are re-validated against the currently registered resources on every refresh.
+//!
+//! Permission tickets are single use as well. Redeeming a ticket removes it from the store, and whenever the
+//! authorization server answers with need_info it rotates the ticket, handing the client a fresh value; presenting an
+//! old value afterwards yields invalid_grant.
+
+use crate::clock::{Clock, SystemClock};
+use crate::ids::{IdGenerator, ResourceId};
+use crate::storage::KeyValueStore;
+use http::{Method, Request, Response, StatusCode};
+use tracing::field::Empty;
+use tracing::instrument;
+use oxiri::Iri;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::result;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::errors::{catch_errors, ErrorMessage, INVALID_REQUEST, UNSUPPORTED_METHOD_TYPE};
+use super::federation::ResourceDescription;
+use super::grants::{
+    AssessmentOutcome, AuthorizationPolicy, ClaimSet, ClaimToken, ClaimTokenConfig, PersistedClaimsToken,
+    RequiredClaims,
+};
+use super::permission::{IssuedTicket, Permission, PermissionTicketStore, INVALID_SCOPE};
+
+/// A permission as granted by the authorization server, bound to an RPT or a refresh token.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct GrantedPermission {
+    /// REQUIRED. The identifier of a previously registered resource to which access has been granted.
+    pub resource_id: ResourceId,
+
+    /// REQUIRED. The scopes, each registered for the referenced resource, to which access has been granted.
+    pub resource_scopes: Vec<String>,
+}
+
+impl GrantedPermission {
+    pub fn new(resource_id: impl Into<ResourceId>, resource_scopes: Vec<String>) -> Self {
+        Self {
+            resource_id: resource_id.into(),
+            resource_scopes,
+        }
+    }
+}
+
+/// An RPT as remembered by the authorization server.
+#[derive(Debug, Clone)]
+pub struct IssuedRpt {
+    pub permissions: Vec<GrantedPermission>,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+/// [NO-SPEC] A refresh token as remembered by the authorization server. It records the RPT it was issued with, so that
+/// rotating the refresh token also retires that RPT.
+#[derive(Debug, Clone)]
+pub struct RefreshGrant {
+    pub rpt: String,
+    pub permissions: Vec<GrantedPermission>,
+    pub exp: i64,
+}
+
+/// [NO-SPEC] Settings of the token endpoint.
+#[derive(Debug, Clone)]
+pub struct TokenConfig {
+    /// Lifetime of an issued RPT.
+    pub rpt_lifetime: Duration,
+
+    /// Lifetime of an issued refresh token, or `None` to not issue refresh tokens at all.
+    pub refresh_token_lifetime: Option<Duration>,
+
+    /// Whether to echo the granted permissions in the token response, sparing the resource server an introspection.
+    pub echo_permissions: bool,
+
+    /// How long a PCT lasts after it was last issued or used, or `None` for PCTs to last indefinitely.
+    pub pct_lifetime: Option<Duration>,
+
+    /// What issued tokens, tickets and PCTs are dated by and checked for expiry against.
+    pub clock: Arc<dyn Clock>,
+
+    /// The issuers and audiences of the claim tokens clients may push. By default, none are accepted.
+    pub claim_tokens: ClaimTokenConfig,
+}
+
+impl Default for TokenConfig {
+    fn default() -> Self {
+        Self {
+            rpt_lifetime: Duration::from_secs(5 * 60),
+            refresh_token_lifetime: None,
+            echo_permissions: false,
+            pct_lifetime: None,
+            clock: Arc::new(SystemClock),
+            claim_tokens: ClaimTokenConfig::default(),
+        }
+    }
+}
+
+/// https://datatracker.ietf.org/doc/html/rfc6749#section-6
+///
+/// The parameters the client sends in the "application/x-www-form-urlencoded" body of a request to the token
+/// endpoint, for any of the grant types it supports.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct TokenRequest {
+    /// REQUIRED. The grant type of the request.
+    pub grant_type: String,
+
+    /// REQUIRED for the refresh_token grant type. The refresh token issued to the client.
+    pub refresh_token: Option<String>,
+
+    /// REQUIRED for the uma-ticket grant type. See `UmaTicketRequest`.
+    pub ticket: Option<String>,
+
+    /// OPTIONAL for the uma-ticket grant type. See `UmaTicketRequest`.
+    pub claim_token: Option<String>,
+
+    /// REQUIRED for the uma-ticket grant type if claim_token is present. See `UmaTicketRequest`.
+    pub claim_token_format: Option<String>,
+
+    /// OPTIONAL for the uma-ticket grant type. See `UmaTicketRequest`.
+    pub pct: Option<String>,
+
+    /// OPTIONAL for the uma-ticket grant type. See `UmaTicketRequest`.
+    pub rpt: Option<String>,
+
+    /// OPTIONAL. A space-delimited list of the scopes the client requests.
+    pub scope: Option<String>,
+}
+
+/// https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-grant-2.0.html#rfc.section.3.3.1
+/// https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-grant-2.0.html#uma-grant-type
+///
+/// The client makes a request to the token endpoint by sending the following parameters using the
+/// "application/x-www-form-urlencoded" format with a character encoding of UTF-8 in the HTTP request entity-body:
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UmaTicketRequest {
+    /// REQUIRED. The most recent permission ticket received by the client as part of this authorization process.
+    pub ticket: String,
+
+    /// OPTIONAL. If this parameter is used, it MUST appear together with the claim_token_format parameter. A string
+    /// containing directly pushed claim information in the indicated format. It MUST be base64url encoded unless
+    /// specified otherwise by the claim token format.
+    pub claim_token: Option<String>,
+
+    /// OPTIONAL. If this parameter is used, it MUST appear together with the claim_token parameter. A string
+    /// specifying the format of the claim token in which the client is directly pushing claims to the authorization
+    /// server.
+    pub claim_token_format: Option<String>,
+
+    /// OPTIONAL. If the authorization server previously returned a PCT along with an RPT, the client MAY include the
+    /// PCT in order to optimize the process of seeking a new RPT.
+    pub pct: Option<String>,
+
+    /// OPTIONAL. Supplying an existing RPT gives the authorization server the option of upgrading that RPT instead of
+    /// issuing a new one.
+    pub rpt: Option<String>,
+
+    /// OPTIONAL. A string of space-separated values representing requested scopes. For the authorization server to
+    /// consider any requested scope in its assessment, the client MUST have pre-registered the same scope with the
+    /// authorization server.
+    pub scope: Vec<String>,
+}
+
+impl TryFrom<TokenRequest> for UmaTicketRequest {
+    type Error = ErrorMessage;
+
+    /// Rejects another grant type with unsupported_grant_type, a missing ticket with invalid_grant, and a claim token
+    /// without its format (or vice versa) with invalid_request.
+    fn try_from(request: TokenRequest) -> result::Result<Self, Self::Error> {
+        if request.grant_type != UMA_TICKET {
+            return Err(UNSUPPORTED_GRANT_TYPE);
+        }
+        let ticket = match request.ticket {
+            Some(ticket) if !ticket.is_empty() => ticket,
+            _ => return Err(INVALID_GRANT),
+        };
+        if request.claim_token.is_some() != request.claim_token_format.is_some() {
+            return Err(ErrorMessage {
+                error_description: Some(Cow::Borrowed("claim_token and claim_token_format must appear together.")),
+                ..INVALID_REQUEST
+            });
+        }
+
+        let scope = request.scope.unwrap_or_default();
+
+        return Ok(Self {
+            ticket,
+            claim_token: request.claim_token,
+            claim_token_format: request.claim_token_format,
+            pct: request.pct,
+            rpt: request.rpt,
+            scope: scope.split(' ').filter(|scope| !scope.is_empty()).map(str::to_string).collect(),
+        });
+    }
+}
+
+/// https://datatracker.ietf.org/doc/html/rfc6749#section-5.1
+///
+/// The authorization server issues an access token and optional refresh token, and constructs the response by adding
+/// the following parameters to the entity-body of the HTTP response with a 200 (OK) status code:
+#[derive(Debug, Serialize, Clone)]
+pub struct SuccessfulResponse {
+    /// REQUIRED. The access token (here: the RPT) issued by the authorization server.
+    pub access_token: String,
+
+    /// REQUIRED. The type of the token issued, which is always Bearer.
+    pub token_type: &'static str,
+
+    /// RECOMMENDED. The lifetime in seconds of the access token.
+    pub expires_in: u64,
+
+    /// OPTIONAL. The refresh token, which can be used to obtain a new access token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+
+    /// [NO-SPEC] OPTIONAL. The permissions granted by the RPT, exactly as a later introspection would report them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<Vec<GrantedPermission>>,
+}
+
+/// https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-grant-2.0.html#rfc.section.3.3.5
+///
+/// If the authorization server's assessment succeeds, it issues an RPT with a 200 (OK) status code, in an access token
+/// response as above, extended with the following parameters:
+#[derive(Debug, Serialize, Clone)]
+pub struct UmaTokenResponse {
+    #[serde(flatten)]
+    pub token: SuccessfulResponse,
+
+    /// OPTIONAL. A persisted claims token, representing the claims collected in this authorization process, which the
+    /// client can present in a later token request to spare the requesting party their collection.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pct: Option<String>,
+
+    /// OPTIONAL. Whether the permissions of the RPT the client presented were added to the newly issued one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upgraded: Option<bool>,
+}
+
+/// https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-grant-2.0.html#rfc.section.3.3.6
+/// https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-grant-2.0.html#authorization-failure
+///
+/// If the authorization server does not have sufficient information to make an authorization determination, it
+/// responds with the HTTP 403 (Forbidden) status code and the need_info error code. It MUST provide a permission
+/// ticket, and the authorization server SHOULD rotate the ticket that was presented.
+#[derive(Debug, Serialize, Clone)]
+pub struct NeedInfo {
+    /// REQUIRED. Always need_info.
+    pub error: &'static str,
+
+    /// REQUIRED. A permission ticket that represents the same permissions as the one presented, to continue the
+    /// authorization process with.
+    pub ticket: String,
+
+    /// OPTIONAL. The claims interaction endpoint URI to which to redirect the end-user requesting party.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect_user: Option<Iri<String>>,
+
+    /// [NO-SPEC] OPTIONAL. The names of the claims the authorization server lacks, as UMA 1.0 used to hint, so that
+    /// the client knows which claims to push.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub required_claims: Vec<String>,
+
+    /// [NO-SPEC] OPTIONAL. Seconds until the rotated ticket expires, if it does; it expires when the presented one
+    /// would have.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_in: Option<u64>,
+}
+
+/// The body of a response to an uma-ticket grant request: either an RPT, or need_info with a rotated ticket.
+#[derive(Debug, Serialize, Clone)]
+#[serde(untagged)]
+pub enum UmaTicketResponse {
+    Issued(UmaTokenResponse),
+    NeedInfo(NeedInfo),
+}
+
+// https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-grant-2.0.html#authorization-failure
+
+pub const REQUEST_DENIED: ErrorMessage = ErrorMessage::new(
+    StatusCode::FORBIDDEN,
+    Cow::Borrowed("request_denied"),
+    Some(Cow::Borrowed(
+        "The client is not authorized to have these permissions.",
+    )),
+    None,
+);
+
+// https://datatracker.ietf.org/doc/html/rfc6749#section-5.2
+
+pub const INVALID_GRANT: ErrorMessage = ErrorMessage::new(
+    StatusCode::BAD_REQUEST,
+    Cow::Borrowed("invalid_grant"),
+    Some(Cow::Borrowed(
+        "The provided authorization grant is invalid, expired, revoked, or was issued to another client.",
+    )),
+    None,
+);
+
+pub const UNSUPPORTED_GRANT_TYPE: ErrorMessage = ErrorMessage::new(
+    StatusCode::BAD_REQUEST,
+    Cow::Borrowed("unsupported_grant_type"),
+    Some(Cow::Borrowed(
+        "The authorization grant type is not supported by the authorization server.",
+    )),
+    None,
+);
+
+pub const REFRESH_TOKEN: &str = "refresh_token";
+pub const UMA_TICKET: &str = "urn:ietf:params:oauth:grant-type:uma-ticket";
+
+type ResourceDescriptionStore = dyn KeyValueStore<Key = String, Value = ResourceDescription>;
+pub type RptStore = dyn KeyValueStore<Key = String, Value = IssuedRpt>;
+pub type RefreshTokenStore = dyn KeyValueStore<Key = String, Value = RefreshGrant>;
+pub type PctStore = dyn KeyValueStore<Key = String, Value = PersistedClaimsToken>;
+type Result<T> = result::Result<Response<T>, Response<ErrorMessage>>;
+
+/// Stores a new RPT for the given permissions and, if configured, a refresh token bound to it.
+pub fn issue_requesting_party_token(
+    rpts: &mut RptStore,
+    refresh_tokens: &mut RefreshTokenStore,
+    ids: &dyn IdGenerator,
+    config: &TokenConfig,
+    permissions: Vec<GrantedPermission>,
+) -> SuccessfulResponse {
+    let iat = config.clock.now();
+    let exp = iat + config.rpt_lifetime.as_secs() as i64;
+
+    let rpt = ids.generate();
+    rpts.set(rpt.clone(), IssuedRpt { permissions: permissions.clone(), iat, exp });
+
+    let refresh_token = config.refresh_token_lifetime.map(|lifetime| {
+        let refresh_token = ids.generate();
+        let exp = iat + lifetime.as_secs() as i64;
+        let grant = RefreshGrant { rpt: rpt.clone(), permissions: permissions.clone(), exp };
+        refresh_tokens.set(refresh_token.clone(), grant);
+        refresh_token
+    });
+
+    return SuccessfulResponse {
+        access_token: rpt,
+        token_type: "Bearer",
+        expires_in: config.rpt_lifetime.as_secs(),
+        refresh_token,
+        permissions: config.echo_permissions.then_some(permissions),
+    };
+}
+
+/// Redeems a permission ticket, returning the permissions it represents. A ticket can be redeemed only once; an
+/// unknown, already redeemed or, as of `now`, expired ticket yields invalid_grant.
+pub fn redeem_permission_ticket<'p>(
+    tickets: &mut PermissionTicketStore<'p>,
+    ticket: &str,
+    now: i64,
+) -> result::Result<IssuedTicket<'p>, Response<ErrorMessage>> {
+    return tickets.del(&ticket.to_string()).filter(|issued| !issued.is_expired(now)).ok_or(INVALID_GRANT.into());
+}
+
+/// Responds with need_info, rotating the ticket: the redeemed ticket is stored under a fresh one, with the same expiry,
+/// which is returned to the client together with the claims it lacks.
+pub fn need_info<'p>(
+    tickets: &mut PermissionTicketStore<'p>,
+    ids: &dyn IdGenerator,
+    issued: IssuedTicket<'p>,
+    required: RequiredClaims,
+    now: i64,
+) -> Result<NeedInfo> {
+    let expires_in = issued.expires_in(now);
+    let ticket = tickets.set(ids.generate(), issued).clone();
+
+    let body = NeedInfo {
+        error: "need_info",
+        ticket,
+        redirect_user: required.redirect_user,
+        required_claims: required.claims,
+        expires_in,
+    };
+    let response = Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-store")
+        .body(body);
+
+    return catch_errors(response);
+}
+
+/// Whether the permission still refers to a registered resource, and only to scopes registered for it.
+fn is_still_valid(resources: &ResourceDescriptionStore, permission: &GrantedPermission) -> bool {
+    match resources.get(permission.resource_id.as_string()) {
+        Some(description) => permission
+            .resource_scopes
+            .iter()
+            .all(|scope| description.resource_scopes.iter().any(|registered| registered == scope)),
+        None => false,
+    }
+}
+
+/// https://datatracker.ietf.org/doc/html/rfc6749#section-6
+///
+/// Exchanges a refresh token for a new RPT and a new refresh token. The presented refresh token, and the RPT it was
+/// issued with, are invalidated whether or not the exchange succeeds. If any of the bound permissions no longer refers
+/// to a registered resource or scope, the authorization server responds with invalid_grant.
+#[instrument(skip_all, fields(method = %request.method(), outcome = Empty))]
+pub async fn refresh_requesting_party_token(
+    resources: &ResourceDescriptionStore,
+    rpts: &mut RptStore,
+    refresh_tokens: &mut RefreshTokenStore,
+    ids: &dyn IdGenerator,
+    config: &TokenConfig,
+    request: Request<TokenRequest>,
+) -> Result<SuccessfulResponse> {
+    if (request.method() != Method::POST) {
+        return Err(UNSUPPORTED_METHOD_TYPE.into());
+    }
+
+    let TokenRequest { grant_type, refresh_token, .. } = request.into_body();
+
+    if grant_type != REFRESH_TOKEN || config.refresh_token_lifetime.is_none() {
+        return Err(UNSUPPORTED_GRANT_TYPE.into());
+    }
+
+    let grant = match refresh_token.and_then(|refresh_token| refresh_tokens.del(&refresh_token)) {
+        Some(grant) => grant,
+        None => return Err(INVALID_GRANT.into()),
+    };
+
+    rpts.del(&grant.rpt);
+
+    if grant.exp < config.clock.now() {
+        return Err(INVALID_GRANT.into());
+    }
+    if !grant.permissions.iter().all(|permission| is_still_valid(resources, permission)) {
+        return Err(INVALID_GRANT.into());
+    }
+
+    let body = issue_requesting_party_token(rpts, refresh_tokens, ids, config, grant.permissions);
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Cache-Control", "no-store")
+        .body(body);
+
+    return catch_errors(response);
+}
+
+/// Deletes the refresh tokens issued with `rpt`.
+fn revoke_refresh_tokens(refresh_tokens: &mut RefreshTokenStore, rpt: &str) {
+    let bound: Vec<String> = refresh_tokens
+        .list()
+        .filter(|refresh_token| refresh_tokens.get(refresh_token).is_some_and(|grant| grant.rpt == rpt))
+        .cloned()
+        .collect();
+    for refresh_token in bound {
+        refresh_tokens.del(&refresh_token);
+    }
+}
+
+/// Narrows the granted permissions down to the requested scopes, dropping the permissions left without any. Scopes
+/// beyond the granted ones yield invalid_scope; requesting none leaves the permissions as they are.
+pub fn narrow_to_scope<'p>(
+    permissions: Vec<Permission<'p>>,
+    scope: &[String],
+) -> result::Result<Vec<Permission<'p>>, Response<ErrorMessage>> {
+    if scope.is_empty() {
+        return Ok(permissions);
+    }
+    let granted = |requested: &String| {
+        permissions.iter().any(|permission| permission.resource_scopes.iter().any(|scope| scope == requested.as_str()))
+    };
+    if !scope.iter().all(granted) {
+        return Err(ErrorMessage {
+            error_description: Some(Cow::Borrowed("The requested scope exceeds the permissions that can be granted.")),
+            ..INVALID_SCOPE
+        }
+        .into());
+    }
+
+    let narrowed = permissions
+        .into_iter()
+        .map(|mut permission| {
+            permission.resource_scopes.retain(|granted| scope.iter().any(|requested| granted == requested.as_str()));
+            permission
+        })
+        .filter(|permission| !permission.resource_scopes.is_empty())
+        .collect();
+
+    return Ok(narrowed);
+}
+
+/// Adds `other` to `permissions`, joining the scopes of permissions for the same resource.
+fn merge_permissions(permissions: &mut Vec<GrantedPermission>, other: Vec<GrantedPermission>) {
+    for permission in other {
+        match permissions.iter_mut().find(|existing| existing.resource_id == permission.resource_id) {
+            Some(existing) => {
+                for scope in permission.resource_scopes {
+                    if !existing.resource_scopes.contains(&scope) {
+                        existing.resource_scopes.push(scope);
+                    }
+                }
+            }
+            None => permissions.push(permission),
+        }
+    }
+}
+
+/// https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-grant-2.0.html#rfc.section.3.3.1
+///
+/// Exchanges a permission ticket for an RPT. The ticket is redeemed whether or not the exchange succeeds, and an
+/// unknown or already redeemed one yields invalid_grant. The permissions it represents are assessed by the policy,
+/// against the claims of the pushed claim token if any: granted permissions are bound to a new RPT, insufficient
+/// claims yield need_info with a rotated ticket, and a denial yields request_denied. A requested scope narrows the
+/// granted permissions down to it.
+///
+/// The claims of a presented PCT are assessed along with the pushed ones, which take precedence. An unknown or expired
+/// PCT is ignored, as it only serves to optimize the process. Whenever claims were assessed, the RPT is issued along
+/// with a PCT representing them: the presented one, updated with the pushed claims, or a new one. Either way, its
+/// lifetime starts anew, if it has one.
+///
+/// A presented RPT that is still valid is upgraded: its permissions are added to the newly granted ones, the combined
+/// RPT is issued with upgraded set, and the presented one is retired, along with the refresh tokens issued with it, so
+/// that it cannot be refreshed into an RPT next to the combined one. An expired or unknown RPT adds nothing.
+#[instrument(skip_all, fields(method = %request.method(), outcome = Empty))]
+pub async fn grant_uma_ticket<'p>(
+    tickets: &mut PermissionTicketStore<'p>,
+    rpts: &mut RptStore,
+    refresh_tokens: &mut RefreshTokenStore,
+    pcts: &mut PctStore,
+    ids: &dyn IdGenerator,
+    config: &TokenConfig,
+    policy: &dyn AuthorizationPolicy,
+    request: Request<TokenRequest>,
+) -> Result<UmaTicketResponse> {
+    if (request.method() != Method::POST) {
+        return Err(UNSUPPORTED_METHOD_TYPE.with_allowed_methods(&[Method::POST]).into());
+    }
+
+    let uma_ticket_request = UmaTicketRequest::try_from(request.into_body())?;
+    let pushed = match (&uma_ticket_request.claim_token, &uma_ticket_request.claim_token_format) {
+        (Some(token), Some(format)) => ClaimToken::parse(token, format, &config.claim_tokens).await?.claims,
+        _ => ClaimSet::new(),
+    };
+    let persisted = uma_ticket_request.pct.as_ref().and_then(|pct| pcts.get(pct));
+    let now = config.clock.now();
+    let persisted = persisted.filter(|persisted| !persisted.is_expired(now));
+    let pct = persisted.map(|persisted| persisted.pct.clone());
+    let mut claims = persisted.map(|persisted| persisted.claims.clone()).unwrap_or_default();
+    claims.extend(pushed);
+    let issued = redeem_permission_ticket(tickets, &uma_ticket_request.ticket, now)?;
+
+    let permissions = match policy.assess(&issued.permissions, &claims) {
+        AssessmentOutcome::Granted(granted) => granted,
+        AssessmentOutcome::NeedInfo(required) => {
+            let response = need_info(tickets, ids, issued, required, now);
+            return response.map(|response| response.map(UmaTicketResponse::NeedInfo));
+        }
+        AssessmentOutcome::Denied => return Err(REQUEST_DENIED.into()),
+    };
+    let permissions = narrow_to_scope(permissions, &uma_ticket_request.scope)?;
+
+    let mut permissions = permissions
+        .into_iter()
+        .map(|permission| {
+            let resource_scopes = permission.resource_scopes.iter().map(ToString::to_string).collect();
+            GrantedPermission::new(permission.resource_id.to_string(), resource_scopes)
+        })
+        .collect();
+    let upgraded = uma_ticket_request.rpt.map(|rpt| match rpts.get(&rpt) {
+        Some(presented) if presented.exp >= now => {
+            let presented = rpts.del(&rpt).unwrap();
+            revoke_refresh_tokens(refresh_tokens, &rpt);
+            merge_permissions(&mut permissions, presented.permissions);
+            true
+        }
+        _ => false,
+    });
+    let token = issue_requesting_party_token(rpts, refresh_tokens, ids, config, permissions);
+    let mut body = UmaTokenResponse { token, pct: None, upgraded };
+    if !claims.is_empty() {
+        let pct = pct.unwrap_or_else(|| ids.generate());
+        pcts.set(pct.clone(), PersistedClaimsToken { pct: pct.clone(), claims, exp: None });
+        if let Some(lifetime) = config.pct_lifetime {
+            pcts.touch_at(&pct, lifetime, now);
+        }
+        body.pct = Some(pct);
+    }
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Cache-Control", "no-store")
+        .body(UmaTicketResponse::Issued(body));
+
+    return catch_errors(response);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::clock::FakeClock;
+    use crate::ids::UuidGenerator;
+    use crate::test_support::{claims, Keypair, MockIssuer};
+    use crate::uma::grants::{GrantAll, JWT_CLAIM_TOKEN_FORMAT};
+    use crate::uma::token_introspection::{introspect_token, IntrospectionConfig, IntrospectionRequest};
+    use std::collections::HashMap;
+
+    fn description(resource_scopes: &[&str]) -> ResourceDescription {
+        ResourceDescription {
+            _id: "",
+            resource_scopes: resource_scopes.iter().map(|&scope| scope.into()).collect(),
+            description: None,
+            icon_uri: None,
+            name: None,
+            r#type: None,
+            owner: None,
+            policy_hint: None,
+        }
+    }
+
+    fn refresh_request(refresh_token: &str) -> Request<TokenRequest> {
+        let body = TokenRequest {
+            grant_type: REFRESH_TOKEN.to_string(),
+            refresh_token: Some(refresh_token.to_string()),
+            ..TokenRequest::default()
+        };
+        Request::builder().method(Method::POST).uri("/token").body(body).unwrap()
+    }
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn config() -> TokenConfig {
+        TokenConfig { refresh_token_lifetime: Some(Duration::from_secs(60 * 60)), ..TokenConfig::default() }
+    }
+
+    const CLIENT: &str = "https://client.example.org";
+
+    /// Trusts the claim tokens `issuer` issues to `CLIENT`.
+    fn trusting(issuer: &MockIssuer) -> ClaimTokenConfig {
+        ClaimTokenConfig { trusted_issuers: vec![issuer.issuer()], audiences: vec![CLIENT.to_string()] }
+    }
+
+    #[tokio::test]
+    async fn refresh_rotates_rpt_and_refresh_token() {
+        let resources = HashMap::from([("photo".to_string(), description(&["view", "print"]))]);
+        let mut rpts = HashMap::new();
+        let mut refresh_tokens = HashMap::new();
+        let config = config();
+
+        let permissions = vec![GrantedPermission::new("photo".to_string(), vec!["view".to_string()])];
+        let issued =
+            issue_requesting_party_token(&mut rpts, &mut refresh_tokens, &UuidGenerator, &config, permissions.clone());
+        let old_refresh_token = issued.refresh_token.unwrap();
+
+        let response = refresh_requesting_party_token(
+            &resources,
+            &mut rpts,
+            &mut refresh_tokens,
+            &UuidGenerator,
+            &config,
+            refresh_request(&old_refresh_token),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let refreshed = response.into_body();
+        assert_ne!(refreshed.access_token, issued.access_token);
+        assert!(rpts.get(&issued.access_token).is_none());
+        assert_eq!(rpts.get(&refreshed.access_token).unwrap().permissions, permissions);
+
+        let new_refresh_token = refreshed.refresh_token.unwrap();
+        assert_ne!(new_refresh_token, old_refresh_token);
+
+        let replay = refresh_requesting_party_token(
+            &resources,
+            &mut rpts,
+            &mut refresh_tokens,
+            &UuidGenerator,
+            &config,
+            refresh_request(&old_refresh_token),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(replay.body().error_code, "invalid_grant");
+    }
+
+    #[test]
+    fn consumed_ticket_is_rejected() {
+        let mut tickets =
+            HashMap::from([("016f84e8".to_string(), vec![Permission::new("photo", vec!["view"])].into())]);
+
+        let issued = redeem_permission_ticket(&mut tickets, "016f84e8", NOW).unwrap();
+        assert_eq!(issued.permissions[0].resource_id, "photo");
+
+        let error = redeem_permission_ticket(&mut tickets, "016f84e8", NOW).unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error.body().error_code, "invalid_grant");
+    }
+
+    #[test]
+    fn need_info_rotates_ticket() {
+        let mut tickets =
+            HashMap::from([("016f84e8".to_string(), vec![Permission::new("photo", vec!["view"])].into())]);
+
+        let issued = redeem_permission_ticket(&mut tickets, "016f84e8", NOW).unwrap();
+        let required = RequiredClaims { claims: vec!["email".to_string()], redirect_user: None };
+        let response = need_info(&mut tickets, &UuidGenerator, issued, required, NOW).unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(response.body().required_claims, vec!["email"]);
+        let rotated = &response.body().ticket;
+        assert_ne!(rotated, "016f84e8");
+
+        let error = redeem_permission_ticket(&mut tickets, "016f84e8", NOW).unwrap_err();
+        assert_eq!(error.body().error_code, "invalid_grant");
+        let rotated = redeem_permission_ticket(&mut tickets, rotated, NOW).unwrap();
+        assert_eq!(rotated.permissions[0].resource_scopes, vec!["view"]);
+    }
+
+    #[test]
+    fn ticket_is_redeemable_within_its_lifetime() {
+        let permissions = vec![Permission::new("photo", vec!["view"])];
+        let exp = NOW + 300;
+        let issued = IssuedTicket { permissions, exp: Some(exp) };
+        let mut tickets = HashMap::from([("016f84e8".to_string(), issued)]);
+
+        let issued = redeem_permission_ticket(&mut tickets, "016f84e8", NOW + 1).unwrap();
+        assert_eq!(issued.permissions[0].resource_id, "photo");
+
+        let required = RequiredClaims { claims: vec!["email".to_string()], redirect_user: None };
+        let response = need_info(&mut tickets, &UuidGenerator, issued, required, NOW + 1).unwrap();
+        assert_eq!(response.body().expires_in, Some(299));
+        assert_eq!(tickets[&response.body().ticket].exp, Some(exp));
+    }
+
+    #[test]
+    fn expired_ticket_is_rejected() {
+        let permissions = vec![Permission::new("photo", vec!["view"])];
+        let issued = IssuedTicket { permissions, exp: Some(NOW - 1) };
+        let mut tickets = HashMap::from([("016f84e8".to_string(), issued)]);
+
+        let error = redeem_permission_ticket(&mut tickets, "016f84e8", NOW).unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error.body().error_code, "invalid_grant");
+        assert!(tickets.is_empty());
+    }
+
+    #[tokio::test]
+    async fn echoed_permissions_match_introspection() {
+        let mut rpts = HashMap::new();
+        let mut refresh_tokens = HashMap::new();
+        let config = TokenConfig { echo_permissions: true, ..TokenConfig::default() };
+
+        let permissions = vec![
+            GrantedPermission::new("photo".to_string(), vec!["view".to_string(), "print".to_string()]),
+            GrantedPermission::new("album".to_string(), vec![]),
+        ];
+        let issued =
+            issue_requesting_party_token(&mut rpts, &mut refresh_tokens, &UuidGenerator, &config, permissions);
+        let echoed = serde_json::to_value(&issued.permissions).unwrap();
+
+        let body = IntrospectionRequest { token: issued.access_token.clone().into(), token_type_hint: None };
+        let request = Request::builder().method(Method::POST).uri("/introspect").body(body).unwrap();
+        let introspection = introspect_token(&rpts, &refresh_tokens, &IntrospectionConfig::default(), request).await.unwrap().into_body();
+
+        assert!(introspection.active);
+        let introspected = serde_json::to_value(&introspection.permissions).unwrap();
+        assert_eq!(echoed.as_array().unwrap().len(), introspected.as_array().unwrap().len());
+        for (echoed, introspected) in echoed.as_array().unwrap().iter().zip(introspected.as_array().unwrap()) {
+            assert_eq!(echoed["resource_id"], introspected["resource_id"]);
+            assert_eq!(echoed["resource_scopes"], introspected["resource_scopes"]);
+        }
+
+        let silent = TokenConfig::default();
+        let issued = issue_requesting_party_token(&mut rpts, &mut refresh_tokens, &UuidGenerator, &silent, vec![]);
+        assert!(issued.permissions.is_none());
+    }
+
+    #[tokio::test]
+    async fn refresh_after_deregistration_is_rejected() {
+        let mut resources = HashMap::from([("photo".to_string(), description(&["view"]))]);
+        let mut rpts = HashMap::new();
+        let mut refresh_tokens = HashMap::new();
+        let config = config();
+
+        let permissions = vec![GrantedPermission::new("photo".to_string(), vec!["view".to_string()])];
+        let issued =
+            issue_requesting_party_token(&mut rpts, &mut refresh_tokens, &UuidGenerator, &config, permissions);
+
+        resources.remove("photo");
+
+        let error = refresh_requesting_party_token(
+            &resources,
+            &mut rpts,
+            &mut refresh_tokens,
+            &UuidGenerator,
+            &config,
+            refresh_request(&issued.refresh_token.unwrap()),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error.body().error_code, "invalid_grant");
+        assert!(refresh_tokens.is_empty());
+    }
+
+    fn uma_ticket_request(ticket: Option<&str>) -> Request<TokenRequest> {
+        let body = TokenRequest {
+            grant_type: UMA_TICKET.to_string(),
+            ticket: ticket.map(str::to_string),
+            ..TokenRequest::default()
+        };
+        Request::builder().method(Method::POST).uri("/token").body(body).unwrap()
+    }
+
+    #[test]
+    fn uma_ticket_request_is_parsed() {
+        let form = concat!(
+            "grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Auma-ticket",
+            "&ticket=016f84e8-f9b9-11e0-bd6f-0021cc6004de",
+            "&claim_token=eyj0...",
+            "&claim_token_format=http%3A%2F%2Fopenid.net%2Fspecs%2Fopenid-connect-core-1_0.html%23IDToken",
+            "&pct=c2F2ZWRjb25zZW50",
+            "&rpt=sbjsbhs(/SSJHBSUSSJHVhjsgvhsgvshgsv",
+            "&scope=read",
+        );
+        let form: serde_json::Map<String, serde_json::Value> =
+            form_urlencoded::parse(form.as_bytes()).map(|(name, value)| (name.into(), value.into())).collect();
+        let body: TokenRequest = serde_json::from_value(form.into()).unwrap();
+
+        let request = UmaTicketRequest::try_from(body).unwrap();
+
+        assert_eq!(request.ticket, "016f84e8-f9b9-11e0-bd6f-0021cc6004de");
+        assert_eq!(request.claim_token.as_deref(), Some("eyj0..."));
+        assert_eq!(
+            request.claim_token_format.as_deref(),
+            Some("http://openid.net/specs/openid-connect-core-1_0.html#IDToken")
+        );
+        assert_eq!(request.pct.as_deref(), Some("c2F2ZWRjb25zZW50"));
+        assert_eq!(request.rpt.as_deref(), Some("sbjsbhs(/SSJHBSUSSJHVhjsgvhsgvshgsv"));
+        assert_eq!(request.scope, vec!["read"]);
+
+        let unpaired = TokenRequest {
+            claim_token_format: Some(JWT_CLAIM_TOKEN_FORMAT.to_string()),
+            ..uma_ticket_request(Some("016f84e8")).into_body()
+        };
+        assert_eq!(UmaTicketRequest::try_from(unpaired).unwrap_err().error_code, "invalid_request");
+        let refresh =
+            TokenRequest { grant_type: REFRESH_TOKEN.to_string(), ..uma_ticket_request(Some("016f84e8")).into_body() };
+        assert_eq!(UmaTicketRequest::try_from(refresh).unwrap_err().error_code, "unsupported_grant_type");
+    }
+
+    #[tokio::test]
+    async fn uma_ticket_grant() {
+        let mut tickets =
+            HashMap::from([("016f84e8".to_string(), vec![Permission::new("photo", vec!["view"])].into())]);
+        let mut rpts = HashMap::new();
+        let mut refresh_tokens = HashMap::new();
+        let config = TokenConfig::default();
+
+        let response = grant_uma_ticket(
+            &mut tickets,
+            &mut rpts,
+            &mut refresh_tokens,
+            &mut HashMap::new(),
+            &UuidGenerator,
+            &config,
+            &GrantAll,
+            uma_ticket_request(Some("016f84e8")),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let UmaTicketResponse::Issued(issued) = response.body() else { panic!("no RPT issued") };
+        let rpt = &rpts[&issued.token.access_token];
+        assert_eq!(rpt.permissions, vec![GrantedPermission::new("photo".to_string(), vec!["view".to_string()])]);
+        assert!(tickets.is_empty());
+
+        for ticket in [None, Some(""), Some("016f84e8")] {
+            let error = grant_uma_ticket(
+                &mut tickets,
+                &mut rpts,
+                &mut refresh_tokens,
+                &mut HashMap::new(),
+                &UuidGenerator,
+                &config,
+                &GrantAll,
+                uma_ticket_request(ticket),
+            )
+            .await
+            .unwrap_err();
+
+            assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(error.body().error_code, "invalid_grant");
+        }
+        assert_eq!(rpts.len(), 1);
+    }
+
+    async fn grant_scoped(scope: &str) -> (Result<UmaTicketResponse>, HashMap<String, IssuedRpt>) {
+        let mut tickets =
+            HashMap::from([("016f84e8".to_string(), vec![Permission::new("photo", vec!["view", "print"])].into())]);
+        let mut rpts = HashMap::new();
+        let body = TokenRequest { scope: Some(scope.to_string()), ..uma_ticket_request(Some("016f84e8")).into_body() };
+        let request = Request::builder().method(Method::POST).uri("/token").body(body).unwrap();
+
+        let response = grant_uma_ticket(
+            &mut tickets,
+            &mut rpts,
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+            &UuidGenerator,
+            &TokenConfig::default(),
+            &GrantAll,
+            request,
+        )
+        .await;
+        return (response, rpts);
+    }
+
+    #[tokio::test]
+    async fn requested_scope_narrows_the_rpt() {
+        let (response, rpts) = grant_scoped(" print  ").await;
+
+        let response = response.unwrap();
+        let UmaTicketResponse::Issued(issued) = response.body() else { panic!("no RPT issued") };
+        assert_eq!(
+            rpts[&issued.token.access_token].permissions,
+            vec![GrantedPermission::new("photo".to_string(), vec!["print".to_string()])]
+        );
+    }
+
+    #[tokio::test]
+    async fn scope_beyond_the_permissions_is_invalid() {
+        let (response, rpts) = grant_scoped("view delete").await;
+
+        let error = response.unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error.body().error_code, "invalid_scope");
+        assert!(rpts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn presented_rpt_is_upgraded() {
+        let mut tickets = HashMap::from([
+            ("016f84e8".to_string(), vec![Permission::new("photo", vec!["view"])].into()),
+            ("1a2b3c4d".to_string(), vec![Permission::new("album", vec!["view"])].into()),
+        ]);
+        let mut rpts = HashMap::new();
+        let mut refresh_tokens = HashMap::new();
+        let config = TokenConfig::default();
+        let grant = |ticket: &str, rpt: Option<String>| {
+            let body = TokenRequest { rpt, ..uma_ticket_request(Some(ticket)).into_body() };
+            Request::builder().method(Method::POST).uri("/token").body(body).unwrap()
+        };
+
+        let request = grant("016f84e8", None);
+        let response = grant_uma_ticket(
+            &mut tickets,
+            &mut rpts,
+            &mut refresh_tokens,
+            &mut HashMap::new(),
+            &UuidGenerator,
+            &config,
+            &GrantAll,
+            request,
+        )
+        .await
+        .unwrap();
+        let UmaTicketResponse::Issued(first) = response.into_body() else { panic!("no RPT issued") };
+        assert_eq!(first.upgraded, None);
+
+        let request = grant("1a2b3c4d", Some(first.token.access_token.clone()));
+        let response = grant_uma_ticket(
+            &mut tickets,
+            &mut rpts,
+            &mut refresh_tokens,
+            &mut HashMap::new(),
+            &UuidGenerator,
+            &config,
+            &GrantAll,
+            request,
+        )
+        .await
+        .unwrap();
+        let UmaTicketResponse::Issued(upgraded) = response.into_body() else { panic!("no RPT issued") };
+
+        assert_eq!(upgraded.upgraded, Some(true));
+        assert!(rpts.get(&first.token.access_token).is_none());
+        assert_eq!(
+            rpts[&upgraded.token.access_token].permissions,
+            vec![
+                GrantedPermission::new("album".to_string(), vec!["view".to_string()]),
+                GrantedPermission::new("photo".to_string(), vec!["view".to_string()]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn upgraded_rpt_can_no_longer_be_refreshed() {
+        let resources = HashMap::from([
+            ("photo".to_string(), description(&["view"])),
+            ("album".to_string(), description(&["view"])),
+        ]);
+        let mut tickets =
+            HashMap::from([("1a2b3c4d".to_string(), vec![Permission::new("album", vec!["view"])].into())]);
+        let mut rpts = HashMap::new();
+        let mut refresh_tokens = HashMap::new();
+        let config = config();
+        let permissions = vec![GrantedPermission::new("photo".to_string(), vec!["view".to_string()])];
+        let presented =
+            issue_requesting_party_token(&mut rpts, &mut refresh_tokens, &UuidGenerator, &config, permissions);
+
+        let rpt = Some(presented.access_token);
+        let body = TokenRequest { rpt, ..uma_ticket_request(Some("1a2b3c4d")).into_body() };
+        let request = Request::builder().method(Method::POST).uri("/token").body(body).unwrap();
+        let response = grant_uma_ticket(
+            &mut tickets,
+            &mut rpts,
+            &mut refresh_tokens,
+            &mut HashMap::new(),
+            &UuidGenerator,
+            &config,
+            &GrantAll,
+            request,
+        )
+        .await
+        .unwrap();
+        let UmaTicketResponse::Issued(upgraded) = response.into_body() else { panic!("no RPT issued") };
+        assert_eq!(upgraded.upgraded, Some(true));
+
+        let error = refresh_requesting_party_token(
+            &resources,
+            &mut rpts,
+            &mut refresh_tokens,
+            &UuidGenerator,
+            &config,
+            refresh_request(&presented.refresh_token.unwrap()),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.body().error_code, "invalid_grant");
+        assert_eq!(rpts.len(), 1);
+
+        let refreshed = refresh_requesting_party_token(
+            &resources,
+            &mut rpts,
+            &mut refresh_tokens,
+            &UuidGenerator,
+            &config,
+            refresh_request(&upgraded.token.refresh_token.unwrap()),
+        )
+        .await;
+        assert_eq!(refreshed.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn expired_rpt_is_not_upgraded() {
+        let mut tickets =
+            HashMap::from([("1a2b3c4d".to_string(), vec![Permission::new("album", vec!["view"])].into())]);
+        let clock = Arc::new(FakeClock::new(NOW));
+        let config = TokenConfig { clock: clock.clone(), ..TokenConfig::default() };
+        let mut rpts = HashMap::new();
+        let permissions = vec![GrantedPermission::new("photo".to_string(), vec!["view".to_string()])];
+        let presented =
+            issue_requesting_party_token(&mut rpts, &mut HashMap::new(), &UuidGenerator, &config, permissions);
+        clock.advance(config.rpt_lifetime + Duration::from_secs(1));
+
+        let rpt = Some(presented.access_token);
+        let body = TokenRequest { rpt, ..uma_ticket_request(Some("1a2b3c4d")).into_body() };
+        let request = Request::builder().method(Method::POST).uri("/token").body(body).unwrap();
+
+        let response = grant_uma_ticket(
+            &mut tickets,
+            &mut rpts,
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+            &UuidGenerator,
+            &config,
+            &GrantAll,
+            request,
+        )
+        .await
+        .unwrap();
+
+        let UmaTicketResponse::Issued(issued) = response.into_body() else { panic!("no RPT issued") };
+        assert_eq!(issued.upgraded, Some(false));
+        assert_eq!(
+            rpts[&issued.token.access_token].permissions,
+            vec![GrantedPermission::new("album".to_string(), vec!["view".to_string()])]
+        );
+    }
+
+    /// Wants to know the requesting party's email address, and grants viewing to example.org addresses only.
+    struct EmailPolicy;
+
+    impl AuthorizationPolicy for EmailPolicy {
+        fn assess<'p>(&self, permissions: &[Permission<'p>], claims: &ClaimSet) -> AssessmentOutcome<'p> {
+            let Some(email) = claims.get("email").and_then(serde_json::Value::as_str) else {
+                let redirect_user = Iri::parse("https://as.example.com/rqp_claims".to_string()).ok();
+                return AssessmentOutcome::NeedInfo(RequiredClaims { claims: vec!["email".to_string()], redirect_user });
+            };
+            if !email.ends_with("@example.org") {
+                return AssessmentOutcome::Denied;
+            }
+            let viewing =
+                permissions.iter().map(|permission| Permission::new(permission.resource_id.clone(), vec!["view"]));
+            return AssessmentOutcome::Granted(viewing.collect());
+        }
+    }
+
+    async fn assess_with_email(email: Option<&str>) -> (Result<UmaTicketResponse>, HashMap<String, IssuedRpt>) {
+        let key = Keypair::es256("key-1");
+        let issuer = MockIssuer::start(vec![key.jwk()]).await;
+        let mut tickets =
+            HashMap::from([("016f84e8".to_string(), vec![Permission::new("photo", vec!["view", "print"])].into())]);
+        let mut rpts = HashMap::new();
+        let mut refresh_tokens = HashMap::new();
+
+        let mut body = uma_ticket_request(Some("016f84e8")).into_body();
+        if let Some(email) = email {
+            let id_token =
+                serde_json::json!({ "iss": issuer.issuer().as_str(), "aud": CLIENT, "sub": "alice", "email": email });
+            body.claim_token = Some(key.sign(&claims(id_token, chrono::Duration::minutes(5))));
+            body.claim_token_format = Some(JWT_CLAIM_TOKEN_FORMAT.to_string());
+        }
+        let request = Request::builder().method(Method::POST).uri("/token").body(body).unwrap();
+
+        let mut pcts = HashMap::new();
+        let response = grant_uma_ticket(
+            &mut tickets,
+            &mut rpts,
+            &mut refresh_tokens,
+            &mut pcts,
+            &UuidGenerator,
+            &TokenConfig { claim_tokens: trusting(&issuer), ..TokenConfig::default() },
+            &EmailPolicy,
+            request,
+        )
+        .await;
+        return (response, rpts);
+    }
+
+    #[tokio::test]
+    async fn granted_permissions_are_issued() {
+        let (response, rpts) = assess_with_email(Some("alice@example.org")).await;
+
+        let response = response.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let UmaTicketResponse::Issued(issued) = response.body() else { panic!("no RPT issued") };
+        assert_eq!(
+            rpts[&issued.token.access_token].permissions,
+            vec![GrantedPermission::new("photo".to_string(), vec!["view".to_string()])]
+        );
+    }
+
+    #[test]
+    fn uma_token_response_shape() {
+        let token = SuccessfulResponse {
+            access_token: "sbjsbhs(/SSJHBSUSSJHVhjsgvhsgvshgsv".to_string(),
+            token_type: "Bearer",
+            expires_in: 300,
+            refresh_token: None,
+            permissions: None,
+        };
+
+        let issued = UmaTokenResponse { token: token.clone(), pct: None, upgraded: None };
+        assert_eq!(
+            serde_json::to_value(UmaTicketResponse::Issued(issued)).unwrap(),
+            serde_json::json!({
+                "access_token": "sbjsbhs(/SSJHBSUSSJHVhjsgvhsgvshgsv",
+                "token_type": "Bearer",
+                "expires_in": 300,
+            })
+        );
+
+        let upgraded = UmaTokenResponse { token, pct: Some("c2F2ZWRjb25zZW50".to_string()), upgraded: Some(true) };
+        assert_eq!(
+            serde_json::to_value(upgraded).unwrap(),
+            serde_json::json!({
+                "access_token": "sbjsbhs(/SSJHBSUSSJHVhjsgvhsgvshgsv",
+                "token_type": "Bearer",
+                "expires_in": 300,
+                "pct": "c2F2ZWRjb25zZW50",
+                "upgraded": true,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_claims_yield_need_info() {
+        let (response, rpts) = assess_with_email(None).await;
+
+        let response = response.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let UmaTicketResponse::NeedInfo(need_info) = response.body() else { panic!("no need_info") };
+        assert_ne!(need_info.ticket, "016f84e8");
+        assert_eq!(need_info.required_claims, vec!["email"]);
+        let json = serde_json::to_value(response.body()).unwrap();
+        assert_eq!(json["error"], "need_info");
+        assert_eq!(json["redirect_user"], "https://as.example.com/rqp_claims");
+        assert!(rpts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn denied_request_yields_request_denied() {
+        let (response, rpts) = assess_with_email(Some("mallory@example.com")).await;
+
+        let error = response.unwrap_err();
+        assert_eq!(error.status(), StatusCode::FORBIDDEN);
+        assert_eq!(error.body().error_code, "request_denied");
+        assert!(rpts.is_empty());
+    }
+
+    /// The stores of a token endpoint assessing with the `EmailPolicy`.
+    #[derive(Default)]
+    struct EmailEndpoint {
+        tickets: HashMap<String, IssuedTicket<'static>>,
+        rpts: HashMap<String, IssuedRpt>,
+        refresh_tokens: HashMap<String, RefreshGrant>,
+        pcts: HashMap<String, PersistedClaimsToken>,
+        config: TokenConfig,
+    }
+
+    impl EmailEndpoint {
+        async fn grant(&mut self, ticket: &str, claim_token: Option<String>, pct: Option<&str>) -> UmaTicketResponse {
+            self.tickets.insert(ticket.to_string(), vec![Permission::new("photo", vec!["view"])].into());
+            let body = TokenRequest {
+                claim_token_format: claim_token.as_ref().map(|_| JWT_CLAIM_TOKEN_FORMAT.to_string()),
+                claim_token,
+                pct: pct.map(str::to_string),
+                ..uma_ticket_request(Some(ticket)).into_body()
+            };
+            let request = Request::builder().method(Method::POST).uri("/token").body(body).unwrap();
+            let response = grant_uma_ticket(
+                &mut self.tickets,
+                &mut self.rpts,
+                &mut self.refresh_tokens,
+                &mut self.pcts,
+                &UuidGenerator,
+                &self.config,
+                &EmailPolicy,
+                request,
+            )
+            .await;
+            return response.unwrap().into_body();
+        }
+    }
+
+    #[tokio::test]
+    async fn persisted_claims_are_reused() {
+        let key = Keypair::es256("key-1");
+        let issuer = MockIssuer::start(vec![key.jwk()]).await;
+        let mut endpoint = EmailEndpoint::default();
+        endpoint.config.claim_tokens = trusting(&issuer);
+
+        let id_token =
+            serde_json::json!({ "iss": issuer.issuer().as_str(), "aud": CLIENT, "email": "alice@example.org" });
+        let claim_token = key.sign(&claims(id_token, chrono::Duration::minutes(5)));
+        let UmaTicketResponse::Issued(first) = endpoint.grant("016f84e8", Some(claim_token), None).await else {
+            panic!("no RPT issued")
+        };
+        let pct = first.pct.expect("no PCT issued");
+        assert_eq!(endpoint.pcts[&pct].claims["email"], "alice@example.org");
+
+        // Replaying the PCT stands in for pushing the claim token again, and keeps the same handle.
+        let UmaTicketResponse::Issued(replayed) = endpoint.grant("1a2b3c4d", None, Some(&pct)).await else {
+            panic!("claims were not reused")
+        };
+        assert_eq!(replayed.pct, Some(pct));
+        assert_eq!(endpoint.rpts.len(), 2);
+
+        let UmaTicketResponse::NeedInfo(_) = endpoint.grant("5e6f7a8b", None, Some("unknown")).await else {
+            panic!("an unknown PCT carried claims")
+        };
+        assert_eq!(endpoint.pcts.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn replayed_pcts_slide_and_expired_ones_are_ignored() {
+        let key = Keypair::es256("key-1");
+        let issuer = MockIssuer::start(vec![key.jwk()]).await;
+        let mut endpoint = EmailEndpoint::default();
+        let clock = Arc::new(FakeClock::new(NOW));
+        let lifetime = Duration::from_secs(60);
+        endpoint.config = TokenConfig {
+            pct_lifetime: Some(lifetime),
+            clock: clock.clone(),
+            claim_tokens: trusting(&issuer),
+            ..TokenConfig::default()
+        };
+
+        let id_token =
+            serde_json::json!({ "iss": issuer.issuer().as_str(), "aud": CLIENT, "email": "alice@example.org" });
+        let claim_token = key.sign(&claims(id_token, chrono::Duration::minutes(5)));
+        let UmaTicketResponse::Issued(first) = endpoint.grant("016f84e8", Some(claim_token), None).await else {
+            panic!("no RPT issued")
+        };
+        let pct = first.pct.expect("no PCT issued");
+        assert_eq!(endpoint.pcts[&pct].exp, Some(NOW + 60));
+
+        // About to expire, a replay gives the PCT its full lifetime again.
+        clock.advance(lifetime - Duration::from_secs(1));
+        let UmaTicketResponse::Issued(_) = endpoint.grant("1a2b3c4d", None, Some(&pct)).await else {
+            panic!("claims were not reused")
+        };
+        assert_eq!(endpoint.pcts[&pct].exp, Some(NOW + 59 + 60));
+
+        clock.advance(lifetime);
+        let UmaTicketResponse::NeedInfo(_) = endpoint.grant("5e6f7a8b", None, Some(&pct)).await else {
+            panic!("an expired PCT carried claims")
+        };
+    }
+}
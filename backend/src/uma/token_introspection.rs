@@ -38,17 +38,28 @@
 //! The authorization server MAY support both UMA-extended and non-UMA introspection requests and responses.
 //!
 
+use crate::clock::{Clock, SystemClock};
+use crate::ids::{ResourceId, RptValue};
 use crate::storage::KeyValueStore;
+use http::header::CONTENT_TYPE;
 use http::{Method, Request, Response, StatusCode};
+use tracing::field::Empty;
+use tracing::{instrument, Span};
 use oxiri::Iri;
-use serde::Serialize;
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Serialize, Serializer};
 use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
 use std::{ops::Deref, result};
-use uuid::Uuid;
 
-use super::errors::{ErrorMessage, INVALID_REQUEST, RESOURCE_NOT_FOUND, UNSUPPORTED_METHOD_TYPE};
+use super::errors::{
+    catch_errors, ErrorMessage, INVALID_REQUEST, RESOURCE_NOT_FOUND, UNSUPPORTED_MEDIA_TYPE, UNSUPPORTED_METHOD_TYPE,
+};
 use super::federation::ResourceDescription;
 use super::permission::PermissionRequest;
+use super::token::{GrantedPermission, IssuedRpt, RefreshGrant};
 
 // https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-federated-authz-2.0.html#rfc.section.5.1
 // https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-federated-authz-2.0.html#token-introspection
@@ -65,62 +76,357 @@ use super::permission::PermissionRequest;
 ///
 /// If the introspection object's active parameter has a Boolean value of true, then the object MUST NOT contain a scope parameter, and MUST contain an extension parameter named permissions that contains an array of objects, each one (representing a single permission) containing these parameters:
 #[derive(Debug, Serialize, Clone/*, Copy */)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema), schema(as = IntrospectedPermission))]
 pub struct SuccessfulResponse<'sr> {
 
     /// REQUIRED. REQUIRED. A string that uniquely identifies the protected resource, access to which has been granted to this client on behalf of this requesting party. The identifier MUST correspond to a resource that was previously registered as protected.
-    pub resource_id: &'sr str,
+    #[serde(rename = "resource_id")]
+    #[cfg_attr(feature = "openapi", schema(value_type = String))]
+    pub resource_id: &'sr ResourceId,
 
     /// REQUIRED. An array referencing zero or more strings representing scopes to which access was granted for this resource. Each string MUST correspond to a scope that was registered by this resource server for the referenced resource.
+    #[serde(rename = "resource_scopes")]
     pub resource_scopes: Vec<&'sr str>,
 
     /// OPTIONAL. Integer timestamp, measured in the number of seconds since January 1 1970 UTC, indicating when this permission will expire. If the token-level exp value pre-dates a permission-level exp value, the token-level value takes precedence.
-    exp: Option<i64>,
+    #[serde(rename = "exp", skip_serializing_if = "Option::is_none")]
+    pub exp: Option<i64>,
 
     /// OPTIONAL. Integer timestamp, measured in the number of seconds since January 1 1970 UTC, indicating when this permission was originally issued. If the token-level iat value post-dates a permission-level iat value, the token-level value takes precedence.
-    iat: Option<i64>,
+    #[serde(rename = "iat", skip_serializing_if = "Option::is_none")]
+    pub iat: Option<i64>,
 
     /// OPTIONAL. Integer timestamp, measured in the number of seconds since January 1 1970 UTC, indicating the time before which this permission is not valid. If the token-level nbf value post-dates a permission-level nbf value, the token-level value takes precedence.
-    nbf: Option<i64>,
+    #[serde(rename = "nbf", skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<i64>,
 
 }
 
-fn catch_errors<T>(result: http::Result<Response<T>>) -> Result<T> {
-    return result.map_err(|error: http::Error| {
-        // log error
-        return ErrorMessage::default().into();
-    });
+impl<'sr> From<&'sr GrantedPermission> for SuccessfulResponse<'sr> {
+    fn from(permission: &'sr GrantedPermission) -> Self {
+        Self {
+            resource_id: &permission.resource_id,
+            resource_scopes: permission.resource_scopes.iter().map(String::as_str).collect(),
+            exp: None,
+            iat: None,
+            nbf: None,
+        }
+    }
+}
+
+impl<'sr> SuccessfulResponse<'sr> {
+    /// A permission of `rpt`, stamped with when the RPT was issued and when it expires. Permissions granted through a
+    /// refresh token carry no timestamps of their own, as they last as long as the token does.
+    pub fn issued(permission: &'sr GrantedPermission, rpt: &IssuedRpt) -> Self {
+        Self { exp: Some(rpt.exp), iat: Some(rpt.iat), ..Self::from(permission) }
+    }
 }
 
-type AccessTokenStore = dyn KeyValueStore<Key = String, Value = ResourceDescription>;
+/// [NO-SPEC] Settings of the introspection endpoint.
+#[derive(Debug, Clone)]
+pub struct IntrospectionConfig {
+    /// Ceiling on how long a resource server may cache the introspection of an active token. Caches never outlive the
+    /// token itself.
+    pub max_cache_age: Duration,
+
+    /// How many tokens a batch introspection request may hold; batch introspection is disabled if None.
+    pub max_batch_size: Option<usize>,
+
+    /// What tokens are checked for expiry against.
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for IntrospectionConfig {
+    fn default() -> Self {
+        Self {
+            max_cache_age: Duration::from_secs(60),
+            max_batch_size: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+type AccessTokenStore = dyn KeyValueStore<Key = String, Value = IssuedRpt>;
+type RefreshTokenStore = dyn KeyValueStore<Key = String, Value = RefreshGrant>;
 type Result<T> = result::Result<Response<T>, Response<ErrorMessage>>;
 
+/// https://datatracker.ietf.org/doc/html/rfc7662#section-2.1
+///
+/// The protected resource calls the introspection endpoint using an HTTP POST request with parameters sent as
+/// "application/x-www-form-urlencoded" data.
+#[derive(Debug, Deserialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct IntrospectionRequest {
+    /// REQUIRED. The string value of the token.
+    #[cfg_attr(feature = "openapi", schema(value_type = String))]
+    pub token: RptValue,
+
+    /// OPTIONAL. A hint about the type of the token submitted for introspection.
+    pub token_type_hint: Option<String>,
+}
+
+/// The methods of the introspection endpoint.
+const INTROSPECTION_METHODS: &[Method] = &[Method::POST];
+
+impl IntrospectionRequest {
+    /// Parses the form-encoded body of `request`, which must be a POST labelled application/x-www-form-urlencoded.
+    /// Parameters other than token and token_type_hint are ignored; a missing or empty token, or a parameter sent more
+    /// than once (see Section 3.2 of [RFC6749]), is an invalid_request.
+    pub fn parse<B: AsRef<[u8]>>(request: Request<B>) -> result::Result<Request<Self>, Response<ErrorMessage>> {
+        if (request.method() != Method::POST) {
+            return Err(UNSUPPORTED_METHOD_TYPE.with_allowed_methods(INTROSPECTION_METHODS).into());
+        }
+        let content_type = request.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok());
+        let essence = content_type.unwrap_or_default().split(';').next().unwrap_or_default().trim();
+        if !essence.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+            return Err(UNSUPPORTED_MEDIA_TYPE.into());
+        }
+
+        let (parts, body) = request.into_parts();
+        let (mut token, mut token_type_hint) = (None, None);
+        for (name, value) in form_urlencoded::parse(body.as_ref()) {
+            let parameter = match name.as_ref() {
+                "token" => &mut token,
+                "token_type_hint" => &mut token_type_hint,
+                _ => continue,
+            };
+            if parameter.replace(value.into_owned()).is_some() {
+                let description = format!("The {name} parameter is sent more than once.");
+                return Err(ErrorMessage { error_description: Some(Cow::Owned(description)), ..INVALID_REQUEST }.into());
+            }
+        }
+        let Some(token) = token.filter(|token| !token.is_empty()) else {
+            let description = "The token parameter is missing.";
+            return Err(ErrorMessage { error_description: Some(Cow::Borrowed(description)), ..INVALID_REQUEST }.into());
+        };
+
+        return Ok(Request::from_parts(parts, Self { token: token.into(), token_type_hint }));
+    }
+}
+
+/// https://datatracker.ietf.org/doc/html/rfc7662#section-2.1
+///
+/// The token type hints this server understands, i.e. which store it searches first. If the server is unable to
+/// locate the token using the given hint, it MUST extend its search across all of its supported token types. An
+/// authorization server MAY ignore this parameter, particularly if it is able to detect the token type automatically.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TokenTypeHint {
+    AccessToken,
+    RefreshToken,
+}
+
+impl TokenTypeHint {
+    /// Unknown hints are ignored rather than rejected, so that they fall back to the default search order.
+    pub(crate) fn parse(hint: Option<&str>) -> Self {
+        match hint {
+            Some("refresh_token") => Self::RefreshToken,
+            _ => Self::AccessToken,
+        }
+    }
+}
+
+/// https://datatracker.ietf.org/doc/html/rfc7662#section-2.2
+///
+/// The top-level introspection object, carrying the permissions of an active RPT. An inactive token is reported with
+/// `active` alone, since RFC 7662 discourages revealing anything more about it.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct IntrospectionResponse<'ir> {
+    /// REQUIRED. Whether or not the presented token is currently active.
+    pub active: bool,
+
+    /// OPTIONAL. Integer timestamp indicating when this token will expire.
+    pub exp: Option<i64>,
+
+    /// OPTIONAL. Integer timestamp indicating when this token was originally issued.
+    pub iat: Option<i64>,
+
+    /// REQUIRED if active, even when empty. The permissions associated with the token.
+    #[cfg_attr(feature = "openapi", schema(required = false))]
+    pub permissions: Vec<SuccessfulResponse<'ir>>,
+}
+
+impl<'ir> IntrospectionResponse<'ir> {
+    pub const INACTIVE: Self = Self { active: false, exp: None, iat: None, permissions: Vec::new() };
+}
+
+impl<'ir> Serialize for IntrospectionResponse<'ir> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("active", &self.active)?;
+        if !self.active {
+            return map.end();
+        }
+        if let Some(exp) = self.exp {
+            map.serialize_entry("exp", &exp)?;
+        }
+        if let Some(iat) = self.iat {
+            map.serialize_entry("iat", &iat)?;
+        }
+        map.serialize_entry("permissions", &self.permissions)?;
+        return map.end();
+    }
+}
+
+/// https://docs.kantarainitiative.org/uma/wg/rec-oauth-uma-federated-authz-2.0.html#rfc.section.5.1.1
+///
+/// Introspects an RPT or a refresh token. The store named by the token type hint is searched first, the other one
+/// after a miss. Unknown and expired tokens are reported as inactive rather than as an error.
+///
+/// https://datatracker.ietf.org/doc/html/rfc7662#section-4
+///
+/// Active responses may be cached until the token expires, capped at the configured ceiling; inactive ones are not to be
+/// cached at all, so that a token issued later under the same value is not mistaken for an inactive one.
+#[cfg_attr(feature = "openapi", utoipa::path(
+    post,
+    path = "/introspect",
+    tag = "token introspection",
+    request_body(content = IntrospectionRequest, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, description = "The state of the token", body = IntrospectionResponse),
+        (status = 400, description = "The request is invalid", body = ErrorMessage),
+    ),
+))]
+#[instrument(skip_all, fields(method = %request.method(), outcome = Empty))]
+pub async fn introspect_token<'sr>(
+    rpts: &'sr AccessTokenStore,
+    refresh_tokens: &'sr RefreshTokenStore,
+    config: &IntrospectionConfig,
+    request: Request<IntrospectionRequest>,
+) -> Result<IntrospectionResponse<'sr>> {
+    if (request.method() != Method::POST) {
+        return Err(UNSUPPORTED_METHOD_TYPE.with_allowed_methods(INTROSPECTION_METHODS).into());
+    }
+
+    let IntrospectionRequest { token, token_type_hint } = request.into_body();
+    let now = config.clock.now();
+    let body = introspect(rpts, refresh_tokens, &token, TokenTypeHint::parse(token_type_hint.as_deref()), now);
+
+    let cache_control = match max_age(&body, config, now) {
+        Some(max_age) => format!("max-age={max_age}"),
+        None => "no-store".to_string(),
+    };
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", cache_control)
+        .body(body);
+
+    return catch_errors(response);
+}
+
+/// [NO-SPEC] Introspects several tokens in one request, for a resource server proxying many requests at once. The
+/// request body is a JSON array of tokens, introspected as if each came without a token type hint; the response body
+/// is an array of introspection objects, in the order of the request. A token occurring more than once is looked up
+/// once. Only enabled if the configuration sets a maximum batch size; a batch that is empty or larger than that is an
+/// invalid_request.
 ///
-// pub async fn introspect_token<'sr>(
-//     store: &'sr mut ResourceDescriptionStore,
-//     request: Request<PermissionRequest<'_>>,
-// ) -> Result<SuccessfulResponse<'sr>> {
-//     if (request.method() != Method::POST) {
-//         return Err(UNSUPPORTED_METHOD_TYPE.into());
-//     }
+/// The response may be cached as long as each of its introspection objects may be, i.e. not at all if any token is
+/// inactive.
+#[instrument(skip_all, fields(method = %request.method(), count = Empty, outcome = Empty))]
+pub async fn introspect_tokens<'sr>(
+    rpts: &'sr AccessTokenStore,
+    refresh_tokens: &'sr RefreshTokenStore,
+    config: &IntrospectionConfig,
+    request: Request<Vec<RptValue>>,
+) -> Result<Vec<IntrospectionResponse<'sr>>> {
+    if (request.method() != Method::POST) {
+        return Err(UNSUPPORTED_METHOD_TYPE.into());
+    }
+    let Some(max_batch_size) = config.max_batch_size else {
+        return Err(ErrorMessage {
+            error_description: Some(Cow::Borrowed("Batch introspection is not enabled.")),
+            ..RESOURCE_NOT_FOUND
+        }
+        .into());
+    };
+
+    let tokens = request.into_body();
+    Span::current().record("count", tokens.len());
+    if tokens.is_empty() || tokens.len() > max_batch_size {
+        return Err(ErrorMessage {
+            error_description: Some(Cow::Owned(format!("A batch holds between 1 and {max_batch_size} tokens."))),
+            ..INVALID_REQUEST
+        }
+        .into());
+    }
 
-//     let id = request.into_body();
+    let now = config.clock.now();
+    let mut introspected: HashMap<&RptValue, IntrospectionResponse<'sr>> = HashMap::new();
+    let mut body = Vec::with_capacity(tokens.len());
+    for token in &tokens {
+        let response = introspected
+            .entry(token)
+            .or_insert_with(|| introspect(rpts, refresh_tokens, token, TokenTypeHint::AccessToken, now));
+        body.push(response.clone());
+    }
 
-//     // ...
+    let max_age = body.iter().map(|response| max_age(response, config, now)).min().flatten();
+    let cache_control = match max_age {
+        Some(max_age) => format!("max-age={max_age}"),
+        None => "no-store".to_string(),
+    };
 
-//     let ticket = Uuid::new_v4().to_string();
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", cache_control)
+        .body(body);
 
-//     let response = Response::builder()
-//         .status(StatusCode::CREATED)
-//         .body(SuccessfulResponse::new(&id, None, None));
+    return catch_errors(response);
+}
 
-//     return catch_errors(response);
-// }
+/// The introspection of `token` at `now`: searching the store named by the hint first, then the other one, and
+/// inactive if neither holds it unexpired.
+fn introspect<'sr>(
+    rpts: &'sr AccessTokenStore,
+    refresh_tokens: &'sr RefreshTokenStore,
+    token: &RptValue,
+    hint: TokenTypeHint,
+    now: i64,
+) -> IntrospectionResponse<'sr> {
+    let introspect_rpt = || match rpts.get(token.as_string()) {
+        Some(rpt) if rpt.exp > now => Some(IntrospectionResponse {
+            active: true,
+            exp: Some(rpt.exp),
+            iat: Some(rpt.iat),
+            permissions: rpt.permissions.iter().map(|permission| SuccessfulResponse::issued(permission, rpt)).collect(),
+        }),
+        _ => None,
+    };
+    let introspect_refresh_token = || match refresh_tokens.get(token.as_string()) {
+        Some(grant) if grant.exp > now => Some(IntrospectionResponse {
+            active: true,
+            exp: Some(grant.exp),
+            iat: None,
+            permissions: grant.permissions.iter().map(SuccessfulResponse::from).collect(),
+        }),
+        _ => None,
+    };
+
+    let response = match hint {
+        TokenTypeHint::AccessToken => introspect_rpt().or_else(introspect_refresh_token),
+        TokenTypeHint::RefreshToken => introspect_refresh_token().or_else(introspect_rpt),
+    };
+    return response.unwrap_or(IntrospectionResponse::INACTIVE);
+}
 
+/// How many seconds an introspection may be cached: until the token expires, capped at the configured ceiling, and not
+/// at all (None) for an inactive token.
+fn max_age(response: &IntrospectionResponse, config: &IntrospectionConfig, now: i64) -> Option<i64> {
+    return match response.exp {
+        Some(exp) if response.active => Some((exp - now).clamp(0, config.max_cache_age.as_secs() as i64)),
+        _ => None,
+    };
+}
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::test_support::serialized_keys;
+    use std::collections::HashMap;
 
     // assert! assert_eq! assert_ne! #[should_panic(expected = "panic msg")] -> Result<(), String> ?
 
@@ -159,6 +465,295 @@ mod tests {
 
     }
 
+    #[test]
+    fn serialized_keys_are_pinned() {
+        let resource_id = ResourceId::from("112210f47de98100");
+        let permission = SuccessfulResponse {
+            resource_id: &resource_id,
+            resource_scopes: vec!["view"],
+            exp: Some(1256953732),
+            iat: Some(1256912345),
+            nbf: Some(1256912345),
+        };
+        assert_eq!(serialized_keys(&permission), ["exp", "iat", "nbf", "resource_id", "resource_scopes"]);
+        assert_eq!(
+            serde_json::to_string(&permission).unwrap(),
+            concat!(
+                r#"{"resource_id":"112210f47de98100","resource_scopes":["view"],"#,
+                r#""exp":1256953732,"iat":1256912345,"nbf":1256912345}"#,
+            )
+        );
+
+        let response = IntrospectionResponse {
+            active: true,
+            exp: Some(1256953732),
+            iat: Some(1256912345),
+            permissions: vec![permission],
+        };
+        assert_eq!(serialized_keys(&response), ["active", "exp", "iat", "permissions"]);
+        let serialized = serde_json::to_string(&response).unwrap();
+        assert!(serialized.starts_with(r#"{"active":true,"exp":1256953732,"iat":1256912345,"permissions":["#));
+        assert_eq!(serde_json::to_string(&IntrospectionResponse::INACTIVE).unwrap(), r#"{"active":false}"#);
+    }
+
+    #[test]
+    fn introspection_response_matches_spec_example() {
+        let resource_id = ResourceId::from("112210f47de98100");
+        let permission = SuccessfulResponse {
+            resource_id: &resource_id,
+            resource_scopes: vec!["view", "http://photoz.example.com/dev/actions/print"],
+            exp: Some(1256953732),
+            iat: None,
+            nbf: None,
+        };
+        let response = IntrospectionResponse {
+            active: true,
+            exp: Some(1256953732),
+            iat: Some(1256912345),
+            permissions: vec![permission],
+        };
+
+        let expected: serde_json::Value = serde_json::from_str(r#"{
+            "active": true,
+            "exp": 1256953732,
+            "iat": 1256912345,
+            "permissions": [
+                {
+                    "resource_id": "112210f47de98100",
+                    "resource_scopes": [
+                        "view",
+                        "http://photoz.example.com/dev/actions/print"
+                    ],
+                    "exp": 1256953732
+                }
+            ]
+        }"#).unwrap();
+        assert_eq!(serde_json::to_value(&response).unwrap(), expected);
+    }
+
+    #[test]
+    fn inactive_token_is_reported_without_details() {
+        let json = serde_json::to_string(&IntrospectionResponse::INACTIVE).unwrap();
+        assert_eq!(json, r#"{"active":false}"#);
+    }
+
+    fn introspection_request(token: &str, token_type_hint: Option<&str>) -> Request<IntrospectionRequest> {
+        let body = IntrospectionRequest {
+            token: RptValue::from(token),
+            token_type_hint: token_type_hint.map(str::to_string),
+        };
+        Request::builder().method(Method::POST).uri("/introspect").body(body).unwrap()
+    }
+
+    fn form(method: Method, content_type: &str, body: &'static str) -> Request<&'static str> {
+        let request = Request::builder().method(method).uri("/introspect").header(CONTENT_TYPE, content_type);
+        return request.body(body).unwrap();
+    }
+
+    #[test]
+    fn parses_a_form_body() {
+        let body = "token=sbjsbhs(%2FSSJHBSUSSJHVhjsgvhsgvshgsv+x&token_type_hint=access_token&client_id=rs";
+        let request = IntrospectionRequest::parse(form(Method::POST, "application/x-www-form-urlencoded", body));
+
+        let request = request.unwrap().into_body();
+        assert_eq!(request.token, "sbjsbhs(/SSJHBSUSSJHVhjsgvhsgvshgsv x");
+        assert_eq!(request.token_type_hint.as_deref(), Some("access_token"));
+
+        let content_type = "Application/X-WWW-Form-Urlencoded; charset=UTF-8";
+        let request = IntrospectionRequest::parse(form(Method::POST, content_type, "token=rpt")).unwrap();
+        assert_eq!(request.body().token, "rpt");
+        assert_eq!(request.body().token_type_hint, None);
+    }
+
+    #[test]
+    fn refuses_other_methods() {
+        let request = form(Method::GET, "application/x-www-form-urlencoded", "token=rpt");
+
+        let error = IntrospectionRequest::parse(request).unwrap_err();
+        assert_eq!(error.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(error.headers()[http::header::ALLOW], "POST");
+    }
+
+    #[test]
+    fn refuses_other_content_types() {
+        let request = form(Method::POST, "application/json", r#"{"token":"rpt"}"#);
+
+        let error = IntrospectionRequest::parse(request).unwrap_err();
+        assert_eq!(error.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[test]
+    fn refuses_a_missing_or_repeated_token() {
+        for body in ["", "token_type_hint=access_token", "token=&token_type_hint=access_token", "token=a&token=b"] {
+            let request = form(Method::POST, "application/x-www-form-urlencoded", body);
+
+            let error = IntrospectionRequest::parse(request).unwrap_err();
+            assert_eq!(error.status(), StatusCode::BAD_REQUEST, "{body}");
+            assert_eq!(error.body().error_code, "invalid_request", "{body}");
+        }
+    }
+
+    fn stores() -> (HashMap<String, IssuedRpt>, HashMap<String, RefreshGrant>) {
+        let permissions = vec![GrantedPermission::new("photo".to_string(), vec!["view".to_string()])];
+        let exp = SystemClock.now() + 60;
+        let rpts = HashMap::from([(
+            "rpt".to_string(),
+            IssuedRpt { permissions: permissions.clone(), iat: exp - 120, exp },
+        )]);
+        let refresh_tokens = HashMap::from([(
+            "refresh".to_string(),
+            RefreshGrant { rpt: "rpt".to_string(), permissions, exp },
+        )]);
+        return (rpts, refresh_tokens);
+    }
+
+    #[tokio::test]
+    async fn introspects_with_matching_hint() {
+        let (rpts, refresh_tokens) = stores();
+
+        let request = introspection_request("rpt", Some("access_token"));
+        let response = introspect_token(&rpts, &refresh_tokens, &IntrospectionConfig::default(), request).await.unwrap().into_body();
+        assert!(response.active);
+        assert!(response.iat.is_some());
+
+        let request = introspection_request("refresh", Some("refresh_token"));
+        let response = introspect_token(&rpts, &refresh_tokens, &IntrospectionConfig::default(), request).await.unwrap().into_body();
+        assert!(response.active);
+        assert_eq!(response.permissions[0].resource_id, "photo");
+    }
+
+    #[test]
+    fn permission_timestamps_are_serialized() {
+        let resource_id = ResourceId::from("112210f47de98100");
+        let permission = SuccessfulResponse {
+            resource_id: &resource_id,
+            resource_scopes: vec!["view"],
+            exp: Some(1256953732),
+            iat: Some(1256912345),
+            nbf: Some(1256912400),
+        };
+
+        let json = serde_json::to_value(&permission).unwrap();
+        assert_eq!(json["exp"], 1256953732);
+        assert_eq!(json["iat"], 1256912345);
+        assert_eq!(json["nbf"], 1256912400);
+    }
+
+    #[tokio::test]
+    async fn rpt_permissions_carry_its_timestamps() {
+        let (rpts, refresh_tokens) = stores();
+        let config = IntrospectionConfig::default();
+
+        let request = introspection_request("rpt", None);
+        let response = introspect_token(&rpts, &refresh_tokens, &config, request).await.unwrap();
+        let permission = &response.body().permissions[0];
+        assert_eq!(permission.exp, Some(rpts["rpt"].exp));
+        assert_eq!(permission.iat, Some(rpts["rpt"].iat));
+        assert_eq!(permission.nbf, None);
+
+        let request = introspection_request("refresh", None);
+        let response = introspect_token(&rpts, &refresh_tokens, &config, request).await.unwrap();
+        assert_eq!(response.body().permissions[0].exp, None);
+    }
+
+    #[tokio::test]
+    async fn falls_back_when_hint_is_wrong() {
+        let (rpts, refresh_tokens) = stores();
+
+        let request = introspection_request("rpt", Some("refresh_token"));
+        let response = introspect_token(&rpts, &refresh_tokens, &IntrospectionConfig::default(), request).await.unwrap().into_body();
+        assert!(response.active);
+        assert!(response.iat.is_some());
+
+        let request = introspection_request("refresh", Some("access_token"));
+        let response = introspect_token(&rpts, &refresh_tokens, &IntrospectionConfig::default(), request).await.unwrap().into_body();
+        assert!(response.active);
+        assert!(response.iat.is_none());
+    }
+
+    #[tokio::test]
+    async fn introspects_without_or_with_unknown_hint() {
+        let (rpts, refresh_tokens) = stores();
+
+        for hint in [None, Some("pairwise_subject")] {
+            let request = introspection_request("refresh", hint);
+            let response = introspect_token(&rpts, &refresh_tokens, &IntrospectionConfig::default(), request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(response.into_body().active);
+
+            let request = introspection_request("unknown", hint);
+            let response = introspect_token(&rpts, &refresh_tokens, &IntrospectionConfig::default(), request).await.unwrap().into_body();
+            assert!(!response.active);
+        }
+    }
+
+    #[tokio::test]
+    async fn active_token_is_cacheable_until_it_expires() {
+        let (rpts, refresh_tokens) = stores();
+
+        let config = IntrospectionConfig { max_cache_age: Duration::from_secs(3600), ..IntrospectionConfig::default() };
+        let request = introspection_request("rpt", None);
+        let response = introspect_token(&rpts, &refresh_tokens, &config, request).await.unwrap();
+        let cache_control = response.headers()["Cache-Control"].to_str().unwrap();
+        let max_age: i64 = cache_control.strip_prefix("max-age=").unwrap().parse().unwrap();
+        assert!((58..=60).contains(&max_age), "max-age was {max_age}");
+
+        let config = IntrospectionConfig { max_cache_age: Duration::from_secs(10), ..IntrospectionConfig::default() };
+        let request = introspection_request("rpt", None);
+        let response = introspect_token(&rpts, &refresh_tokens, &config, request).await.unwrap();
+        assert_eq!(response.headers()["Cache-Control"], "max-age=10");
+    }
+
+    #[tokio::test]
+    async fn inactive_token_is_not_cached() {
+        let (rpts, refresh_tokens) = stores();
 
+        let request = introspection_request("unknown", None);
+        let response =
+            introspect_token(&rpts, &refresh_tokens, &IntrospectionConfig::default(), request).await.unwrap();
+        assert_eq!(response.headers()["Cache-Control"], "no-store");
+    }
+
+    fn batch_request(tokens: &[&str]) -> Request<Vec<RptValue>> {
+        let body = tokens.iter().map(|&token| RptValue::from(token)).collect();
+        return Request::builder().method(Method::POST).uri("/introspect/batch").body(body).unwrap();
+    }
+
+    #[tokio::test]
+    async fn introspects_a_mixed_batch_in_order() {
+        let (rpts, refresh_tokens) = stores();
+        let config = IntrospectionConfig { max_batch_size: Some(10), ..IntrospectionConfig::default() };
+
+        let request = batch_request(&["rpt", "unknown", "refresh", "rpt"]);
+        let response = introspect_tokens(&rpts, &refresh_tokens, &config, request).await.unwrap();
+        assert_eq!(response.headers()["Cache-Control"], "no-store");
+        let body = serde_json::to_value(response.body()).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 4);
+        assert_eq!(body[0]["active"], true);
+        assert!(body[0]["iat"].is_number());
+        assert_eq!(body[1], serde_json::json!({ "active": false }));
+        assert_eq!(body[2]["active"], true);
+        assert_eq!(body[2]["permissions"][0]["resource_id"], "photo");
+        assert_eq!(body[3], body[0]);
+
+        let request = batch_request(&["rpt", "refresh"]);
+        let response = introspect_tokens(&rpts, &refresh_tokens, &config, request).await.unwrap();
+        assert!(response.headers()["Cache-Control"].to_str().unwrap().starts_with("max-age="));
+    }
+
+    #[tokio::test]
+    async fn batches_are_opt_in_and_bounded() {
+        let (rpts, refresh_tokens) = stores();
+
+        let config = IntrospectionConfig::default();
+        let error = introspect_tokens(&rpts, &refresh_tokens, &config, batch_request(&["rpt"])).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::NOT_FOUND);
+
+        let config = IntrospectionConfig { max_batch_size: Some(2), ..IntrospectionConfig::default() };
+        for tokens in [&[][..], &["rpt", "refresh", "unknown"][..]] {
+            let error = introspect_tokens(&rpts, &refresh_tokens, &config, batch_request(tokens)).await.unwrap_err();
+            assert_eq!(error.body().error_code, "invalid_request");
+        }
+    }
 
 }
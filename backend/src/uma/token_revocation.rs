@@ -0,0 +1,155 @@
+//! https://datatracker.ietf.org/doc/html/rfc7009
+//!
+//! The revocation endpoint, as advertised in the authorization server's discovery document, allows a client to notify
+//! the authorization server that a previously obtained RPT or refresh token is no longer needed, so that it can be
+//! invalidated before it expires. A revoked token introspects as inactive from then on.
+
+use crate::storage::KeyValueStore;
+use http::{Method, Request, Response, StatusCode};
+use serde::Deserialize;
+use std::result;
+use tracing::field::Empty;
+use tracing::{instrument, Span};
+
+use super::errors::{catch_errors, ErrorMessage, UNSUPPORTED_METHOD_TYPE};
+use super::token::{IssuedRpt, RefreshGrant};
+use super::token_introspection::TokenTypeHint;
+
+/// https://datatracker.ietf.org/doc/html/rfc7009#section-2.1
+///
+/// The client requests the revocation of a particular token by making an HTTP POST request to the token revocation
+/// endpoint, with parameters sent as "application/x-www-form-urlencoded" data.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RevocationRequest {
+    /// REQUIRED. The token that the client wants to get revoked.
+    pub token: String,
+
+    /// OPTIONAL. A hint about the type of the token submitted for revocation.
+    pub token_type_hint: Option<String>,
+}
+
+type AccessTokenStore = dyn KeyValueStore<Key = String, Value = IssuedRpt>;
+type RefreshTokenStore = dyn KeyValueStore<Key = String, Value = RefreshGrant>;
+type Result<T> = result::Result<Response<T>, Response<ErrorMessage>>;
+
+/// https://datatracker.ietf.org/doc/html/rfc7009#section-2.2
+///
+/// The authorization server responds with HTTP status code 200 if the token has been revoked successfully or if the
+/// client submitted an invalid token, since the client cannot handle such an error in a reasonable way and the purpose
+/// of the revocation request, invalidating the particular token, is already achieved.
+///
+/// As with introspection, the store named by the token type hint is searched first and the other one after a miss.
+/// Revoking a refresh token also revokes the RPT it was issued with, as both are based on the same grant; revoking an
+/// RPT leaves its refresh token usable.
+#[instrument(skip_all, fields(method = %request.method(), revoked = Empty, outcome = Empty))]
+pub async fn revoke_token(
+    rpts: &mut AccessTokenStore,
+    refresh_tokens: &mut RefreshTokenStore,
+    request: Request<RevocationRequest>,
+) -> Result<()> {
+    if (request.method() != Method::POST) {
+        return Err(UNSUPPORTED_METHOD_TYPE.with_allowed_methods(&[Method::POST]).into());
+    }
+
+    let RevocationRequest { token, token_type_hint } = request.into_body();
+
+    let revoked = match TokenTypeHint::parse(token_type_hint.as_deref()) {
+        TokenTypeHint::AccessToken => rpts.del(&token).is_some() || revoke_refresh_token(rpts, refresh_tokens, &token),
+        TokenTypeHint::RefreshToken => revoke_refresh_token(rpts, refresh_tokens, &token) || rpts.del(&token).is_some(),
+    };
+    Span::current().record("revoked", revoked);
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Cache-Control", "no-store")
+        .body(());
+
+    return catch_errors(response);
+}
+
+fn revoke_refresh_token(rpts: &mut AccessTokenStore, refresh_tokens: &mut RefreshTokenStore, token: &String) -> bool {
+    let Some(grant) = refresh_tokens.del(token) else {
+        return false;
+    };
+    rpts.del(&grant.rpt);
+    return true;
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::ids::UuidGenerator;
+    use crate::uma::token::{issue_requesting_party_token, GrantedPermission, TokenConfig};
+    use crate::uma::token_introspection::{introspect_token, IntrospectionConfig, IntrospectionRequest};
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    fn revocation(token: &str, token_type_hint: Option<&str>) -> Request<RevocationRequest> {
+        let body = RevocationRequest {
+            token: token.to_string(),
+            token_type_hint: token_type_hint.map(str::to_string),
+        };
+        Request::builder().method(Method::POST).uri("/revoke").body(body).unwrap()
+    }
+
+    async fn is_active(rpts: &AccessTokenStore, refresh_tokens: &RefreshTokenStore, token: &str) -> bool {
+        let body = IntrospectionRequest { token: token.into(), token_type_hint: None };
+        let request = Request::builder().method(Method::POST).uri("/introspect").body(body).unwrap();
+        let response = introspect_token(rpts, refresh_tokens, &IntrospectionConfig::default(), request).await.unwrap();
+        return response.body().active;
+    }
+
+    #[tokio::test]
+    async fn revoked_rpt_introspects_inactive() {
+        let mut rpts = HashMap::new();
+        let mut refresh_tokens = HashMap::new();
+        let config = TokenConfig { refresh_token_lifetime: Some(Duration::from_secs(60 * 60)), ..TokenConfig::default() };
+        let permissions = vec![GrantedPermission::new("photo".to_string(), vec!["view".to_string()])];
+        let issued = issue_requesting_party_token(&mut rpts, &mut refresh_tokens, &UuidGenerator, &config, permissions);
+        assert!(is_active(&rpts, &refresh_tokens, &issued.access_token).await);
+
+        let response = revoke_token(&mut rpts, &mut refresh_tokens, revocation(&issued.access_token, None)).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!is_active(&rpts, &refresh_tokens, &issued.access_token).await);
+        assert!(is_active(&rpts, &refresh_tokens, issued.refresh_token.as_deref().unwrap()).await);
+    }
+
+    #[tokio::test]
+    async fn revoked_refresh_token_takes_its_rpt_along() {
+        let mut rpts = HashMap::new();
+        let mut refresh_tokens = HashMap::new();
+        let config = TokenConfig { refresh_token_lifetime: Some(Duration::from_secs(60 * 60)), ..TokenConfig::default() };
+        let issued = issue_requesting_party_token(&mut rpts, &mut refresh_tokens, &UuidGenerator, &config, vec![]);
+        let refresh_token = issued.refresh_token.unwrap();
+
+        // A wrong hint only changes the search order.
+        let request = revocation(&refresh_token, Some("access_token"));
+        revoke_token(&mut rpts, &mut refresh_tokens, request).await.unwrap();
+
+        assert!(!is_active(&rpts, &refresh_tokens, &refresh_token).await);
+        assert!(!is_active(&rpts, &refresh_tokens, &issued.access_token).await);
+    }
+
+    #[tokio::test]
+    async fn unknown_token_is_revoked_all_the_same() {
+        let mut rpts = HashMap::new();
+        let mut refresh_tokens = HashMap::new();
+
+        for hint in [None, Some("refresh_token"), Some("id_token")] {
+            let request = revocation("2YotnFZFEjr1zCsicMWpAA", hint);
+            let response = revoke_token(&mut rpts, &mut refresh_tokens, request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let request = Request::builder()
+            .method(Method::GET)
+            .uri("/revoke")
+            .body(RevocationRequest { token: "2YotnFZFEjr1zCsicMWpAA".to_string(), token_type_hint: None })
+            .unwrap();
+        let error = revoke_token(&mut rpts, &mut refresh_tokens, request).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(error.headers()["allow"], "POST");
+    }
+}
@@ -0,0 +1,7 @@
+//! Identifiers of different kinds are not interchangeable: passing one where another is expected does not compile.
+
+#[test]
+fn identifiers_do_not_mix() {
+    let cases = trybuild::TestCases::new();
+    cases.compile_fail("tests/ui/*.rs");
+}
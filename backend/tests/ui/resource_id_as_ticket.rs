@@ -0,0 +1,10 @@
+use oxiri::Iri;
+use uma_rs::ids::ResourceId;
+use uma_rs::uma::permission::permission_challenge;
+
+fn main() {
+    let resource_id = ResourceId::from("KX3A-39WE");
+    let as_uri = Iri::parse("https://as.example.com".to_string()).unwrap();
+
+    permission_challenge(&resource_id, &as_uri);
+}